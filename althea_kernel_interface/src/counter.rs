@@ -5,10 +5,48 @@ use std::net::IpAddr;
 use std::str::FromStr;
 
 use regex::Regex;
+use serde_json::Value;
 
 use failure::Error;
 
-#[derive(Debug, Eq, PartialEq)]
+/// The nftables table holding our counters
+const NFT_TABLE: &str = "rita";
+/// Every counter set holds a marker element recording its generation, the marker is keyed on
+/// this address and an interface named after the generation that no packet can match
+const MARKER_IP: &str = "::1";
+const MARKER_PREFIX: &str = "gen";
+
+/// The counters of one target as read from the kernel
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Counters {
+    /// The generation of the set the counters were read from, None if the set has no
+    /// marker, which means someone other than Rita created it. Counters only count up
+    /// within a generation, a different generation starts over from zero
+    pub generation: Option<u32>,
+    pub values: HashMap<(IpAddr, String), u64>,
+}
+
+fn marker_iface(generation: u32) -> String {
+    format!("{}{}", MARKER_PREFIX, generation)
+}
+
+/// The generation a marker interface records, None for anything else
+fn parse_marker(ip: &str, iface: &str) -> Option<u32> {
+    if ip != MARKER_IP || !iface.starts_with(MARKER_PREFIX) {
+        return None;
+    }
+    iface[MARKER_PREFIX.len()..].parse().ok()
+}
+
+/// How the kernel counts traffic for billing. Older systems count with ipset and iptables,
+/// newer OpenWrt releases only ship nftables
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CounterBackend {
+    Ipset,
+    Nftables,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub enum FilterTarget {
     Input,
     Output,
@@ -40,6 +78,23 @@ impl FilterTarget {
             &FilterTarget::ForwardOutput | &FilterTarget::ForwardInput => "FORWARD",
         }
     }
+
+    /// The nftables hook our chain for this target attaches to
+    pub fn hook(&self) -> &str {
+        match self {
+            &FilterTarget::Input => "input",
+            &FilterTarget::Output => "output",
+            &FilterTarget::ForwardOutput | &FilterTarget::ForwardInput => "forward",
+        }
+    }
+
+    /// The nftables expression for the interface we key the counters on
+    pub fn nft_interface(&self) -> &str {
+        match self {
+            &FilterTarget::Input | &FilterTarget::ForwardInput => "iifname",
+            &FilterTarget::Output | &FilterTarget::ForwardOutput => "oifname",
+        }
+    }
 }
 
 #[test]
@@ -66,35 +121,121 @@ fn test_filter_table_table() {
     assert_eq!(FilterTarget::ForwardInput.table(), "FORWARD");
 }
 
-fn parse_ipset(input: &str) -> Result<HashMap<(IpAddr, String), u64>, Error> {
+fn parse_ipset(input: &str) -> Result<Counters, Error> {
     lazy_static! {
         static ref RE: Regex =
             Regex::new(r"(?m)^add \S+ ([a-f0-9:]+),(wg\d+) packets (\d+) bytes (\d+)")
                 .expect("Unable to compile regular expression");
+        static ref MARKER_RE: Regex = Regex::new(r"(?m)^add \S+ ([a-f0-9:]+),(\S+)")
+            .expect("Unable to compile regular expression");
     }
-    let mut map = HashMap::new();
+    let mut counters = Counters::default();
 
     // example line `add aa fd00::1,wg0 packets 28 bytes 2212`
 
     for caps in RE.captures_iter(input) {
-        map.insert(
+        counters.values.insert(
             (IpAddr::from_str(&caps[1])?, String::from(&caps[2])),
             caps[4].parse::<u64>()? + caps[3].parse::<u64>()? * 40,
         );
     }
-    Ok(map)
+    // the marker `add aa ::1,gen3 packets 0 bytes 0`
+    counters.generation = MARKER_RE
+        .captures_iter(input)
+        .filter_map(|caps| parse_marker(&caps[1], &caps[2]))
+        .max();
+    Ok(counters)
+}
+
+/// Only tunnels are billed, mirrors the `wg\d+` the ipset regex matches
+fn is_tunnel(iface: &str) -> bool {
+    iface.len() > 2 && iface.starts_with("wg") && iface[2..].chars().all(|c| c.is_ascii_digit())
+}
+
+/// Parses the output of `nft -j list set`, every element of our sets is a destination and
+/// interface with a counter apart from the generation marker
+fn parse_nft_set(input: &str) -> Result<Counters, Error> {
+    let output: Value = serde_json::from_str(input)?;
+    let mut counters = Counters::default();
+
+    let objects = match output["nftables"].as_array() {
+        Some(objects) => objects,
+        None => bail!("No nftables objects in {}", input),
+    };
+    for set in objects.iter().filter_map(|object| object.get("set")) {
+        let elems = match set["elem"].as_array() {
+            Some(elems) => elems,
+            // nft leaves out elem for an empty set
+            None => continue,
+        };
+        for elem in elems {
+            // elements with a counter are wrapped, the marker we added ourselves has none
+            let (elem, val) = match elem.get("elem") {
+                Some(elem) => (elem, &elem["val"]),
+                None => (elem, elem),
+            };
+            let (ip, iface) = match (val["concat"][0].as_str(), val["concat"][1].as_str()) {
+                (Some(ip), Some(iface)) => (ip, iface),
+                _ => bail!("Unexpected nftables set element {}", elem),
+            };
+            if let Some(generation) = parse_marker(ip, iface) {
+                counters.generation = counters.generation.max(Some(generation));
+                continue;
+            }
+            if !is_tunnel(iface) {
+                continue;
+            }
+            let (packets, bytes) = match (
+                elem["counter"]["packets"].as_u64(),
+                elem["counter"]["bytes"].as_u64(),
+            ) {
+                (Some(packets), Some(bytes)) => (packets, bytes),
+                _ => bail!("No counter on nftables set element {}", elem),
+            };
+            counters.values.insert(
+                (IpAddr::from_str(ip)?, iface.to_string()),
+                bytes + packets * 40,
+            );
+        }
+    }
+    Ok(counters)
+}
+
+#[test]
+fn test_parse_nft_set() {
+    use std::net::Ipv6Addr;
+    // captured from `nft -j list set ip6 rita rita_fwd_output`
+    let data = r#"{"nftables": [{"metainfo": {"version": "0.9.2", "release_name": "Scram", "json_schema_version": 1}}, {"set": {"family": "ip6", "name": "rita_fwd_output", "table": "rita", "type": ["ipv6_addr", "ifname"], "handle": 4, "size": 65535, "flags": ["dynamic"], "elem": [{"concat": ["::1", "gen3"]}, {"elem": {"val": {"concat": ["fd00::1337:1e", "wg42"]}, "counter": {"packets": 123456789, "bytes": 987654321}}}, {"elem": {"val": {"concat": ["fd00::1337:1f", "wg0"]}, "counter": {"packets": 3, "bytes": 228}}}, {"elem": {"val": {"concat": ["fd00::1337:20", "br-lan"]}, "counter": {"packets": 5, "bytes": 380}}}]}}]}"#;
+    let result = parse_nft_set(data).expect("Unable to parse nft output");
+    assert_eq!(result.generation, Some(3));
+    assert_eq!(result.values.len(), 2);
+    let value = result
+        .values
+        .get(&(
+            IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0x1337, 0x1e)),
+            "wg42".into(),
+        ))
+        .expect("Unable to find key");
+    assert_eq!(value, &(987654321u64 + 123456789u64 * 40));
+
+    // a set nothing has been added to yet, not even a marker
+    let data = r#"{"nftables": [{"metainfo": {"version": "0.9.2", "release_name": "Scram", "json_schema_version": 1}}, {"set": {"family": "ip6", "name": "rita_input", "table": "rita", "type": ["ipv6_addr", "ifname"], "handle": 1, "size": 65535, "flags": ["dynamic"]}}]}"#;
+    assert_eq!(parse_nft_set(data).unwrap(), Counters::default());
 }
 
 #[test]
 fn test_parse_ipset() {
     use std::net::Ipv6Addr;
     let data = r#"
+add asdf ::1,gen12 packets 0 bytes 0
 add asdf 1234:5678:9801:2345:6789:0123:4567:8901,wg42 packets 123456789 bytes 987654321
 add zxcv 1234:5678:9801:2345:6789:0123:4567:8902,wg0 packets 123456789 bytes 987654320
 "#;
     let result = parse_ipset(data);
     match result {
         Ok(result) => {
+            assert_eq!(result.generation, Some(12));
+            let result = result.values;
             let addr1 = Ipv6Addr::new(
                 0x1234, 0x5678, 0x9801, 0x2345, 0x6789, 0x0123, 0x4567, 0x8901,
             );
@@ -118,8 +259,235 @@ add zxcv 1234:5678:9801:2345:6789:0123:4567:8902,wg0 packets 123456789 bytes 987
     }
 }
 
+/// Turns counter values into the traffic counted since `last`, which is then updated to the
+/// current values. Elements are only ever added to a set, never removed or reset, so within a
+/// generation a counter only goes up. A set of another generation was swapped in since
+/// `last` and counted up from zero, so all of it is new traffic. As long as every successful
+/// read, including the final read of a set that was swapped out, is passed through here every
+/// byte is counted exactly once
+pub fn counter_deltas(last: &mut Counters, current: Counters) -> HashMap<(IpAddr, String), u64> {
+    let reset = last.generation != current.generation;
+    if reset {
+        trace!(
+            "Counter generation went from {:?} to {:?}, counting from zero",
+            last.generation,
+            current.generation
+        );
+    }
+    let mut deltas = HashMap::new();
+    for (key, value) in current.values.iter() {
+        let delta = match last.values.get(key) {
+            Some(previous) if !reset && previous <= value => value - previous,
+            Some(previous) if !reset => {
+                error!(
+                    "Counter {:?} went from {} to {} without a new generation",
+                    key, previous, value
+                );
+                *value
+            }
+            _ => *value,
+        };
+        if delta > 0 {
+            deltas.insert(key.clone(), delta);
+        }
+    }
+    *last = current;
+    deltas
+}
+
+#[test]
+fn test_counter_deltas_conserve_traffic() {
+    use std::net::Ipv6Addr;
+    let a = (
+        IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1)),
+        "wg0".to_string(),
+    );
+    let b = (
+        IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2)),
+        "wg1".to_string(),
+    );
+
+    enum Event {
+        Nothing,
+        /// the read fails, the traffic shows up in the next read
+        FailedRead,
+        /// Rita swaps in a new generation after reading, with some traffic landing in the
+        /// old set between the read and the swap
+        Swap,
+        /// someone else replaces the set with one without a marker before the traffic
+        Recreate,
+    }
+
+    // what the kernel counts between reads and what happens to the set
+    let rounds = vec![
+        (100, 0, Event::Nothing),
+        (50, 700, Event::Swap),
+        (0, 0, Event::Nothing),
+        (25, 10, Event::Nothing),
+        (3000, 1, Event::FailedRead),
+        (5, 5, Event::Nothing),
+        // more than was counted before the set was replaced
+        (5000, 7, Event::Recreate),
+        (7, 7, Event::Nothing),
+        (1, 2, Event::Swap),
+        (9, 9, Event::Nothing),
+    ];
+
+    fn count(counters: &mut Counters, key: &(IpAddr, String), bytes: u64) {
+        if bytes > 0 {
+            *counters.values.entry(key.clone()).or_insert(0) += bytes;
+        }
+    }
+
+    let mut kernel = Counters {
+        generation: Some(0),
+        values: HashMap::new(),
+    };
+    let mut last = kernel.clone();
+    let (mut sent_a, mut sent_b) = (0, 0);
+    let (mut billed_a, mut billed_b) = (0, 0);
+    let mut bill = |deltas: HashMap<(IpAddr, String), u64>| {
+        billed_a += deltas.get(&a).cloned().unwrap_or(0);
+        billed_b += deltas.get(&b).cloned().unwrap_or(0);
+    };
+    for (traffic_a, traffic_b, event) in rounds {
+        if let Event::Recreate = event {
+            kernel = Counters::default();
+        }
+        count(&mut kernel, &a, traffic_a);
+        count(&mut kernel, &b, traffic_b);
+        sent_a += traffic_a;
+        sent_b += traffic_b;
+
+        match event {
+            Event::FailedRead => continue,
+            Event::Swap => {
+                bill(counter_deltas(&mut last, kernel.clone()));
+                count(&mut kernel, &a, 3);
+                sent_a += 3;
+                let generation = kernel.generation.map(|generation| generation + 1);
+                let old = kernel;
+                kernel = Counters {
+                    generation,
+                    values: HashMap::new(),
+                };
+                bill(counter_deltas(&mut last, old));
+                last = kernel.clone();
+            }
+            Event::Nothing | Event::Recreate => bill(counter_deltas(&mut last, kernel.clone())),
+        }
+    }
+    assert_eq!(billed_a, sent_a);
+    assert_eq!(billed_b, sent_b);
+}
+
 impl dyn KernelInterface {
-    pub fn init_counter(&self, target: &FilterTarget) -> Result<(), Error> {
+    /// Picks the counter backend for this system, ipset if it's installed as that's what
+    /// we've always used and nftables otherwise
+    pub fn detect_counter_backend(&self) -> Result<CounterBackend, Error> {
+        match self.run_command("ipset", &["-v"]) {
+            Ok(ref output) if output.status.success() => return Ok(CounterBackend::Ipset),
+            _ => {}
+        }
+        match self.run_command("nft", &["--version"]) {
+            Ok(ref output) if output.status.success() => Ok(CounterBackend::Nftables),
+            _ => bail!("Neither ipset nor nftables is available to count traffic"),
+        }
+    }
+
+    /// Sets up counting for a target with a fresh, empty set of generation 0. Whatever was
+    /// counted before is dropped, it may already have been billed by a previous run
+    pub fn init_counter(
+        &self,
+        backend: CounterBackend,
+        target: &FilterTarget,
+    ) -> Result<(), Error> {
+        match backend {
+            CounterBackend::Ipset => self.init_ipset_counter(target),
+            CounterBackend::Nftables => self.init_nft_counter(target),
+        }
+    }
+
+    /// Reads the counters for a target. The counters are never reset, they count up from
+    /// when an entry is first added, so reading can't race with traffic being counted. Use
+    /// `counter_deltas` to turn them into traffic since the last read.
+    ///
+    /// `generation` is the generation we expect to be counting, nftables can't swap sets so
+    /// every generation has a set of its own and this picks which to read. ipset always
+    /// reads the set that is counting
+    pub fn read_counters(
+        &self,
+        backend: CounterBackend,
+        target: &FilterTarget,
+        generation: u32,
+    ) -> Result<Counters, Error> {
+        match backend {
+            CounterBackend::Ipset => self.read_ipset(target.set_name()),
+            CounterBackend::Nftables => self.read_nft_set(&nft_set_name(target, generation)),
+        }
+    }
+
+    /// Atomically replaces the set counting a target with an empty one of `generation` and
+    /// returns the final counters of the old one, including anything counted since the last
+    /// read. Sets only grow, so this has to happen every now and then to drop destinations we
+    /// no longer talk to before the set is full.
+    ///
+    /// An error means the old set is still counting. If only reading the old set fails the
+    /// new set is counting and None is returned, what the old set counted since the last read
+    /// is lost
+    pub fn rotate_counters(
+        &self,
+        backend: CounterBackend,
+        target: &FilterTarget,
+        generation: u32,
+    ) -> Result<Option<Counters>, Error> {
+        let (old, delete) = match backend {
+            CounterBackend::Ipset => {
+                let old = ipset_spare_name(target);
+                self.swap_in_ipset(target, generation)?;
+                let read = self.read_ipset(&old);
+                (read, self.run_ipset(&["destroy", &old]).map(|_| ()))
+            }
+            CounterBackend::Nftables => {
+                let old = nft_set_name(target, generation.wrapping_sub(1));
+                self.swap_in_nft_set(target, generation)?;
+                let read = self.read_nft_set(&old);
+                (
+                    read,
+                    self.run_nft(&["delete", "set", "ip6", NFT_TABLE, &old])
+                        .map(|_| ()),
+                )
+            }
+        };
+        if let Err(e) = delete {
+            warn!("Failed to delete the old {:?} counters {:?}", target, e);
+        }
+        match old {
+            Ok(old) => Ok(Some(old)),
+            Err(e) => {
+                error!(
+                    "Failed to read the old {:?} counters, traffic since the last read is lost {:?}",
+                    target, e
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    /// Runs an ipset command, ipset exits non zero when it rejects a command
+    fn run_ipset(&self, args: &[&str]) -> Result<Vec<u8>, Error> {
+        let output = self.run_command("ipset", args)?;
+        if !output.status.success() {
+            bail!(
+                "ipset {:?} failed {}",
+                args,
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        Ok(output.stdout)
+    }
+
+    fn init_ipset_counter(&self, target: &FilterTarget) -> Result<(), Error> {
         self.run_command(
             "ipset",
             &[
@@ -151,115 +519,211 @@ impl dyn KernelInterface {
                 &format!("dst,{}", target.interface()),
             ],
         )?;
+        self.swap_in_ipset(target, 0)?;
+        self.run_ipset(&["destroy", &ipset_spare_name(target)])?;
         Ok(())
     }
 
-    pub fn read_counters(
-        &self,
-        target: &FilterTarget,
-    ) -> Result<HashMap<(IpAddr, String), u64>, Error> {
-        self.run_command(
-            "ipset",
-            &[
-                "create",
-                &format!("tmp_{}", target.set_name()),
-                "hash:net,iface",
-                "family",
-                "inet6",
-                "counters",
-            ],
-        )?;
-
-        self.run_command(
-            "ipset",
-            &[
-                "swap",
-                &format!("tmp_{}", target.set_name()),
-                target.set_name(),
-            ],
-        )?;
+    /// Builds an empty set of `generation` and swaps it with the one the iptables rule
+    /// counts into, which ipset does atomically. The old set is left under the spare name
+    fn swap_in_ipset(&self, target: &FilterTarget, generation: u32) -> Result<(), Error> {
+        let spare = ipset_spare_name(target);
+        // left over if we failed half way through last time
+        let _ = self.run_command("ipset", &["destroy", &spare]);
+        self.run_ipset(&[
+            "create",
+            &spare,
+            "hash:net,iface",
+            "family",
+            "inet6",
+            "counters",
+        ])?;
+        self.run_ipset(&[
+            "add",
+            &spare,
+            &format!("{},{}", MARKER_IP, marker_iface(generation)),
+        ])?;
+        self.run_ipset(&["swap", target.set_name(), &spare])?;
+        Ok(())
+    }
 
-        let output = self.run_command("ipset", &["save", &format!("tmp_{}", target.set_name())])?;
-        let res = parse_ipset(&String::from_utf8(output.stdout)?);
+    fn read_ipset(&self, name: &str) -> Result<Counters, Error> {
+        let output = self.run_ipset(&["save", name])?;
+        let res = parse_ipset(&String::from_utf8(output)?);
         trace!("ipset parsed into {:?}", res);
+        res
+    }
+
+    /// Runs an nft command, nft exits non zero when it rejects a command
+    fn run_nft(&self, args: &[&str]) -> Result<Vec<u8>, Error> {
+        let output = self.run_command("nft", args)?;
+        if !output.status.success() {
+            bail!(
+                "nft {:?} failed {}",
+                args,
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        Ok(output.stdout)
+    }
 
-        self.run_command("ipset", &["destroy", &format!("tmp_{}", target.set_name())])?;
+    /// Each target gets its own chain on the target's hook with a single rule adding every
+    /// packet to a dynamic set keyed by destination and interface, the set keeps a counter
+    /// per element. Every step is safe to repeat so a restarted Rita picks up where it left off
+    fn init_nft_counter(&self, target: &FilterTarget) -> Result<(), Error> {
+        let name = target.set_name();
+        self.run_nft(&["add", "table", "ip6", NFT_TABLE])?;
+        self.run_nft(&[
+            "add",
+            "chain",
+            "ip6",
+            NFT_TABLE,
+            name,
+            "{",
+            "type",
+            "filter",
+            "hook",
+            target.hook(),
+            "priority",
+            "0",
+            ";",
+            "}",
+        ])?;
+        // a set can't be deleted while a rule uses it
+        self.run_nft(&["flush", "chain", "ip6", NFT_TABLE, name])?;
+        for generation in 0..2 {
+            let set = nft_set_name(target, generation);
+            // fails if there is no such set
+            let _ = self.run_command("nft", &["delete", "set", "ip6", NFT_TABLE, &set]);
+        }
+        self.swap_in_nft_set(target, 0)
+    }
+
+    /// Adds an empty set of `generation` and points the rule at it, in a single nft command
+    /// so that it happens in one transaction. Generations alternate between two set names
+    fn swap_in_nft_set(&self, target: &FilterTarget, generation: u32) -> Result<(), Error> {
+        let name = target.set_name();
+        let set = nft_set_name(target, generation);
+        // left over if we failed half way through last time, it's not in use
+        let _ = self.run_command("nft", &["delete", "set", "ip6", NFT_TABLE, &set]);
+        let marker = marker_iface(generation);
+        let update = format!("@{}", set);
+        self.run_nft(&[
+            "add",
+            "set",
+            "ip6",
+            NFT_TABLE,
+            &set,
+            "{",
+            "type",
+            "ipv6_addr",
+            ".",
+            "ifname",
+            ";",
+            "flags",
+            "dynamic",
+            ";",
+            "size",
+            "65535",
+            ";",
+            "}",
+            ";",
+            "add",
+            "element",
+            "ip6",
+            NFT_TABLE,
+            &set,
+            "{",
+            MARKER_IP,
+            ".",
+            &marker,
+            "}",
+            ";",
+            "flush",
+            "chain",
+            "ip6",
+            NFT_TABLE,
+            name,
+            ";",
+            "add",
+            "rule",
+            "ip6",
+            NFT_TABLE,
+            name,
+            "update",
+            &update,
+            "{",
+            "ip6",
+            "daddr",
+            ".",
+            target.nft_interface(),
+            "counter",
+            "}",
+        ])?;
+        Ok(())
+    }
+
+    fn read_nft_set(&self, set: &str) -> Result<Counters, Error> {
+        let output = self.run_nft(&["-j", "list", "set", "ip6", NFT_TABLE, set])?;
+        let res = parse_nft_set(&String::from_utf8(output)?);
+        trace!("nft set parsed into {:?}", res);
         res
     }
 }
 
-#[test]
-fn test_init_counter() {
+/// The name the old ipset ends up under when a new generation is swapped in
+fn ipset_spare_name(target: &FilterTarget) -> String {
+    format!("{}_old", target.set_name())
+}
+
+fn nft_set_name(target: &FilterTarget, generation: u32) -> String {
+    format!("{}_{}", target.set_name(), generation % 2)
+}
+
+#[cfg(test)]
+fn mock_output(stdout: &[u8]) -> Result<std::process::Output, Error> {
     use std::os::unix::process::ExitStatusExt;
     use std::process::ExitStatus;
     use std::process::Output;
 
+    Ok(Output {
+        stdout: stdout.to_vec(),
+        stderr: b"".to_vec(),
+        status: ExitStatus::from_raw(0),
+    })
+}
+
+#[test]
+fn test_init_counter() {
     use crate::KI;
 
     let mut counter = 0;
 
     KI.set_mock(Box::new(move |program, args| {
         counter += 1;
-        match counter {
-            1 => {
-                assert_eq!(program, "ipset");
-                assert_eq!(
-                    args,
-                    vec![
-                        "create",
-                        "rita_input",
-                        "hash:net,iface",
-                        "family",
-                        "inet6",
-                        "counters",
-                    ]
-                );
-                Ok(Output {
-                    stdout: b"".to_vec(),
-                    stderr: b"".to_vec(),
-                    status: ExitStatus::from_raw(0),
-                })
-            }
+        let expected = match counter {
+            1 => "ipset create rita_input hash:net,iface family inet6 counters",
             2 => {
-                assert_eq!(program, "ip6tables");
-                assert_eq!(
-                    args,
-                    vec![
-                        "-w",
-                        "-C",
-                        "INPUT",
-                        "-m",
-                        "set",
-                        "!",
-                        "--match-set",
-                        "rita_input",
-                        "dst,src",
-                        "-j",
-                        "SET",
-                        "--add-set",
-                        "rita_input",
-                        "dst,src",
-                    ]
-                );
-                Ok(Output {
-                    stdout: b"".to_vec(),
-                    stderr: b"".to_vec(),
-                    status: ExitStatus::from_raw(0),
-                })
+                "ip6tables -w -C INPUT -m set ! --match-set rita_input dst,src -j SET --add-set \
+                 rita_input dst,src"
             }
-
+            3 => "ipset destroy rita_input_old",
+            4 => "ipset create rita_input_old hash:net,iface family inet6 counters",
+            5 => "ipset add rita_input_old ::1,gen0",
+            6 => "ipset swap rita_input rita_input_old",
+            7 => "ipset destroy rita_input_old",
             _ => panic!("Unexpected call {} {:?} {:?}", counter, program, args),
-        }
+        };
+        assert_eq!(format!("{} {}", program, args.join(" ")), expected);
+        mock_output(b"")
     }));
-    KI.init_counter(&FilterTarget::Input)
+    KI.init_counter(CounterBackend::Ipset, &FilterTarget::Input)
         .expect("Unable to init counter");
 }
+
 #[test]
 fn test_read_counters() {
     use std::net::Ipv6Addr;
-    use std::os::unix::process::ExitStatusExt;
-    use std::process::ExitStatus;
-    use std::process::Output;
 
     use crate::KI;
 
@@ -270,62 +734,97 @@ fn test_read_counters() {
         match counter {
             1 => {
                 assert_eq!(program, "ipset");
-                assert_eq!(
-                    args,
-                    vec![
-                        "create",
-                        "tmp_rita_input",
-                        "hash:net,iface",
-                        "family",
-                        "inet6",
-                        "counters",
-                    ]
-                );
-                Ok(Output {
-                    stdout: b"".to_vec(),
-                    stderr: b"".to_vec(),
-                    status: ExitStatus::from_raw(0),
-                })
-            }
-            2 => {
-                assert_eq!(program, "ipset");
-                assert_eq!(args, vec!["swap", "tmp_rita_input", "rita_input"]);
-                Ok(Output {
-                    stdout: b"".to_vec(),
-                    stderr: b"".to_vec(),
-                    status: ExitStatus::from_raw(0),
-                })
-            }
-            3 => {
-                assert_eq!(program, "ipset");
-                assert_eq!(args, vec!["save", "tmp_rita_input"]);
-                Ok(Output {
-                    stdout: b"
+                assert_eq!(args, vec!["save", "rita_input"]);
+                mock_output(
+                    b"
+add xxx ::1,gen3 packets 0 bytes 0
 add xxx fd00::dead:beef,wg42 packets 111 bytes 222
-"
-                    .to_vec(),
-                    stderr: b"".to_vec(),
-                    status: ExitStatus::from_raw(0),
-                })
+",
+                )
             }
-            4 => {
-                assert_eq!(program, "ipset");
-                assert_eq!(args, vec!["destroy", "tmp_rita_input"]);
-                Ok(Output {
-                    stdout: b"".to_vec(),
-                    stderr: b"".to_vec(),
-                    status: ExitStatus::from_raw(0),
-                })
+            _ => panic!("Unexpected call {} {:?} {:?}", counter, program, args),
+        }
+    }));
+    let result = KI
+        .read_counters(CounterBackend::Ipset, &FilterTarget::Input, 3)
+        .expect("Unable to read values");
+    assert_eq!(result.generation, Some(3));
+    assert_eq!(result.values.len(), 1);
+
+    let value = result
+        .values
+        .get(&(
+            IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0xdead, 0xbeef)),
+            "wg42".into(),
+        ))
+        .expect("Unable to find key");
+    assert_eq!(value, &(222u64 + 111u64 * 40));
+}
+
+#[test]
+fn test_rotate_counters() {
+    use crate::KI;
+
+    let mut counter = 0;
+
+    KI.set_mock(Box::new(move |program, args| {
+        counter += 1;
+        let expected = match counter {
+            1 => "ipset destroy rita_output_old",
+            2 => "ipset create rita_output_old hash:net,iface family inet6 counters",
+            3 => "ipset add rita_output_old ::1,gen8",
+            4 => "ipset swap rita_output rita_output_old",
+            5 => "ipset save rita_output_old",
+            6 => "ipset destroy rita_output_old",
+            _ => panic!("Unexpected call {} {:?} {:?}", counter, program, args),
+        };
+        assert_eq!(format!("{} {}", program, args.join(" ")), expected);
+        if counter == 5 {
+            mock_output(
+                b"
+add xxx ::1,gen7 packets 0 bytes 0
+add xxx fd00::dead:beef,wg42 packets 1 bytes 60
+",
+            )
+        } else {
+            mock_output(b"")
+        }
+    }));
+    let old = KI
+        .rotate_counters(CounterBackend::Ipset, &FilterTarget::Output, 8)
+        .expect("Unable to rotate")
+        .expect("Unable to read the old set");
+    assert_eq!(old.generation, Some(7));
+    assert_eq!(old.values.values().sum::<u64>(), 100);
+}
+
+#[test]
+fn test_read_nft_counters() {
+    use std::net::Ipv6Addr;
+
+    use crate::KI;
+
+    let mut counter = 0;
+
+    KI.set_mock(Box::new(move |program, args| {
+        counter += 1;
+        assert_eq!(program, "nft");
+        match counter {
+            1 => {
+                assert_eq!(args, vec!["-j", "list", "set", "ip6", "rita", "rita_input_1"]);
+                mock_output(br#"{"nftables": [{"metainfo": {"version": "0.9.2", "release_name": "Scram", "json_schema_version": 1}}, {"set": {"family": "ip6", "name": "rita_input_1", "table": "rita", "type": ["ipv6_addr", "ifname"], "handle": 1, "size": 65535, "flags": ["dynamic"], "elem": [{"concat": ["::1", "gen5"]}, {"elem": {"val": {"concat": ["fd00::dead:beef", "wg42"]}, "counter": {"packets": 111, "bytes": 222}}}]}}]}"#)
             }
             _ => panic!("Unexpected call {} {:?} {:?}", counter, program, args),
         }
     }));
     let result = KI
-        .read_counters(&FilterTarget::Input)
+        .read_counters(CounterBackend::Nftables, &FilterTarget::Input, 5)
         .expect("Unable to read values");
-    assert_eq!(result.len(), 1);
+    assert_eq!(result.generation, Some(5));
+    assert_eq!(result.values.len(), 1);
 
     let value = result
+        .values
         .get(&(
             IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0xdead, 0xbeef)),
             "wg42".into(),
@@ -333,3 +832,34 @@ add xxx fd00::dead:beef,wg42 packets 111 bytes 222
         .expect("Unable to find key");
     assert_eq!(value, &(222u64 + 111u64 * 40));
 }
+
+#[test]
+fn test_init_nft_counter() {
+    use crate::KI;
+
+    let mut counter = 0;
+
+    KI.set_mock(Box::new(move |program, args| {
+        counter += 1;
+        assert_eq!(program, "nft");
+        let expected = match counter {
+            1 => "add table ip6 rita",
+            2 => "add chain ip6 rita rita_fwd_output { type filter hook forward priority 0 ; }",
+            3 => "flush chain ip6 rita rita_fwd_output",
+            4 => "delete set ip6 rita rita_fwd_output_0",
+            5 => "delete set ip6 rita rita_fwd_output_1",
+            6 => "delete set ip6 rita rita_fwd_output_0",
+            7 => {
+                "add set ip6 rita rita_fwd_output_0 { type ipv6_addr . ifname ; flags dynamic ; \
+                 size 65535 ; } ; add element ip6 rita rita_fwd_output_0 { ::1 . gen0 } ; flush \
+                 chain ip6 rita rita_fwd_output ; add rule ip6 rita rita_fwd_output update \
+                 @rita_fwd_output_0 { ip6 daddr . oifname counter }"
+            }
+            _ => panic!("Unexpected call {} {:?} {:?}", counter, program, args),
+        };
+        assert_eq!(args.join(" "), expected);
+        mock_output(b"")
+    }));
+    KI.init_counter(CounterBackend::Nftables, &FilterTarget::ForwardOutput)
+        .expect("Unable to init counter");
+}
@@ -2,6 +2,8 @@ use super::{KernelInterface, KernelInterfaceError};
 
 use failure::Error;
 
+use ipnetwork::IpNetwork;
+
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
 use althea_types::WgKey;
@@ -16,7 +18,13 @@ impl dyn KernelInterface {
         local_ip: IpAddr,
         netmask: u8,
         rita_hello_port: u16,
+        ipv6_prefix: Option<IpNetwork>,
     ) -> Result<(), Error> {
+        // without a delegated prefix we have nowhere to route ipv6 from so it stays off the tunnel
+        let allowed_ips = match ipv6_prefix {
+            Some(_) => "0.0.0.0/0,::/0",
+            None => "0.0.0.0/0",
+        };
         self.run_command(
             "wg",
             &[
@@ -31,7 +39,7 @@ impl dyn KernelInterface {
                 "endpoint",
                 &format!("[{}]:{}", endpoint.ip(), endpoint.port()),
                 "allowed-ips",
-                "0.0.0.0/0",
+                allowed_ips,
                 "persistent-keepalive",
                 "5",
             ],
@@ -156,6 +164,41 @@ impl dyn KernelInterface {
         Ok(())
     }
 
+    /// Sends all internet bound ipv6 traffic into the exit tunnel, mesh traffic still takes
+    /// babel's more specific routes
+    pub fn set_ipv6_route_to_tunnel(&self) -> Result<(), Error> {
+        match self.run_command("ip", &["-6", "route", "del", "default"]) {
+            Err(e) => warn!("Failed to delete default ipv6 route {:?}", e),
+            _ => (),
+        };
+
+        let output =
+            self.run_command("ip", &["-6", "route", "add", "default", "dev", "wg_exit"])?;
+        if !output.stderr.is_empty() {
+            return Err(KernelInterfaceError::RuntimeError(format!(
+                "received error setting ipv6 route: {}",
+                String::from_utf8(output.stderr)?
+            ))
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// Hands the delegated prefix out on the lan, openwrt's odhcpd then advertises it to lan
+    /// clients. Returns true if the prefix changed and the network needs a restart
+    pub fn set_lan_ipv6_prefix(&self, prefix: &IpNetwork) -> Result<bool, Error> {
+        let prefix = prefix.to_string();
+        match self.get_uci_var("network.lan.ip6prefix") {
+            Ok(ref current) if *current == prefix => Ok(false),
+            _ => {
+                self.set_uci_var("network.lan.ip6prefix", &prefix)?;
+                self.uci_commit("network")?;
+                Ok(true)
+            }
+        }
+    }
+
     pub fn add_client_nat_rules(&self, lan_nic: &str) -> Result<(), Error> {
         self.add_iptables_rule(
             "iptables",
@@ -197,7 +240,33 @@ impl dyn KernelInterface {
                 "--clamp-mss-to-pmtu", //should be the same as --set-mss 1300
             ],
         )?;
-        //TODO ipv6 support
+
+        // ipv6 isn't translated, lan clients use addresses from the delegated prefix so we only
+        // need to forward it. Since those addresses are reachable from outside only replies are
+        // let back in
+        self.add_iptables_rule(
+            "ip6tables",
+            &[
+                "-A", "FORWARD", "-i", &lan_nic, "-o", "wg_exit", "-j", "ACCEPT",
+            ],
+        )?;
+        self.add_iptables_rule(
+            "ip6tables",
+            &[
+                "-A",
+                "FORWARD",
+                "-i",
+                "wg_exit",
+                "-o",
+                &lan_nic,
+                "-m",
+                "state",
+                "--state",
+                "RELATED,ESTABLISHED",
+                "-j",
+                "ACCEPT",
+            ],
+        )?;
 
         Ok(())
     }
@@ -6,11 +6,15 @@ use std::collections::HashSet;
 
 use failure::Error;
 
+use ipnetwork::IpNetwork;
+
 use std::net::IpAddr;
 
 #[derive(Debug)]
 pub struct ExitClient {
     pub internal_ip: IpAddr,
+    /// The client's delegated ipv6 prefix
+    pub internal_ipv6: Option<IpNetwork>,
     pub public_key: WgKey,
     pub mesh_ip: IpAddr,
     pub port: u16,
@@ -24,6 +28,7 @@ impl dyn KernelInterface {
         private_key_path: &str,
         local_ip: &IpAddr,
         netmask: u8,
+        local_ipv6: Option<IpNetwork>,
     ) -> Result<(), Error> {
         let command = "wg".to_string();
 
@@ -43,7 +48,10 @@ impl dyn KernelInterface {
             args.push("endpoint".into());
             args.push(format!("[{}]:{}", c.mesh_ip, c.port));
             args.push("allowed-ips".into());
-            args.push(format!("{}", c.internal_ip));
+            match c.internal_ipv6 {
+                Some(prefix) => args.push(format!("{},{}", c.internal_ip, prefix)),
+                None => args.push(format!("{}", c.internal_ip)),
+            }
             args.push("persistent-keepalive".into());
             args.push("5".into());
 
@@ -74,6 +82,15 @@ impl dyn KernelInterface {
             ],
         )?;
 
+        // with the whole subnet on wg_exit client prefixes are routed into the tunnel and
+        // wireguard picks the client by its allowed ips
+        if let Some(local_ipv6) = local_ipv6 {
+            let _output = self.run_command(
+                "ip",
+                &["address", "add", &local_ipv6.to_string(), "dev", "wg_exit"],
+            )?;
+        }
+
         let output = self.run_command("ip", &["link", "set", "dev", "wg_exit", "mtu", "1340"])?;
         if !output.stderr.is_empty() {
             return Err(KernelInterfaceError::RuntimeError(format!(
@@ -106,8 +123,17 @@ impl dyn KernelInterface {
                     if !self.has_flow(&addr, "wg_exit")? {
                         self.create_flow_by_ip("wg_exit", &addr)?
                     }
+                    // ipv6 traffic shares the class of the client's ipv4 address
+                    if let Some(IpNetwork::V6(prefix)) = c.internal_ipv6 {
+                        if !self.has_ipv6_flow(&addr, "wg_exit")? {
+                            self.create_flow_by_ipv6("wg_exit", &prefix, &addr)?
+                        }
+                    }
                 }
-                _ => panic!("Could not derive ipv4 addr for client! Corrupt DB!"),
+                _ => error!(
+                    "Client {} has no ipv4 address to shape its traffic by",
+                    c.public_key
+                ),
             }
         }
 
@@ -170,4 +196,75 @@ impl dyn KernelInterface {
 
         Ok(())
     }
+
+    /// Lets client ipv6 traffic through to the internet, with nat66 it's masqueraded behind the
+    /// exit's own address otherwise the subnet must be routed to us
+    pub fn setup_ipv6_forwarding(
+        &self,
+        external_interface: &str,
+        subnet: &IpNetwork,
+        nat66: bool,
+    ) -> Result<(), Error> {
+        if nat66 {
+            self.add_iptables_rule(
+                "ip6tables",
+                &[
+                    "-w",
+                    "-t",
+                    "nat",
+                    "-A",
+                    "POSTROUTING",
+                    "-s",
+                    &subnet.to_string(),
+                    "-o",
+                    external_interface,
+                    "-j",
+                    "MASQUERADE",
+                ],
+            )?;
+        }
+
+        self.add_iptables_rule(
+            "ip6tables",
+            &[
+                "-w",
+                "-t",
+                "filter",
+                "-A",
+                "FORWARD",
+                "-o",
+                external_interface,
+                "-i",
+                "wg_exit",
+                "-j",
+                "ACCEPT",
+            ],
+        )?;
+
+        // routed prefixes are reachable from outside, only replies are let in
+        self.add_iptables_rule(
+            "ip6tables",
+            &[
+                "-w",
+                "-t",
+                "filter",
+                "-A",
+                "FORWARD",
+                "-o",
+                "wg_exit",
+                "-i",
+                external_interface,
+                "-m",
+                "state",
+                "--state",
+                "RELATED,ESTABLISHED",
+                "-j",
+                "ACCEPT",
+            ],
+        )?;
+
+        self.run_command("sysctl", &["-w", "net.ipv6.conf.all.forwarding=1"])?;
+
+        Ok(())
+    }
 }
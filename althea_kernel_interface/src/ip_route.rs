@@ -72,12 +72,26 @@ impl dyn KernelInterface {
         Ok(())
     }
 
+    /// Pins the route to a manual peer to the default route we had before the exit tunnel took
+    /// it over, so that the tunnel to that peer does not end up routed through the exit.
+    /// Only ipv4 is routed over the exit tunnel, any other endpoint is left alone.
     pub fn manual_peers_route(
         &self,
         endpoint_ip: &IpAddr,
         settings_default_route: &mut Vec<String>,
     ) -> Result<(), Error> {
+        if !endpoint_ip.is_ipv4() {
+            return Ok(());
+        }
+
         self.update_settings_route(settings_default_route)?;
+        if settings_default_route.is_empty() {
+            trace!(
+                "No default route known, not pinning route to {}",
+                endpoint_ip
+            );
+            return Ok(());
+        }
 
         self.set_route(&IpRoute::ToAddr(*endpoint_ip), &settings_default_route)?;
         Ok(())
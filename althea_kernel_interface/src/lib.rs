@@ -37,7 +37,7 @@ mod traffic_control;
 mod udp_socket_table;
 pub mod wg_iface_counter;
 
-pub use crate::counter::FilterTarget;
+pub use crate::counter::{counter_deltas, CounterBackend, Counters, FilterTarget};
 pub use crate::create_wg_key::WgKeypair;
 pub use crate::exit_server_tunnel::ExitClient;
 
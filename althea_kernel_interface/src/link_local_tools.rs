@@ -141,6 +141,23 @@ impl dyn KernelInterface {
         }
         Err(KernelInterfaceError::RuntimeError("Interface not found".to_string()).into())
     }
+
+    /// Returns the name of the interface with the given index
+    pub fn get_iface_name(&self, index: u32) -> Result<String, Error> {
+        let links = String::from_utf8(self.run_command("ip", &["link"])?.stdout)?;
+
+        lazy_static! {
+            static ref RE: Regex =
+                Regex::new(r"([0-9]+): (.*?)(:|@)").expect("Unable to compile regular expression");
+        }
+
+        for caps in RE.captures_iter(&links) {
+            if caps[1].parse::<u32>()? == index {
+                return Ok(caps[2].to_string());
+            }
+        }
+        Err(KernelInterfaceError::RuntimeError("Interface not found".to_string()).into())
+    }
 }
 
 #[test]
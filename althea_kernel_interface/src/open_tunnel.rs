@@ -137,6 +137,46 @@ impl dyn KernelInterface {
         Ok(())
     }
 
+    /// Points the peer of an existing tunnel at a new endpoint, used when a neighbor's address
+    /// changes underneath an otherwise working tunnel
+    pub fn set_peer_endpoint(
+        &self,
+        interface: &str,
+        remote_pub_key: &WgKey,
+        endpoint: &SocketAddr,
+    ) -> Result<(), Error> {
+        let phy_name = match self.get_device_name(endpoint.ip()) {
+            Ok(phy_name) => Some(phy_name),
+            // link local endpoints are meaningless without an interface
+            Err(e) => {
+                if is_link_local(endpoint.ip()) {
+                    return Err(e);
+                }
+                None
+            }
+        };
+        let socket_connect_str = socket_to_string(endpoint, phy_name);
+        let output = self.run_command(
+            "wg",
+            &[
+                "set",
+                interface,
+                "peer",
+                &format!("{}", remote_pub_key),
+                "endpoint",
+                &socket_connect_str,
+            ],
+        )?;
+        if !output.stderr.is_empty() {
+            return Err(KernelInterfaceError::RuntimeError(format!(
+                "received error from wg command: {}",
+                String::from_utf8(output.stderr)?
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
     pub fn open_tunnel_listener(
         &self,
         interface: &String,
@@ -59,6 +59,51 @@ impl dyn KernelInterface {
         Ok(())
     }
 
+    /// Returns the latest handshake time for every peer on every WireGuard interface as
+    /// (interface name, peer key, handshake time), the time is None if there has never
+    /// been a handshake
+    pub fn get_latest_handshakes(&self) -> Result<Vec<(String, WgKey, Option<SystemTime>)>, Error> {
+        let output = self.run_command("wg", &["show", "all", "latest-handshakes"])?;
+        if !output.stderr.is_empty() {
+            return Err(KernelInterfaceError::RuntimeError(format!(
+                "received error from wg command: {}",
+                String::from_utf8(output.stderr)?
+            ))
+            .into());
+        }
+        let out = String::from_utf8(output.stdout)?;
+
+        let mut result = Vec::new();
+        for line in out.lines() {
+            let content: Vec<&str> = line.split('\t').collect();
+            if content.len() != 3 {
+                warn!("Could not parse handshake line {}", line);
+                continue;
+            }
+            let key = match content[1].parse() {
+                Ok(key) => key,
+                Err(e) => {
+                    warn!("Failed to parse WgKey {} with {:?}", content[1], e);
+                    continue;
+                }
+            };
+            let timestamp: u64 = match content[2].parse() {
+                Ok(timestamp) => timestamp,
+                Err(e) => {
+                    warn!("Failed to parse handshake time {} with {:?}", content[2], e);
+                    continue;
+                }
+            };
+            let handshake = if timestamp == 0 {
+                None
+            } else {
+                Some(UNIX_EPOCH + Duration::from_secs(timestamp))
+            };
+            result.push((content[0].to_string(), key, handshake));
+        }
+        Ok(result)
+    }
+
     /// Returns the number of clients that are active on the wg_exit tunnel
     pub fn get_wg_exit_clients_online(&self) -> Result<u32, Error> {
         let output = self.run_command("wg", &["show", "wg_exit", "latest-handshakes"])?;
@@ -151,3 +196,52 @@ fn test_get_wg_exit_clients_online() {
 
     assert_eq!(KI.get_wg_exit_clients_online().unwrap(), 1);
 }
+
+#[test]
+fn test_get_latest_handshakes() {
+    use crate::KI;
+
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::process::Output;
+
+    let mut counter = 0;
+
+    KI.set_mock(Box::new(move |program, args| {
+        assert_eq!(program, "wg");
+        counter += 1;
+
+        match counter {
+            1 => {
+                assert_eq!(args, &["show", "all", "latest-handshakes"]);
+                Ok(Output {
+                    stdout: b"wg0\t88gbNAZx7NoNK9hatYuDkeZOjQ8EBmJ8VBpcFhXPqHs=\t1536936247
+wg1\tW1BwNSC9ulTutCg53KIlo+z2ihkXao3sXHaBBpaCXEw=\t0
+wg2\tW1BwNSC9ulTutCg53KIlo+z2ihkXao3sXHaBBpaCXEw=\tnever
+wg_exit\t9jRr6euMHu3tBIsZyqxUmjbuKVVFZCBOYApOR2pLNkQ=\t1536936250
+"
+                    .to_vec(),
+                    stderr: b"".to_vec(),
+                    status: ExitStatus::from_raw(0),
+                })
+            }
+            _ => panic!("command called too many times"),
+        }
+    }));
+
+    let handshakes = KI.get_latest_handshakes().unwrap();
+    assert_eq!(handshakes.len(), 3);
+    assert_eq!(handshakes[0].0, "wg0");
+    assert_eq!(
+        handshakes[0].1,
+        "88gbNAZx7NoNK9hatYuDkeZOjQ8EBmJ8VBpcFhXPqHs="
+            .parse()
+            .unwrap()
+    );
+    assert_eq!(
+        handshakes[0].2,
+        Some(UNIX_EPOCH + Duration::from_secs(1536936247))
+    );
+    assert_eq!(handshakes[1].2, None);
+    assert_eq!(handshakes[2].0, "wg_exit");
+}
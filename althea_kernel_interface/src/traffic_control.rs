@@ -6,8 +6,12 @@
 
 use super::KernelInterface;
 use failure::Error;
+use ipnetwork::Ipv6Network;
 use std::net::Ipv4Addr;
 
+/// DSCP classes we may mark tunnel traffic with, lowest priority first
+const TUNNEL_DSCP_CLASSES: [&str; 2] = ["CS1", "CS6"];
+
 impl KernelInterface {
     /// Determines if the provided interface has a configured qdisc
     pub fn has_qdisc(&self, iface_name: &str) -> Result<bool, Error> {
@@ -38,6 +42,23 @@ impl KernelInterface {
         Ok(stdout.contains(&format!("1:{}", class_id)))
     }
 
+    /// Determines if the ipv6 filter for the class of the provided ipv4 address is assigned
+    pub fn has_ipv6_flow(&self, ip: &Ipv4Addr, iface_name: &str) -> Result<bool, Error> {
+        let class_id = self.get_class_id(&ip);
+        let result = self.run_command(
+            "tc",
+            &["filter", "show", "dev", iface_name, "protocol", "ipv6"],
+        )?;
+
+        if !result.status.success() {
+            let res = String::from_utf8(result.stderr)?;
+            bail!("Failed to check ipv6 filter for {}! {:?}", class_id, res);
+        }
+
+        let stdout = &String::from_utf8(result.stdout)?;
+        Ok(stdout.contains(&format!("1:{}", class_id)))
+    }
+
     /// Determines if the provided flow is assigned
     pub fn has_class(&self, ip: &Ipv4Addr, iface_name: &str) -> Result<bool, Error> {
         let class_id = self.get_class_id(ip);
@@ -118,23 +139,33 @@ impl KernelInterface {
 
     /// Creates a qdisc limit with the given bandwidth tuned for the correct rate
     /// this limit uses tbf which is classless and faster since we leave prioritization
-    /// to the fq_codel on the ingress and egress interfaces
+    /// to the fq_codel on the ingress and egress interfaces. An existing tbf limit is
+    /// changed in place so that updating it doesn't drop the packets queued in it
     pub fn set_classless_limit(&self, iface_name: &str, bw: u32) -> Result<(), Error> {
-        if self.has_qdisc(iface_name)? {
-            self.delete_qdisc(iface_name)?;
+        if bw == 0 {
+            bail!("Refusing to limit {} to 0kbit", iface_name);
         }
-
         // we need 1kbyte of burst cache per mbit of bandwidth to actually keep things
-        // moving
-        let burst = bw * 1000 as u32;
+        // moving, tc takes it as a u32 which caps the limit at u32::max_value() / 1000
+        let burst = match bw.checked_mul(1000) {
+            Some(burst) => burst,
+            None => bail!("Limit of {}kbit for {} is too large", bw, iface_name),
+        };
         // amount of time a packet can spend in the burst cache, 40ms
         let latency = 40u32;
 
+        let result = self.run_command("tc", &["qdisc", "show", "dev", iface_name])?;
+        let modifier = if String::from_utf8(result.stdout)?.contains("tbf") {
+            "change"
+        } else {
+            "replace"
+        };
+
         let output = self.run_command(
             "tc",
             &[
                 "qdisc",
-                "add",
+                modifier,
                 "dev",
                 iface_name,
                 "root",
@@ -158,6 +189,48 @@ impl KernelInterface {
         }
     }
 
+    /// Puts the cake qdisc of a physical interface in diffserv4 mode so that the DSCP classes
+    /// set by `set_tunnel_dscp` are honored when the link is congested, CS6 traffic goes in
+    /// the voice tin and CS1 traffic in the bulk tin. Only the tin mode of the existing cake is
+    /// changed, an interface with any other root qdisc is the operator's to manage and is left
+    /// alone with an error. Does nothing if it's already set up
+    pub fn set_diffserv_egress(&self, iface_name: &str) -> Result<(), Error> {
+        let result = self.run_command("tc", &["qdisc", "show", "dev", iface_name])?;
+        let stdout = String::from_utf8(result.stdout)?;
+        let root = stdout.lines().find(|line| line.contains(" root "));
+        match root {
+            Some(root) if root.starts_with("qdisc cake ") => {
+                if root.contains("diffserv4") {
+                    return Ok(());
+                }
+            }
+            _ => bail!(
+                "The root qdisc of {} isn't cake, not changing it for neighbor priorities",
+                iface_name
+            ),
+        }
+
+        let output = self.run_command(
+            "tc",
+            &[
+                "qdisc",
+                "change",
+                "dev",
+                iface_name,
+                "root",
+                "cake",
+                "diffserv4",
+            ],
+        )?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            let res = String::from_utf8(output.stderr)?;
+            bail!("Failed to set cake diffserv4 on {}! {:?}", iface_name, res);
+        }
+    }
+
     /// Creates the root limit on the wg_exit tunnel for the exit, under which all other classes
     /// operate
     pub fn create_root_classful_limit(&self, iface_name: &str) -> Result<(), Error> {
@@ -239,8 +312,8 @@ impl KernelInterface {
 
     /// Filters traffic from a given ipv4 address into the class that we are using
     /// to shape that traffic on the exit side, uses the last two octets of the ip
-    /// to generate a class id.
-    /// TODO when ipv6 exit support is added this will need to be revisited
+    /// to generate a class id. A client's ipv6 prefix is filtered into the same
+    /// class with `create_flow_by_ipv6`
     pub fn create_flow_by_ip(&self, iface_name: &str, ip: &Ipv4Addr) -> Result<(), Error> {
         let class_id = self.get_class_id(ip);
 
@@ -273,6 +346,98 @@ impl KernelInterface {
         }
     }
 
+    /// Filters traffic to a client's ipv6 prefix into the class of their ipv4 address, so
+    /// both share a single limit
+    pub fn create_flow_by_ipv6(
+        &self,
+        iface_name: &str,
+        prefix: &Ipv6Network,
+        ip: &Ipv4Addr,
+    ) -> Result<(), Error> {
+        let class_id = self.get_class_id(ip);
+
+        let output = self.run_command(
+            "tc",
+            &[
+                "filter",
+                "add",
+                "dev",
+                iface_name,
+                "parent",
+                "1:",
+                "protocol",
+                "ipv6",
+                "u32",
+                "match",
+                "ip6",
+                "dst",
+                &prefix.to_string(),
+                "flowid",
+                &format!("1:{}", class_id),
+            ],
+        )?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            let res = String::from_utf8(output.stderr)?;
+            bail!("Failed to create limit by ipv6 prefix! {:?}", res);
+        }
+    }
+
+    /// Marks the encrypted packets a tunnel sends from its listen port with the given DSCP
+    /// class, so that the qdisc set up by `set_diffserv_egress` on the physical interface (and
+    /// anything upstream that honors DSCP) can prioritize one tunnel over another. None removes
+    /// any marking.
+    pub fn set_tunnel_dscp(&self, listen_port: u16, class: Option<&str>) -> Result<(), Error> {
+        let port = listen_port.to_string();
+        for command in ["iptables", "ip6tables"].iter() {
+            for old_class in TUNNEL_DSCP_CLASSES.iter() {
+                if Some(*old_class) == class {
+                    continue;
+                }
+                // fails harmlessly when the rule does not exist
+                self.run_command(
+                    command,
+                    &[
+                        "-t",
+                        "mangle",
+                        "-D",
+                        "OUTPUT",
+                        "-p",
+                        "udp",
+                        "--sport",
+                        &port,
+                        "-j",
+                        "DSCP",
+                        "--set-dscp-class",
+                        old_class,
+                    ],
+                )?;
+            }
+            if let Some(class) = class {
+                self.add_iptables_rule(
+                    command,
+                    &[
+                        "-t",
+                        "mangle",
+                        "-A",
+                        "OUTPUT",
+                        "-p",
+                        "udp",
+                        "--sport",
+                        &port,
+                        "-j",
+                        "DSCP",
+                        "--set-dscp-class",
+                        class,
+                    ],
+                )?;
+            }
+        }
+        Ok(())
+    }
+
     /// deletes the interface qdisc
     pub fn delete_qdisc(&self, iface_name: &str) -> Result<(), Error> {
         let output = self.run_command("tc", &["qdisc", "del", "dev", iface_name, "root"])?;
@@ -283,3 +448,159 @@ impl KernelInterface {
         }
     }
 }
+
+#[test]
+fn test_set_tunnel_dscp() {
+    use crate::KI;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::process::Output;
+    let mut counter = 0;
+
+    KI.set_mock(Box::new(move |program, args| {
+        counter += 1;
+        let expected_program = if counter <= 3 {
+            "iptables"
+        } else {
+            "ip6tables"
+        };
+        assert_eq!(program, expected_program);
+        match counter {
+            1 | 4 => {
+                assert_eq!(
+                    args,
+                    vec![
+                        "-t",
+                        "mangle",
+                        "-D",
+                        "OUTPUT",
+                        "-p",
+                        "udp",
+                        "--sport",
+                        "60001",
+                        "-j",
+                        "DSCP",
+                        "--set-dscp-class",
+                        "CS1"
+                    ]
+                );
+                Ok(Output {
+                    stdout: b"".to_vec(),
+                    stderr: b"iptables: Bad rule".to_vec(),
+                    status: ExitStatus::from_raw(256),
+                })
+            }
+            2 | 5 => {
+                assert_eq!(args[2], "-C");
+                assert_eq!(args[11], "CS6");
+                Ok(Output {
+                    stdout: b"".to_vec(),
+                    stderr: b"iptables: Bad rule".to_vec(),
+                    status: ExitStatus::from_raw(256),
+                })
+            }
+            3 | 6 => {
+                assert_eq!(args[2], "-A");
+                assert_eq!(args[11], "CS6");
+                Ok(Output {
+                    stdout: b"".to_vec(),
+                    stderr: b"".to_vec(),
+                    status: ExitStatus::from_raw(0),
+                })
+            }
+            _ => panic!("Unexpected call {} {:?} {:?}", counter, program, args),
+        }
+    }));
+
+    KI.set_tunnel_dscp(60001, Some("CS6")).unwrap();
+}
+
+#[test]
+fn test_set_classless_limit_bounds() {
+    use crate::KI;
+    // both are rejected before tc is run
+    assert!(KI.set_classless_limit("wg0", 0).is_err());
+    assert!(KI.set_classless_limit("wg0", u32::max_value()).is_err());
+}
+
+#[test]
+fn test_set_classless_limit_max() {
+    use crate::KI;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::process::Output;
+    let mut counter = 0;
+
+    KI.set_mock(Box::new(move |program, args| {
+        counter += 1;
+        assert_eq!(program, "tc");
+        match counter {
+            1 => {
+                assert_eq!(args, vec!["qdisc", "show", "dev", "wg0"]);
+                Ok(Output {
+                    stdout: b"qdisc cake 8001: root refcnt 2 bandwidth unlimited".to_vec(),
+                    stderr: b"".to_vec(),
+                    status: ExitStatus::from_raw(0),
+                })
+            }
+            2 => {
+                assert_eq!(args[1], "replace");
+                assert_eq!(args[11], "4294967000");
+                assert_eq!(args[13], "4294967kbit");
+                Ok(Output {
+                    stdout: b"".to_vec(),
+                    stderr: b"".to_vec(),
+                    status: ExitStatus::from_raw(0),
+                })
+            }
+            _ => panic!("Unexpected call {} {:?} {:?}", counter, program, args),
+        }
+    }));
+
+    // the largest limit the settings accept
+    KI.set_classless_limit("wg0", 4_294_967).unwrap();
+}
+
+#[test]
+fn test_set_diffserv_egress() {
+    use crate::KI;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::process::Output;
+    let mut counter = 0;
+
+    KI.set_mock(Box::new(move |program, args| {
+        counter += 1;
+        assert_eq!(program, "tc");
+        let stdout: &[u8] = match counter {
+            1 => b"qdisc cake 8001: root refcnt 2 bandwidth 100Mbit diffserv3 triple-isolate",
+            2 => {
+                // only the tin mode is changed, the bandwidth limit stays
+                assert_eq!(
+                    args,
+                    vec![
+                        "qdisc",
+                        "change",
+                        "dev",
+                        "eth0",
+                        "root",
+                        "cake",
+                        "diffserv4"
+                    ]
+                );
+                b""
+            }
+            3 => b"qdisc htb 1: root refcnt 2 r2q 10 default 0",
+            _ => panic!("Unexpected call {} {:?} {:?}", counter, program, args),
+        };
+        Ok(Output {
+            stdout: stdout.to_vec(),
+            stderr: b"".to_vec(),
+            status: ExitStatus::from_raw(0),
+        })
+    }));
+
+    KI.set_diffserv_egress("eth0").unwrap();
+    // anything but cake is left alone
+    assert!(KI.set_diffserv_egress("eth0").is_err());
+}
@@ -1,6 +1,7 @@
 use crate::wg_key::WgKey;
 use arrayvec::ArrayString;
 use clarity::Address;
+use ipnetwork::IpNetwork;
 use num256::Uint256;
 use std::hash::{Hash, Hasher};
 use std::net::IpAddr;
@@ -206,9 +207,18 @@ fn default_verif_mode() -> ExitVerifMode {
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
 pub struct ExitDetails {
     pub server_internal_ip: IpAddr,
+    /// Our ipv6 gateway in the exit tunnel, None if the exit does not offer ipv6
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_internal_ipv6: Option<IpAddr>,
     pub netmask: u8,
     pub wg_exit_port: u16,
+    /// The price for both directions, when the prices differ this is the higher of the two so
+    /// clients that only know this field never expect less than they are billed
     pub exit_price: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_upload_price: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_download_price: Option<u64>,
     // TODO remove this in Beta 3
     #[serde(default = "default_system_chain")]
     pub exit_currency: SystemChain,
@@ -217,9 +227,67 @@ pub struct ExitDetails {
     pub verif_mode: ExitVerifMode,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
+impl ExitDetails {
+    /// Price per byte for what we send out through the exit
+    pub fn upload_price(&self) -> u64 {
+        self.exit_upload_price.unwrap_or(self.exit_price)
+    }
+
+    /// Price per byte for what we receive through the exit, not including the route to us
+    pub fn download_price(&self) -> u64 {
+        self.exit_download_price.unwrap_or(self.exit_price)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
 pub struct ExitClientDetails {
     pub client_internal_ip: IpAddr,
+    /// The ipv6 prefix the exit delegated to us, None if the exit does not offer ipv6
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_internal_ipv6: Option<IpNetwork>,
+    /// The plan the exit bills us under, None if we pay per byte at the exit price
+    #[serde(default)]
+    pub plan: Option<ExitPlanStatus>,
+}
+
+/// How an exit bills a client that has a plan
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ExitPlanType {
+    /// The price is charged at the start of every period and the quota renews with it
+    FlatRate,
+    /// The price is charged once for a bundle of data that lasts until the quota is used
+    /// up or the period ends
+    Prepaid,
+}
+
+/// What the exit does once a client has used up their plan
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum OverQuotaAction {
+    /// Limit the client to the free tier
+    Throttle,
+    /// Bill the client per byte at the exit price
+    PerByte,
+}
+
+/// A client's plan and how much of it they have used
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+pub struct ExitPlanStatus {
+    pub plan_type: ExitPlanType,
+    /// Bytes, upload and download combined, included in each period
+    pub quota: u64,
+    /// The length of a period in seconds
+    pub period: u64,
+    /// What a period or bundle costs in wei
+    pub price: Uint256,
+    pub over_quota: OverQuotaAction,
+    /// Unix time the current period started, zero if it has not started yet
+    pub period_start: u64,
+    /// Bytes used so far this period
+    pub used: u64,
+    /// Bytes left this period, zero once a prepaid bundle has expired
+    pub remaining: u64,
 }
 
 #[cfg(feature = "actix")]
@@ -1,7 +1,7 @@
 use std::time::SystemTime;
 
 /// This is a helper struct for measuring the round trip time over the exit tunnel independently
-/// from Babel. In the future, `RTTimestamps` should aid in RTT-related overadvertisement detection.
+/// from Babel, the client fraud detector compares it to the rtt babel advertises for the exit route.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RTTimestamps {
     pub exit_rx: SystemTime,
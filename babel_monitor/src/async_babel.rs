@@ -0,0 +1,476 @@
+//! A non blocking version of `Babel` for use inside actors. Every operation consumes the client
+//! and returns a future that resolves to the client along with the result, the same way
+//! tokio's io helpers hand back the stream they were given, so operations chain with
+//! `and_then` and never block the thread they run on.
+
+use crate::config::{Filter, InterfaceConfig};
+use crate::{
+    local_fee_from_dump, neighs_from_dump, routes_from_dump, unsupported, BabelMonitorError,
+    BabelTable, Capabilities, Neighbor, Preamble, Route, ALTHEA_CAPABILITIES,
+    BABEL_OPERATION_TIMEOUT,
+};
+use failure::Error;
+use futures::future::{self, Loop};
+use futures::{Future, Sink, Stream};
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
+use tokio::codec::{Framed, LinesCodec};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::timer::timeout;
+use tokio::timer::Timeout;
+
+type BabelFuture<T, I> = Box<dyn Future<Item = (AsyncBabel<T>, I), Error = Error>>;
+
+fn timeout_error(e: timeout::Error<Error>, operation: &str) -> Error {
+    if e.is_elapsed() {
+        BabelMonitorError::Timeout(operation.to_string()).into()
+    } else if e.is_timer() {
+        format_err!("Timer failed while waiting for babel {:?}", e)
+    } else {
+        // it wasn't the timer so it was the operation
+        e.into_inner().unwrap()
+    }
+}
+
+/// Opens a connection to the babel management socket, failing if it takes longer than a
+/// blocking `open_babel_stream` would wait
+pub fn open_babel_stream_async(
+    babel_port: u16,
+) -> Box<dyn Future<Item = TcpStream, Error = Error>> {
+    let socket: SocketAddr = match format!("[::1]:{}", babel_port).parse() {
+        Ok(socket) => socket,
+        Err(e) => return Box::new(future::err(e.into())),
+    };
+    Box::new(
+        Timeout::new(
+            TcpStream::connect(&socket).from_err(),
+            BABEL_OPERATION_TIMEOUT,
+        )
+        .map_err(|e| timeout_error(e, "connect")),
+    )
+}
+
+pub struct AsyncBabel<T> {
+    framed: Framed<T, LinesCodec>,
+    preamble: Option<Preamble>,
+    capabilities: Capabilities,
+}
+
+impl AsyncBabel<TcpStream> {
+    /// Connects to the local babeld and negotiates the configuration protocol
+    pub fn connect(
+        babel_port: u16,
+    ) -> Box<dyn Future<Item = AsyncBabel<TcpStream>, Error = Error>> {
+        Box::new(
+            open_babel_stream_async(babel_port)
+                .and_then(|stream| AsyncBabel::new(stream).start_connection()),
+        )
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> AsyncBabel<T> {
+    pub fn new(stream: T) -> AsyncBabel<T> {
+        AsyncBabel {
+            framed: Framed::new(stream, LinesCodec::new()),
+            preamble: None,
+            capabilities: ALTHEA_CAPABILITIES,
+        }
+    }
+
+    pub fn get_preamble(&self) -> Option<&Preamble> {
+        self.preamble.as_ref()
+    }
+
+    pub fn get_capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// Reads lines until babel's terminator, `ok` ends the output and `bad` or `no` fail it
+    fn read_babel(self) -> BabelFuture<T, String> {
+        let AsyncBabel {
+            framed,
+            preamble,
+            capabilities,
+        } = self;
+        let read = future::loop_fn((framed, String::new()), |(framed, mut output)| {
+            framed
+                .into_future()
+                .map_err(|(e, _framed)| Error::from(e))
+                .and_then(move |(line, framed)| {
+                    let line = match line {
+                        Some(line) => line,
+                        None => {
+                            warn!(
+                                "Terminator was never found; full output:\n{:?}\nEND OF BABEL OUTPUT",
+                                output
+                            );
+                            return Err(BabelMonitorError::NoTerminator(output).into());
+                        }
+                    };
+                    output.push_str(&line);
+                    output.push_str("\n");
+                    match line.trim() {
+                        "ok" => {
+                            trace!(
+                                "Babel returned ok; full output:\n{}\nEND OF BABEL OUTPUT",
+                                output
+                            );
+                            Ok(Loop::Break((framed, output)))
+                        }
+                        "bad" | "no" => {
+                            warn!(
+                                "Babel returned bad/no; full output:\n{}\nEND OF BABEL OUTPUT",
+                                output
+                            );
+                            Err(BabelMonitorError::ReadFailed(output).into())
+                        }
+                        _ => Ok(Loop::Continue((framed, output))),
+                    }
+                })
+        });
+        Box::new(
+            Timeout::new(read, BABEL_OPERATION_TIMEOUT)
+                .map_err(|e| timeout_error(e, "read"))
+                .map(move |(framed, output)| {
+                    (
+                        AsyncBabel {
+                            framed,
+                            preamble,
+                            capabilities,
+                        },
+                        output,
+                    )
+                }),
+        )
+    }
+
+    fn command(self, cmd: &str) -> BabelFuture<T, String> {
+        let AsyncBabel {
+            framed,
+            preamble,
+            capabilities,
+        } = self;
+        let cmd = cmd.to_string();
+        trace!("Sending '{}' to babel", cmd);
+        Box::new(
+            framed
+                .send(cmd.clone())
+                .from_err()
+                .and_then(move |framed| {
+                    AsyncBabel {
+                        framed,
+                        preamble,
+                        capabilities,
+                    }
+                    .read_babel()
+                })
+                .map_err(move |e| BabelMonitorError::CommandFailed(cmd, e.to_string()).into()),
+        )
+    }
+
+    /// Runs a command whose output we have no use for
+    fn command_only(self, cmd: &str) -> Box<dyn Future<Item = AsyncBabel<T>, Error = Error>> {
+        Box::new(self.command(cmd).map(|(babel, _output)| babel))
+    }
+
+    /// Consumes the automated Preamble and negotiates which features of the configuration
+    /// api this babeld supports
+    pub fn start_connection(self) -> Box<dyn Future<Item = AsyncBabel<T>, Error = Error>> {
+        Box::new(self.read_babel().and_then(|(mut babel, output)| {
+            let preamble = Preamble::parse(&output)?;
+            babel.capabilities = preamble.negotiate()?;
+            trace!(
+                "Attached OK to Babel {} with capabilities {:?}",
+                preamble.describe(),
+                babel.capabilities
+            );
+            babel.preamble = Some(preamble);
+            Ok(babel)
+        }))
+    }
+
+    pub fn dump(self) -> BabelFuture<T, BabelTable> {
+        Box::new(
+            self.command("dump")
+                .map(|(babel, output)| (babel, BabelTable::from_dump(&output))),
+        )
+    }
+
+    pub fn get_local_fee(self) -> BabelFuture<T, u32> {
+        Box::new(
+            self.command("dump")
+                .and_then(|(babel, output)| Ok((babel, local_fee_from_dump(&output)?))),
+        )
+    }
+
+    pub fn parse_neighs(self) -> BabelFuture<T, VecDeque<Neighbor>> {
+        Box::new(
+            self.command("dump")
+                .and_then(|(babel, output)| Ok((babel, neighs_from_dump(&output)?))),
+        )
+    }
+
+    pub fn parse_routes(self) -> BabelFuture<T, VecDeque<Route>> {
+        Box::new(
+            self.command("dump")
+                .and_then(|(babel, output)| Ok((babel, routes_from_dump(&output)?))),
+        )
+    }
+
+    pub fn set_local_fee(
+        self,
+        new_fee: u32,
+    ) -> Box<dyn Future<Item = AsyncBabel<T>, Error = Error>> {
+        if !self.capabilities.fees {
+            return Box::new(future::err(unsupported(self.preamble.as_ref(), "fees")));
+        }
+        self.command_only(&format!("fee {}", new_fee))
+    }
+
+    pub fn set_metric_factor(
+        self,
+        new_factor: u32,
+    ) -> Box<dyn Future<Item = AsyncBabel<T>, Error = Error>> {
+        if !self.capabilities.metric_factor {
+            return Box::new(future::err(unsupported(
+                self.preamble.as_ref(),
+                "metric-factor",
+            )));
+        }
+        self.command_only(&format!("metric-factor {}", new_factor))
+    }
+
+    pub fn monitor(self, iface: &str) -> Box<dyn Future<Item = AsyncBabel<T>, Error = Error>> {
+        self.configure_interface(iface, &InterfaceConfig::tunnel_default())
+    }
+
+    /// Starts babel on an interface or, if it is already running there, updates the
+    /// interface's parameters
+    pub fn configure_interface(
+        self,
+        iface: &str,
+        config: &InterfaceConfig,
+    ) -> Box<dyn Future<Item = AsyncBabel<T>, Error = Error>> {
+        let command = config.to_command(iface, self.capabilities);
+        self.command_only(&command)
+    }
+
+    pub fn unmonitor(self, iface: &str) -> Box<dyn Future<Item = AsyncBabel<T>, Error = Error>> {
+        self.command_only(&format!("flush interface {}", iface))
+    }
+
+    /// Appends a filter to babeld's filters, babeld has no way to remove a filter short of
+    /// restarting it
+    pub fn add_filter(
+        self,
+        filter: &Filter,
+    ) -> Box<dyn Future<Item = AsyncBabel<T>, Error = Error>> {
+        self.command_only(&filter.to_command())
+    }
+
+    pub fn redistribute_ip(
+        self,
+        ip: &IpAddr,
+        allow: bool,
+    ) -> Box<dyn Future<Item = AsyncBabel<T>, Error = Error>> {
+        let command = format!(
+            "redistribute ip {}/128 {}",
+            ip,
+            if allow { "allow" } else { "deny" }
+        );
+        Box::new(
+            self.command_only(&command)
+                .and_then(|babel| babel.read_babel())
+                .map(|(babel, _output)| babel),
+        )
+    }
+
+    /// Subscribes this connection to babeld's table updates and returns the table babeld
+    /// dumps when monitoring starts, `into_events` then streams the updates
+    pub fn start_monitor(self) -> BabelFuture<T, BabelTable> {
+        Box::new(
+            self.command("monitor")
+                .map(|(babel, output)| (babel, BabelTable::from_dump(&output))),
+        )
+    }
+
+    /// Every line babel sends from here on, for a connection that is monitoring
+    pub fn into_events(self) -> Box<dyn Stream<Item = String, Error = Error>> {
+        Box::new(
+            self.framed
+                .from_err()
+                .inspect(|line| trace!("Babel event {}", line)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockstream::SharedMockStream;
+    use std::io::{self, Read, Write};
+    use tokio::prelude::{Async, Poll};
+    use tokio::runtime::current_thread::Runtime;
+
+    /// An in memory babel, everything pushed to read is what babel says
+    #[derive(Clone)]
+    struct MockBabel(SharedMockStream);
+
+    impl Read for MockBabel {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for MockBabel {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    impl AsyncRead for MockBabel {}
+
+    impl AsyncWrite for MockBabel {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    static PREAMBLE: &'static str =
+        "ALTHEA 0.1\nversion babeld-1.8.0-24-g6335378\nhost raspberrypi\nmy-id \
+         ba:27:eb:ff:fe:09:06:dd\nok\n";
+
+    static DUMP: &'static str = "local fee 1024\n\
+metric factor 1900\n\
+add neighbour 14f19a8 address fe80::2cee:2fff:648:8796 if wg0 reach ffff rxcost 256 txcost 256 rtt \
+26.723 rttcost 912 cost 1168\n\
+add route 14f0820 prefix fd00::1/128 from ::/0 installed yes id ba:27:eb:ff:fe:5b:fe:c7 \
+metric 1596 price 3072 fee 3072 refmetric 638 full-path-rtt 22.805 via fe80::2cee:2fff:648:8796 if wg0\n\
+ok\n";
+
+    fn mock_babel(output: &[&str]) -> MockBabel {
+        let mut s = SharedMockStream::new();
+        s.push_bytes_to_read(PREAMBLE.as_bytes());
+        for out in output {
+            s.push_bytes_to_read(out.as_bytes());
+        }
+        MockBabel(s)
+    }
+
+    #[test]
+    fn async_connect_and_configure() {
+        let mut s = mock_babel(&["ok\n", "ok\n"]);
+        let mut runtime = Runtime::new().unwrap();
+        let babel = runtime
+            .block_on(
+                AsyncBabel::new(s.clone())
+                    .start_connection()
+                    .and_then(|babel| babel.set_local_fee(10))
+                    .and_then(|babel| babel.monitor("wg0")),
+            )
+            .unwrap();
+        assert_eq!(babel.get_preamble().unwrap().protocol, "ALTHEA");
+        assert_eq!(
+            s.0.pop_bytes_written(),
+            &b"fee 10\ninterface wg0 max-rtt-penalty 500 enable-timestamps true\n"[..]
+        );
+    }
+
+    #[test]
+    fn async_dump() {
+        let s = mock_babel(&[DUMP, DUMP, DUMP]);
+        let mut runtime = Runtime::new().unwrap();
+        let (fee, routes, table) = runtime
+            .block_on(
+                AsyncBabel::new(s)
+                    .start_connection()
+                    .and_then(|babel| babel.get_local_fee())
+                    .and_then(|(babel, fee)| {
+                        babel
+                            .parse_routes()
+                            .map(move |(babel, routes)| (babel, fee, routes))
+                    })
+                    .and_then(|(babel, fee, routes)| {
+                        babel
+                            .dump()
+                            .map(move |(_babel, table)| (fee, routes, table))
+                    }),
+            )
+            .unwrap();
+        assert_eq!(fee, 1024);
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].price, 3072);
+        assert_eq!(table.neighs().len(), 1);
+    }
+
+    #[test]
+    fn async_errors() {
+        let mut runtime = Runtime::new().unwrap();
+        // babel refusing a command
+        let s = mock_babel(&["no\n"]);
+        assert!(runtime
+            .block_on(
+                AsyncBabel::new(s)
+                    .start_connection()
+                    .and_then(|babel| babel.set_metric_factor(10))
+            )
+            .is_err());
+
+        // babel hanging up mid output
+        let s = mock_babel(&["local fee 1024\n"]);
+        assert!(runtime
+            .block_on(
+                AsyncBabel::new(s)
+                    .start_connection()
+                    .and_then(|babel| babel.get_local_fee())
+            )
+            .is_err());
+
+        // an upstream babeld can't take fees
+        let mut s = SharedMockStream::new();
+        s.push_bytes_to_read(b"BABEL 1.0\nversion babeld-1.6.3\nok\n");
+        assert!(runtime
+            .block_on(
+                AsyncBabel::new(MockBabel(s))
+                    .start_connection()
+                    .and_then(|babel| babel.set_local_fee(10))
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn async_monitor() {
+        let s = mock_babel(&[
+            DUMP,
+            "change route 14f0820 prefix fd00::1/128 from ::/0 installed yes id \
+             ba:27:eb:ff:fe:5b:fe:c7 metric 1596 price 4096 fee 3072 refmetric 638 \
+             full-path-rtt 22.805 via fe80::2cee:2fff:648:8796 if wg0\n\
+             local fee 2048\n",
+        ]);
+        let mut runtime = Runtime::new().unwrap();
+        let (mut table, events) = runtime
+            .block_on(
+                AsyncBabel::new(s)
+                    .start_connection()
+                    .and_then(|babel| babel.start_monitor())
+                    .and_then(|(babel, table)| {
+                        babel
+                            .into_events()
+                            .take(2)
+                            .collect()
+                            .map(move |events| (table, events))
+                    }),
+            )
+            .unwrap();
+        for event in events {
+            table.apply_line(&event);
+        }
+        assert_eq!(table.routes["14f0820"].price, 4096);
+        assert_eq!(table.local_fee, Some(2048));
+    }
+}
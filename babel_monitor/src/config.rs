@@ -0,0 +1,239 @@
+//! Runtime configuration of babeld, per interface parameters and route filters. Both are
+//! written in the same syntax babeld.conf uses and sent over the management socket.
+
+use crate::Capabilities;
+use ipnetwork::IpNetwork;
+use std::fmt::Write;
+use std::net::IpAddr;
+
+/// How babeld computes the cost of a link, see babeld(8)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkType {
+    Auto,
+    Wired,
+    Wireless,
+    Tunnel,
+}
+
+impl LinkType {
+    fn as_str(self) -> &'static str {
+        match self {
+            LinkType::Auto => "auto",
+            LinkType::Wired => "wired",
+            LinkType::Wireless => "wireless",
+            LinkType::Tunnel => "tunnel",
+        }
+    }
+}
+
+/// Parameters babeld accepts on an `interface` line, anything left as None keeps babeld's
+/// default. Intervals and rtts are in milliseconds.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InterfaceConfig {
+    pub link_type: Option<LinkType>,
+    /// The base cost of the link, overrides what babeld computes from the link type
+    pub rxcost: Option<u16>,
+    pub hello_interval: Option<u32>,
+    pub update_interval: Option<u32>,
+    pub split_horizon: Option<bool>,
+    pub enable_timestamps: Option<bool>,
+    /// Below this rtt a link gets no rtt penalty
+    pub rtt_min: Option<u32>,
+    /// Above this rtt a link gets the full `max_rtt_penalty`
+    pub rtt_max: Option<u32>,
+    pub max_rtt_penalty: Option<u32>,
+}
+
+impl InterfaceConfig {
+    /// What Rita has always configured its tunnels with
+    pub fn tunnel_default() -> InterfaceConfig {
+        InterfaceConfig {
+            enable_timestamps: Some(true),
+            max_rtt_penalty: Some(500),
+            ..Default::default()
+        }
+    }
+
+    /// Returns this config with every value set in `other` replacing ours
+    pub fn merge(&self, other: &InterfaceConfig) -> InterfaceConfig {
+        InterfaceConfig {
+            link_type: other.link_type.or(self.link_type),
+            rxcost: other.rxcost.or(self.rxcost),
+            hello_interval: other.hello_interval.or(self.hello_interval),
+            update_interval: other.update_interval.or(self.update_interval),
+            split_horizon: other.split_horizon.or(self.split_horizon),
+            enable_timestamps: other.enable_timestamps.or(self.enable_timestamps),
+            rtt_min: other.rtt_min.or(self.rtt_min),
+            rtt_max: other.rtt_max.or(self.rtt_max),
+            max_rtt_penalty: other.max_rtt_penalty.or(self.max_rtt_penalty),
+        }
+    }
+
+    /// The `interface` command for this config, rtt options are left out if babeld
+    /// can't timestamp hellos
+    pub fn to_command(&self, iface: &str, capabilities: Capabilities) -> String {
+        let mut command = format!("interface {}", iface);
+        // writing to a String can't fail
+        if let Some(link_type) = self.link_type {
+            write!(command, " type {}", link_type.as_str()).unwrap();
+        }
+        if let Some(rxcost) = self.rxcost {
+            write!(command, " rxcost {}", rxcost).unwrap();
+        }
+        if let Some(interval) = self.hello_interval {
+            write!(command, " hello-interval {}", seconds(interval)).unwrap();
+        }
+        if let Some(interval) = self.update_interval {
+            write!(command, " update-interval {}", seconds(interval)).unwrap();
+        }
+        if let Some(split_horizon) = self.split_horizon {
+            write!(command, " split-horizon {}", split_horizon).unwrap();
+        }
+        if capabilities.timestamps {
+            if let Some(rtt_min) = self.rtt_min {
+                write!(command, " rtt-min {}", rtt_min).unwrap();
+            }
+            if let Some(rtt_max) = self.rtt_max {
+                write!(command, " rtt-max {}", rtt_max).unwrap();
+            }
+            if let Some(penalty) = self.max_rtt_penalty {
+                write!(command, " max-rtt-penalty {}", penalty).unwrap();
+            }
+            if let Some(enable) = self.enable_timestamps {
+                write!(command, " enable-timestamps {}", enable).unwrap();
+            }
+        }
+        command
+    }
+}
+
+/// babeld takes intervals in seconds with up to millisecond precision
+fn seconds(millis: u32) -> String {
+    format!("{}.{:03}", millis / 1000, millis % 1000)
+}
+
+/// Which set of routes a filter applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterType {
+    /// Routes learned from neighbors
+    In,
+    /// Routes announced to neighbors
+    Out,
+    /// Routes from the kernel we announce as our own
+    Redistribute,
+    /// Routes babeld installs in the kernel
+    Install,
+}
+
+impl FilterType {
+    fn as_str(self) -> &'static str {
+        match self {
+            FilterType::In => "in",
+            FilterType::Out => "out",
+            FilterType::Redistribute => "redistribute",
+            FilterType::Install => "install",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterAction {
+    Allow,
+    Deny,
+    /// Allow the route but add this much to its metric
+    Metric(u16),
+}
+
+/// A babeld route filter, a route matches if it matches every selector that is set
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter {
+    pub filter_type: FilterType,
+    pub iface: Option<String>,
+    pub prefix: Option<IpNetwork>,
+    /// The neighbor a route was learned from, only meaningful for `In` filters
+    pub neigh: Option<IpAddr>,
+    pub action: FilterAction,
+}
+
+impl Filter {
+    pub fn to_command(&self) -> String {
+        let mut command = self.filter_type.as_str().to_string();
+        if let Some(ref iface) = self.iface {
+            write!(command, " if {}", iface).unwrap();
+        }
+        if let Some(prefix) = self.prefix {
+            write!(command, " ip {}", prefix).unwrap();
+        }
+        if let Some(neigh) = self.neigh {
+            write!(command, " neigh {}", neigh).unwrap();
+        }
+        match self.action {
+            FilterAction::Allow => command.push_str(" allow"),
+            FilterAction::Deny => command.push_str(" deny"),
+            FilterAction::Metric(metric) => write!(command, " metric {}", metric).unwrap(),
+        }
+        command
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ALTHEA_CAPABILITIES;
+
+    #[test]
+    fn test_interface_command() {
+        assert_eq!(
+            InterfaceConfig::tunnel_default().to_command("wg0", ALTHEA_CAPABILITIES),
+            "interface wg0 max-rtt-penalty 500 enable-timestamps true"
+        );
+
+        let wired = InterfaceConfig {
+            link_type: Some(LinkType::Wired),
+            rxcost: Some(96),
+            hello_interval: Some(500),
+            rtt_min: Some(10),
+            rtt_max: Some(120),
+            ..Default::default()
+        };
+        let config = InterfaceConfig::tunnel_default().merge(&wired);
+        assert_eq!(
+            config.to_command("wg1", ALTHEA_CAPABILITIES),
+            "interface wg1 type wired rxcost 96 hello-interval 0.500 rtt-min 10 rtt-max 120 \
+             max-rtt-penalty 500 enable-timestamps true"
+        );
+
+        let no_timestamps = Capabilities {
+            timestamps: false,
+            ..ALTHEA_CAPABILITIES
+        };
+        assert_eq!(
+            config.to_command("wg1", no_timestamps),
+            "interface wg1 type wired rxcost 96 hello-interval 0.500"
+        );
+    }
+
+    #[test]
+    fn test_filter_command() {
+        let filter = Filter {
+            filter_type: FilterType::In,
+            iface: Some("wg3".to_string()),
+            prefix: Some("fd00::/8".parse().unwrap()),
+            neigh: Some("fe80::1".parse().unwrap()),
+            action: FilterAction::Metric(256),
+        };
+        assert_eq!(
+            filter.to_command(),
+            "in if wg3 ip fd00::/8 neigh fe80::1 metric 256"
+        );
+
+        let filter = Filter {
+            filter_type: FilterType::Redistribute,
+            iface: None,
+            prefix: Some("10.0.0.0/8".parse().unwrap()),
+            neigh: None,
+            action: FilterAction::Deny,
+        };
+        assert_eq!(filter.to_command(), "redistribute ip 10.0.0.0/8 deny");
+    }
+}
@@ -3,6 +3,18 @@ extern crate failure;
 #[macro_use]
 extern crate log;
 
+mod async_babel;
+mod config;
+mod monitor;
+mod parser;
+mod version;
+
+pub use crate::async_babel::{open_babel_stream_async, AsyncBabel};
+pub use crate::config::{Filter, FilterAction, FilterType, InterfaceConfig, LinkType};
+pub use crate::monitor::BabelTable;
+pub use crate::parser::{parse_line, parse_output, Action, BabelLine, Entry, ParseError};
+pub use crate::version::{Capabilities, Preamble, ALTHEA_CAPABILITIES};
+
 use bufstream::BufStream;
 use failure::Error;
 use ipnetwork::IpNetwork;
@@ -19,8 +31,6 @@ const BABEL_OPERATION_TIMEOUT: Duration = Duration::from_secs(1);
 
 #[derive(Debug, Fail)]
 pub enum BabelMonitorError {
-    #[fail(display = "variable '{}' not found in '{}'", _0, _1)]
-    VariableNotFound(String, String),
     #[fail(display = "Invalid preamble: {}", _0)]
     InvalidPreamble(String),
     #[fail(display = "Could not find local fee in '{}'", _0)]
@@ -33,30 +43,90 @@ pub enum BabelMonitorError {
     NoTerminator(String),
     #[fail(display = "No Neighbor was found matching address:\n{}", _0)]
     NoNeighbor(String),
+    #[fail(display = "Babel did not answer {} in time", _0)]
+    Timeout(String),
+    #[fail(display = "Babel closed the connection")]
+    ConnectionClosed,
+    #[fail(
+        display = "Incompatible babeld {}, supported protocols are ALTHEA 0.x and BABEL 1.x",
+        _0
+    )]
+    IncompatibleVersion(String),
+    #[fail(display = "babeld {} does not support {}", _0, _1)]
+    Unsupported(String, String),
 }
 
 use crate::BabelMonitorError::{
-    CommandFailed, InvalidPreamble, LocalFeeNotFound, NoNeighbor, NoTerminator, ReadFailed,
-    VariableNotFound,
+    CommandFailed, LocalFeeNotFound, NoNeighbor, NoTerminator, ReadFailed, Unsupported,
 };
 
-// If a function doesn't need internal state of the Babel object
-// we don't want to place it as a member function.
-fn find_babel_val(val: &str, line: &str) -> Result<String, Error> {
-    let mut iter = line.split(" ");
-    while let Some(entry) = iter.next() {
-        if entry.to_string() == val {
-            match iter.next() {
-                Some(v) => return Ok(v.to_string()),
-                None => continue,
+fn unsupported(preamble: Option<&Preamble>, feature: &str) -> Error {
+    let version = match preamble {
+        Some(preamble) => preamble.describe(),
+        None => "unknown".to_string(),
+    };
+    Unsupported(version, feature.to_string()).into()
+}
+
+/// Finds our fee in the output of `dump`, where it is the first line
+fn local_fee_from_dump(babel_output: &str) -> Result<u32, Error> {
+    let fee_entry = match babel_output.lines().next() {
+        Some(entry) => entry,
+        None => return Err(LocalFeeNotFound(String::from("<Babel output is empty>")).into()),
+    };
+
+    match parse_line(fee_entry) {
+        Ok(BabelLine::LocalFee(fee)) => {
+            trace!("Retrieved a local fee of {}", fee);
+            Ok(fee)
+        }
+        Ok(_) => Err(LocalFeeNotFound(String::from(fee_entry)).into()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn neighs_from_dump(babel_output: &str) -> Result<VecDeque<Neighbor>, Error> {
+    let mut vector: VecDeque<Neighbor> = VecDeque::with_capacity(5);
+    let mut found_neigh = false;
+    for entry in babel_output.split("\n") {
+        if entry.contains("add neighbour") {
+            found_neigh = true;
+            match parse_line(entry) {
+                Ok(BabelLine::Update(_, Entry::Neighbour(neigh))) => vector.push_back(neigh),
+                Ok(_) => {}
+                Err(e) => warn!("Failed to parse babel neighbour {}", e),
+            }
+        }
+    }
+    if vector.len() == 0 && found_neigh {
+        bail!("All Babel neigh parsing failed!")
+    }
+    Ok(vector)
+}
+
+fn routes_from_dump(babel_output: &str) -> Result<VecDeque<Route>, Error> {
+    let mut vector: VecDeque<Route> = VecDeque::with_capacity(20);
+    let mut found_route = false;
+    trace!("Got from babel dump: {}", babel_output);
+
+    for entry in babel_output.split("\n") {
+        if entry.contains("add route") {
+            trace!("Parsing 'add route' entry: {}", entry);
+            found_route = true;
+            match parse_line(entry) {
+                Ok(BabelLine::Update(_, Entry::Route(route))) => vector.push_back(route),
+                Ok(_) => {}
+                Err(e) => warn!("Failed to parse babel route {}", e),
             }
         }
     }
-    warn!("find_babel_val warn! Can not find {} in {}", val, line);
-    return Err(VariableNotFound(String::from(val), String::from(line)).into());
+    if vector.len() == 0 && found_route {
+        bail!("All Babel route parsing failed!")
+    }
+    Ok(vector)
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Route {
     pub id: String,
     pub iface: String,
@@ -64,14 +134,19 @@ pub struct Route {
     pub installed: bool,
     pub neigh_ip: IpAddr,
     pub prefix: IpNetwork,
+    /// The source prefix for source specific routes, ::/0 or 0.0.0.0/0 otherwise
+    pub src_prefix: IpNetwork,
+    /// The router id of the node that originated this route
+    pub router_id: String,
     pub metric: u16,
     pub refmetric: u16,
     pub full_path_rtt: f32,
+    /// 0 when babeld doesn't support fees
     pub price: u32,
     pub fee: u32,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Neighbor {
     pub id: String,
     pub address: IpAddr,
@@ -84,6 +159,23 @@ pub struct Neighbor {
     pub cost: u16,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct Interface {
+    pub name: String,
+    pub up: bool,
+    pub ipv6: Option<IpAddr>,
+    pub ipv4: Option<IpAddr>,
+}
+
+/// A route we export to our neighbors
+#[derive(Debug, Clone, PartialEq)]
+pub struct Xroute {
+    pub id: String,
+    pub prefix: IpNetwork,
+    pub src_prefix: IpNetwork,
+    pub metric: u16,
+}
+
 /// Opens a tcpstream to the babel management socket using a standard timeout
 /// for both the open and read operations
 pub fn open_babel_stream(babel_port: u16) -> Result<TcpStream, Error> {
@@ -95,12 +187,17 @@ pub fn open_babel_stream(babel_port: u16) -> Result<TcpStream, Error> {
 
 pub struct Babel<T: Read + Write> {
     stream: BufStream<T>,
+    /// an event line that has only partially arrived
+    preamble: Option<Preamble>,
+    capabilities: Capabilities,
 }
 
 impl<T: Read + Write> Babel<T> {
     pub fn new(stream: T) -> Babel<T> {
         Babel {
             stream: BufStream::new(stream),
+            preamble: None,
+            capabilities: ALTHEA_CAPABILITIES,
         }
     }
 
@@ -146,51 +243,75 @@ impl<T: Read + Write> Babel<T> {
         }
     }
 
-    // Consumes the automated Preamble and validates configuration api version
+    /// Consumes the automated Preamble and negotiates which features of the configuration
+    /// api this babeld supports
     pub fn start_connection(&mut self) -> Result<(), Error> {
-        let preamble = self.read_babel()?;
-        // Note you have changed the config interface, bump to 1.1 in babel
-        if preamble.contains("ALTHEA 0.1") {
-            trace!("Attached OK to Babel with preamble: {}", preamble);
-            return Ok(());
-        } else {
-            return Err(InvalidPreamble(preamble).into());
-        }
+        let output = self.read_babel()?;
+        let preamble = Preamble::parse(&output)?;
+        self.capabilities = preamble.negotiate()?;
+        trace!(
+            "Attached OK to Babel {} with capabilities {:?}",
+            preamble.describe(),
+            self.capabilities
+        );
+        self.preamble = Some(preamble);
+        Ok(())
     }
 
-    pub fn get_local_fee(&mut self) -> Result<u32, Error> {
-        let babel_output = self.command("dump")?;
-        let fee_entry = match babel_output.split("\n").nth(0) {
-            Some(entry) => entry,
-            // Even an empty string wouldn't yield None
-            None => return Err(LocalFeeNotFound(String::from("<Babel output is None>")).into()),
-        };
+    /// The preamble babeld sent when we connected
+    pub fn get_preamble(&self) -> Option<&Preamble> {
+        self.preamble.as_ref()
+    }
 
-        if fee_entry.contains("local fee") {
-            let fee = find_babel_val("fee", fee_entry)?.parse()?;
-            trace!("Retrieved a local fee of {}", fee);
-            return Ok(fee);
-        }
+    pub fn get_capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    fn unsupported(&self, feature: &str) -> Error {
+        unsupported(self.preamble.as_ref(), feature)
+    }
 
-        Err(LocalFeeNotFound(String::from(fee_entry)).into())
+    pub fn get_local_fee(&mut self) -> Result<u32, Error> {
+        local_fee_from_dump(&self.command("dump")?)
     }
 
     pub fn set_local_fee(&mut self, new_fee: u32) -> Result<(), Error> {
+        if !self.capabilities.fees {
+            return Err(self.unsupported("fees"));
+        }
         let _babel_output = self.command(&format!("fee {}", new_fee))?;
         Ok(())
     }
 
     pub fn set_metric_factor(&mut self, new_factor: u32) -> Result<(), Error> {
+        if !self.capabilities.metric_factor {
+            return Err(self.unsupported("metric-factor"));
+        }
         let _babel_output = self.command(&format!("metric-factor {}", new_factor))?;
         Ok(())
     }
 
     pub fn monitor(&mut self, iface: &str) -> Result<(), Error> {
-        let _ = self.command(&format!(
-            "interface {} max-rtt-penalty 500 enable-timestamps true",
-            iface
-        ))?;
-        trace!("Babel started monitoring: {}", iface);
+        self.configure_interface(iface, &InterfaceConfig::tunnel_default())
+    }
+
+    /// Starts babel on an interface or, if it is already running there, updates the
+    /// interface's parameters
+    pub fn configure_interface(
+        &mut self,
+        iface: &str,
+        config: &InterfaceConfig,
+    ) -> Result<(), Error> {
+        self.command(&config.to_command(iface, self.capabilities))?;
+        trace!("Babel started monitoring: {} with {:?}", iface, config);
+        Ok(())
+    }
+
+    /// Appends a filter to babeld's filters, babeld has no way to remove a filter short of
+    /// restarting it
+    pub fn add_filter(&mut self, filter: &Filter) -> Result<(), Error> {
+        self.command(&filter.to_command())?;
+        trace!("Babel added filter {:?}", filter);
         Ok(())
     }
 
@@ -211,205 +332,11 @@ impl<T: Read + Write> Babel<T> {
     }
 
     pub fn parse_neighs(&mut self) -> Result<VecDeque<Neighbor>, Error> {
-        let mut vector: VecDeque<Neighbor> = VecDeque::with_capacity(5);
-        let mut found_neigh = false;
-        for entry in self.command("dump")?.split("\n") {
-            if entry.contains("add neighbour") {
-                found_neigh = true;
-                let neigh = Neighbor {
-                    id: match find_babel_val("neighbour", entry) {
-                        Ok(val) => val,
-                        Err(_) => continue,
-                    },
-                    address: match find_babel_val("address", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing address for neigh {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                    iface: match find_babel_val("if", entry) {
-                        Ok(val) => val,
-                        Err(_) => continue,
-                    },
-                    reach: match find_babel_val("reach", entry) {
-                        Ok(val) => match u16::from_str_radix(&val, 16) {
-                            Ok(val) => val,
-                            Err(e) => {
-                                warn!("Failed to convert reach {:?} {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                    txcost: match find_babel_val("txcost", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing txcost for neigh {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                    rxcost: match find_babel_val("rxcost", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing rxcost for neigh {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                    rtt: match find_babel_val("rtt", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing rtt for neigh {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        // it's possible that our neigh does not have rtt enabled, handle
-                        Err(_) => 0.0,
-                    },
-                    rttcost: match find_babel_val("rttcost", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing rtt for neigh {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        // it's possible that our neigh does not have rtt enabled, handle
-                        Err(_) => 0,
-                    },
-                    cost: match find_babel_val("cost", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing cost for neigh {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                };
-                vector.push_back(neigh);
-            }
-        }
-        if vector.len() == 0 && found_neigh {
-            bail!("All Babel neigh parsing failed!")
-        }
-        Ok(vector)
+        neighs_from_dump(&self.command("dump")?)
     }
 
     pub fn parse_routes(&mut self) -> Result<VecDeque<Route>, Error> {
-        let mut vector: VecDeque<Route> = VecDeque::with_capacity(20);
-        let babel_out = self.command("dump")?;
-        let mut found_route = false;
-        trace!("Got from babel dump: {}", babel_out);
-
-        for entry in babel_out.split("\n") {
-            if entry.contains("add route") {
-                trace!("Parsing 'add route' entry: {}", entry);
-                found_route = true;
-                let route = Route {
-                    id: match find_babel_val("route", entry) {
-                        Ok(value) => value,
-                        Err(_) => continue,
-                    },
-                    iface: match find_babel_val("if", entry) {
-                        Ok(value) => value,
-                        Err(_) => continue,
-                    },
-                    xroute: false,
-                    installed: match find_babel_val("installed", entry) {
-                        Ok(value) => value.contains("yes"),
-                        Err(_) => continue,
-                    },
-                    neigh_ip: match find_babel_val("via", entry) {
-                        Ok(value) => match value.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing neigh_ip for route {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                    prefix: match find_babel_val("prefix", entry) {
-                        Ok(value) => match value.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing prefix for route {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                    metric: match find_babel_val("metric", entry) {
-                        Ok(value) => match value.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing metric for route {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                    refmetric: match find_babel_val("refmetric", entry) {
-                        Ok(value) => match value.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing refmetric {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                    full_path_rtt: match find_babel_val("full-path-rtt", entry) {
-                        Ok(value) => match value.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing full_path_rtt {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                    price: match find_babel_val("price", entry) {
-                        Ok(value) => match value.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing price {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                    fee: match find_babel_val("fee", entry) {
-                        Ok(value) => match value.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing fee {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                };
-
-                vector.push_back(route);
-            }
-        }
-        if vector.len() == 0 && found_route {
-            bail!("All Babel route parsing failed!")
-        }
-        Ok(vector)
+        routes_from_dump(&self.command("dump")?)
     }
 
     /// In this function we take a route snapshot then loop over the routes list twice
@@ -422,24 +349,7 @@ impl<T: Read + Write> Babel<T> {
         dest_mesh_ip: IpAddr,
         routes: &VecDeque<Route>,
     ) -> Result<Route, Error> {
-        // First find the neighbors route to itself to get the local address
-        for neigh_route in routes.iter() {
-            // This will fail on v4 babel routes etc
-            if let IpNetwork::V6(ref ip) = neigh_route.prefix {
-                if ip.ip() == neigh_mesh_ip {
-                    let neigh_local_ip = neigh_route.neigh_ip;
-                    // Now we take the neigh_local_ip and search for a route via that
-                    for route in routes.iter() {
-                        if let IpNetwork::V6(ref ip) = route.prefix {
-                            if ip.ip() == dest_mesh_ip && route.neigh_ip == neigh_local_ip {
-                                return Ok(route.clone());
-                            }
-                        }
-                    }
-                }
-            }
-        }
-        Err(NoNeighbor(neigh_mesh_ip.to_string()).into())
+        get_route_via_neigh(neigh_mesh_ip, dest_mesh_ip, routes)
     }
 
     /// Checks if Babel has an installed route to the given destination
@@ -448,14 +358,7 @@ impl<T: Read + Write> Babel<T> {
         mesh_ip: &IpAddr,
         routes: &VecDeque<Route>,
     ) -> Result<bool, Error> {
-        for route in routes.iter() {
-            if let IpNetwork::V6(ref ip) = route.prefix {
-                if ip.ip() == *mesh_ip && route.installed {
-                    return Ok(true);
-                }
-            }
-        }
-        Ok(false)
+        Ok(do_we_have_route(mesh_ip, routes))
     }
 
     /// Returns the installed route to a given destination
@@ -464,23 +367,64 @@ impl<T: Read + Write> Babel<T> {
         mesh_ip: &IpAddr,
         routes: &VecDeque<Route>,
     ) -> Result<Route, Error> {
-        let mut exit_route = None;
-        for route in routes.iter() {
-            // Only ip6
-            if let IpNetwork::V6(ref ip) = route.prefix {
-                // Only host addresses and installed routes
-                if ip.prefix() == 128 && route.installed && IpAddr::V6(ip.ip()) == *mesh_ip {
-                    exit_route = Some(route);
-                    break;
+        get_installed_route(mesh_ip, routes)
+    }
+}
+
+fn get_route_via_neigh(
+    neigh_mesh_ip: IpAddr,
+    dest_mesh_ip: IpAddr,
+    routes: &VecDeque<Route>,
+) -> Result<Route, Error> {
+    // First find the neighbors route to itself to get the local address
+    for neigh_route in routes.iter() {
+        // This will fail on v4 babel routes etc
+        if let IpNetwork::V6(ref ip) = neigh_route.prefix {
+            if ip.ip() == neigh_mesh_ip {
+                let neigh_local_ip = neigh_route.neigh_ip;
+                // Now we take the neigh_local_ip and search for a route via that
+                for route in routes.iter() {
+                    if let IpNetwork::V6(ref ip) = route.prefix {
+                        if ip.ip() == dest_mesh_ip && route.neigh_ip == neigh_local_ip {
+                            return Ok(route.clone());
+                        }
+                    }
                 }
             }
         }
-        if exit_route.is_none() {
-            bail!("No installed route to that destination!");
+    }
+    Err(NoNeighbor(neigh_mesh_ip.to_string()).into())
+}
+
+fn do_we_have_route(mesh_ip: &IpAddr, routes: &VecDeque<Route>) -> bool {
+    for route in routes.iter() {
+        if let IpNetwork::V6(ref ip) = route.prefix {
+            if ip.ip() == *mesh_ip && route.installed {
+                return true;
+            }
         }
-        Ok(exit_route.unwrap().clone())
     }
+    false
 }
+
+fn get_installed_route(mesh_ip: &IpAddr, routes: &VecDeque<Route>) -> Result<Route, Error> {
+    let mut exit_route = None;
+    for route in routes.iter() {
+        // Only ip6
+        if let IpNetwork::V6(ref ip) = route.prefix {
+            // Only host addresses and installed routes
+            if ip.prefix() == 128 && route.installed && IpAddr::V6(ip.ip()) == *mesh_ip {
+                exit_route = Some(route);
+                break;
+            }
+        }
+    }
+    if exit_route.is_none() {
+        bail!("No installed route to that destination!");
+    }
+    Ok(exit_route.unwrap().clone())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -537,14 +481,65 @@ ok\n";
     static IFACE_LINE: &'static str =
         "add interface wlan0 up true ipv6 fe80::1a8b:ec1:8542:1bd8 ipv4 10.28.119.131";
 
-    static PRICE_LINE: &'static str = "local price 1024";
+    static FEE_LINE: &'static str = "local fee 1024";
 
     #[test]
     fn mock_connect() {
         let mut s = SharedMockStream::new();
         s.push_bytes_to_read(PREAMBLE.as_bytes());
         let mut b = Babel::new(s);
-        b.start_connection().unwrap()
+        b.start_connection().unwrap();
+        assert_eq!(b.get_preamble().unwrap().protocol_version, "0.1");
+    }
+
+    #[test]
+    fn mock_connect_upstream() {
+        let mut s = SharedMockStream::new();
+        s.push_bytes_to_read(b"BABEL 1.0\nversion babeld-1.6.3\nok\n");
+        s.push_bytes_to_read(b"ok\n");
+        let mut b = Babel::new(s.clone());
+        b.start_connection().unwrap();
+
+        assert!(b.set_local_fee(10).is_err());
+        b.monitor("wg0").unwrap();
+        assert_eq!(s.pop_bytes_written(), b"interface wg0\n");
+    }
+
+    #[test]
+    fn mock_configure() {
+        let mut s = SharedMockStream::new();
+        s.push_bytes_to_read(PREAMBLE.as_bytes());
+        s.push_bytes_to_read(b"ok\n");
+        s.push_bytes_to_read(b"ok\n");
+        let mut b = Babel::new(s.clone());
+        b.start_connection().unwrap();
+
+        let config = InterfaceConfig {
+            link_type: Some(LinkType::Wireless),
+            ..InterfaceConfig::tunnel_default()
+        };
+        b.configure_interface("wg0", &config).unwrap();
+        b.add_filter(&Filter {
+            filter_type: FilterType::Out,
+            iface: Some("wg0".to_string()),
+            prefix: None,
+            neigh: None,
+            action: FilterAction::Deny,
+        })
+        .unwrap();
+        assert_eq!(
+            s.pop_bytes_written(),
+            &b"interface wg0 type wireless max-rtt-penalty 500 enable-timestamps true\n\
+               out if wg0 deny\n"[..]
+        );
+    }
+
+    #[test]
+    fn mock_connect_incompatible() {
+        let mut s = SharedMockStream::new();
+        s.push_bytes_to_read(b"ALTHEA 1.0\nversion babeld-2.0.0\nok\n");
+        let mut b = Babel::new(s);
+        assert!(b.start_connection().is_err());
     }
 
     #[test]
@@ -559,41 +554,56 @@ ok\n";
 
     #[test]
     fn line_parse() {
-        assert_eq!(find_babel_val("metric", XROUTE_LINE).unwrap(), "0");
-        assert_eq!(
-            find_babel_val("prefix", XROUTE_LINE).unwrap(),
-            "10.28.119.131/32"
-        );
-        assert_eq!(find_babel_val("route", ROUTE_LINE).unwrap(), "14f06d8");
-        assert_eq!(find_babel_val("if", ROUTE_LINE).unwrap(), "wlan0");
-        assert_eq!(
-            find_babel_val("via", ROUTE_LINE).unwrap(),
-            "fe80::e9d0:498f:6c61:be29"
-        );
-        assert_eq!(
-            find_babel_val("route", PROBLEM_ROUTE_LINE).unwrap(),
-            "241fee0"
-        );
-        assert_eq!(find_babel_val("fee", PROBLEM_ROUTE_LINE).unwrap(), "354600");
-        assert_eq!(
-            find_babel_val("price", PROBLEM_ROUTE_LINE).unwrap(),
-            "426000"
-        );
-        assert_eq!(find_babel_val("if", PROBLEM_ROUTE_LINE).unwrap(), "wg36");
-        assert_eq!(
-            find_babel_val("prefix", PROBLEM_ROUTE_LINE).unwrap(),
-            "fdc5:5bcb:24ac:b35a:4b7f:146a:a2a1:bdc4/128"
-        );
-        assert_eq!(
-            find_babel_val("full-path-rtt", PROBLEM_ROUTE_LINE).unwrap(),
-            "38.286"
-        );
-        assert_eq!(find_babel_val("reach", NEIGH_LINE).unwrap(), "ffff");
-        assert_eq!(find_babel_val("rxcost", NEIGH_LINE).unwrap(), "256");
-        assert_eq!(find_babel_val("rtt", NEIGH_LINE).unwrap(), "29.264");
-        assert_eq!(find_babel_val("interface", IFACE_LINE).unwrap(), "wlan0");
-        assert_eq!(find_babel_val("ipv4", IFACE_LINE).unwrap(), "10.28.119.131");
-        assert_eq!(find_babel_val("price", PRICE_LINE).unwrap(), "1024");
+        match parse_line(XROUTE_LINE).unwrap() {
+            BabelLine::Update(Action::Add, Entry::Xroute(xroute)) => {
+                assert_eq!(xroute.metric, 0);
+                assert_eq!(xroute.prefix, "10.28.119.131/32".parse().unwrap());
+            }
+            line => panic!("Not an xroute {:?}", line),
+        }
+        match parse_line(ROUTE_LINE).unwrap() {
+            BabelLine::Update(Action::Add, Entry::Route(route)) => {
+                assert_eq!(route.id, "14f06d8");
+                assert_eq!(route.iface, "wlan0");
+                assert_eq!(
+                    route.neigh_ip,
+                    "fe80::e9d0:498f:6c61:be29".parse::<IpAddr>().unwrap()
+                );
+            }
+            line => panic!("Not a route {:?}", line),
+        }
+        match parse_line(PROBLEM_ROUTE_LINE).unwrap() {
+            BabelLine::Update(Action::Add, Entry::Route(route)) => {
+                assert_eq!(route.id, "241fee0");
+                assert_eq!(route.fee, 354600);
+                assert_eq!(route.price, 426000);
+                assert_eq!(route.iface, "wg36");
+                assert_eq!(
+                    route.prefix,
+                    "fdc5:5bcb:24ac:b35a:4b7f:146a:a2a1:bdc4/128"
+                        .parse()
+                        .unwrap()
+                );
+                assert_eq!(route.full_path_rtt, 38.286);
+            }
+            line => panic!("Not a route {:?}", line),
+        }
+        match parse_line(NEIGH_LINE).unwrap() {
+            BabelLine::Update(Action::Add, Entry::Neighbour(neigh)) => {
+                assert_eq!(neigh.reach, 0xffff);
+                assert_eq!(neigh.rxcost, 256);
+                assert_eq!(neigh.rtt, 29.264);
+            }
+            line => panic!("Not a neighbour {:?}", line),
+        }
+        match parse_line(IFACE_LINE).unwrap() {
+            BabelLine::Update(Action::Add, Entry::Interface(iface)) => {
+                assert_eq!(iface.name, "wlan0");
+                assert_eq!(iface.ipv4, Some("10.28.119.131".parse().unwrap()));
+            }
+            line => panic!("Not an interface {:?}", line),
+        }
+        assert_eq!(parse_line(FEE_LINE).unwrap(), BabelLine::LocalFee(1024));
     }
 
     #[test]
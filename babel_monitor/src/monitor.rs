@@ -0,0 +1,171 @@
+//! An in memory copy of babeld's tables, built from the dump babeld sends when a connection
+//! starts monitoring and then kept up to date from the add/change/flush events that follow
+
+use crate::{
+    do_we_have_route, get_installed_route, get_route_via_neigh, parse_line, Action, BabelLine,
+    Entry, Interface, Neighbor, ParseError, Route, Xroute,
+};
+use failure::Error;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+
+#[derive(Debug, Clone, Default)]
+pub struct BabelTable {
+    /// Set when babeld doesn't support fees, every route is then free and we have no fee
+    pub unpriced: bool,
+    pub local_fee: Option<u32>,
+    pub metric_factor: Option<u32>,
+    /// keyed by interface name
+    pub interfaces: HashMap<String, Interface>,
+    /// keyed by babel's neighbour id
+    pub neighbours: HashMap<String, Neighbor>,
+    /// keyed by babel's route id
+    pub routes: HashMap<String, Route>,
+    /// keyed by babel's xroute id
+    pub xroutes: HashMap<String, Xroute>,
+}
+
+impl BabelTable {
+    /// Builds a table from the output of `dump` or `monitor`
+    pub fn from_dump(dump: &str) -> BabelTable {
+        let mut table = BabelTable::default();
+        for line in dump.lines() {
+            table.apply_line(line);
+        }
+        table
+    }
+
+    /// Applies a single line of babel output to the table, lines that are not table
+    /// entries or updates (like the `ok` terminator) are ignored
+    pub fn apply_line(&mut self, line: &str) {
+        match parse_line(line) {
+            Ok(BabelLine::LocalFee(fee)) => self.local_fee = Some(fee),
+            Ok(BabelLine::MetricFactor(factor)) => self.metric_factor = Some(factor),
+            Ok(BabelLine::Update(Action::Flush, entry)) => match entry {
+                Entry::Interface(iface) => {
+                    self.interfaces.remove(&iface.name);
+                }
+                Entry::Neighbour(neigh) => {
+                    self.neighbours.remove(&neigh.id);
+                }
+                Entry::Route(route) => {
+                    self.routes.remove(&route.id);
+                }
+                Entry::Xroute(xroute) => {
+                    self.xroutes.remove(&xroute.id);
+                }
+            },
+            Ok(BabelLine::Update(_, entry)) => match entry {
+                Entry::Interface(iface) => {
+                    self.interfaces.insert(iface.name.clone(), iface);
+                }
+                Entry::Neighbour(neigh) => {
+                    self.neighbours.insert(neigh.id.clone(), neigh);
+                }
+                Entry::Route(route) => {
+                    self.routes.insert(route.id.clone(), route);
+                }
+                Entry::Xroute(xroute) => {
+                    self.xroutes.insert(xroute.id.clone(), xroute);
+                }
+            },
+            Ok(line) => trace!("Ignoring babel line {:?}", line),
+            Err(ParseError::EmptyLine) => {}
+            Err(e) => warn!("Failed to parse babel update {}", e),
+        }
+    }
+
+    pub fn neighs(&self) -> VecDeque<Neighbor> {
+        self.neighbours.values().cloned().collect()
+    }
+
+    pub fn routes(&self) -> VecDeque<Route> {
+        self.routes.values().cloned().collect()
+    }
+
+    /// Finds the route to dest_mesh_ip through the neighbor with neigh_mesh_ip
+    pub fn get_route_via_neigh(
+        &self,
+        neigh_mesh_ip: IpAddr,
+        dest_mesh_ip: IpAddr,
+    ) -> Result<Route, Error> {
+        get_route_via_neigh(neigh_mesh_ip, dest_mesh_ip, &self.routes())
+    }
+
+    /// Checks if Babel has an installed route to the given destination
+    pub fn do_we_have_route(&self, mesh_ip: &IpAddr) -> bool {
+        do_we_have_route(mesh_ip, &self.routes())
+    }
+
+    /// Returns the installed route to a given destination
+    pub fn get_installed_route(&self, mesh_ip: &IpAddr) -> Result<Route, Error> {
+        get_installed_route(mesh_ip, &self.routes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static DUMP: &'static str = "local fee 1024\n\
+metric factor 1900\n\
+add interface lo up false\n\
+add interface wlan0 up true ipv6 fe80::1a8b:ec1:8542:1bd8 ipv4 10.28.119.131\n\
+add neighbour 14f19a8 address fe80::2cee:2fff:648:8796 if wg0 reach ffff rxcost 256 txcost 256 rtt \
+26.723 rttcost 912 cost 1168\n\
+add xroute 10.28.119.131/32-::/0 prefix 10.28.119.131/32 from ::/0 metric 0\n\
+add route 14f0820 prefix fd00::1/128 from ::/0 installed yes id ba:27:eb:ff:fe:5b:fe:c7 \
+metric 1596 price 3072 fee 3072 refmetric 638 full-path-rtt 22.805 via fe80::2cee:2fff:648:8796 if wg0\n\
+ok\n";
+
+    #[test]
+    fn test_from_dump() {
+        let table = BabelTable::from_dump(DUMP);
+        assert_eq!(table.local_fee, Some(1024));
+        assert_eq!(table.metric_factor, Some(1900));
+        assert_eq!(table.interfaces.len(), 2);
+        assert!(!table.interfaces["lo"].up);
+        assert!(table.interfaces["wlan0"].up);
+        assert_eq!(table.neighbours["14f19a8"].iface, "wg0");
+        assert_eq!(table.xroutes.len(), 1);
+        assert_eq!(table.routes["14f0820"].price, 3072);
+        assert!(table.do_we_have_route(&"fd00::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_events() {
+        let mut table = BabelTable::from_dump(DUMP);
+
+        table.apply_line(
+            "change route 14f0820 prefix fd00::1/128 from ::/0 installed no id \
+             ba:27:eb:ff:fe:5b:fe:c7 metric 1596 price 4096 fee 3072 refmetric 638 \
+             full-path-rtt 22.805 via fe80::2cee:2fff:648:8796 if wg0",
+        );
+        assert_eq!(table.routes["14f0820"].price, 4096);
+        assert!(!table.do_we_have_route(&"fd00::1".parse().unwrap()));
+
+        table.apply_line(
+            "add neighbour 14f0640 address fe80::e841:e384:491e:8eb9 if wlan0 reach 9ff7 \
+             rxcost 512 txcost 256 rtt 19.323 rttcost 508 cost 1020",
+        );
+        assert_eq!(table.neighs().len(), 2);
+
+        table.apply_line("flush neighbour 14f19a8 address fe80::2cee:2fff:648:8796 if wg0");
+        table.apply_line(
+            "flush route 14f0820 prefix fd00::1/128 from ::/0 installed no id \
+             ba:27:eb:ff:fe:5b:fe:c7 metric 65535 price 4096 fee 3072 refmetric 638 \
+             full-path-rtt 22.805 via fe80::2cee:2fff:648:8796 if wg0",
+        );
+        table.apply_line("flush interface lo");
+        table.apply_line("local fee 2048");
+        assert_eq!(table.neighs().len(), 1);
+        assert!(table.routes.is_empty());
+        assert_eq!(table.interfaces.len(), 1);
+        assert_eq!(table.local_fee, Some(2048));
+
+        // garbage does not touch the table
+        table.apply_line("ok");
+        table.apply_line("add route broken");
+        assert_eq!(table.neighs().len(), 1);
+    }
+}
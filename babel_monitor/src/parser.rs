@@ -0,0 +1,476 @@
+//! A typed parser for the lines babeld writes on its configuration socket. Every line babeld
+//! sends is either part of the preamble, a terminator, one of the global values in a dump or
+//! an add/change/flush of one of babel's tables, the tables lines are made up of an id
+//! followed by key value pairs which we parse into the matching struct.
+//!
+//! Keys we don't know about are ignored so that newer babeld versions adding fields don't
+//! break parsing, missing or malformed fields we need produce a ParseError for that line.
+
+use crate::{Interface, Neighbor, Route, Xroute};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+#[derive(Debug, Fail, Clone, PartialEq)]
+pub enum ParseError {
+    #[fail(display = "Empty line")]
+    EmptyLine,
+    #[fail(display = "Unknown line '{}'", _0)]
+    UnknownLine(String),
+    #[fail(display = "Missing field '{}' in '{}'", _0, _1)]
+    MissingField(String, String),
+    #[fail(display = "Invalid value '{}' for field '{}' in '{}'", _1, _0, _2)]
+    InvalidField(String, String, String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Add,
+    Change,
+    Flush,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Entry {
+    Interface(Interface),
+    Neighbour(Neighbor),
+    Route(Route),
+    Xroute(Xroute),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BabelLine {
+    /// The first line of the preamble, `ALTHEA 0.1` for example
+    Protocol {
+        name: String,
+        version: String,
+    },
+    /// The babeld version string from the preamble
+    Version(String),
+    Host(String),
+    /// Our router id
+    MyId(String),
+    LocalFee(u32),
+    MetricFactor(u32),
+    Update(Action, Entry),
+    Ok,
+    No,
+    Bad,
+}
+
+/// Parses every line of a babel output, such as a preamble or a dump
+pub fn parse_output(output: &str) -> Vec<Result<BabelLine, ParseError>> {
+    output
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(parse_line)
+        .collect()
+}
+
+pub fn parse_line(line: &str) -> Result<BabelLine, ParseError> {
+    let line = line.trim();
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        [] => Err(ParseError::EmptyLine),
+        ["ok"] => Ok(BabelLine::Ok),
+        ["no"] => Ok(BabelLine::No),
+        ["bad"] => Ok(BabelLine::Bad),
+        ["version", version] => Ok(BabelLine::Version(version.to_string())),
+        ["host", host] => Ok(BabelLine::Host(host.to_string())),
+        ["my-id", id] => Ok(BabelLine::MyId(id.to_string())),
+        ["local", "fee", fee] => Ok(BabelLine::LocalFee(parse_value("fee", fee, line)?)),
+        ["metric", "factor", factor] => Ok(BabelLine::MetricFactor(parse_value(
+            "factor", factor, line,
+        )?)),
+        [name, version] if name.chars().all(|c| c.is_ascii_uppercase()) => {
+            Ok(BabelLine::Protocol {
+                name: name.to_string(),
+                version: version.to_string(),
+            })
+        }
+        _ => parse_update(line, &words),
+    }
+}
+
+/// Parses a table entry, `<action> <kind> <id> <key> <value>...`
+fn parse_update(line: &str, words: &[&str]) -> Result<BabelLine, ParseError> {
+    if words.len() < 3 {
+        return Err(ParseError::UnknownLine(line.to_string()));
+    }
+    let action = match words[0] {
+        "add" => Action::Add,
+        "change" => Action::Change,
+        "flush" => Action::Flush,
+        _ => return Err(ParseError::UnknownLine(line.to_string())),
+    };
+    let id = words[2];
+    let fields = Fields::new(line, &words[3..]);
+    let entry = match words[1] {
+        "interface" => Entry::Interface(parse_interface(id, action, &fields)?),
+        "neighbour" => Entry::Neighbour(parse_neighbour(id, &fields)?),
+        "route" => Entry::Route(parse_route(id, &fields)?),
+        "xroute" => Entry::Xroute(parse_xroute(id, &fields)?),
+        _ => return Err(ParseError::UnknownLine(line.to_string())),
+    };
+    Ok(BabelLine::Update(action, entry))
+}
+
+/// The key value pairs that follow the id of a table entry
+struct Fields<'a> {
+    line: &'a str,
+    values: HashMap<&'a str, &'a str>,
+}
+
+impl<'a> Fields<'a> {
+    fn new(line: &'a str, words: &[&'a str]) -> Fields<'a> {
+        let mut values = HashMap::new();
+        for pair in words.chunks(2) {
+            if let [key, value] = pair {
+                values.entry(*key).or_insert(*value);
+            }
+        }
+        Fields { line, values }
+    }
+
+    fn get_str(&self, key: &str) -> Result<&'a str, ParseError> {
+        match self.values.get(key) {
+            Some(value) => Ok(*value),
+            None => Err(ParseError::MissingField(
+                key.to_string(),
+                self.line.to_string(),
+            )),
+        }
+    }
+
+    fn get<T: FromStr>(&self, key: &str) -> Result<T, ParseError> {
+        parse_value(key, self.get_str(key)?, self.line)
+    }
+
+    /// For fields babeld leaves out in some configurations, rtt for example is only sent
+    /// for neighbours with timestamps enabled
+    fn get_or<T: FromStr>(&self, key: &str, default: T) -> Result<T, ParseError> {
+        match self.values.get(key) {
+            Some(value) => parse_value(key, value, self.line),
+            None => Ok(default),
+        }
+    }
+
+    fn get_opt<T: FromStr>(&self, key: &str) -> Result<Option<T>, ParseError> {
+        match self.values.get(key) {
+            Some(value) => Ok(Some(parse_value(key, value, self.line)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_bool(&self, key: &str) -> Result<bool, ParseError> {
+        match self.get_str(key)? {
+            "yes" | "true" => Ok(true),
+            "no" | "false" => Ok(false),
+            value => Err(invalid(key, value, self.line)),
+        }
+    }
+}
+
+fn parse_value<T: FromStr>(key: &str, value: &str, line: &str) -> Result<T, ParseError> {
+    value.parse().map_err(|_| invalid(key, value, line))
+}
+
+fn invalid(key: &str, value: &str, line: &str) -> ParseError {
+    ParseError::InvalidField(key.to_string(), value.to_string(), line.to_string())
+}
+
+fn parse_interface(name: &str, action: Action, fields: &Fields) -> Result<Interface, ParseError> {
+    Ok(Interface {
+        name: name.to_string(),
+        // babeld only sends the interface name when flushing
+        up: action != Action::Flush && fields.get_bool("up")?,
+        ipv6: fields.get_opt("ipv6")?,
+        ipv4: fields.get_opt("ipv4")?,
+    })
+}
+
+fn parse_neighbour(id: &str, fields: &Fields) -> Result<Neighbor, ParseError> {
+    let reach = fields.get_str("reach")?;
+    Ok(Neighbor {
+        id: id.to_string(),
+        address: fields.get("address")?,
+        iface: fields.get_str("if")?.to_string(),
+        reach: u16::from_str_radix(reach, 16).map_err(|_| invalid("reach", reach, fields.line))?,
+        txcost: fields.get("txcost")?,
+        rxcost: fields.get("rxcost")?,
+        rtt: fields.get_or("rtt", 0.0)?,
+        rttcost: fields.get_or("rttcost", 0)?,
+        cost: fields.get("cost")?,
+    })
+}
+
+fn parse_route(id: &str, fields: &Fields) -> Result<Route, ParseError> {
+    Ok(Route {
+        id: id.to_string(),
+        iface: fields.get_str("if")?.to_string(),
+        xroute: false,
+        installed: fields.get_bool("installed")?,
+        neigh_ip: fields.get("via")?,
+        prefix: fields.get("prefix")?,
+        src_prefix: fields.get("from")?,
+        router_id: fields.get_str("id")?.to_string(),
+        metric: fields.get("metric")?,
+        refmetric: fields.get("refmetric")?,
+        full_path_rtt: fields.get_or("full-path-rtt", 0.0)?,
+        // stock babeld has no fees, its routes are free as far as we are concerned
+        price: fields.get_or("price", 0)?,
+        fee: fields.get_or("fee", 0)?,
+    })
+}
+
+fn parse_xroute(id: &str, fields: &Fields) -> Result<Xroute, ParseError> {
+    Ok(Xroute {
+        id: id.to_string(),
+        prefix: fields.get("prefix")?,
+        src_prefix: fields.get("from")?,
+        metric: fields.get("metric")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static ALTHEA_PREAMBLE: &'static str =
+        include_str!("../test_data/synthetic_althea_preamble.txt");
+    static BABEL_PREAMBLE: &'static str = include_str!("../test_data/synthetic_babel_preamble.txt");
+    static GATEWAY_DUMP: &'static str = include_str!("../test_data/synthetic_gateway_dump.txt");
+    static EXIT_DUMP: &'static str = include_str!("../test_data/synthetic_exit_dump.txt");
+    static STOCK_DUMP: &'static str = include_str!("../test_data/synthetic_stock_dump.txt");
+    static MONITOR_EVENTS: &'static str = include_str!("../test_data/synthetic_monitor_events.txt");
+
+    fn parse_all(output: &str) -> Vec<BabelLine> {
+        parse_output(output)
+            .into_iter()
+            .map(|line| line.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_preambles() {
+        let lines = parse_all(ALTHEA_PREAMBLE);
+        assert_eq!(
+            lines[0],
+            BabelLine::Protocol {
+                name: "ALTHEA".to_string(),
+                version: "0.1".to_string()
+            }
+        );
+        assert_eq!(
+            lines[1],
+            BabelLine::Version("babeld-1.8.0-24-g6335378".to_string())
+        );
+        assert_eq!(lines[2], BabelLine::Host("raspberrypi".to_string()));
+        assert_eq!(
+            lines[3],
+            BabelLine::MyId("ba:27:eb:ff:fe:09:06:dd".to_string())
+        );
+        assert_eq!(lines[4], BabelLine::Ok);
+
+        let lines = parse_all(BABEL_PREAMBLE);
+        assert_eq!(
+            lines[0],
+            BabelLine::Protocol {
+                name: "BABEL".to_string(),
+                version: "1.0".to_string()
+            }
+        );
+        assert_eq!(lines.len(), 5);
+    }
+
+    #[test]
+    fn test_gateway_dump() {
+        let lines = parse_all(GATEWAY_DUMP);
+        assert_eq!(lines[0], BabelLine::LocalFee(1024));
+        assert_eq!(lines[1], BabelLine::MetricFactor(1900));
+        assert_eq!(*lines.last().unwrap(), BabelLine::Ok);
+
+        let count = |kind: fn(&Entry) -> bool| {
+            lines
+                .iter()
+                .filter(|line| match line {
+                    BabelLine::Update(Action::Add, entry) => kind(entry),
+                    _ => false,
+                })
+                .count()
+        };
+        assert_eq!(count(matches_interface), 3);
+        assert_eq!(count(matches_neighbour), 4);
+        assert_eq!(count(matches_xroute), 1);
+        assert_eq!(count(matches_route), 5);
+
+        match &lines[2] {
+            BabelLine::Update(Action::Add, Entry::Interface(iface)) => {
+                assert_eq!(iface.name, "lo");
+                assert!(!iface.up);
+                assert_eq!(iface.ipv6, None);
+            }
+            line => panic!("Unexpected line {:?}", line),
+        }
+        match &lines[9] {
+            BabelLine::Update(Action::Add, Entry::Xroute(xroute)) => {
+                assert_eq!(xroute.id, "10.28.119.131/32-::/0");
+                assert_eq!(xroute.prefix, "10.28.119.131/32".parse().unwrap());
+                assert_eq!(xroute.src_prefix, "::/0".parse().unwrap());
+                assert_eq!(xroute.metric, 0);
+            }
+            line => panic!("Unexpected line {:?}", line),
+        }
+        match &lines[14] {
+            BabelLine::Update(Action::Add, Entry::Route(route)) => {
+                assert_eq!(route.id, "241fee0");
+                assert_eq!(route.router_id, "e6:95:6e:ff:fe:44:c4:12");
+                assert_eq!(route.src_prefix, "::/0".parse().unwrap());
+                assert!(!route.installed);
+                assert_eq!(route.price, 426000);
+                assert_eq!(route.fee, 354600);
+                assert_eq!(route.iface, "wg36");
+            }
+            line => panic!("Unexpected line {:?}", line),
+        }
+    }
+
+    #[test]
+    fn test_exit_dump() {
+        let lines = parse_all(EXIT_DUMP);
+        match &lines[3] {
+            // neighbours without timestamps have no rtt
+            BabelLine::Update(Action::Add, Entry::Neighbour(neigh)) => {
+                assert_eq!(neigh.reach, 0xffff);
+                assert_eq!(neigh.rtt, 0.0);
+                assert_eq!(neigh.rttcost, 0);
+                assert_eq!(neigh.cost, 96);
+            }
+            line => panic!("Unexpected line {:?}", line),
+        }
+    }
+
+    #[test]
+    fn test_stock_dump() {
+        let lines = parse_all(STOCK_DUMP);
+        match &lines[1] {
+            BabelLine::Update(Action::Add, Entry::Neighbour(neigh)) => {
+                assert_eq!(neigh.reach, 0xffff);
+                assert_eq!(neigh.cost, 96);
+            }
+            line => panic!("Unexpected line {:?}", line),
+        }
+        // stock babeld has no fees, its routes are free
+        match &lines[3] {
+            BabelLine::Update(Action::Add, Entry::Route(route)) => {
+                assert!(route.installed);
+                assert_eq!(route.metric, 96);
+                assert_eq!(route.price, 0);
+                assert_eq!(route.fee, 0);
+            }
+            line => panic!("Unexpected line {:?}", line),
+        }
+    }
+
+    #[test]
+    fn test_monitor_events() {
+        let lines = parse_all(MONITOR_EVENTS);
+        let actions: Vec<Action> = lines
+            .iter()
+            .filter_map(|line| match line {
+                BabelLine::Update(action, _) => Some(*action),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            actions,
+            vec![
+                Action::Change,
+                Action::Add,
+                Action::Change,
+                Action::Flush,
+                Action::Flush,
+                Action::Flush,
+                Action::Add
+            ]
+        );
+        match &lines[5] {
+            BabelLine::Update(Action::Flush, Entry::Interface(iface)) => {
+                assert_eq!(iface.name, "wg3");
+                assert!(!iface.up);
+            }
+            line => panic!("Unexpected line {:?}", line),
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(parse_line(""), Err(ParseError::EmptyLine));
+        assert_eq!(
+            parse_line("hello there"),
+            Err(ParseError::UnknownLine("hello there".to_string()))
+        );
+        assert_eq!(
+            parse_line("add bridge 1 up true"),
+            Err(ParseError::UnknownLine("add bridge 1 up true".to_string()))
+        );
+
+        let line = "add route 14f06d8 prefix 10.28.20.151/32 from 0.0.0.0/0 installed yes id \
+                    ba:27:eb:ff:fe:c1:2d:d5 metric 1306 price 4008 refmetric 0 full-path-rtt \
+                    18.674 if wlan0";
+        assert_eq!(
+            parse_line(line),
+            Err(ParseError::MissingField(
+                "via".to_string(),
+                line.to_string()
+            ))
+        );
+
+        let line = "add neighbour 14f05f0 address fe80::e9d0:498f:6c61:be29 if wlan0 reach \
+                    zzzz rxcost 256 txcost 256 cost 1306";
+        assert_eq!(
+            parse_line(line),
+            Err(ParseError::InvalidField(
+                "reach".to_string(),
+                "zzzz".to_string(),
+                line.to_string()
+            ))
+        );
+
+        assert_eq!(
+            parse_line("local fee lots"),
+            Err(ParseError::InvalidField(
+                "fee".to_string(),
+                "lots".to_string(),
+                "local fee lots".to_string()
+            ))
+        );
+    }
+
+    fn matches_interface(entry: &Entry) -> bool {
+        match entry {
+            Entry::Interface(_) => true,
+            _ => false,
+        }
+    }
+
+    fn matches_neighbour(entry: &Entry) -> bool {
+        match entry {
+            Entry::Neighbour(_) => true,
+            _ => false,
+        }
+    }
+
+    fn matches_route(entry: &Entry) -> bool {
+        match entry {
+            Entry::Route(_) => true,
+            _ => false,
+        }
+    }
+
+    fn matches_xroute(entry: &Entry) -> bool {
+        match entry {
+            Entry::Xroute(_) => true,
+            _ => false,
+        }
+    }
+}
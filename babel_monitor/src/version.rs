@@ -0,0 +1,178 @@
+//! babeld announces the configuration protocol it speaks and its own version in the preamble
+//! it sends when we connect. Rather than requiring one exact protocol string we look the
+//! protocol up in a table of the ones we know and turn the features it lacks off, so that a
+//! babeld upgrade in the field degrades gracefully or fails with an error that says why.
+
+use crate::parser::{parse_output, BabelLine};
+use crate::BabelMonitorError::{IncompatibleVersion, InvalidPreamble};
+use failure::Error;
+
+/// The optional features of the configuration protocol that we use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Routes carry prices and the `fee` command sets our own
+    pub fees: bool,
+    /// The `metric-factor` command that trades off price against route quality
+    pub metric_factor: bool,
+    /// Interfaces can be configured with `enable-timestamps` and `max-rtt-penalty`
+    pub timestamps: bool,
+}
+
+/// Everything Althea's babeld supports, used until a preamble says otherwise
+pub const ALTHEA_CAPABILITIES: Capabilities = Capabilities {
+    fees: true,
+    metric_factor: true,
+    timestamps: true,
+};
+
+/// The configuration protocols we can talk, by name and major version, minor versions only
+/// add features so any of them will do
+const KNOWN_PROTOCOLS: [(&str, u32, Capabilities); 2] = [
+    ("ALTHEA", 0, ALTHEA_CAPABILITIES),
+    (
+        "BABEL",
+        1,
+        Capabilities {
+            fees: false,
+            metric_factor: false,
+            timestamps: true,
+        },
+    ),
+];
+
+/// babeld releases before this don't have rtt based metrics
+const MIN_TIMESTAMPS_RELEASE: (u32, u32) = (1, 7);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Preamble {
+    /// The name of the configuration protocol, ALTHEA for Althea's fork
+    pub protocol: String,
+    pub protocol_version: String,
+    /// The full babeld version string, `babeld-1.8.0-24-g6335378` for example
+    pub babeld_version: Option<String>,
+    pub host: Option<String>,
+    pub my_id: Option<String>,
+}
+
+impl Preamble {
+    pub fn parse(output: &str) -> Result<Preamble, Error> {
+        let mut protocol = None;
+        let mut babeld_version = None;
+        let mut host = None;
+        let mut my_id = None;
+        for line in parse_output(output) {
+            match line {
+                Ok(BabelLine::Protocol { name, version }) => protocol = Some((name, version)),
+                Ok(BabelLine::Version(version)) => babeld_version = Some(version),
+                Ok(BabelLine::Host(name)) => host = Some(name),
+                Ok(BabelLine::MyId(id)) => my_id = Some(id),
+                Ok(_) => {}
+                Err(e) => warn!("Unexpected line in babel preamble {}", e),
+            }
+        }
+        match protocol {
+            Some((protocol, protocol_version)) => Ok(Preamble {
+                protocol,
+                protocol_version,
+                babeld_version,
+                host,
+                my_id,
+            }),
+            None => Err(InvalidPreamble(output.to_string()).into()),
+        }
+    }
+
+    /// The major and minor babeld release, if the version string can be understood
+    pub fn babeld_release(&self) -> Option<(u32, u32)> {
+        let version = self.babeld_version.as_ref()?;
+        let version = version.trim_start_matches("babeld-");
+        let mut numbers = version.split(|c| c == '.' || c == '-');
+        let major = numbers.next()?.parse().ok()?;
+        let minor = numbers.next()?.parse().ok()?;
+        Some((major, minor))
+    }
+
+    /// Finds the features we can use with this babeld, or an error explaining why we can't
+    /// talk to it at all
+    pub fn negotiate(&self) -> Result<Capabilities, Error> {
+        let major: Option<u32> = self
+            .protocol_version
+            .split('.')
+            .next()
+            .and_then(|major| major.parse().ok());
+
+        let mut capabilities = None;
+        for (name, known_major, known_capabilities) in KNOWN_PROTOCOLS.iter() {
+            if *name == self.protocol && Some(*known_major) == major {
+                capabilities = Some(*known_capabilities);
+            }
+        }
+        let mut capabilities = match capabilities {
+            Some(capabilities) => capabilities,
+            None => return Err(IncompatibleVersion(self.describe()).into()),
+        };
+
+        if let Some(release) = self.babeld_release() {
+            if release < MIN_TIMESTAMPS_RELEASE {
+                capabilities.timestamps = false;
+            }
+        }
+        Ok(capabilities)
+    }
+
+    /// A human readable summary for logs and errors, `ALTHEA 0.1 (babeld-1.8.0)`
+    pub fn describe(&self) -> String {
+        format!(
+            "{} {} ({})",
+            self.protocol,
+            self.protocol_version,
+            self.babeld_version
+                .clone()
+                .unwrap_or_else(|| "unknown babeld version".to_string())
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static ALTHEA_PREAMBLE: &'static str =
+        include_str!("../test_data/synthetic_althea_preamble.txt");
+    static BABEL_PREAMBLE: &'static str = include_str!("../test_data/synthetic_babel_preamble.txt");
+
+    #[test]
+    fn test_althea_preamble() {
+        let preamble = Preamble::parse(ALTHEA_PREAMBLE).unwrap();
+        assert_eq!(preamble.protocol, "ALTHEA");
+        assert_eq!(preamble.protocol_version, "0.1");
+        assert_eq!(preamble.babeld_release(), Some((1, 8)));
+        assert_eq!(preamble.host, Some("raspberrypi".to_string()));
+        assert_eq!(preamble.negotiate().unwrap(), ALTHEA_CAPABILITIES);
+
+        // a newer minor version of our protocol is fine
+        let preamble = Preamble::parse("ALTHEA 0.2\nversion babeld-1.9.0\nok\n").unwrap();
+        assert_eq!(preamble.negotiate().unwrap(), ALTHEA_CAPABILITIES);
+    }
+
+    #[test]
+    fn test_upstream_preamble() {
+        let preamble = Preamble::parse(BABEL_PREAMBLE).unwrap();
+        let capabilities = preamble.negotiate().unwrap();
+        assert!(!capabilities.fees);
+        assert!(!capabilities.metric_factor);
+        assert!(capabilities.timestamps);
+
+        let preamble = Preamble::parse("BABEL 1.0\nversion babeld-1.6.3\nok\n").unwrap();
+        assert!(!preamble.negotiate().unwrap().timestamps);
+    }
+
+    #[test]
+    fn test_incompatible_preamble() {
+        let preamble = Preamble::parse("ALTHEA 1.0\nversion babeld-2.0.0\nok\n").unwrap();
+        let error = preamble.negotiate().unwrap_err().to_string();
+        assert!(error.contains("ALTHEA 1.0 (babeld-2.0.0)"));
+
+        assert!(Preamble::parse("version babeld-1.8.0\nok\n").is_err());
+    }
+}
@@ -196,7 +196,7 @@ fn linux_init(config: Arc<RwLock<settings::client::RitaSettingsStruct>>) -> Resu
     // Yield the mut lock
     drop(payment_settings);
 
-    let local_fee = config.get_payment().local_fee;
+    let local_fee = config.get_payment().get_relay_fee();
     let metric_factor = config.get_network().metric_factor;
     if local_fee == 0 {
         warn!("THIS NODE IS GIVING BANDWIDTH AWAY FOR FREE. PLEASE SET local_fee TO A NON-ZERO VALUE TO DISABLE THIS WARNING.");
@@ -313,7 +313,7 @@ fn linux_exit_init(
     // Yield the mut lock
     drop(payment_settings);
 
-    let local_fee = config.get_payment().local_fee;
+    let local_fee = config.get_payment().get_relay_fee();
     let metric_factor = config.get_network().metric_factor;
 
     let stream = TcpStream::connect::<SocketAddr>(
@@ -0,0 +1,11 @@
+CREATE TABLE client_plans
+(
+    mesh_ip varchar(40) PRIMARY KEY REFERENCES clients (mesh_ip) ON DELETE CASCADE,
+    plan_type varchar(16) NOT NULL,
+    quota bigint NOT NULL,
+    period bigint NOT NULL,
+    price varchar(78) NOT NULL,
+    over_quota varchar(16) NOT NULL,
+    period_start bigint DEFAULT 0 NOT NULL,
+    used bigint DEFAULT 0 NOT NULL
+);
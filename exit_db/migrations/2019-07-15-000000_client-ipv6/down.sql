@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE clients DROP COLUMN internal_ipv6;
\ No newline at end of file
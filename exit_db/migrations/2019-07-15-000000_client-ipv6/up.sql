@@ -0,0 +1 @@
+ALTER TABLE clients ADD COLUMN internal_ipv6 varchar(43) DEFAULT '' NOT NULL;
\ No newline at end of file
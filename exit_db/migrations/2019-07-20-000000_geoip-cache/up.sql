@@ -0,0 +1,6 @@
+CREATE TABLE geoip_cache
+(
+    ip varchar(39) PRIMARY KEY,
+    country varchar(2) NOT NULL,
+    lookup_time bigint NOT NULL
+);
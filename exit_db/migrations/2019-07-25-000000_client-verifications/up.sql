@@ -0,0 +1,8 @@
+CREATE TABLE client_verifications
+(
+    mesh_ip varchar(40) REFERENCES clients (mesh_ip) ON DELETE CASCADE,
+    method varchar(16) NOT NULL,
+    state varchar(16) NOT NULL,
+    updated bigint NOT NULL,
+    PRIMARY KEY (mesh_ip, method)
+);
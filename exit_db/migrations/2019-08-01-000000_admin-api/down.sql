@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE admin_audit_log;
+DROP TABLE banned_keys;
\ No newline at end of file
@@ -0,0 +1,16 @@
+CREATE TABLE banned_keys
+(
+    wg_pubkey varchar(44) PRIMARY KEY,
+    reason text DEFAULT '' NOT NULL,
+    banned_at bigint NOT NULL
+);
+
+CREATE TABLE admin_audit_log
+(
+    id bigserial PRIMARY KEY,
+    time bigint NOT NULL,
+    source varchar(45) NOT NULL,
+    action varchar(32) NOT NULL,
+    target varchar(64) NOT NULL,
+    details text DEFAULT '' NOT NULL
+);
@@ -1,4 +1,9 @@
+use crate::schema::admin_audit_log;
+use crate::schema::banned_keys;
+use crate::schema::client_plans;
+use crate::schema::client_verifications;
 use crate::schema::clients;
+use crate::schema::geoip_cache;
 
 #[derive(Queryable, Serialize, Deserialize, Debug, Insertable, Clone, AsChangeset, Default)]
 #[table_name = "clients"]
@@ -18,4 +23,74 @@ pub struct Client {
     pub text_sent: i32,
     pub last_seen: i64,
     pub last_balance_warning_time: i64,
+    /// The client's delegated ipv6 prefix, empty if it has none
+    pub internal_ipv6: String,
+}
+
+/// A client's billing plan, clients without one are billed per byte at the exit price.
+/// `plan_type` is `flat_rate` or `prepaid`, `over_quota` is `throttle` or `per_byte` and
+/// `price` is in wei
+#[derive(Queryable, Serialize, Deserialize, Debug, Insertable, Clone, AsChangeset, Default)]
+#[table_name = "client_plans"]
+pub struct ClientPlan {
+    pub mesh_ip: String,
+    pub plan_type: String,
+    pub quota: i64,
+    pub period: i64,
+    pub price: String,
+    pub over_quota: String,
+    pub period_start: i64,
+    pub used: i64,
+}
+
+/// A client's progress through one verification method, `state` is `pending`, `verified` or
+/// `denied` and `updated` is in seconds since the unix epoch
+#[derive(Queryable, Serialize, Deserialize, Debug, Insertable, Clone, AsChangeset, Default)]
+#[table_name = "client_verifications"]
+pub struct ClientVerification {
+    pub mesh_ip: String,
+    pub method: String,
+    pub state: String,
+    pub updated: i64,
+}
+
+/// A country the geoip api found for a gateway ip, `lookup_time` is in seconds since the
+/// unix epoch
+#[derive(Queryable, Serialize, Deserialize, Debug, Insertable, Clone, AsChangeset, Default)]
+#[table_name = "geoip_cache"]
+pub struct GeoipCacheEntry {
+    pub ip: String,
+    pub country: String,
+    pub lookup_time: i64,
+}
+
+/// A WireGuard key that may not register with the exit
+#[derive(Queryable, Serialize, Deserialize, Debug, Insertable, Clone, Default)]
+#[table_name = "banned_keys"]
+pub struct BannedKey {
+    pub wg_pubkey: String,
+    pub reason: String,
+    pub banned_at: i64,
+}
+
+/// Something an operator did through the admin api, `source` is the address the request
+/// came from and `time` is in seconds since the unix epoch
+#[derive(Queryable, Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub time: i64,
+    pub source: String,
+    pub action: String,
+    pub target: String,
+    pub details: String,
+}
+
+#[derive(Insertable, Debug, Clone)]
+#[table_name = "admin_audit_log"]
+pub struct NewAuditEntry {
+    pub time: i64,
+    pub source: String,
+    pub action: String,
+    pub target: String,
+    pub details: String,
 }
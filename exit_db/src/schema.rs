@@ -1,3 +1,44 @@
+table! {
+    admin_audit_log (id) {
+        id -> Int8,
+        time -> Int8,
+        source -> Varchar,
+        action -> Varchar,
+        target -> Varchar,
+        details -> Text,
+    }
+}
+
+table! {
+    banned_keys (wg_pubkey) {
+        wg_pubkey -> Varchar,
+        reason -> Text,
+        banned_at -> Int8,
+    }
+}
+
+table! {
+    client_plans (mesh_ip) {
+        mesh_ip -> Varchar,
+        plan_type -> Varchar,
+        quota -> Int8,
+        period -> Int8,
+        price -> Varchar,
+        over_quota -> Varchar,
+        period_start -> Int8,
+        used -> Int8,
+    }
+}
+
+table! {
+    client_verifications (mesh_ip, method) {
+        mesh_ip -> Varchar,
+        method -> Varchar,
+        state -> Varchar,
+        updated -> Int8,
+    }
+}
+
 table! {
     clients (mesh_ip) {
         mesh_ip -> Varchar,
@@ -15,5 +56,26 @@ table! {
         text_sent -> Int4,
         last_seen -> Int8,
         last_balance_warning_time -> Int8,
+        internal_ipv6 -> Varchar,
+    }
+}
+
+table! {
+    geoip_cache (ip) {
+        ip -> Varchar,
+        country -> Varchar,
+        lookup_time -> Int8,
     }
 }
+
+joinable!(client_plans -> clients (mesh_ip));
+joinable!(client_verifications -> clients (mesh_ip));
+
+allow_tables_to_appear_in_same_query!(
+    admin_audit_log,
+    banned_keys,
+    client_plans,
+    client_verifications,
+    clients,
+    geoip_cache,
+);
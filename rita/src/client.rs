@@ -50,6 +50,7 @@ mod rita_common;
 
 use crate::rita_client::dashboard::eth_private_key::*;
 use crate::rita_client::dashboard::exits::*;
+use crate::rita_client::dashboard::fraud::*;
 use crate::rita_client::dashboard::interfaces::*;
 use crate::rita_client::dashboard::logging::*;
 use crate::rita_client::dashboard::mesh_ip::*;
@@ -61,13 +62,18 @@ use crate::rita_client::dashboard::usage::*;
 use crate::rita_client::dashboard::wifi::*;
 
 use crate::rita_common::dashboard::babel::*;
+use crate::rita_common::dashboard::babel_config::*;
 use crate::rita_common::dashboard::dao::*;
 use crate::rita_common::dashboard::debts::*;
 use crate::rita_common::dashboard::development::*;
+use crate::rita_common::dashboard::neighbor_shaping::*;
 use crate::rita_common::dashboard::nickname::*;
 use crate::rita_common::dashboard::own_info::*;
+use crate::rita_common::dashboard::peer_acl::*;
 use crate::rita_common::dashboard::pricing::*;
+use crate::rita_common::dashboard::route_history::*;
 use crate::rita_common::dashboard::settings::*;
+use crate::rita_common::dashboard::tunnel_ports::*;
 use crate::rita_common::dashboard::usage::*;
 use crate::rita_common::dashboard::wallet::*;
 
@@ -208,6 +214,7 @@ fn main() {
     assert!(rita_common::traffic_watcher::TrafficWatcher::from_registry().connected());
     assert!(rita_common::peer_listener::PeerListener::from_registry().connected());
     assert!(rita_client::exit_manager::ExitManager::from_registry().connected());
+    assert!(rita_client::fraud_detector::FraudDetector::from_registry().connected());
 
     // rita
     server::new(|| App::new().resource("/hello", |r| r.method(Method::POST).with(hello_response)))
@@ -251,6 +258,28 @@ fn main() {
             .route("/dao_fee/{fee}", Method::POST, set_dao_fee)
             .route("/metric_factor", Method::GET, get_metric_factor)
             .route("/metric_factor/{factor}", Method::POST, set_metric_factor)
+            .route("/babel_config", Method::GET, get_babel_config)
+            .route(
+                "/babel_config/tunnel_defaults",
+                Method::POST,
+                set_babel_tunnel_defaults,
+            )
+            .route(
+                "/babel_config/interfaces/{name}",
+                Method::POST,
+                set_babel_interface,
+            )
+            .route(
+                "/babel_config/interfaces/{name}/remove",
+                Method::POST,
+                remove_babel_interface,
+            )
+            .route("/babel_config/filters/add", Method::POST, add_babel_filter)
+            .route(
+                "/babel_config/filters/remove",
+                Method::POST,
+                remove_babel_filter,
+            )
             .route(
                 "/exits/{name}/verify/{code}",
                 Method::POST,
@@ -302,6 +331,26 @@ fn main() {
             .route("/blockchain/get/", Method::GET, get_system_blockchain)
             .route("/nickname/get/", Method::GET, get_nickname)
             .route("/nickname/set/", Method::POST, set_nickname)
+            .route("/peer_acl", Method::GET, get_peer_acl)
+            .route("/peer_acl/add", Method::POST, add_to_peer_acl)
+            .route("/peer_acl/remove", Method::POST, remove_from_peer_acl)
+            .route("/peer_acl/mode/{mode}", Method::POST, set_peer_acl_mode)
+            .route("/neighbor_shaping", Method::GET, get_neighbor_shaping)
+            .route("/neighbor_shaping", Method::POST, set_neighbor_shaping)
+            .route(
+                "/neighbor_shaping/remove",
+                Method::POST,
+                remove_neighbor_shaping,
+            )
+            .route("/tunnel_ports", Method::GET, get_tunnel_ports)
+            .route("/route_history", Method::GET, get_route_history)
+            .route(
+                "/route_history/{destination}",
+                Method::GET,
+                get_destination_route_history,
+            )
+            .route("/fraud_suspects", Method::GET, get_fraud_suspects)
+            .route("/exit_bills", Method::GET, get_exit_bills)
             .route(
                 "/low_balance_notification",
                 Method::GET,
@@ -315,6 +364,11 @@ fn main() {
             .route("/usage/relay", Method::GET, get_relay_usage)
             .route("/usage/client", Method::GET, get_client_usage)
             .route("/usage/payments", Method::GET, get_payments)
+            .route(
+                "/usage/relay_destinations",
+                Method::GET,
+                get_relay_destinations,
+            )
             .route("/router/update", Method::POST, update_router)
             .route("/wipe", Method::POST, wipe)
             .route("/crash_actors", Method::POST, crash_actors)
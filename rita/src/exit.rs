@@ -46,19 +46,27 @@ mod rita_common;
 mod rita_exit;
 
 use crate::rita_common::dashboard::babel::*;
+use crate::rita_common::dashboard::babel_config::*;
 use crate::rita_common::dashboard::dao::*;
 use crate::rita_common::dashboard::debts::*;
 use crate::rita_common::dashboard::development::*;
+use crate::rita_common::dashboard::neighbor_shaping::*;
 use crate::rita_common::dashboard::nickname::*;
 use crate::rita_common::dashboard::own_info::*;
+use crate::rita_common::dashboard::peer_acl::*;
 use crate::rita_common::dashboard::pricing::*;
+use crate::rita_common::dashboard::route_history::*;
 use crate::rita_common::dashboard::settings::*;
+use crate::rita_common::dashboard::tunnel_ports::*;
 use crate::rita_common::dashboard::usage::*;
 use crate::rita_common::dashboard::wallet::*;
 
 use crate::rita_common::network_endpoints::*;
+use crate::rita_exit::admin_endpoints::*;
+use crate::rita_exit::database::verification::check_providers;
 use crate::rita_exit::network_endpoints::*;
 
+use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
 
 #[cfg(test)]
@@ -143,10 +151,24 @@ lazy_static! {
 /// used to crash the exit on first startup if config does not make sense
 /// as is usually desirable for cloud infrastruture
 fn sanity_check_config() {
-    if !SETTING.get_allowed_countries().is_empty()
-        && SETTING.get_exit_network().geoip_api_key.is_none()
+    let geoip_enforced = !SETTING.get_allowed_countries().is_empty();
+    let exit_network = SETTING.get_exit_network();
+    if geoip_enforced
+        && exit_network.geoip_database.is_none()
+        && (exit_network.geoip_api_user.is_none() || exit_network.geoip_api_key.is_none())
     {
-        panic!("GEOIP enforcement configured but not api key provided!");
+        panic!("GEOIP enforcement configured but no geoip database or api key provided!");
+    }
+    drop(exit_network);
+    if let Some(admin_api) = SETTING.get_admin_api() {
+        if admin_api.token.is_empty() {
+            panic!("Admin api enabled but no token provided!");
+        }
+    }
+    if let Some(verif_settings) = SETTING.get_verif_settings() {
+        if let Err(e) = check_providers(&verif_settings) {
+            panic!("Invalid verification settings! {}", e);
+        }
     }
 }
 
@@ -242,6 +264,28 @@ fn main() {
             .route("/dao_fee/{fee}", Method::POST, set_dao_fee)
             .route("/metric_factor", Method::GET, get_metric_factor)
             .route("/metric_factor/{factor}", Method::POST, set_metric_factor)
+            .route("/babel_config", Method::GET, get_babel_config)
+            .route(
+                "/babel_config/tunnel_defaults",
+                Method::POST,
+                set_babel_tunnel_defaults,
+            )
+            .route(
+                "/babel_config/interfaces/{name}",
+                Method::POST,
+                set_babel_interface,
+            )
+            .route(
+                "/babel_config/interfaces/{name}/remove",
+                Method::POST,
+                remove_babel_interface,
+            )
+            .route("/babel_config/filters/add", Method::POST, add_babel_filter)
+            .route(
+                "/babel_config/filters/remove",
+                Method::POST,
+                remove_babel_filter,
+            )
             .route("/settings", Method::GET, get_settings)
             .route("/settings", Method::POST, set_settings)
             .route("/version", Method::GET, version)
@@ -264,8 +308,31 @@ fn main() {
             .route("/auto_price/enabled", Method::GET, auto_pricing_status)
             .route("/nickname/get/", Method::GET, get_nickname)
             .route("/nickname/set/", Method::POST, set_nickname)
+            .route("/peer_acl", Method::GET, get_peer_acl)
+            .route("/peer_acl/add", Method::POST, add_to_peer_acl)
+            .route("/peer_acl/remove", Method::POST, remove_from_peer_acl)
+            .route("/peer_acl/mode/{mode}", Method::POST, set_peer_acl_mode)
+            .route("/neighbor_shaping", Method::GET, get_neighbor_shaping)
+            .route("/neighbor_shaping", Method::POST, set_neighbor_shaping)
+            .route(
+                "/neighbor_shaping/remove",
+                Method::POST,
+                remove_neighbor_shaping,
+            )
+            .route("/tunnel_ports", Method::GET, get_tunnel_ports)
+            .route("/route_history", Method::GET, get_route_history)
+            .route(
+                "/route_history/{destination}",
+                Method::GET,
+                get_destination_route_history,
+            )
             .route("/crash_actors", Method::POST, crash_actors)
             .route("/usage/payments", Method::GET, get_payments)
+            .route(
+                "/usage/relay_destinations",
+                Method::GET,
+                get_relay_destinations,
+            )
     })
     .bind(format!(
         "[::0]:{}",
@@ -275,6 +342,41 @@ fn main() {
     .shutdown_timeout(0)
     .start();
 
+    // Operator admin api
+    if let Some(admin_api) = SETTING.get_admin_api() {
+        if !admin_api.bind.is_loopback() {
+            warn!(
+                "The admin api is plain http on {}, make sure that address is only reachable over an encrypted link",
+                admin_api.bind
+            );
+        }
+        server::new(|| {
+            App::new()
+                .middleware(AdminAuth)
+                .route("/clients", Method::GET, list_clients)
+                .route("/clients/{mesh_ip}", Method::GET, client_details)
+                .route(
+                    "/clients/{mesh_ip}/verify",
+                    Method::POST,
+                    verify_client_http,
+                )
+                .route("/clients/{mesh_ip}/deny", Method::POST, deny_client_http)
+                .route(
+                    "/clients/{mesh_ip}/internal_ip",
+                    Method::POST,
+                    set_internal_ip,
+                )
+                .route("/bans", Method::GET, list_bans)
+                .route("/bans/add", Method::POST, add_ban)
+                .route("/bans/remove", Method::POST, remove_ban)
+                .route("/audit_log", Method::GET, audit_log)
+        })
+        .bind(SocketAddr::new(admin_api.bind, admin_api.port))
+        .unwrap()
+        .shutdown_timeout(0)
+        .start();
+    }
+
     assert!(rita_common::rita_loop::RitaLoop::from_registry().connected());
     assert!(rita_exit::rita_loop::RitaLoop::from_registry().connected());
 
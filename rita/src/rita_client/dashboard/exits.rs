@@ -1,6 +1,7 @@
 //! The Exit info endpoint gathers infromation about exit status and presents it to the dashbaord.
 
 use crate::rita_client::exit_manager::exit_setup_request;
+use crate::rita_common::babel_client::{BabelClient, GetBabelTable};
 use crate::rita_common::dashboard::Dashboard;
 use crate::ARGS;
 use crate::KI;
@@ -11,8 +12,7 @@ use ::actix_web::AsyncResponder;
 use ::actix_web::Path;
 use ::actix_web::{HttpRequest, HttpResponse, Json};
 use althea_types::ExitState;
-use babel_monitor::open_babel_stream;
-use babel_monitor::Babel;
+use babel_monitor::BabelTable;
 use failure::Error;
 use futures::{future, Future};
 use reqwest;
@@ -33,7 +33,9 @@ pub struct ExitInfo {
     is_tunnel_working: bool,
 }
 
-pub struct GetExitInfo;
+pub struct GetExitInfo {
+    pub babel_table: BabelTable,
+}
 
 impl Message for GetExitInfo {
     type Result = Result<Vec<ExitInfo>, Error>;
@@ -65,12 +67,7 @@ fn is_tunnel_working(exit: &ExitServer, current_exit: Option<&ExitServer>) -> bo
 impl Handler<GetExitInfo> for Dashboard {
     type Result = Result<Vec<ExitInfo>, Error>;
 
-    fn handle(&mut self, _msg: GetExitInfo, _ctx: &mut Self::Context) -> Self::Result {
-        let stream = open_babel_stream(SETTING.get_network().babel_port)?;
-        let mut babel = Babel::new(stream);
-        babel.start_connection()?;
-        let route_table_sample = babel.parse_routes()?;
-
+    fn handle(&mut self, msg: GetExitInfo, _ctx: &mut Self::Context) -> Self::Result {
         let mut output = Vec::new();
 
         let exit_client = SETTING.get_exit_client();
@@ -78,7 +75,7 @@ impl Handler<GetExitInfo> for Dashboard {
 
         for exit in exit_client.exits.clone().into_iter() {
             let selected = is_selected(&exit.1, current_exit);
-            let have_route = babel.do_we_have_route(&exit.1.id.mesh_ip, &route_table_sample)?;
+            let have_route = msg.babel_table.do_we_have_route(&exit.1.id.mesh_ip);
 
             // failed pings block for one second, so we should be sure it's at least reasonable
             // to expect the pings to work before issuing them.
@@ -226,9 +223,15 @@ pub fn get_exit_info(
     _req: HttpRequest,
 ) -> Box<dyn Future<Item = Json<Vec<ExitInfo>>, Error = Error>> {
     debug!("Exit endpoint hit!");
-    Dashboard::from_registry()
-        .send(GetExitInfo {})
+    BabelClient::from_registry()
+        .send(GetBabelTable)
         .from_err()
+        .and_then(|babel_table| babel_table)
+        .and_then(|babel_table| {
+            Dashboard::from_registry()
+                .send(GetExitInfo { babel_table })
+                .from_err()
+        })
         .and_then(move |reply| Ok(Json(reply?)))
         .responder()
 }
@@ -0,0 +1,29 @@
+use crate::rita_client::fraud_detector::{FraudDetector, GetSuspects, Suspect};
+use crate::rita_client::traffic_watcher::{ExitBillCheck, GetExitBills, TrafficWatcher};
+use ::actix::registry::SystemService;
+use ::actix_web::{AsyncResponder, HttpRequest, Json};
+use failure::Error;
+use futures::Future;
+use std::boxed::Box;
+
+pub fn get_fraud_suspects(
+    _req: HttpRequest,
+) -> Box<dyn Future<Item = Json<Vec<Suspect>>, Error = Error>> {
+    trace!("/fraud_suspects hit");
+    FraudDetector::from_registry()
+        .send(GetSuspects)
+        .from_err()
+        .and_then(|reply| Ok(Json(reply?)))
+        .responder()
+}
+
+pub fn get_exit_bills(
+    _req: HttpRequest,
+) -> Box<dyn Future<Item = Json<ExitBillCheck>, Error = Error>> {
+    trace!("/exit_bills hit");
+    TrafficWatcher::from_registry()
+        .send(GetExitBills)
+        .from_err()
+        .and_then(|reply| Ok(Json(reply?)))
+        .responder()
+}
@@ -6,6 +6,7 @@
 
 pub mod eth_private_key;
 pub mod exits;
+pub mod fraud;
 pub mod interfaces;
 pub mod logging;
 pub mod mesh_ip;
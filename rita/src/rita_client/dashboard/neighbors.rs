@@ -1,14 +1,13 @@
+use crate::rita_common::babel_client::{BabelClient, GetBabelTable};
 use crate::rita_common::dashboard::Dashboard;
 use crate::rita_common::debt_keeper::{DebtKeeper, Dump, NodeDebtData};
-use crate::rita_common::tunnel_manager::{GetNeighbors, Neighbor, TunnelManager};
+use crate::rita_common::tunnel_manager::{GetNeighbors, Neighbor, TunnelHealth, TunnelManager};
 use crate::SETTING;
 use ::actix::{Handler, Message, ResponseFuture, SystemService};
 use ::actix_web::AsyncResponder;
 use ::actix_web::{HttpRequest, Json};
 use althea_types::Identity;
 use arrayvec::ArrayString;
-use babel_monitor::open_babel_stream;
-use babel_monitor::Babel;
 use failure::Error;
 use futures::Future;
 use num256::{Int256, Uint256};
@@ -17,6 +16,12 @@ use settings::RitaCommonSettings;
 use std::boxed::Box;
 use std::collections::HashMap;
 
+#[derive(Serialize)]
+pub struct TunnelInfo {
+    pub iface_name: String,
+    pub health: TunnelHealth,
+}
+
 #[derive(Serialize)]
 pub struct NodeInfo {
     pub nickname: String,
@@ -26,6 +31,7 @@ pub struct NodeInfo {
     pub debt: Int256,
     pub link_cost: u16,
     pub price_to_exit: u32,
+    pub tunnels: Vec<TunnelInfo>,
 }
 
 pub struct GetNeighborInfo;
@@ -44,80 +50,93 @@ impl Handler<GetNeighborInfo> for Dashboard {
 
     fn handle(&mut self, _msg: GetNeighborInfo, _ctx: &mut Self::Context) -> Self::Result {
         Box::new(
-            DebtKeeper::from_registry()
-                .send(Dump {})
+            BabelClient::from_registry()
+                .send(GetBabelTable)
                 .from_err()
-                .and_then(|debts| {
-                    TunnelManager::from_registry()
-                        .send(GetNeighbors {})
+                .and_then(|babel_table| {
+                    DebtKeeper::from_registry()
+                        .send(Dump {})
                         .from_err()
-                        .and_then(|neighbors| {
-                            let mut debts = debts?;
-                            if neighbors.is_ok() {
-                                let neighbors = neighbors?;
-                                merge_debts_and_neighbors(neighbors, &mut debts);
-                            }
-
-                            let stream = open_babel_stream(SETTING.get_network().babel_port)?;
-                            let mut babel = Babel::new(stream);
-                            babel.start_connection()?;
-                            let route_table_sample = babel.parse_routes()?;
-
-                            let mut output = Vec::new();
-
-                            let exit_client = SETTING.get_exit_client();
-                            let current_exit = exit_client.get_current_exit();
-
-                            for (identity, debt_info) in debts.iter() {
-                                let nickname = match identity.nickname {
-                                    Some(val) => val,
-                                    None => ArrayString::<[u8; 32]>::from("No Nickname").unwrap(),
-                                };
-
-                                if current_exit.is_some() {
-                                    let exit_ip = current_exit.unwrap().id.mesh_ip;
-                                    let maybe_route = babel.get_route_via_neigh(
-                                        identity.mesh_ip,
-                                        exit_ip,
-                                        &route_table_sample,
-                                    );
-
-                                    // We have a peer that is an exit, so we can't find a route
-                                    // from them to our selected exit. Other errors can also get
-                                    // caught here
-                                    if maybe_route.is_err() {
-                                        output.push(nonviable_node_info(
-                                            nickname,
-                                            identity.mesh_ip.to_string(),
-                                        ));
-                                        continue;
+                        .and_then(move |debts| {
+                            TunnelManager::from_registry()
+                                .send(GetNeighbors {})
+                                .from_err()
+                                .and_then(move |neighbors| {
+                                    let mut debts = debts?;
+                                    let mut tunnels = HashMap::new();
+                                    if neighbors.is_ok() {
+                                        let neighbors = neighbors?;
+                                        tunnels = get_tunnel_info(&neighbors);
+                                        merge_debts_and_neighbors(neighbors, &mut debts);
+                                    }
+
+                                    let babel_table = babel_table?;
+
+                                    let mut output = Vec::new();
+
+                                    let exit_client = SETTING.get_exit_client();
+                                    let current_exit = exit_client.get_current_exit();
+
+                                    for (identity, debt_info) in debts.iter() {
+                                        let nickname = match identity.nickname {
+                                            Some(val) => val,
+                                            None => ArrayString::<[u8; 32]>::from("No Nickname")
+                                                .unwrap(),
+                                        };
+                                        let node_tunnels =
+                                            tunnels.remove(identity).unwrap_or_default();
+
+                                        if current_exit.is_some() {
+                                            let exit_ip = current_exit.unwrap().id.mesh_ip;
+                                            let maybe_route = babel_table
+                                                .get_route_via_neigh(identity.mesh_ip, exit_ip);
+
+                                            // We have a peer that is an exit, so we can't find a route
+                                            // from them to our selected exit. Other errors can also get
+                                            // caught here
+                                            if maybe_route.is_err() {
+                                                output.push(nonviable_node_info(
+                                                    nickname,
+                                                    identity.mesh_ip.to_string(),
+                                                    node_tunnels,
+                                                ));
+                                                continue;
+                                            }
+                                            // we check that this is safe above
+                                            let route = maybe_route.unwrap();
+
+                                            output.push(NodeInfo {
+                                                nickname: nickname.to_string(),
+                                                ip: serde_json::to_string(&identity.mesh_ip)
+                                                    .unwrap(),
+                                                route_metric_to_exit: route.metric,
+                                                total_payments: debt_info
+                                                    .total_payment_received
+                                                    .clone(),
+                                                debt: debt_info.debt.clone(),
+                                                link_cost: route.refmetric,
+                                                price_to_exit: route.price,
+                                                tunnels: node_tunnels,
+                                            })
+                                        } else {
+                                            output.push(NodeInfo {
+                                                nickname: nickname.to_string(),
+                                                ip: serde_json::to_string(&identity.mesh_ip)
+                                                    .unwrap(),
+                                                route_metric_to_exit: u16::max_value(),
+                                                total_payments: debt_info
+                                                    .total_payment_received
+                                                    .clone(),
+                                                debt: debt_info.debt.clone(),
+                                                link_cost: u16::max_value(),
+                                                price_to_exit: u32::max_value(),
+                                                tunnels: node_tunnels,
+                                            })
+                                        }
                                     }
-                                    // we check that this is safe above
-                                    let route = maybe_route.unwrap();
-
-                                    output.push(NodeInfo {
-                                        nickname: nickname.to_string(),
-                                        ip: serde_json::to_string(&identity.mesh_ip).unwrap(),
-                                        route_metric_to_exit: route.metric,
-                                        total_payments: debt_info.total_payment_received.clone(),
-                                        debt: debt_info.debt.clone(),
-                                        link_cost: route.refmetric,
-                                        price_to_exit: route.price,
-                                    })
-                                } else {
-                                    output.push(NodeInfo {
-                                        nickname: nickname.to_string(),
-                                        ip: serde_json::to_string(&identity.mesh_ip).unwrap(),
-                                        route_metric_to_exit: u16::max_value(),
-                                        total_payments: debt_info.total_payment_received.clone(),
-                                        debt: debt_info.debt.clone(),
-                                        link_cost: u16::max_value(),
-                                        price_to_exit: u32::max_value(),
-                                    })
-                                }
-                            }
-
-                            Ok(output)
+
+                                    Ok(output)
+                                })
                         })
                 }),
         )
@@ -148,7 +167,25 @@ fn merge_debts_and_neighbors(
     }
 }
 
-fn nonviable_node_info(nickname: ArrayString<[u8; 32]>, ip: String) -> NodeInfo {
+/// Groups the health of every tunnel by the identity of the neighbor on the other end
+fn get_tunnel_info(neighbors: &[Neighbor]) -> HashMap<Identity, Vec<TunnelInfo>> {
+    let mut res: HashMap<Identity, Vec<TunnelInfo>> = HashMap::new();
+    for neighbor in neighbors {
+        res.entry(neighbor.identity.global)
+            .or_insert_with(Vec::new)
+            .push(TunnelInfo {
+                iface_name: neighbor.iface_name.clone(),
+                health: neighbor.health,
+            });
+    }
+    res
+}
+
+fn nonviable_node_info(
+    nickname: ArrayString<[u8; 32]>,
+    ip: String,
+    tunnels: Vec<TunnelInfo>,
+) -> NodeInfo {
     NodeInfo {
         nickname: nickname.to_string(),
         ip,
@@ -157,5 +194,6 @@ fn nonviable_node_info(nickname: ArrayString<[u8; 32]>, ip: String) -> NodeInfo
         link_cost: 0,
         price_to_exit: 0,
         route_metric_to_exit: u16::max_value(),
+        tunnels,
     }
 }
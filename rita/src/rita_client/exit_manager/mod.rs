@@ -83,9 +83,17 @@ fn linux_setup_exit_tunnel(
         our_details.client_internal_ip,
         general_details.netmask,
         SETTING.get_network().rita_hello_port,
+        our_details.client_internal_ipv6,
     )?;
     KI.set_route_to_tunnel(&general_details.server_internal_ip)?;
 
+    if let Some(prefix) = our_details.client_internal_ipv6 {
+        KI.set_ipv6_route_to_tunnel()?;
+        if KI.is_openwrt() && KI.set_lan_ipv6_prefix(&prefix)? {
+            KI.refresh_initd("network")?;
+        }
+    }
+
     let lan_nics = &SETTING.get_exit_client().lan_nics;
     for nic in lan_nics {
         KI.add_client_nat_rules(&nic)?;
@@ -434,6 +442,26 @@ impl Handler<Tick> for ExitManager {
                         },
                     )));
                 }
+                // nothing for the user to enter, we're waiting on the operator or an outside
+                // service so keep asking until we get in
+                ExitState::Pending {
+                    ref general_details,
+                    ..
+                } if general_details.verif_mode == ExitVerifMode::Off => {
+                    futs.push(Box::new(exit_setup_request(k.clone(), None).then(
+                        move |res| {
+                            match res {
+                                Ok(_) => {
+                                    trace!("exit setup request to {} was successful", k);
+                                }
+                                Err(e) => {
+                                    trace!("exit setup request to {} failed with {:?}", k, e);
+                                }
+                            };
+                            Ok(())
+                        },
+                    )));
+                }
                 ExitState::Registered { .. } => {
                     futs.push(Box::new(exit_status_request(k.clone()).then(move |res| {
                         match res {
@@ -0,0 +1,478 @@
+//! The fraud detector checks what the neighbor carrying our exit route tells babel about that
+//! route against what we actually get when we use it. Babel picks routes on the advertised
+//! metric and price alone, so a neighbor that claims a faster or cheaper path to the exit than
+//! it really has attracts our traffic without anyone noticing.
+//!
+//! Periodically we time a request to the exit's `/rtt` endpoint and compare it to the full path
+//! rtt babel advertises for the route. A probe can fail once for innocent reasons (a congested
+//! link, a route change mid interval) so each neighbor gets a score that only reaches a useful
+//! confidence after repeated misreports, and only that score can get a neighbor blocked.
+//!
+//! Every bill from the exit the traffic watcher checks (see `ExitBillCheck`) against an estimate
+//! made with the advertised route price is also recorded against the neighbor carrying the
+//! route, but never counts towards its score. A bill over the estimate is just as likely to be
+//! the exit overbilling as the neighbor underadvertising its price, and we have no way to tell
+//! which, so blocking on it could cut off an honest neighbor. The recorded numbers are there so
+//! an operator can tell.
+
+use crate::rita_common::babel_client::{BabelClient, GetBabelTable};
+use crate::rita_common::tunnel_manager::{EnforcePeerAcl, GetNeighbors, Neighbor, TunnelManager};
+use crate::ARGS;
+use crate::SETTING;
+use ::actix::{Actor, Arbiter, AsyncContext, Context, Handler, Message, Supervised, SystemService};
+use actix_web::client;
+use actix_web::client::Connection;
+use actix_web::HttpMessage;
+use althea_types::{Identity, RTTimestamps};
+use babel_monitor::{BabelTable, Route};
+use failure::Error;
+use futures::future::{self, Either};
+use futures::Future;
+use num256::Int256;
+use settings::client::RitaClientSettings;
+use settings::network::{PeerAclEntry, PeerAclMode};
+use settings::FileWrite;
+use settings::RitaCommonSettings;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream as TokioTcpStream;
+
+/// How often we probe the rtt of our route to the exit
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// A measured rtt up to this many times the advertised full path rtt is not held against anyone
+const RTT_TOLERANCE_FACTOR: f32 = 2.0;
+/// Plus this many milliseconds for the http, wireguard and scheduling overhead babel's own
+/// timestamps don't see
+const RTT_TOLERANCE_MS: f32 = 50.0;
+/// How much the latest observation moves a neighbor's score
+const SCORE_WEIGHT: f64 = 0.2;
+/// Observations we want of a neighbor before reporting any confidence at all
+const MIN_OBSERVATIONS: u32 = 10;
+/// Confidence at which we block a neighbor, if the operator allows it
+const BLOCK_CONFIDENCE: f64 = 0.8;
+
+/// A disagreement between what a neighbor advertised and what we measured
+#[derive(Debug, Clone, Serialize)]
+pub enum Misreport {
+    Rtt {
+        advertised_ms: f32,
+        measured_ms: f32,
+    },
+    Billing {
+        advertised_price: u32,
+        expected: Int256,
+        billed: Int256,
+    },
+}
+
+/// Returns a misreport if the measured rtt to the exit is too far above the advertised one
+pub fn check_rtt(advertised_ms: f32, measured_ms: f32) -> Option<Misreport> {
+    if measured_ms > advertised_ms * RTT_TOLERANCE_FACTOR + RTT_TOLERANCE_MS {
+        Some(Misreport::Rtt {
+            advertised_ms,
+            measured_ms,
+        })
+    } else {
+        None
+    }
+}
+
+/// Everything we have observed about one neighbor
+#[derive(Debug, Clone, Serialize)]
+pub struct Suspect {
+    pub identity: Identity,
+    /// Rtt probes of the route through this neighbor
+    pub observations: u32,
+    pub misreports: u32,
+    /// Between 0 and 1, how sure we are this neighbor keeps misreporting its routes. Zero
+    /// until we have at least `MIN_OBSERVATIONS`
+    pub confidence: f64,
+    pub last_misreport: Option<Misreport>,
+    /// Exit bills checked while this neighbor carried our route, these don't affect confidence
+    pub bill_checks: u32,
+    pub billing_misreports: u32,
+    pub last_billing_misreport: Option<Misreport>,
+    pub blocked: bool,
+    #[serde(skip)]
+    score: f64,
+}
+
+impl Suspect {
+    fn new(identity: Identity) -> Suspect {
+        Suspect {
+            identity,
+            observations: 0,
+            misreports: 0,
+            confidence: 0.0,
+            last_misreport: None,
+            bill_checks: 0,
+            billing_misreports: 0,
+            last_billing_misreport: None,
+            blocked: false,
+            score: 0.0,
+        }
+    }
+
+    /// Records the outcome of one exit bill check, kept apart from the score since an
+    /// overbilling exit looks the same as an underadvertising neighbor
+    pub fn observe_bill(&mut self, misreport: Option<Misreport>) {
+        self.bill_checks += 1;
+        if let Some(misreport) = misreport {
+            self.billing_misreports += 1;
+            self.last_billing_misreport = Some(misreport);
+        }
+    }
+
+    /// Records the outcome of one check, the score is a moving average of misreports so that
+    /// a neighbor that stops misreporting is forgiven over time
+    pub fn observe(&mut self, misreport: Option<Misreport>) {
+        self.observations += 1;
+        let sample = match misreport {
+            Some(misreport) => {
+                self.misreports += 1;
+                self.last_misreport = Some(misreport);
+                1.0
+            }
+            None => 0.0,
+        };
+        self.score = self.score * (1.0 - SCORE_WEIGHT) + sample * SCORE_WEIGHT;
+        self.confidence = if self.observations >= MIN_OBSERVATIONS {
+            self.score
+        } else {
+            0.0
+        };
+    }
+}
+
+/// The neighbor and route we used to reach the exit at the last check
+#[derive(Debug, Clone)]
+pub struct ExitPath {
+    pub neighbor: Identity,
+    pub route: Route,
+}
+
+/// Finds the neighbor babel is currently routing our exit traffic through
+fn find_exit_path(
+    neighbors: &[Neighbor],
+    babel_table: &BabelTable,
+    exit_ip: IpAddr,
+) -> Result<ExitPath, Error> {
+    let route = babel_table.get_installed_route(&exit_ip)?;
+    for neighbor in neighbors {
+        if neighbor.iface_name == route.iface {
+            return Ok(ExitPath {
+                neighbor: neighbor.identity.global,
+                route,
+            });
+        }
+    }
+    bail!(
+        "No neighbor on {} which carries our exit route",
+        route.iface
+    )
+}
+
+/// Times a request to the exit's rtt endpoint, without the time the exit spent answering
+fn probe_exit_rtt(exit_ip: IpAddr, port: u16) -> impl Future<Item = f32, Error = Error> {
+    let endpoint = format!("http://[{}]:{}/rtt", exit_ip, port);
+    let stream = TokioTcpStream::connect(&SocketAddr::new(exit_ip, port));
+
+    stream.from_err().and_then(move |stream| {
+        // the connection is already open so this times a single round trip
+        let start = Instant::now();
+        client::get(&endpoint)
+            .timeout(PROBE_TIMEOUT)
+            .with_connection(Connection::from_stream(stream))
+            .finish()
+            .unwrap()
+            .send()
+            .from_err()
+            .and_then(|response| response.json().from_err())
+            .and_then(move |timestamps: RTTimestamps| {
+                let total = start.elapsed();
+                let processing = timestamps.exit_tx.duration_since(timestamps.exit_rx)?;
+                let rtt = total.checked_sub(processing).unwrap_or_default();
+                Ok(rtt.as_secs() as f32 * 1000.0 + rtt.subsec_nanos() as f32 / 1_000_000.0)
+            })
+    })
+}
+
+/// Looks up our route to the current exit and probes it, the result is sent back to the
+/// FraudDetector as `ExitRouteChecked`
+fn check_exit_route() -> impl Future<Item = (), Error = ()> {
+    let exit = match SETTING.get_exit_client().get_current_exit() {
+        Some(exit) => exit.clone(),
+        None => return Either::A(future::ok(())),
+    };
+    let exit_ip = exit.id.mesh_ip;
+
+    Either::B(
+        TunnelManager::from_registry()
+            .send(GetNeighbors)
+            .from_err()
+            .and_then(move |neighbors| {
+                BabelClient::from_registry()
+                    .send(GetBabelTable)
+                    .from_err()
+                    .and_then(move |babel_table| {
+                        find_exit_path(&neighbors?, &babel_table?, exit_ip)
+                    })
+            })
+            .and_then(move |path| {
+                probe_exit_rtt(exit_ip, exit.registration_port).then(move |measured| {
+                    let measured_ms = match measured {
+                        Ok(measured_ms) => Some(measured_ms),
+                        Err(e) => {
+                            warn!("Failed to probe the rtt to the exit {:?}", e);
+                            None
+                        }
+                    };
+                    FraudDetector::from_registry().do_send(ExitRouteChecked { path, measured_ms });
+                    Ok(())
+                })
+            })
+            .map_err(|e: Error| trace!("Could not check our route to the exit {:?}", e)),
+    )
+}
+
+/// Adds a neighbor to the peer blocklist and tears down its tunnels
+fn block_neighbor(identity: &Identity) -> Result<(), Error> {
+    {
+        let mut network = SETTING.get_network_mut();
+        if network.peer_acl.mode != PeerAclMode::Blocklist {
+            bail!("The peer acl is an allowlist, not blocking");
+        }
+        network
+            .peer_acl
+            .entries
+            .insert(PeerAclEntry::WgKey(identity.wg_public_key));
+    }
+    SETTING.write().unwrap().write(&ARGS.flag_config)?;
+    TunnelManager::from_registry().do_send(EnforcePeerAcl);
+    Ok(())
+}
+
+pub struct FraudDetector {
+    /// keyed by the neighbor's identity
+    suspects: HashMap<Identity, Suspect>,
+    exit_path: Option<ExitPath>,
+}
+
+impl Actor for FraudDetector {
+    type Context = Context<Self>;
+}
+
+impl Supervised for FraudDetector {}
+
+impl SystemService for FraudDetector {
+    fn service_started(&mut self, ctx: &mut Context<Self>) {
+        ctx.run_interval(CHECK_INTERVAL, |_act, _ctx| {
+            Arbiter::spawn(check_exit_route());
+        });
+        info!("Fraud detector started");
+    }
+}
+
+impl Default for FraudDetector {
+    fn default() -> FraudDetector {
+        FraudDetector {
+            suspects: HashMap::new(),
+            exit_path: None,
+        }
+    }
+}
+
+impl FraudDetector {
+    fn observe(&mut self, neighbor: Identity, misreport: Option<Misreport>) {
+        if let Some(ref misreport) = misreport {
+            info!(
+                "Neighbor {:?} misreported {:?}",
+                neighbor.mesh_ip, misreport
+            );
+        }
+        let suspect = self
+            .suspects
+            .entry(neighbor)
+            .or_insert_with(|| Suspect::new(neighbor));
+        suspect.observe(misreport);
+
+        if suspect.confidence >= BLOCK_CONFIDENCE && !suspect.blocked {
+            warn!(
+                "Neighbor {:?} keeps misreporting its route to the exit, confidence {}",
+                neighbor.mesh_ip, suspect.confidence
+            );
+            if SETTING.get_exit_client().block_fraudulent_neighbors {
+                match block_neighbor(&neighbor) {
+                    Ok(()) => suspect.blocked = true,
+                    Err(e) => error!("Failed to block {:?} {:?}", neighbor.mesh_ip, e),
+                }
+            }
+        }
+    }
+
+    fn observe_bill(&mut self, neighbor: Identity, misreport: Option<Misreport>) {
+        if let Some(ref misreport) = misreport {
+            info!(
+                "Exit bill disagrees with the route through {:?} {:?}",
+                neighbor.mesh_ip, misreport
+            );
+        }
+        self.suspects
+            .entry(neighbor)
+            .or_insert_with(|| Suspect::new(neighbor))
+            .observe_bill(misreport);
+    }
+}
+
+/// The result of a periodic probe of our route to the exit, measured_ms is None if the
+/// probe failed, which is not held against the neighbor
+pub struct ExitRouteChecked {
+    pub path: ExitPath,
+    pub measured_ms: Option<f32>,
+}
+
+impl Message for ExitRouteChecked {
+    type Result = ();
+}
+
+impl Handler<ExitRouteChecked> for FraudDetector {
+    type Result = ();
+
+    fn handle(&mut self, msg: ExitRouteChecked, _: &mut Context<Self>) -> Self::Result {
+        if let Some(measured_ms) = msg.measured_ms {
+            trace!(
+                "Measured {}ms to the exit, advertised {}ms",
+                measured_ms,
+                msg.path.route.full_path_rtt
+            );
+            let misreport = check_rtt(msg.path.route.full_path_rtt, measured_ms);
+            self.observe(msg.path.neighbor, misreport);
+        }
+        self.exit_path = Some(msg.path);
+    }
+}
+
+/// The outcome of a bill from the exit that said something about our route to it, see
+/// `ExitBillCheck::check`. None if the bill was fair
+pub struct ExitBillChecked {
+    pub misreport: Option<Misreport>,
+}
+
+impl Message for ExitBillChecked {
+    type Result = ();
+}
+
+impl Handler<ExitBillChecked> for FraudDetector {
+    type Result = ();
+
+    fn handle(&mut self, msg: ExitBillChecked, _: &mut Context<Self>) -> Self::Result {
+        match self.exit_path.clone() {
+            Some(path) => self.observe_bill(path.neighbor, msg.misreport),
+            None => trace!("No exit route to hold the exit bill against"),
+        }
+    }
+}
+
+/// Everyone we have observations for, most suspicious first
+pub struct GetSuspects;
+
+impl Message for GetSuspects {
+    type Result = Result<Vec<Suspect>, Error>;
+}
+
+impl Handler<GetSuspects> for FraudDetector {
+    type Result = Result<Vec<Suspect>, Error>;
+
+    fn handle(&mut self, _: GetSuspects, _: &mut Context<Self>) -> Self::Result {
+        let mut suspects: Vec<Suspect> = self.suspects.values().cloned().collect();
+        suspects.sort_by(|a, b| {
+            b.confidence
+                .partial_cmp(&a.confidence)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        Ok(suspects)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_test_identity() -> Identity {
+        Identity::new(
+            "2001::3".parse().unwrap(),
+            "0x0000000000000000000000000000000000000001"
+                .parse()
+                .unwrap(),
+            "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+            None,
+        )
+    }
+
+    #[test]
+    fn test_check_rtt() {
+        assert!(check_rtt(20.0, 30.0).is_none());
+        // slow but within the fixed allowance for overhead
+        assert!(check_rtt(5.0, 55.0).is_none());
+        assert!(check_rtt(20.0, 200.0).is_some());
+    }
+
+    #[test]
+    fn test_suspect_confidence() {
+        let mut suspect = Suspect::new(get_test_identity());
+        for _ in 0..MIN_OBSERVATIONS - 1 {
+            suspect.observe(check_rtt(10.0, 500.0));
+        }
+        // no confidence until we have enough observations
+        assert_eq!(suspect.confidence, 0.0);
+        suspect.observe(check_rtt(10.0, 500.0));
+        assert!(suspect.confidence >= BLOCK_CONFIDENCE);
+        assert_eq!(suspect.misreports, MIN_OBSERVATIONS);
+
+        // an honest neighbor is forgiven over time
+        for _ in 0..20 {
+            suspect.observe(check_rtt(10.0, 15.0));
+        }
+        assert!(suspect.confidence < 0.1);
+
+        // the occasional bad probe never adds up to much
+        let mut suspect = Suspect::new(get_test_identity());
+        for i in 0..100 {
+            if i % 10 == 0 {
+                suspect.observe(check_rtt(10.0, 500.0));
+            } else {
+                suspect.observe(None);
+            }
+        }
+        assert!(suspect.confidence < 0.3);
+    }
+
+    #[test]
+    fn test_overbilling_exit() {
+        let neighbor = get_test_identity();
+        let mut detector = FraudDetector::default();
+        // an exit that overbills every time looks the same as a neighbor advertising too low
+        // a price, it must never be enough to block the neighbor
+        for _ in 0..MIN_OBSERVATIONS * 3 {
+            detector.observe_bill(
+                neighbor,
+                Some(Misreport::Billing {
+                    advertised_price: 1000,
+                    expected: Int256::from(1_000_000),
+                    billed: Int256::from(5_000_000),
+                }),
+            );
+        }
+        let suspect = &detector.suspects[&neighbor];
+        assert_eq!(suspect.bill_checks, MIN_OBSERVATIONS * 3);
+        assert_eq!(suspect.billing_misreports, MIN_OBSERVATIONS * 3);
+        assert!(suspect.last_billing_misreport.is_some());
+        assert_eq!(suspect.observations, 0);
+        assert_eq!(suspect.confidence, 0.0);
+        assert!(!suspect.blocked);
+    }
+}
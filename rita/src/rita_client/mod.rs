@@ -1,4 +1,5 @@
 pub mod dashboard;
 pub mod exit_manager;
+pub mod fraud_detector;
 pub mod rita_loop;
 pub mod traffic_watcher;
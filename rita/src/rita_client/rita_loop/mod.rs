@@ -5,13 +5,12 @@
 //! tunnel if the signup was successful on the selected exit.
 
 use crate::rita_client::exit_manager::ExitManager;
+use crate::rita_common::route_history::{RouteHistory, SetExitDestinations};
 use crate::SETTING;
 use actix::{
     Actor, ActorContext, Addr, AsyncContext, Context, Handler, Message, Supervised, SystemService,
 };
-use althea_types::RTTimestamps;
 use failure::Error;
-use reqwest;
 use settings::client::RitaClientSettings;
 use std::time::{Duration, Instant};
 
@@ -68,6 +67,13 @@ impl Handler<Tick> for RitaLoop {
 
         ExitManager::from_registry().do_send(Tick {});
 
+        let exits = SETTING
+            .get_exits()
+            .values()
+            .map(|exit| exit.id.mesh_ip)
+            .collect();
+        RouteHistory::from_registry().do_send(SetExitDestinations(exits));
+
         info!(
             "Rita Client loop completed in {}s {}ms",
             start.elapsed().as_secs(),
@@ -76,39 +82,3 @@ impl Handler<Tick> for RitaLoop {
         Ok(())
     }
 }
-
-pub fn _compute_verification_rtt() -> Result<RTTimestamps, Error> {
-    let exit = match SETTING.get_exit_client().get_current_exit() {
-        Some(current_exit) => current_exit.clone(),
-        None => {
-            return Err(format_err!(
-                "No current exit even though an exit route is present"
-            ));
-        }
-    };
-
-    let client = reqwest::Client::builder()
-        .timeout(Duration::from_secs(1))
-        .build()?;
-
-    let timestamps: RTTimestamps = client
-        .get(&format!(
-            "http://[{}]:{}/rtt",
-            exit.id.mesh_ip, exit.registration_port
-        ))
-        .send()?
-        .json()?;
-
-    let exit_rx = timestamps.exit_rx;
-    let exit_tx = timestamps.exit_tx;
-    let client_tx = Instant::now();
-    let client_rx = Instant::now();
-
-    let inner_rtt = client_rx.duration_since(client_tx) - exit_tx.duration_since(exit_rx)?;
-    let inner_rtt_millis =
-        inner_rtt.as_secs() as f32 * 1000.0 + inner_rtt.subsec_nanos() as f32 / 1_000_000.0;
-    //                        secs -> millis                            nanos -> millis
-
-    info!("Computed RTTs: inner {}ms", inner_rtt_millis);
-    Ok(timestamps)
-}
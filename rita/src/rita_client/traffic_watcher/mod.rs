@@ -5,25 +5,443 @@
 //! This is the client specific billing code used to determine how exits should be compensted. Which is
 //! different in that mesh nodes are paid by forwarding traffic, but exits have to return traffic and
 //! must get paid for doing so.
+//!
+//! The exit tells us what we owe it, but we keep our own estimate from the exit tunnel counters,
+//! the exit's prices and our plan with the exit if we have one. Every time the exit reports our
+//! debt the claim is compared with that estimate, claims over the tolerance are kept in a
+//! history for the dashboard and depending on the settings are only paid up to the tolerance or
+//! get us to switch exits. Each verdict is also handed to the fraud detector, which records it
+//! for the neighbor advertising our route to the exit without holding it against them.
 
+use crate::rita_client::fraud_detector::{ExitBillChecked, FraudDetector, Misreport};
+use crate::rita_common::babel_client::{BabelClient, GetBabelTable};
 use crate::rita_common::debt_keeper::{DebtKeeper, Traffic, TrafficReplace};
+use crate::ARGS;
+use crate::KI;
 use crate::SETTING;
 use ::actix::{Actor, Arbiter, Context, Handler, Message, Supervised, SystemService};
 use actix_web::client;
 use actix_web::client::Connection;
 use actix_web::HttpMessage;
-use althea_types::Identity;
+use althea_kernel_interface::wg_iface_counter::WgUsage;
+use althea_types::{ExitPlanStatus, ExitPlanType, ExitState, Identity, OverQuotaAction};
 use failure::Error;
 use futures::future::ok as future_ok;
 use futures::future::Future;
-use num256::Int256;
+use num256::{Int256, Uint256};
+use settings::client::{ExitBillSettings, RitaClientSettings};
+use settings::FileWrite;
 use settings::RitaCommonSettings;
+use std::collections::VecDeque;
 use std::net::{IpAddr, SocketAddr};
 use std::time::Duration;
 use std::time::Instant;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::net::TcpStream as TokioTcpStream;
 
-pub struct TrafficWatcher {}
+/// Bills over the tolerance we keep for the dashboard
+const MAX_BILL_HISTORY: usize = 100;
+/// The exit bills on its own schedule so a claim can be ahead of our counters by a round, this
+/// much traffic at the download price is never counted against the exit
+const BILL_SLACK_BYTES: u64 = 1_000_000;
+/// The exit starts plan periods on its own clock and schedule, a period starting this many
+/// seconds either side of when we think it does may already or not yet be in a claim
+const PLAN_SLACK_SECS: u64 = 300;
+
+/// A bill from the exit that was over the tolerance of our estimate
+#[derive(Debug, Clone, Serialize)]
+pub struct BillDiscrepancy {
+    pub time: SystemTime,
+    pub exit: Identity,
+    /// What we think we owe
+    pub estimated: Int256,
+    /// What the exit says we owe
+    pub claimed: Int256,
+    /// What we accepted as our debt
+    pub accepted: Int256,
+}
+
+/// What a bill says about the route to the exit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BillVerdict {
+    /// Too little traffic since the last bill we agreed with to tell
+    Pending,
+    /// Within the tolerance over a full window of traffic
+    Fair,
+    /// Over the tolerance
+    Overbilled,
+}
+
+/// Our side of the exit's bills, the estimate is kept as the debt the exit last reported
+/// (the anchor) plus what we think we have used since. The anchor only moves to a claim that
+/// is within the tolerance over a full window of traffic or to one that went down, which
+/// happens when we pay
+#[derive(Debug, Clone, Serialize)]
+pub struct ExitBillCheck {
+    pub exit: Option<Identity>,
+    pub anchor: Int256,
+    /// What we think we owe, the anchor plus what we used since
+    pub estimated: Int256,
+    /// Our estimate of the per byte charges since the anchor in wei
+    #[serde(skip)]
+    charges: u128,
+    /// Plan periods started since the anchor, these are exact so no tolerance applies
+    #[serde(skip)]
+    period_charges: Int256,
+    #[serde(skip)]
+    window_bytes: u64,
+    #[serde(skip)]
+    last_usage: Option<WgUsage>,
+    /// Our copy of the exit's plan for us, kept up to date with our own usage between the
+    /// exit's reports
+    #[serde(skip)]
+    plan: Option<ExitPlanStatus>,
+    /// The plan as the exit last reported it, only a change in the report is news
+    #[serde(skip)]
+    reported_plan: Option<ExitPlanStatus>,
+    /// Bills over the tolerance in a row
+    pub strikes: u32,
+    pub history: VecDeque<BillDiscrepancy>,
+}
+
+impl Default for ExitBillCheck {
+    fn default() -> ExitBillCheck {
+        ExitBillCheck {
+            exit: None,
+            anchor: Int256::from(0),
+            estimated: Int256::from(0),
+            charges: 0,
+            period_charges: Int256::from(0),
+            window_bytes: 0,
+            last_usage: None,
+            plan: None,
+            reported_plan: None,
+            strikes: 0,
+            history: VecDeque::new(),
+        }
+    }
+}
+
+/// The prices the exit bills us at, the route price is our price to the exit which we take
+/// as the price of the exit's route back to us
+#[derive(Debug, Clone, Copy)]
+pub struct ExitPrices {
+    pub upload: u64,
+    pub download: u64,
+    pub route: u64,
+}
+
+/// What the exit bills us under
+#[derive(Debug, Clone)]
+pub struct ExitTerms {
+    pub prices: ExitPrices,
+    /// Our plan with the exit as it last reported it
+    pub plan: Option<ExitPlanStatus>,
+}
+
+/// Amounts are clamped rather than overflowing, no real bill gets near the limit
+fn wei(amount: u128) -> Int256 {
+    Int256::from(amount.min(i64::max_value() as u128) as i64)
+}
+
+fn secs_since_unix_epoch() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+fn same_terms(a: &ExitPlanStatus, b: &ExitPlanStatus) -> bool {
+    a.plan_type == b.plan_type
+        && a.quota == b.quota
+        && a.period == b.period
+        && a.price == b.price
+        && a.over_quota == b.over_quota
+}
+
+/// Runs a round of our usage through our copy of the plan the same way the exit does (see
+/// `rita_exit::database::plans::apply_plan`). Returns the price of a period if one started
+/// and the part of the usage that is billed per byte
+fn apply_plan(plan: &mut ExitPlanStatus, used: &WgUsage, now: u64) -> (Option<Uint256>, WgUsage) {
+    let starts_period = match plan.plan_type {
+        ExitPlanType::FlatRate => plan.period_start == 0 || now >= plan.period_start + plan.period,
+        ExitPlanType::Prepaid => plan.period_start == 0,
+    };
+    let period_price = if starts_period {
+        plan.period_start = if plan.period_start != 0 && now < plan.period_start + 2 * plan.period {
+            plan.period_start + plan.period
+        } else {
+            now
+        };
+        plan.used = 0;
+        Some(plan.price.clone())
+    } else {
+        None
+    };
+
+    let total = used.upload + used.download;
+    let expired = plan.plan_type == ExitPlanType::Prepaid
+        && plan.period_start != 0
+        && now >= plan.period_start + plan.period;
+    let remaining = if expired {
+        0
+    } else {
+        plan.quota.saturating_sub(plan.used)
+    };
+    let over = total.saturating_sub(remaining);
+    let billable = if over == 0 || plan.over_quota == OverQuotaAction::Throttle {
+        WgUsage {
+            upload: 0,
+            download: 0,
+        }
+    } else {
+        let upload = (u128::from(used.upload) * u128::from(over) / u128::from(total)) as u64;
+        WgUsage {
+            upload,
+            download: over - upload,
+        }
+    };
+    plan.used += total;
+    plan.remaining = remaining.saturating_sub(total);
+    (period_price, billable)
+}
+
+/// The price of a period the exit may start before our copy of the plan does
+fn upcoming_period_price(plan: &ExitPlanStatus, now: u64) -> Option<&Uint256> {
+    let upcoming = match plan.plan_type {
+        ExitPlanType::FlatRate => {
+            plan.period_start == 0 || now + PLAN_SLACK_SECS >= plan.period_start + plan.period
+        }
+        ExitPlanType::Prepaid => plan.period_start == 0,
+    };
+    if upcoming {
+        Some(&plan.price)
+    } else {
+        None
+    }
+}
+
+impl ExitBillCheck {
+    fn start(
+        &mut self,
+        exit: Identity,
+        usage: WgUsage,
+        plan: Option<&ExitPlanStatus>,
+        claimed: Int256,
+    ) {
+        self.exit = Some(exit);
+        self.last_usage = Some(usage);
+        self.plan = None;
+        self.reported_plan = None;
+        self.sync_plan(plan);
+        self.strikes = 0;
+        self.reanchor(claimed);
+    }
+
+    fn reanchor(&mut self, claimed: Int256) {
+        self.anchor = claimed.clone();
+        self.estimated = claimed;
+        self.charges = 0;
+        self.period_charges = Int256::from(0);
+        self.window_bytes = 0;
+    }
+
+    fn charge_period(&mut self, price: &Uint256) {
+        match price.to_int256() {
+            Some(price) => self.period_charges = self.period_charges.clone() + price,
+            None => warn!("Plan price {} is out of range", price),
+        }
+    }
+
+    /// Brings our copy of the plan in line with what the exit reported. Reports are refreshed
+    /// less often than we bill so only a changed report is taken into account, and then only
+    /// where it is ahead of our copy
+    fn sync_plan(&mut self, reported: Option<&ExitPlanStatus>) {
+        if self.reported_plan.as_ref() == reported {
+            return;
+        }
+        self.reported_plan = reported.cloned();
+        let reported = match reported {
+            Some(reported) => reported,
+            None => {
+                self.plan = None;
+                return;
+            }
+        };
+        let ours = match self.plan.take() {
+            Some(ours) => {
+                if same_terms(&ours, reported) {
+                    ours
+                } else {
+                    self.plan = Some(reported.clone());
+                    return;
+                }
+            }
+            None => {
+                self.plan = Some(reported.clone());
+                return;
+            }
+        };
+        let slack = PLAN_SLACK_SECS.min(ours.period);
+        if reported.period_start == 0 {
+            // renewed by the operator, it starts again on our next round
+            self.plan = Some(reported.clone());
+        } else if ours.period_start == 0 || reported.period_start >= ours.period_start + slack {
+            // a period we did not see start
+            self.plan = Some(reported.clone());
+            self.charge_period(&reported.price);
+        } else if reported.period_start + slack > ours.period_start {
+            // the same period, the exit counts from its own clock
+            let used = ours.used.max(reported.used);
+            self.plan = Some(ExitPlanStatus { used, ..ours });
+        } else {
+            // from before a period we already started
+            self.plan = Some(ours);
+        }
+    }
+
+    /// True if no plan period is close enough to starting that it may be in one of the exit's
+    /// claims and not the other, the anchor stays put until then
+    fn plan_settled(&self, now: u64) -> bool {
+        match self.plan {
+            Some(ref plan) => {
+                plan.period_start != 0
+                    && now >= plan.period_start + PLAN_SLACK_SECS
+                    && upcoming_period_price(plan, now).is_none()
+            }
+            None => true,
+        }
+    }
+
+    /// Compares a claim from the exit with our estimate and returns the debt we accept
+    pub fn check(
+        &mut self,
+        exit: Identity,
+        usage: WgUsage,
+        terms: &ExitTerms,
+        claimed: Int256,
+        settings: &ExitBillSettings,
+        now: u64,
+    ) -> (Int256, BillVerdict) {
+        let prices = terms.prices;
+        let last_usage = match self.last_usage {
+            Some(ref last_usage) if self.exit == Some(exit) => last_usage.clone(),
+            _ => {
+                self.start(exit, usage, terms.plan.as_ref(), claimed.clone());
+                return (claimed, BillVerdict::Pending);
+            }
+        };
+        // a tunnel reset starts the counters over
+        let used = if usage.upload < last_usage.upload || usage.download < last_usage.download {
+            usage.clone()
+        } else {
+            WgUsage {
+                upload: usage.upload - last_usage.upload,
+                download: usage.download - last_usage.download,
+            }
+        };
+        self.last_usage = Some(usage);
+        self.window_bytes += used.upload + used.download;
+
+        self.sync_plan(terms.plan.as_ref());
+        let (period_price, billable) = match self.plan {
+            Some(ref mut plan) => apply_plan(plan, &used, now),
+            None => (None, used),
+        };
+        if let Some(price) = period_price {
+            self.charge_period(&price);
+        }
+        self.charges += u128::from(billable.upload) * u128::from(prices.upload)
+            + u128::from(billable.download) * u128::from(prices.download + prices.route);
+        self.estimated = self.anchor.clone() + self.period_charges.clone() + wei(self.charges);
+
+        if claimed < self.anchor {
+            self.strikes = 0;
+            self.reanchor(claimed.clone());
+            return (claimed, BillVerdict::Pending);
+        }
+
+        let slack = u128::from(BILL_SLACK_BYTES) * u128::from(prices.download + prices.route);
+        let mut limit = self.anchor.clone()
+            + self.period_charges.clone()
+            + wei(self.charges * u128::from(settings.tolerance_percent) / 100 + slack);
+        if let Some(price) = self
+            .plan
+            .as_ref()
+            .and_then(|plan| upcoming_period_price(plan, now))
+            .and_then(|price| price.to_int256())
+        {
+            limit = limit + price;
+        }
+        if claimed <= limit {
+            self.strikes = 0;
+            if self.window_bytes >= BILL_SLACK_BYTES && self.plan_settled(now) {
+                self.reanchor(claimed.clone());
+                return (claimed, BillVerdict::Fair);
+            }
+            return (claimed, BillVerdict::Pending);
+        }
+
+        self.strikes += 1;
+        let accepted = if settings.refuse_overbilling {
+            limit
+        } else {
+            claimed.clone()
+        };
+        warn!(
+            "The exit claims we owe {} but we estimate {}, accepting {}",
+            claimed, self.estimated, accepted
+        );
+        self.history.push_front(BillDiscrepancy {
+            time: SystemTime::now(),
+            exit,
+            estimated: self.estimated.clone(),
+            claimed,
+            accepted: accepted.clone(),
+        });
+        self.history.truncate(MAX_BILL_HISTORY);
+        (accepted, BillVerdict::Overbilled)
+    }
+
+    /// True once the exit has overbilled us enough times in a row to leave it
+    pub fn should_switch(&self, settings: &ExitBillSettings) -> bool {
+        settings
+            .switch_after
+            .map_or(false, |switch_after| self.strikes >= switch_after)
+    }
+}
+
+/// Selects a registered exit other than the one that has been overbilling us, the exit manager
+/// sets up the tunnel to it on its next tick
+fn switch_exit(overbilling: &Identity) -> Result<(), Error> {
+    {
+        let mut exit_client = SETTING.get_exit_client_mut();
+        let next = exit_client
+            .exits
+            .iter()
+            .find(|(_, exit)| {
+                exit.id != *overbilling
+                    && match exit.info {
+                        ExitState::Registered { .. } => true,
+                        _ => false,
+                    }
+            })
+            .map(|(name, _)| name.clone());
+        match next {
+            Some(name) => {
+                warn!("Switching to exit {} after repeated overbilling", name);
+                exit_client.current_exit = Some(name);
+            }
+            None => bail!("No other registered exit to switch to"),
+        }
+    }
+    SETTING.write().unwrap().write(&ARGS.flag_config)?;
+    Ok(())
+}
+
+pub struct TrafficWatcher {
+    bills: ExitBillCheck,
+}
 
 impl Actor for TrafficWatcher {
     type Context = Context<Self>;
@@ -36,13 +454,35 @@ impl SystemService for TrafficWatcher {
 }
 impl Default for TrafficWatcher {
     fn default() -> TrafficWatcher {
-        TrafficWatcher {}
+        TrafficWatcher {
+            bills: ExitBillCheck::default(),
+        }
     }
 }
 
+/// Looks up our route price to the exit and hands the debt it reported to `ExitBill`
+fn check_exit_bill(exit_id: Identity, debt: Int256) -> impl Future<Item = (), Error = ()> {
+    BabelClient::from_registry()
+        .send(GetBabelTable)
+        .then(move |babel_table| {
+            let route_price = match babel_table {
+                Ok(Ok(babel_table)) => babel_table
+                    .get_installed_route(&exit_id.mesh_ip)
+                    .ok()
+                    .map(|route| route.price),
+                _ => None,
+            };
+            TrafficWatcher::from_registry().do_send(ExitBill {
+                exit: exit_id,
+                debt,
+                route_price,
+            });
+            Ok(())
+        })
+}
+
 /// Used to request what the exits thinks this clients debts are. We will compare
-/// this value to our own computation and alert to any large discrepencies, but in
-/// general we have to trust the exit. In a pay per forward system nodes within the
+/// this value to our own computation, see `ExitBillCheck`. In a pay per forward system nodes within the
 /// network have either two states, properly paid, or in the face of packet loss or
 /// network issues, overpaid. Because packet loss presents as the sending node having
 /// a higher total packets sent count than the receiving node. Resulting in what looks
@@ -103,23 +543,18 @@ impl Handler<QueryExitDebts> for TrafficWatcher {
                                         start.elapsed().subsec_millis()
                                     );
                                     if debt >= Int256::from(0) {
-                                        let exit_replace = TrafficReplace {
-                                            traffic: Traffic {
-                                                from: exit_id,
-                                                amount: debt,
-                                            },
-                                        };
-
-                                        DebtKeeper::from_registry().do_send(exit_replace);
+                                        Box::new(check_exit_bill(exit_id, debt))
+                                            as Box<dyn Future<Item = (), Error = ()>>
                                     } else {
                                         error!("The exit owes us? That shouldn't be possible!");
+                                        Box::new(future_ok(())) as Box<dyn Future<Item = (), Error = ()>>
                                     }
                                 }
                                 Err(e) => {
-                                    error!("Failed deserializing exit debts update with {:?}", e)
+                                    error!("Failed deserializing exit debts update with {:?}", e);
+                                    Box::new(future_ok(())) as Box<dyn Future<Item = (), Error = ()>>
                                 }
                             }
-                            Ok(()) as Result<(), ()>
                         })),
                         Err(e) => {
                             trace!("Exit debts request to {} failed with {:?}", request, e);
@@ -140,3 +575,342 @@ impl Handler<QueryExitDebts> for TrafficWatcher {
         Ok(())
     }
 }
+
+/// The debt the exit reported, checked against our estimate before it replaces our debt to the
+/// exit. The route price is None if we have no route to the exit
+pub struct ExitBill {
+    pub exit: Identity,
+    pub debt: Int256,
+    pub route_price: Option<u32>,
+}
+
+impl Message for ExitBill {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<ExitBill> for TrafficWatcher {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: ExitBill, _: &mut Context<Self>) -> Self::Result {
+        let exit_id = msg.exit;
+        let usage = KI
+            .read_wg_counters("wg_exit")?
+            .get(&exit_id.wg_public_key)
+            .cloned();
+        let info = SETTING
+            .get_exit_client()
+            .get_current_exit()
+            .filter(|exit| exit.id == exit_id)
+            .map(|exit| exit.info.clone());
+        let details = info.as_ref().and_then(|info| info.general_details());
+        let plan = info
+            .as_ref()
+            .and_then(|info| info.our_details())
+            .and_then(|details| details.plan.clone());
+        let settings = SETTING.get_exit_client().bill_check.clone();
+
+        let accepted = match (usage, details, msg.route_price) {
+            (Some(usage), Some(details), Some(route_price)) => {
+                let terms = ExitTerms {
+                    prices: ExitPrices {
+                        upload: details.upload_price(),
+                        download: details.download_price(),
+                        route: u64::from(route_price),
+                    },
+                    plan,
+                };
+                let (accepted, verdict) = self.bills.check(
+                    exit_id,
+                    usage,
+                    &terms,
+                    msg.debt.clone(),
+                    &settings,
+                    secs_since_unix_epoch(),
+                );
+                let misreport = match verdict {
+                    BillVerdict::Pending => None,
+                    BillVerdict::Fair => Some(None),
+                    BillVerdict::Overbilled => Some(Some(Misreport::Billing {
+                        advertised_price: route_price,
+                        expected: self.bills.estimated.clone(),
+                        billed: msg.debt,
+                    })),
+                };
+                if let Some(misreport) = misreport {
+                    FraudDetector::from_registry().do_send(ExitBillChecked { misreport });
+                }
+                if self.bills.should_switch(&settings) {
+                    match switch_exit(&exit_id) {
+                        Ok(()) => self.bills.strikes = 0,
+                        Err(e) => error!("Failed to switch away from an overbilling exit {:?}", e),
+                    }
+                }
+                accepted
+            }
+            _ => {
+                trace!("Not enough information to check the exit's bill");
+                msg.debt
+            }
+        };
+
+        DebtKeeper::from_registry().do_send(TrafficReplace {
+            traffic: Traffic {
+                from: exit_id,
+                amount: accepted,
+            },
+        });
+        Ok(())
+    }
+}
+
+/// Our estimate of the exit's bills and the history of those over the tolerance
+pub struct GetExitBills;
+
+impl Message for GetExitBills {
+    type Result = Result<ExitBillCheck, Error>;
+}
+
+impl Handler<GetExitBills> for TrafficWatcher {
+    type Result = Result<ExitBillCheck, Error>;
+
+    fn handle(&mut self, _: GetExitBills, _: &mut Context<Self>) -> Self::Result {
+        Ok(self.bills.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(upload: u64, download: u64) -> WgUsage {
+        WgUsage { upload, download }
+    }
+
+    fn get_test_identity() -> Identity {
+        Identity::new(
+            "fd00::1".parse().unwrap(),
+            "0x0000000000000000000000000000000000000001"
+                .parse()
+                .unwrap(),
+            "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+            None,
+        )
+    }
+
+    fn terms(plan: Option<ExitPlanStatus>) -> ExitTerms {
+        ExitTerms {
+            prices: ExitPrices {
+                upload: 10,
+                download: 10,
+                route: 5,
+            },
+            plan,
+        }
+    }
+
+    fn test_plan(plan_type: ExitPlanType, over_quota: OverQuotaAction) -> ExitPlanStatus {
+        ExitPlanStatus {
+            plan_type,
+            quota: 1_000_000,
+            period: 1000,
+            price: Uint256::from(50_000_000u64),
+            over_quota,
+            period_start: 0,
+            used: 0,
+            remaining: 1_000_000,
+        }
+    }
+
+    #[test]
+    fn test_exit_bill_check() {
+        let exit = get_test_identity();
+        let terms = terms(None);
+        let mut settings = ExitBillSettings::default();
+        settings.refuse_overbilling = true;
+        settings.switch_after = Some(2);
+        let mut check = ExitBillCheck::default();
+
+        // the first claim is taken as is
+        let (accepted, verdict) =
+            check.check(exit, usage(0, 0), &terms, Int256::from(1000), &settings, 0);
+        assert_eq!(accepted, Int256::from(1000));
+        assert_eq!(verdict, BillVerdict::Pending);
+
+        // 2MB down at 15 wei a byte, billed honestly
+        let claimed = Int256::from(1000 + 30_000_000);
+        let (accepted, verdict) = check.check(
+            exit,
+            usage(0, 2_000_000),
+            &terms,
+            claimed.clone(),
+            &settings,
+            0,
+        );
+        assert_eq!(accepted, claimed);
+        assert_eq!(verdict, BillVerdict::Fair);
+        assert_eq!(check.anchor, claimed);
+
+        // billed four times over, only the tolerance plus slack is accepted
+        let (accepted, verdict) = check.check(
+            exit,
+            usage(0, 4_000_000),
+            &terms,
+            claimed.clone() + Int256::from(120_000_000),
+            &settings,
+            0,
+        );
+        assert_eq!(
+            accepted,
+            claimed.clone() + Int256::from(45_000_000 + 15_000_000)
+        );
+        assert_eq!(verdict, BillVerdict::Overbilled);
+        assert_eq!(check.history.len(), 1);
+        assert!(!check.should_switch(&settings));
+
+        // still over, time to leave
+        check.check(
+            exit,
+            usage(0, 4_000_001),
+            &terms,
+            claimed.clone() + Int256::from(200_000_000),
+            &settings,
+            0,
+        );
+        assert!(check.should_switch(&settings));
+
+        // a payment lowers the claim, we start over from there
+        let (accepted, verdict) = check.check(
+            exit,
+            usage(0, 4_000_001),
+            &terms,
+            Int256::from(5),
+            &settings,
+            0,
+        );
+        assert_eq!(accepted, Int256::from(5));
+        assert_eq!(verdict, BillVerdict::Pending);
+        assert_eq!(check.strikes, 0);
+    }
+
+    #[test]
+    fn test_exit_bill_check_flat_rate() {
+        let exit = get_test_identity();
+        let terms = terms(Some(test_plan(
+            ExitPlanType::FlatRate,
+            OverQuotaAction::PerByte,
+        )));
+        let settings = ExitBillSettings::default();
+        let mut check = ExitBillCheck::default();
+        check.check(
+            exit,
+            usage(0, 0),
+            &terms,
+            Int256::from(0),
+            &settings,
+            10_000,
+        );
+
+        // the first period is charged, 1MB is in the quota and the other 2MB at 15 wei a byte
+        let claimed = Int256::from(50_000_000 + 30_000_000);
+        let (accepted, verdict) = check.check(
+            exit,
+            usage(0, 3_000_000),
+            &terms,
+            claimed.clone(),
+            &settings,
+            10_010,
+        );
+        assert_eq!(check.estimated, claimed);
+        assert_eq!(accepted, claimed);
+        // the period only just started, the exit may not have charged it yet
+        assert_eq!(verdict, BillVerdict::Pending);
+        let (_, verdict) = check.check(
+            exit,
+            usage(0, 3_000_000),
+            &terms,
+            claimed.clone(),
+            &settings,
+            10_400,
+        );
+        assert_eq!(verdict, BillVerdict::Fair);
+        assert_eq!(check.anchor, claimed);
+
+        // the exit may start the next period a little before we do
+        let (_, verdict) = check.check(
+            exit,
+            usage(0, 3_000_000),
+            &terms,
+            claimed.clone() + Int256::from(50_000_000),
+            &settings,
+            10_800,
+        );
+        assert_eq!(verdict, BillVerdict::Pending);
+
+        // a new period renews the quota, but it doesn't cover a second period charge
+        let (_, verdict) = check.check(
+            exit,
+            usage(0, 3_500_000),
+            &terms,
+            claimed.clone() + Int256::from(100_000_000),
+            &settings,
+            11_010,
+        );
+        assert_eq!(check.estimated, claimed.clone() + Int256::from(50_000_000));
+        assert_eq!(verdict, BillVerdict::Overbilled);
+    }
+
+    #[test]
+    fn test_exit_bill_check_prepaid() {
+        let exit = get_test_identity();
+        let mut plan = test_plan(ExitPlanType::Prepaid, OverQuotaAction::Throttle);
+        plan.period = 10_000;
+        let settings = ExitBillSettings::default();
+        let mut check = ExitBillCheck::default();
+        check.check(
+            exit,
+            usage(0, 0),
+            &terms(Some(plan.clone())),
+            Int256::from(0),
+            &settings,
+            100,
+        );
+
+        // the bundle is charged and everything over the quota is throttled, not billed
+        check.check(
+            exit,
+            usage(0, 2_000_000),
+            &terms(Some(plan.clone())),
+            Int256::from(50_000_000),
+            &settings,
+            110,
+        );
+        assert_eq!(check.estimated, Int256::from(50_000_000));
+
+        // the operator renewed the bundle, we learn of it from the exit's report
+        plan.period_start = 5000;
+        let (_, verdict) = check.check(
+            exit,
+            usage(0, 2_000_000),
+            &terms(Some(plan.clone())),
+            Int256::from(100_000_000),
+            &settings,
+            5100,
+        );
+        assert_eq!(check.estimated, Int256::from(100_000_000));
+        assert_eq!(verdict, BillVerdict::Pending);
+
+        // the same report again is not another bundle
+        let (_, verdict) = check.check(
+            exit,
+            usage(0, 2_000_000),
+            &terms(Some(plan)),
+            Int256::from(100_000_000),
+            &settings,
+            5400,
+        );
+        assert_eq!(check.estimated, Int256::from(100_000_000));
+        assert_eq!(verdict, BillVerdict::Fair);
+    }
+}
@@ -0,0 +1,79 @@
+//! Commands that change babel's state, monitoring tunnels and setting our fee. Each is sent over
+//! its own short lived non blocking connection so that a slow babeld delays the command but
+//! never the arbiter. Commands run one at a time in the order they were sent, otherwise
+//! flushing the interface of a closed tunnel could overtake monitoring a new tunnel that has
+//! been given the same interface name.
+
+use crate::SETTING;
+use ::actix::fut::wrap_future;
+use ::actix::prelude::{Actor, AsyncContext, Context, Handler, Message, Supervised, SystemService};
+use babel_monitor::{AsyncBabel, InterfaceConfig};
+use failure::Error;
+use futures::Future;
+use settings::RitaCommonSettings;
+use tokio::net::TcpStream;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BabelCommand {
+    /// Starts babel on an interface or, if it is already running there, updates the
+    /// interface's parameters
+    ConfigureInterface(String, InterfaceConfig),
+    /// Stops babel on an interface
+    Unmonitor(String),
+    SetLocalFee(u32),
+}
+
+impl Message for BabelCommand {
+    type Result = ();
+}
+
+impl BabelCommand {
+    fn run(
+        self,
+        babel: AsyncBabel<TcpStream>,
+    ) -> Box<dyn Future<Item = AsyncBabel<TcpStream>, Error = Error>> {
+        match self {
+            BabelCommand::ConfigureInterface(iface, config) => {
+                babel.configure_interface(&iface, &config)
+            }
+            BabelCommand::Unmonitor(iface) => babel.unmonitor(&iface),
+            BabelCommand::SetLocalFee(fee) => babel.set_local_fee(fee),
+        }
+    }
+}
+
+/// Runs `BabelCommand`s, nobody waits for the result so failures are only logged. A tunnel
+/// babel failed to pick up shows up in the tunnel health checks and is rebuilt from there
+#[derive(Default)]
+pub struct BabelCommander;
+
+impl Actor for BabelCommander {
+    type Context = Context<Self>;
+}
+
+impl Supervised for BabelCommander {}
+
+impl SystemService for BabelCommander {
+    fn service_started(&mut self, _ctx: &mut Context<Self>) {
+        info!("Babel commander started");
+    }
+}
+
+impl Handler<BabelCommand> for BabelCommander {
+    type Result = ();
+
+    fn handle(&mut self, msg: BabelCommand, ctx: &mut Context<Self>) -> Self::Result {
+        trace!("Sending {:?} to babel", msg);
+        let description = format!("{:?}", msg);
+        let command = AsyncBabel::connect(SETTING.get_network().babel_port)
+            .and_then(move |babel| msg.run(babel))
+            .then(move |res| {
+                if let Err(e) = res {
+                    warn!("Babel command {} failed with {:?}", description, e);
+                }
+                Ok(())
+            });
+        // the next command stays in the mailbox until this one is done
+        ctx.wait(wrap_future(command));
+    }
+}
@@ -0,0 +1,211 @@
+//! Serves babel's tables to the rest of Rita. They normally come from a monitoring connection to
+//! babeld that is kept up to date incrementally (see `BabeldSource`), but any `RouteSource` will
+//! do, `network.static_route_table` swaps in a fixed table for testing.
+//!
+//! If babeld goes away the last table it gave us keeps being served for a while so that billing
+//! carries on through a babeld restart, routes rarely change that quickly.
+//!
+//! Only reads are served from this table, commands that change babel's state (monitoring
+//! interfaces, setting fees) go through the `BabelCommander` which uses its own short lived
+//! connections so that a slow command can never stall the event stream.
+
+mod commands;
+mod source;
+
+pub use self::commands::{BabelCommand, BabelCommander};
+pub use self::source::{BabeldSource, RouteSource, StaticSource};
+
+use crate::SETTING;
+use ::actix::{Actor, AsyncContext, Context, Handler, Message, Supervised, SystemService};
+use babel_monitor::BabelTable;
+use babel_monitor::Preamble;
+use failure::Error;
+use settings::RitaCommonSettings;
+use std::time::Duration;
+
+/// How often we read the events babel has sent since the last poll
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// The oldest table we will serve when the route source can't give us a current one
+const MAX_TABLE_AGE: Duration = Duration::from_secs(300);
+
+pub struct BabelClient {
+    source: Box<dyn RouteSource>,
+}
+
+impl Actor for BabelClient {
+    type Context = Context<Self>;
+}
+
+impl Supervised for BabelClient {}
+
+impl SystemService for BabelClient {
+    fn service_started(&mut self, ctx: &mut Context<Self>) {
+        ctx.run_interval(POLL_INTERVAL, |act, _ctx| act.poll());
+        info!("Babel client started");
+    }
+}
+
+impl Default for BabelClient {
+    fn default() -> BabelClient {
+        let static_route_table = SETTING.get_network().static_route_table.clone();
+        if let Some(path) = static_route_table {
+            match StaticSource::from_file(&path) {
+                Ok(source) => {
+                    warn!("Using the static route table {} instead of babel", path);
+                    return BabelClient::new(Box::new(source));
+                }
+                Err(e) => error!("Failed to load static route table {} {:?}", path, e),
+            }
+        }
+        BabelClient::new(Box::new(BabeldSource::default()))
+    }
+}
+
+impl BabelClient {
+    pub fn new(source: Box<dyn RouteSource>) -> BabelClient {
+        BabelClient { source }
+    }
+
+    fn poll(&mut self) {
+        if let Err(e) = self.source.poll() {
+            warn!("Failed to update the babel table {:?}", e);
+        }
+    }
+
+    /// The freshest table we have, as long as it's not too old to trust
+    fn current_table(&mut self) -> Result<BabelTable, Error> {
+        self.poll();
+        match self.source.table() {
+            Some((table, age)) if age <= MAX_TABLE_AGE => {
+                if age > POLL_INTERVAL * 2 {
+                    warn!("Serving a babel table last updated {}s ago", age.as_secs());
+                }
+                Ok(table.clone())
+            }
+            Some((_, age)) => bail!(
+                "The last babel table is {}s old, too old to use",
+                age.as_secs()
+            ),
+            None => bail!("No babel table available"),
+        }
+    }
+}
+
+/// Returns a copy of babel's current tables
+pub struct GetBabelTable;
+
+impl Message for GetBabelTable {
+    type Result = Result<BabelTable, Error>;
+}
+
+impl Handler<GetBabelTable> for BabelClient {
+    type Result = Result<BabelTable, Error>;
+
+    fn handle(&mut self, _: GetBabelTable, _: &mut Context<Self>) -> Self::Result {
+        self.current_table()
+    }
+}
+
+/// Returns the preamble of the babeld we are (or were last) connected to
+pub struct GetBabelPreamble;
+
+impl Message for GetBabelPreamble {
+    type Result = Result<Preamble, Error>;
+}
+
+impl Handler<GetBabelPreamble> for BabelClient {
+    type Result = Result<Preamble, Error>;
+
+    fn handle(&mut self, _: GetBabelPreamble, _: &mut Context<Self>) -> Self::Result {
+        if self.source.preamble().is_none() {
+            self.poll();
+        }
+        match self.source.preamble() {
+            Some(preamble) => Ok(preamble.clone()),
+            None => bail!("Never connected to babel"),
+        }
+    }
+}
+
+/// Our fee as babel reports it, 0 if babel doesn't support fees. If babel has lost it (for
+/// example because it was restarted) the configured fee is returned and babel is asked to set
+/// it again
+pub fn get_local_fee(table: &BabelTable) -> Result<u32, Error> {
+    if table.unpriced {
+        return Ok(0);
+    }
+    match table.local_fee {
+        Some(fee) => Ok(fee),
+        None => {
+            error!("Babel fee not set properly! this is a bad sign!");
+            let configured_fee = SETTING.get_payment().get_relay_fee();
+            BabelCommander::from_registry().do_send(BabelCommand::SetLocalFee(configured_fee));
+            Ok(configured_fee)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// A source that has always just failed, with a table of a given age
+    struct FailingSource {
+        table: Option<BabelTable>,
+        age: Rc<Cell<Duration>>,
+    }
+
+    impl RouteSource for FailingSource {
+        fn poll(&mut self) -> Result<(), Error> {
+            bail!("babeld is gone")
+        }
+
+        fn table(&self) -> Option<(&BabelTable, Duration)> {
+            self.table.as_ref().map(|table| (table, self.age.get()))
+        }
+    }
+
+    static DUMP: &'static str = "local fee 1024\n\
+add route 14f0820 prefix fd00::1/128 from ::/0 installed yes id ba:27:eb:ff:fe:5b:fe:c7 \
+metric 1596 price 3072 fee 3072 refmetric 638 full-path-rtt 22.805 via fe80::2cee:2fff:648:8796 if wg0\n\
+ok\n";
+
+    #[test]
+    fn test_static_source() {
+        let mut client = BabelClient::new(Box::new(StaticSource::new(BabelTable::from_dump(DUMP))));
+        let table = client.current_table().unwrap();
+        assert_eq!(table.local_fee, Some(1024));
+        assert!(table.do_we_have_route(&"fd00::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_unpriced_local_fee() {
+        let mut table = BabelTable::from_dump(
+            "add route 2a3b3f0 prefix fd00::5/128 from ::/0 installed yes \
+id 52:54:00:ff:fe:65:43:21 metric 96 refmetric 0 via fe80::5054:ff:fe65:4321 if eth0\nok\n",
+        );
+        table.unpriced = true;
+        // a stock babeld never reports a fee and we must not keep trying to set one
+        assert_eq!(get_local_fee(&table).unwrap(), 0);
+        assert_eq!(table.routes.values().next().unwrap().price, 0);
+    }
+
+    #[test]
+    fn test_last_good_table() {
+        let age = Rc::new(Cell::new(Duration::from_secs(30)));
+        let mut client = BabelClient::new(Box::new(FailingSource {
+            table: Some(BabelTable::from_dump(DUMP)),
+            age: age.clone(),
+        }));
+        // babeld failing briefly doesn't stop anyone
+        assert_eq!(client.current_table().unwrap().routes.len(), 1);
+
+        age.set(MAX_TABLE_AGE + Duration::from_secs(1));
+        assert!(client.current_table().is_err());
+
+        let mut client = BabelClient::new(Box::new(FailingSource { table: None, age }));
+        assert!(client.current_table().is_err());
+    }
+}
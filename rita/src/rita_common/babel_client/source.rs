@@ -0,0 +1,319 @@
+//! Where the BabelClient gets its tables from. Normally that's babeld, but billing only needs
+//! a route table, so tests, simulations and nodes without a working babeld can be given a
+//! static one instead.
+
+use crate::rita_common::babel_config::install_filters;
+use crate::SETTING;
+use ::actix::Arbiter;
+use babel_monitor::AsyncBabel;
+use babel_monitor::BabelMonitorError::ConnectionClosed;
+use babel_monitor::BabelTable;
+use babel_monitor::Capabilities;
+use babel_monitor::Preamble;
+use failure::Error;
+use futures::future;
+use futures::sync::oneshot;
+use futures::{Future, Stream};
+use settings::RitaCommonSettings;
+use std::fs::File;
+use std::io::Read;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+/// Upper bound on events applied per poll so a flood of updates can't starve the arbiter
+const MAX_EVENTS_PER_POLL: usize = 10_000;
+/// Babel does not announce every change (setting our fee for example) as an event, so we
+/// start over with a fresh dump this often
+const RESYNC_INTERVAL: Duration = Duration::from_secs(60);
+
+pub trait RouteSource {
+    /// Brings the table up to date, called before every read of it
+    fn poll(&mut self) -> Result<(), Error>;
+
+    /// The table along with how long ago it was last known to be current, None if the
+    /// source never had one
+    fn table(&self) -> Option<(&BabelTable, Duration)>;
+
+    /// The preamble of the babeld behind this source, if there is one
+    fn preamble(&self) -> Option<&Preamble> {
+        None
+    }
+}
+
+/// What the task driving a monitoring connection tells its `BabeldSource`
+enum MonitorEvent {
+    /// The connection is up, along with the table babel dumped when monitoring started
+    Connected {
+        table: BabelTable,
+        preamble: Option<Preamble>,
+        capabilities: Capabilities,
+        filters_installed: bool,
+    },
+    Line(String),
+    /// The connection is gone, `session_over` if babeld wasn't running or closed it
+    Failed {
+        error: Error,
+        session_over: bool,
+    },
+}
+
+/// A monitoring connection driven by a task on the arbiter, dropping it drops the connection
+struct Connection {
+    events: Receiver<MonitorEvent>,
+    _cancel: oneshot::Sender<()>,
+}
+
+/// Keeps a single connection to babeld open in monitor mode, babeld dumps its tables when
+/// monitoring starts and then streams every change to them, so rather than reconnecting and
+/// dumping the full route table every time someone needs it we keep an up to date copy here.
+///
+/// The connection is made and read by a task on the arbiter that passes what babel sends
+/// through a channel, so polling never blocks even when babeld is slow to answer.
+///
+/// When the connection fails the table is kept, it's up to the reader to decide how old a
+/// table it is willing to use
+pub struct BabeldSource {
+    connection: Option<Connection>,
+    table: Option<BabelTable>,
+    connected_at: Option<Instant>,
+    /// The last time we read everything babel had sent without an error
+    synced_at: Option<Instant>,
+    /// The preamble of the last babeld we connected to, kept across reconnects
+    preamble: Option<Preamble>,
+    /// Whether the configured filters were sent to the babeld we are talking to. babeld keeps
+    /// filters for as long as it runs and only appends new ones, so they are sent once per
+    /// babeld session. A session ends when babeld closes our monitoring connection or refuses
+    /// a new one, our own periodic reconnects don't end it
+    filters_installed: bool,
+}
+
+impl Default for BabeldSource {
+    fn default() -> BabeldSource {
+        BabeldSource {
+            connection: None,
+            table: None,
+            connected_at: None,
+            synced_at: None,
+            preamble: None,
+            filters_installed: false,
+        }
+    }
+}
+
+/// Connects to babeld, installs the filters if asked to and then forwards everything babel
+/// sends until either side goes away
+fn run_monitor(
+    install: bool,
+    events: Sender<MonitorEvent>,
+    cancel: oneshot::Receiver<()>,
+) -> impl Future<Item = (), Error = ()> {
+    let failed = events.clone();
+    let ends_session = |error: Error| (error, true);
+    let during_setup = |error: Error| (error, false);
+
+    AsyncBabel::connect(SETTING.get_network().babel_port)
+        .map_err(ends_session)
+        .and_then(move |babel| {
+            let babel: Box<dyn Future<Item = AsyncBabel<TcpStream>, Error = Error>> = if install {
+                install_filters(babel)
+            } else {
+                Box::new(future::ok(babel))
+            };
+            babel
+                .and_then(|babel| babel.start_monitor())
+                .map_err(during_setup)
+        })
+        .and_then(move |(babel, table)| {
+            // if nobody is listening we have been dropped and the cancel ends us
+            let _ = events.send(MonitorEvent::Connected {
+                table,
+                preamble: babel.get_preamble().cloned(),
+                capabilities: babel.get_capabilities(),
+                filters_installed: install,
+            });
+            babel
+                .into_events()
+                .for_each(move |line| {
+                    events
+                        .send(MonitorEvent::Line(line))
+                        .map_err(|_| format_err!("Nobody is reading the babel monitor"))
+                })
+                .and_then(|_| Err::<(), Error>(ConnectionClosed.into()))
+                .map_err(ends_session)
+        })
+        .then(move |res| {
+            if let Err((error, session_over)) = res {
+                let _ = failed.send(MonitorEvent::Failed {
+                    error,
+                    session_over,
+                });
+            }
+            Ok::<(), ()>(())
+        })
+        .select2(cancel)
+        .then(|_| Ok(()))
+}
+
+impl BabeldSource {
+    fn connect(&mut self) {
+        let (events, receiver) = channel();
+        let (cancel, cancelled) = oneshot::channel();
+        Arbiter::spawn(run_monitor(!self.filters_installed, events, cancelled));
+        self.connection = Some(Connection {
+            events: receiver,
+            _cancel: cancel,
+        });
+    }
+
+    fn connected(
+        &mut self,
+        mut table: BabelTable,
+        preamble: Option<Preamble>,
+        capabilities: Capabilities,
+        filters_installed: bool,
+    ) {
+        if let Some(ref preamble) = preamble {
+            if self.preamble.as_ref() != Some(preamble) {
+                info!("Connected to babeld {}", preamble.describe());
+            }
+        }
+        let unpriced = !capabilities.fees;
+        // without fees we can't price routes, so we route for free rather than not at all
+        if unpriced && self.preamble != preamble {
+            warn!(
+                "babeld {} does not support fees, routes are unpriced and nobody is billed",
+                preamble
+                    .as_ref()
+                    .map(|preamble| preamble.describe())
+                    .unwrap_or_default()
+            );
+        }
+        self.preamble = preamble;
+        self.filters_installed |= filters_installed;
+        table.unpriced = unpriced;
+
+        info!(
+            "Babel monitor connected with {} neighbors and {} routes",
+            table.neighbours.len(),
+            table.routes.len()
+        );
+        self.table = Some(table);
+        self.connected_at = Some(Instant::now());
+    }
+
+    /// Applies what the connection task has sent since the last poll
+    fn read_events(&mut self) -> Result<(), Error> {
+        for _ in 0..MAX_EVENTS_PER_POLL {
+            let event = match self.connection.as_ref() {
+                Some(connection) => connection.events.try_recv(),
+                None => bail!("Babel monitor is not connected"),
+            };
+            match event {
+                Ok(MonitorEvent::Connected {
+                    table,
+                    preamble,
+                    capabilities,
+                    filters_installed,
+                }) => self.connected(table, preamble, capabilities, filters_installed),
+                Ok(MonitorEvent::Line(line)) => {
+                    trace!("Babel event {}", line);
+                    if let Some(table) = self.table.as_mut() {
+                        table.apply_line(&line);
+                    }
+                }
+                Ok(MonitorEvent::Failed {
+                    error,
+                    session_over,
+                }) => {
+                    // babeld isn't running or closed the connection, most likely because it
+                    // exited, the next one we reach starts without our filters
+                    if session_over {
+                        self.filters_installed = false;
+                    }
+                    return Err(error);
+                }
+                Err(TryRecvError::Empty) => {
+                    if self.connected_at.is_some() {
+                        self.synced_at = Some(Instant::now());
+                    }
+                    return Ok(());
+                }
+                Err(TryRecvError::Disconnected) => bail!("Babel monitor task is gone"),
+            }
+        }
+        warn!(
+            "Babel sent more than {} events in one poll",
+            MAX_EVENTS_PER_POLL
+        );
+        Ok(())
+    }
+
+    /// Drops the connection, the table stays until a new connection replaces it
+    fn disconnect(&mut self) {
+        self.connection = None;
+        self.connected_at = None;
+    }
+}
+
+impl RouteSource for BabeldSource {
+    /// Applies any pending events to the table, connecting first if we have no connection.
+    /// Any error drops the connection so that the next poll starts over with a fresh dump
+    /// rather than applying events to a table that may have missed some
+    fn poll(&mut self) -> Result<(), Error> {
+        if self
+            .connected_at
+            .map_or(false, |connected| connected.elapsed() > RESYNC_INTERVAL)
+        {
+            self.disconnect();
+        }
+        if self.connection.is_none() {
+            self.connect();
+        }
+
+        let res = self.read_events();
+        if res.is_err() {
+            self.disconnect();
+        }
+        res
+    }
+
+    fn table(&self) -> Option<(&BabelTable, Duration)> {
+        match (self.table.as_ref(), self.synced_at) {
+            (Some(table), Some(synced_at)) => Some((table, synced_at.elapsed())),
+            _ => None,
+        }
+    }
+
+    fn preamble(&self) -> Option<&Preamble> {
+        self.preamble.as_ref()
+    }
+}
+
+/// A fixed table that never changes or ages
+pub struct StaticSource {
+    table: BabelTable,
+}
+
+impl StaticSource {
+    pub fn new(table: BabelTable) -> StaticSource {
+        StaticSource { table }
+    }
+
+    /// Loads a table from a file holding the output of babel's `dump` command
+    pub fn from_file(path: &str) -> Result<StaticSource, Error> {
+        let mut dump = String::new();
+        File::open(path)?.read_to_string(&mut dump)?;
+        Ok(StaticSource::new(BabelTable::from_dump(&dump)))
+    }
+}
+
+impl RouteSource for StaticSource {
+    fn poll(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn table(&self) -> Option<(&BabelTable, Duration)> {
+        Some((&self.table, Duration::from_secs(0)))
+    }
+}
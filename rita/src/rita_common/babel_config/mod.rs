@@ -0,0 +1,135 @@
+//! Translates the operator's babel tuning in `network.babel_config` into babeld commands.
+//! Interface parameters are sent whenever a tunnel is monitored and can be sent again to
+//! update a running interface. Filters are installed once per babeld session, when Rita first
+//! reaches babeld and again only after the old babeld has gone away.
+
+use crate::KI;
+use crate::SETTING;
+use babel_monitor::{AsyncBabel, Filter, FilterAction, FilterType, InterfaceConfig, LinkType};
+use failure::Error;
+use futures::{stream, Future, Stream};
+use settings::network::{
+    BabelFilter, BabelFilterAction, BabelFilterType, BabelInterfaceConfig, BabelLinkType,
+};
+use settings::RitaCommonSettings;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+fn interface_config(config: &BabelInterfaceConfig) -> InterfaceConfig {
+    InterfaceConfig {
+        link_type: config.link_type.map(|link_type| match link_type {
+            BabelLinkType::Auto => LinkType::Auto,
+            BabelLinkType::Wired => LinkType::Wired,
+            BabelLinkType::Wireless => LinkType::Wireless,
+            BabelLinkType::Tunnel => LinkType::Tunnel,
+        }),
+        rxcost: config.rxcost,
+        hello_interval: config.hello_interval,
+        update_interval: config.update_interval,
+        split_horizon: config.split_horizon,
+        enable_timestamps: config.enable_timestamps,
+        rtt_min: config.rtt_min,
+        rtt_max: config.rtt_max,
+        max_rtt_penalty: config.max_rtt_penalty,
+    }
+}
+
+/// Interface names end up in babel commands, so anything but a plain Linux interface name
+/// (at most 15 characters, no whitespace or separators) would let an operator inject them
+pub fn is_valid_iface_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 15
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// Validates a filter from the settings and turns it into one babel_monitor can send
+pub fn filter(filter: &BabelFilter) -> Result<Filter, Error> {
+    let prefix = match filter.prefix {
+        Some(ref prefix) => match prefix.parse() {
+            Ok(prefix) => Some(prefix),
+            Err(e) => bail!("Invalid filter prefix {} {:?}", prefix, e),
+        },
+        None => None,
+    };
+    if filter.neigh.is_some() && filter.filter_type != BabelFilterType::In {
+        bail!("Only in filters can select on the neighbor");
+    }
+    if let Some(ref iface) = filter.iface {
+        if !is_valid_iface_name(iface) {
+            bail!("Invalid filter interface {:?}", iface);
+        }
+    }
+
+    Ok(Filter {
+        filter_type: match filter.filter_type {
+            BabelFilterType::In => FilterType::In,
+            BabelFilterType::Out => FilterType::Out,
+            BabelFilterType::Redistribute => FilterType::Redistribute,
+            BabelFilterType::Install => FilterType::Install,
+        },
+        iface: filter.iface.clone(),
+        prefix,
+        neigh: filter.neigh,
+        action: match filter.action {
+            BabelFilterAction::Allow => FilterAction::Allow,
+            BabelFilterAction::Deny => FilterAction::Deny,
+            BabelFilterAction::Metric(metric) => FilterAction::Metric(metric),
+        },
+    })
+}
+
+/// The parameters for a tunnel, the tunnel defaults overridden by any entry for the physical
+/// interface the tunnel runs over and then by any entry for the tunnel itself
+pub fn tunnel_config(iface_name: &str, listen_ifidx: u32) -> InterfaceConfig {
+    let babel_config = SETTING.get_network().babel_config.clone();
+    let mut config = interface_config(&babel_config.tunnel_defaults);
+    for (name, physical) in babel_config.interfaces.iter() {
+        if name != iface_name && KI.get_iface_index(name).ok() == Some(listen_ifidx) {
+            config = config.merge(&interface_config(physical));
+        }
+    }
+    if let Some(tunnel) = babel_config.interfaces.get(iface_name) {
+        config = config.merge(&interface_config(tunnel));
+    }
+    config
+}
+
+/// Sends every configured filter to babel, filters that fail validation are skipped
+pub fn install_filters<T: AsyncRead + AsyncWrite + 'static>(
+    babel: AsyncBabel<T>,
+) -> Box<dyn Future<Item = AsyncBabel<T>, Error = Error>> {
+    let filters: Vec<Filter> = SETTING
+        .get_network()
+        .babel_config
+        .filters
+        .iter()
+        .filter_map(|babel_filter| match filter(babel_filter) {
+            Ok(filter) => Some(filter),
+            Err(e) => {
+                error!("Skipping babel filter {:?} {:?}", babel_filter, e);
+                None
+            }
+        })
+        .collect();
+    Box::new(
+        stream::iter_ok::<_, Error>(filters).fold(babel, |babel, filter| babel.add_filter(&filter)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_iface_name() {
+        assert!(is_valid_iface_name("wg3"));
+        assert!(is_valid_iface_name("eth0.100"));
+        assert!(is_valid_iface_name("br-lan"));
+        assert!(!is_valid_iface_name(""));
+        assert!(!is_valid_iface_name("wlan0 rxcost 1"));
+        assert!(!is_valid_iface_name("wlan0\nflush"));
+        assert!(!is_valid_iface_name("../wg0"));
+        assert!(!is_valid_iface_name("averyverylongname"));
+    }
+}
@@ -0,0 +1,144 @@
+use crate::rita_common::babel_config::{filter, is_valid_iface_name};
+use crate::rita_common::tunnel_manager::{ConfigureBabelInterfaces, TunnelManager};
+use crate::ARGS;
+use crate::SETTING;
+use ::actix::SystemService;
+use ::actix_web::http::StatusCode;
+use ::actix_web::Path;
+use ::actix_web::{HttpRequest, HttpResponse, Json, Result};
+use ::settings::network::{BabelConfig, BabelFilter, BabelInterfaceConfig};
+use ::settings::FileWrite;
+use ::settings::RitaCommonSettings;
+use babel_monitor::AsyncBabel;
+use failure::Error;
+use futures::future;
+use futures::Future;
+
+pub fn get_babel_config(_req: HttpRequest) -> Result<Json<BabelConfig>, Error> {
+    trace!("get babel config: Hit");
+    Ok(Json(SETTING.get_network().babel_config.clone()))
+}
+
+pub fn set_babel_tunnel_defaults(config: Json<BabelInterfaceConfig>) -> Result<Json<()>, Error> {
+    let config = config.into_inner();
+    trace!("Set babel tunnel defaults: Hit {:?}", config);
+    SETTING.get_network_mut().babel_config.tunnel_defaults = config;
+
+    // try and save the config and fail if we can't
+    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+        return Err(e);
+    }
+
+    TunnelManager::from_registry().do_send(ConfigureBabelInterfaces);
+    Ok(Json(()))
+}
+
+pub fn set_babel_interface(
+    (path, config): (Path<String>, Json<BabelInterfaceConfig>),
+) -> Result<HttpResponse, Error> {
+    let name = path.into_inner();
+    let config = config.into_inner();
+    trace!("Set babel interface {}: Hit {:?}", name, config);
+    if !is_valid_iface_name(&name) {
+        return Ok(HttpResponse::new(StatusCode::BAD_REQUEST)
+            .into_builder()
+            .json(format!("{:?} is not a valid interface name", name)));
+    }
+    SETTING
+        .get_network_mut()
+        .babel_config
+        .interfaces
+        .insert(name, config);
+
+    // try and save the config and fail if we can't
+    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+        return Err(e);
+    }
+
+    TunnelManager::from_registry().do_send(ConfigureBabelInterfaces);
+    Ok(HttpResponse::Ok().json(()))
+}
+
+/// Tunnels fall back to the tunnel defaults, but babel only forgets the removed values when
+/// the tunnel is next recreated
+pub fn remove_babel_interface(path: Path<String>) -> Result<Json<()>, Error> {
+    let name = path.into_inner();
+    trace!("Remove babel interface {}: Hit", name);
+    SETTING
+        .get_network_mut()
+        .babel_config
+        .interfaces
+        .remove(&name);
+
+    // try and save the config and fail if we can't
+    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+        return Err(e);
+    }
+
+    TunnelManager::from_registry().do_send(ConfigureBabelInterfaces);
+    Ok(Json(()))
+}
+
+/// The filter is only saved once babel has accepted it
+pub fn add_babel_filter(
+    new_filter: Json<BabelFilter>,
+) -> Box<dyn Future<Item = Json<()>, Error = Error>> {
+    let new_filter = new_filter.into_inner();
+    trace!("Add babel filter: Hit {:?}", new_filter);
+    if SETTING
+        .get_network()
+        .babel_config
+        .filters
+        .contains(&new_filter)
+    {
+        return Box::new(future::ok(Json(())));
+    }
+    let babel_filter = match filter(&new_filter) {
+        Ok(babel_filter) => babel_filter,
+        Err(e) => return Box::new(future::err(e)),
+    };
+
+    Box::new(
+        AsyncBabel::connect(SETTING.get_network().babel_port)
+            .and_then(move |babel| babel.add_filter(&babel_filter))
+            .and_then(move |_babel| {
+                SETTING
+                    .get_network_mut()
+                    .babel_config
+                    .filters
+                    .push(new_filter);
+
+                // try and save the config and fail if we can't
+                if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+                    return Err(e);
+                }
+                Ok(Json(()))
+            }),
+    )
+}
+
+#[derive(Serialize)]
+pub struct FilterRemoved {
+    /// The filter is still active in the running babeld
+    restart_required: bool,
+}
+
+/// babeld can't drop a filter while running, a removed filter stays in effect until babeld
+/// is restarted so rather than claiming success we answer with 202 and say so
+pub fn remove_babel_filter(old_filter: Json<BabelFilter>) -> Result<HttpResponse, Error> {
+    let old_filter = old_filter.into_inner();
+    trace!("Remove babel filter: Hit {:?}", old_filter);
+    SETTING
+        .get_network_mut()
+        .babel_config
+        .filters
+        .retain(|val| *val != old_filter);
+
+    // try and save the config and fail if we can't
+    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+        return Err(e);
+    }
+    Ok(HttpResponse::Accepted().json(FilterRemoved {
+        restart_required: true,
+    }))
+}
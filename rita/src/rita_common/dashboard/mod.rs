@@ -6,13 +6,18 @@ use ::actix::prelude::*;
 use ::actix::registry::SystemService;
 
 pub mod babel;
+pub mod babel_config;
 pub mod dao;
 pub mod debts;
 pub mod development;
+pub mod neighbor_shaping;
 pub mod nickname;
 pub mod own_info;
+pub mod peer_acl;
 pub mod pricing;
+pub mod route_history;
 pub mod settings;
+pub mod tunnel_ports;
 pub mod usage;
 pub mod wallet;
 
@@ -0,0 +1,61 @@
+use crate::rita_common::tunnel_manager::{ApplyNeighborShaping, TunnelManager};
+use crate::ARGS;
+use crate::SETTING;
+use ::actix::SystemService;
+use ::actix_web::http::StatusCode;
+use ::actix_web::{HttpRequest, HttpResponse, Json, Result};
+use ::settings::network::{NeighborShaping, MAX_NEIGHBOR_BANDWIDTH};
+use ::settings::FileWrite;
+use ::settings::RitaCommonSettings;
+use althea_types::WgKey;
+use failure::Error;
+
+pub fn get_neighbor_shaping(_req: HttpRequest) -> Result<Json<Vec<NeighborShaping>>, Error> {
+    trace!("get neighbor shaping: Hit");
+    Ok(Json(SETTING.get_network().neighbor_shaping.clone()))
+}
+
+pub fn set_neighbor_shaping(shaping: Json<NeighborShaping>) -> Result<HttpResponse, Error> {
+    let shaping = shaping.into_inner();
+    trace!("Set neighbor shaping: Hit {:?}", shaping);
+    if !shaping.bandwidth_is_valid() {
+        return Ok(HttpResponse::new(StatusCode::BAD_REQUEST)
+            .into_builder()
+            .json(format!(
+                "max_bandwidth must be between 1 and {} kbit/s",
+                MAX_NEIGHBOR_BANDWIDTH
+            )));
+    }
+    {
+        let mut network = SETTING.get_network_mut();
+        network
+            .neighbor_shaping
+            .retain(|val| val.wg_public_key != shaping.wg_public_key);
+        network.neighbor_shaping.push(shaping);
+    }
+
+    // try and save the config and fail if we can't
+    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+        return Err(e);
+    }
+
+    TunnelManager::from_registry().do_send(ApplyNeighborShaping);
+    Ok(HttpResponse::Ok().json(()))
+}
+
+pub fn remove_neighbor_shaping(key: Json<WgKey>) -> Result<Json<()>, Error> {
+    let key = key.into_inner();
+    trace!("Remove neighbor shaping: Hit {:?}", key);
+    SETTING
+        .get_network_mut()
+        .neighbor_shaping
+        .retain(|val| val.wg_public_key != key);
+
+    // try and save the config and fail if we can't
+    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+        return Err(e);
+    }
+
+    TunnelManager::from_registry().do_send(ApplyNeighborShaping);
+    Ok(Json(()))
+}
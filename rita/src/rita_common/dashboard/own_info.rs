@@ -1,9 +1,14 @@
+use crate::rita_common::babel_client::{BabelClient, GetBabelPreamble};
 use crate::rita_common::oracle::low_balance;
 use crate::SETTING;
+use ::actix::SystemService;
+use ::actix_web::AsyncResponder;
 use ::settings::RitaCommonSettings;
 use actix_web::{HttpRequest, Json};
+use babel_monitor::Preamble;
 use clarity::Address;
 use failure::Error;
+use futures::Future;
 use num256::{Int256, Uint256};
 
 pub static READABLE_VERSION: &str = "Beta 5 RC2";
@@ -20,10 +25,22 @@ pub struct OwnInfo {
     pub device: Option<String>,
     pub rita_version: String,
     pub version: String,
+    /// babeld's version string, None if we have never reached babel
+    pub babeld_version: Option<String>,
+    /// The configuration protocol babeld speaks, `ALTHEA 0.1` for example
+    pub babel_protocol: Option<String>,
 }
 
-pub fn get_own_info(_req: HttpRequest) -> Result<Json<OwnInfo>, Error> {
+pub fn get_own_info(_req: HttpRequest) -> Box<dyn Future<Item = Json<OwnInfo>, Error = Error>> {
     debug!("Get own info endpoint hit!");
+    BabelClient::from_registry()
+        .send(GetBabelPreamble)
+        .from_err()
+        .and_then(|preamble| Ok(Json(make_own_info(preamble.ok()))))
+        .responder()
+}
+
+fn make_own_info(preamble: Option<Preamble>) -> OwnInfo {
     let payment_settings = SETTING.get_payment();
     let eth_address = payment_settings.eth_address.unwrap();
     let balance = payment_settings.balance.clone();
@@ -35,7 +52,7 @@ pub fn get_own_info(_req: HttpRequest) -> Result<Json<OwnInfo>, Error> {
     let metric_factor = SETTING.get_network().metric_factor;
     let device = network_settings.device.clone();
 
-    let reply = OwnInfo {
+    OwnInfo {
         address: eth_address,
         balance,
         local_fee,
@@ -46,6 +63,10 @@ pub fn get_own_info(_req: HttpRequest) -> Result<Json<OwnInfo>, Error> {
         device,
         rita_version: env!("CARGO_PKG_VERSION").to_string(),
         version: READABLE_VERSION.to_string(),
-    };
-    Ok(Json(reply))
+        babeld_version: preamble
+            .as_ref()
+            .and_then(|preamble| preamble.babeld_version.clone()),
+        babel_protocol: preamble
+            .map(|preamble| format!("{} {}", preamble.protocol, preamble.protocol_version)),
+    }
 }
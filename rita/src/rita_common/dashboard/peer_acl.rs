@@ -0,0 +1,67 @@
+use crate::rita_common::tunnel_manager::{EnforcePeerAcl, TunnelManager};
+use crate::ARGS;
+use crate::SETTING;
+use ::actix::SystemService;
+use ::actix_web::http::StatusCode;
+use ::actix_web::Path;
+use ::actix_web::{HttpRequest, HttpResponse, Json, Result};
+use ::settings::network::{PeerAcl, PeerAclEntry, PeerAclMode};
+use ::settings::FileWrite;
+use ::settings::RitaCommonSettings;
+use failure::Error;
+
+pub fn get_peer_acl(_req: HttpRequest) -> Result<Json<PeerAcl>, Error> {
+    trace!("get peer acl: Hit");
+    Ok(Json(SETTING.get_network().peer_acl.clone()))
+}
+
+pub fn add_to_peer_acl(entry: Json<PeerAclEntry>) -> Result<Json<()>, Error> {
+    let entry = entry.into_inner();
+    trace!("Add to peer acl: Hit {:?}", entry);
+    SETTING.get_network_mut().peer_acl.entries.insert(entry);
+
+    // try and save the config and fail if we can't
+    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+        return Err(e);
+    }
+
+    TunnelManager::from_registry().do_send(EnforcePeerAcl);
+    Ok(Json(()))
+}
+
+pub fn remove_from_peer_acl(entry: Json<PeerAclEntry>) -> Result<Json<()>, Error> {
+    let entry = entry.into_inner();
+    trace!("Remove from peer acl: Hit {:?}", entry);
+    SETTING.get_network_mut().peer_acl.entries.remove(&entry);
+
+    // try and save the config and fail if we can't
+    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+        return Err(e);
+    }
+
+    // in allowlist mode removing an entry blocks it
+    TunnelManager::from_registry().do_send(EnforcePeerAcl);
+    Ok(Json(()))
+}
+
+pub fn set_peer_acl_mode(path: Path<String>) -> Result<HttpResponse, Error> {
+    let mode: Result<PeerAclMode, ()> = path.into_inner().parse();
+    debug!("/peer_acl/mode POST hit with {:?}", mode);
+    let mode = match mode {
+        Ok(mode) => mode,
+        Err(_) => {
+            return Ok(HttpResponse::new(StatusCode::BAD_REQUEST)
+                .into_builder()
+                .json("Mode must be either Blocklist or Allowlist"));
+        }
+    };
+    SETTING.get_network_mut().peer_acl.mode = mode;
+
+    // try and save the config and fail if we can't
+    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+        return Err(e);
+    }
+
+    TunnelManager::from_registry().do_send(EnforcePeerAcl);
+    Ok(HttpResponse::Ok().json(()))
+}
@@ -0,0 +1,32 @@
+use crate::rita_common::route_history::{
+    DestinationHistory, GetDestinationHistory, GetRouteSummaries, RouteHistory, RouteSummary,
+};
+use ::actix::registry::SystemService;
+use ::actix_web::{AsyncResponder, HttpRequest, Json, Path};
+use failure::Error;
+use futures::Future;
+use std::boxed::Box;
+use std::net::IpAddr;
+
+pub fn get_route_history(
+    _req: HttpRequest,
+) -> Box<dyn Future<Item = Json<Vec<RouteSummary>>, Error = Error>> {
+    trace!("/route_history hit");
+    RouteHistory::from_registry()
+        .send(GetRouteSummaries)
+        .from_err()
+        .and_then(|reply| Ok(Json(reply?)))
+        .responder()
+}
+
+pub fn get_destination_route_history(
+    path: Path<IpAddr>,
+) -> Box<dyn Future<Item = Json<DestinationHistory>, Error = Error>> {
+    let destination = path.into_inner();
+    trace!("/route_history/{} hit", destination);
+    RouteHistory::from_registry()
+        .send(GetDestinationHistory(destination))
+        .from_err()
+        .and_then(|reply| Ok(Json(reply?)))
+        .responder()
+}
@@ -0,0 +1,17 @@
+use crate::rita_common::tunnel_manager::{GetPortStats, PortStats, TunnelManager};
+use ::actix::registry::SystemService;
+use ::actix_web::{AsyncResponder, HttpRequest, Json};
+use failure::Error;
+use futures::Future;
+use std::boxed::Box;
+
+pub fn get_tunnel_ports(
+    _req: HttpRequest,
+) -> Box<dyn Future<Item = Json<PortStats>, Error = Error>> {
+    trace!("get_tunnel_ports: Hit");
+    TunnelManager::from_registry()
+        .send(GetPortStats)
+        .from_err()
+        .and_then(move |reply| Ok(Json(reply?)))
+        .responder()
+}
@@ -1,4 +1,6 @@
+use crate::rita_common::usage_tracker::DestinationHour;
 use crate::rita_common::usage_tracker::GetPayments;
+use crate::rita_common::usage_tracker::GetRelayDestinations;
 use crate::rita_common::usage_tracker::PaymentHour;
 use crate::rita_common::usage_tracker::UsageTracker;
 use ::actix::registry::SystemService;
@@ -18,3 +20,14 @@ pub fn get_payments(
         .and_then(|reply| Ok(Json(reply?)))
         .responder()
 }
+
+pub fn get_relay_destinations(
+    _req: HttpRequest,
+) -> Box<dyn Future<Item = Json<VecDeque<DestinationHour>>, Error = Error>> {
+    trace!("/usage/relay_destinations hit");
+    UsageTracker::from_registry()
+        .send(GetRelayDestinations {})
+        .from_err()
+        .and_then(|reply| Ok(Json(reply?)))
+        .responder()
+}
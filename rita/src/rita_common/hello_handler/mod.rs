@@ -21,6 +21,7 @@ use crate::rita_common::tunnel_manager::{IdentityCallback, PortCallback, TunnelM
 
 use actix_web::client::Connection;
 use failure::Error;
+use std::net::SocketAddr;
 
 #[derive(Default)]
 pub struct HelloHandler;
@@ -56,11 +57,7 @@ impl Handler<Hello> for HelloHandler {
 
         let stream = TokioTcpStream::connect(&msg.to.contact_socket);
 
-        let endpoint = format!(
-            "http://[{}]:{}/hello",
-            msg.to.contact_socket.ip(),
-            msg.to.contact_socket.port()
-        );
+        let endpoint = hello_url(&msg.to.contact_socket);
 
         Box::new(stream.then(move |stream| {
             trace!("stream status {:?}, to: {:?}", stream, &msg.to);
@@ -123,3 +120,24 @@ impl Handler<Hello> for HelloHandler {
         }))
     }
 }
+
+/// Builds the hello url for a peer, manual peers may be contacted over ipv4
+fn hello_url(socket: &SocketAddr) -> String {
+    match socket {
+        SocketAddr::V6(socket) => format!("http://[{}]:{}/hello", socket.ip(), socket.port()),
+        SocketAddr::V4(socket) => format!("http://{}:{}/hello", socket.ip(), socket.port()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hello_url() {
+        let v6: SocketAddr = "[fe80::1]:4876".parse().unwrap();
+        assert_eq!(hello_url(&v6), "http://[fe80::1]:4876/hello");
+        let v4: SocketAddr = "192.0.2.1:4876".parse().unwrap();
+        assert_eq!(hello_url(&v4), "http://192.0.2.1:4876/hello");
+    }
+}
@@ -1,3 +1,5 @@
+pub mod babel_client;
+pub mod babel_config;
 pub mod dao_manager;
 pub mod dashboard;
 pub mod debt_keeper;
@@ -8,6 +10,9 @@ pub mod payment_controller;
 pub mod payment_validator;
 pub mod peer_listener;
 pub mod rita_loop;
+pub mod route_history;
+#[cfg(test)]
+pub mod simulation;
 pub mod traffic_watcher;
 pub mod tunnel_manager;
 pub mod usage_tracker;
@@ -10,13 +10,14 @@ use futures::{future, Future};
 
 use failure::Error;
 
+use crate::KI;
 use crate::SETTING;
 use settings::RitaCommonSettings;
 
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
 use crate::rita_common::payment_validator::{PaymentValidator, ToValidate, ValidateLater};
-use crate::rita_common::peer_listener::Peer;
+use crate::rita_common::peer_listener::{is_link_local, Peer};
 use crate::rita_common::tunnel_manager::{IdentityCallback, TunnelManager};
 
 use std::time::Instant;
@@ -85,13 +86,29 @@ pub fn hello_response(
         .unwrap()
         .parse::<SocketAddr>()
         .unwrap();
+    let socket = unmap_socket(socket);
 
     info!("Got Hello from {:?}", req.1.connection_info().remote());
+
+    {
+        let network = SETTING.get_network();
+        let acl = &network.peer_acl;
+        if !acl.is_allowed(&their_id.global) || acl.is_ip_blocked(&socket.ip()) {
+            info!("Refusing Hello from {:?}, blocked by peer acl", their_id);
+            return Box::new(future::err(format_err!("Peer is not allowed")));
+        }
+    }
+
     info!("opening tunnel in hello_response for {:?}", their_id);
 
-    let peer = Peer {
-        contact_socket: socket,
-        ifidx: 0, // only works because we lookup ifname in kernel interface
+    let peer = if is_link_local(socket.ip()) {
+        Peer {
+            contact_socket: socket,
+            ifidx: link_local_ifidx(socket.ip()),
+            manual: false,
+        }
+    } else {
+        Peer::manual(socket)
     };
 
     // We send the callback, which can safely allocate a port because it already successfully
@@ -102,7 +119,10 @@ pub fn hello_response(
             .send(IdentityCallback::new(their_id, peer, None))
             .from_err()
             .and_then(|tunnel| {
-                let tunnel = tunnel.unwrap();
+                let tunnel = match tunnel {
+                    Some(tunnel) => tunnel,
+                    None => return Err(format_err!("Failed to open tunnel")),
+                };
                 Ok(Json(LocalIdentity {
                     global: match SETTING.get_identity() {
                         Some(id) => id,
@@ -116,6 +136,40 @@ pub fn hello_response(
     )
 }
 
+/// The index of the interface a link local neighbor is on, found through the neighbor table
+/// because the hello doesn't tell us which interface it came in on. Zero if it can't be found,
+/// the tunnel then works but can't be repaired
+fn link_local_ifidx(ip: IpAddr) -> u32 {
+    match KI
+        .get_device_name(ip)
+        .and_then(|name| KI.get_iface_index(&name))
+    {
+        Ok(ifidx) => ifidx,
+        Err(e) => {
+            warn!("Can't find the interface of {} {:?}", ip, e);
+            0
+        }
+    }
+}
+
+/// We listen on a dual stack socket, so hellos from manual peers contacting us over ipv4
+/// show up as ipv4 mapped ipv6 addresses, turn those back into plain ipv4
+fn unmap_socket(socket: SocketAddr) -> SocketAddr {
+    if let SocketAddr::V6(v6) = socket {
+        let segments = v6.ip().segments();
+        if segments[..5] == [0, 0, 0, 0, 0] && segments[5] == 0xffff {
+            let ip = Ipv4Addr::new(
+                (segments[6] >> 8) as u8,
+                segments[6] as u8,
+                (segments[7] >> 8) as u8,
+                segments[7] as u8,
+            );
+            return SocketAddr::new(IpAddr::V4(ip), v6.port());
+        }
+    }
+    socket
+}
+
 pub fn version(_req: HttpRequest) -> String {
     format!(
         "crate ver {}\ngit hash {}",
@@ -123,3 +177,14 @@ pub fn version(_req: HttpRequest) -> String {
         env!("GIT_HASH")
     )
 }
+
+#[test]
+fn test_unmap_socket() {
+    let mapped: SocketAddr = "[::ffff:192.0.2.1]:4876".parse().unwrap();
+    assert_eq!(
+        unmap_socket(mapped),
+        "192.0.2.1:4876".parse::<SocketAddr>().unwrap()
+    );
+    let v6: SocketAddr = "[fe80::1]:4876".parse().unwrap();
+    assert_eq!(unmap_socket(v6), v6);
+}
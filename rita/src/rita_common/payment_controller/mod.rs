@@ -5,6 +5,8 @@
 
 use crate::rita_common::debt_keeper::DebtKeeper;
 use crate::rita_common::debt_keeper::PaymentFailed;
+#[cfg(test)]
+use crate::rita_common::debt_keeper::PaymentSucceeded;
 use crate::rita_common::oracle::update_nonce;
 use crate::rita_common::payment_validator::{PaymentValidator, ToValidate, ValidateLater};
 use crate::rita_common::rita_loop::get_web3_server;
@@ -17,8 +19,11 @@ use clarity::Transaction;
 use failure::Error;
 use futures::future::Either;
 use futures::{future, Future};
+use num256::Uint256;
 use settings::RitaCommonSettings;
 use std::net::SocketAddr;
+#[cfg(test)]
+use std::sync::Mutex;
 use std::time::Duration;
 use std::time::Instant;
 use tokio::net::TcpStream as TokioTcpStream;
@@ -36,6 +41,28 @@ impl SystemService for PaymentController {
     }
 }
 
+/// Stands in for the full node, the neighbor and the payment validator in tests, returns the
+/// txid of a payment the chain accepted
+#[cfg(test)]
+pub type MockNetwork = Box<dyn FnMut(&PaymentTx) -> Result<Uint256, Error> + Send>;
+
+#[cfg(test)]
+lazy_static! {
+    pub static ref MOCK_NETWORK: Mutex<Option<MockNetwork>> = Mutex::new(None);
+}
+
+/// Refuses payments we can't cover or that wouldn't pay anything
+fn check_payment(balance: &Uint256, amount: &Uint256) -> Result<(), Error> {
+    if balance < amount {
+        warn!("Not enough money to pay debts! Cutoff immenient");
+        bail!("Not enough money!")
+    } else if *amount == 0u32.into() {
+        error!("Trying to pay nothing!");
+        bail!("Zero payment!");
+    }
+    Ok(())
+}
+
 #[derive(Message)]
 pub struct MakePayment(pub PaymentTx);
 
@@ -77,13 +104,7 @@ impl PaymentController {
             pmt.to.eth_address,
             nonce
         );
-        if balance < pmt.amount {
-            warn!("Not enough money to pay debts! Cutoff immenient");
-            bail!("Not enough money!")
-        } else if pmt.amount == 0u32.into() {
-            error!("Trying to pay nothing!");
-            bail!("Zero payment!");
-        }
+        check_payment(&balance, &pmt.amount)?;
 
         let contact_socket: SocketAddr = match format!(
             "[{}]:{}",
@@ -97,22 +118,6 @@ impl PaymentController {
                 bail!("Failed to make socket for payment message! {:?}", e);
             }
         };
-        let stream = TokioTcpStream::connect(&contact_socket);
-
-        // testing hack
-        let neighbor_url = if cfg!(not(test)) {
-            format!(
-                "http://[{}]:{}/make_payment",
-                contact_socket.ip(),
-                contact_socket.port(),
-            )
-        } else {
-            String::from("http://127.0.0.1:1234/make_payment")
-        };
-
-        let full_node = get_web3_server();
-        let web3 = Web3::new(&full_node);
-
         let tx = Transaction {
             nonce,
             gas_price,
@@ -133,6 +138,33 @@ impl PaymentController {
             Ok(bytes) => bytes,
             Err(e) => bail!("Failed to generate transaction, {:?}", e),
         };
+        // the nonce is updated through the settings once the payment is out
+        drop(payment_settings);
+
+        #[cfg(test)]
+        {
+            let mut network = MOCK_NETWORK.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(network) = network.as_mut() {
+                mock_payment(network, pmt);
+                return Ok(());
+            }
+        }
+
+        let stream = TokioTcpStream::connect(&contact_socket);
+
+        // testing hack
+        let neighbor_url = if cfg!(not(test)) {
+            format!(
+                "http://[{}]:{}/make_payment",
+                contact_socket.ip(),
+                contact_socket.port(),
+            )
+        } else {
+            String::from("http://127.0.0.1:1234/make_payment")
+        };
+
+        let full_node = get_web3_server();
+        let web3 = Web3::new(&full_node);
 
         let transaction_status = web3.eth_send_raw_transaction(transaction_bytes);
 
@@ -215,3 +247,23 @@ impl PaymentController {
         Ok(())
     }
 }
+
+/// The outcome of a payment on the mock network, an accepted payment is reported as validated
+/// straight away rather than once the validator finds it on chain
+#[cfg(test)]
+fn mock_payment(network: &mut MockNetwork, pmt: PaymentTx) {
+    match network(&pmt) {
+        Ok(txid) => {
+            info!("Sending bw payment with txid: {:#066x}", txid);
+            SETTING.get_payment_mut().nonce += 1u64.into();
+            DebtKeeper::from_registry().do_send(PaymentSucceeded {
+                to: pmt.to,
+                amount: pmt.amount,
+            });
+        }
+        Err(e) => {
+            warn!("Failed to send bandwidth payment {:?}", e);
+            DebtKeeper::from_registry().do_send(PaymentFailed { to: pmt.to });
+        }
+    }
+}
@@ -29,6 +29,10 @@ pub struct PeerListener {
 pub struct Peer {
     pub ifidx: u32,
     pub contact_socket: SocketAddr,
+    /// Set for peers that aren't on a local link, from the manual peers list or a hello from
+    /// one, these may be behind NAT so the address we know is not always where their packets
+    /// come from
+    pub manual: bool,
 }
 
 impl Peer {
@@ -38,8 +42,25 @@ impl Peer {
         Peer {
             ifidx: idx,
             contact_socket: socket.into(),
+            manual: false,
         }
     }
+
+    pub fn manual(contact_socket: SocketAddr) -> Peer {
+        Peer {
+            ifidx: 0,
+            contact_socket,
+            manual: true,
+        }
+    }
+}
+
+/// True for ipv6 link local addresses, which are only meaningful along with an interface
+pub fn is_link_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V6(ip) => (ip.segments()[0] & 0xffc0) == 0xfe80,
+        IpAddr::V4(_) => false,
+    }
 }
 
 impl Actor for PeerListener {
@@ -304,6 +325,11 @@ fn receive_im_here(
                 continue;
             }
 
+            if SETTING.get_network().peer_acl.is_ip_blocked(&ipaddr.into()) {
+                trace!("Discarding ImHere from blocked peer {:?}", ipaddr);
+                continue;
+            }
+
             if output.contains_key(&ipaddr.into()) {
                 trace!(
                     "Discarding ImHere We already have a peer with {:?} for this cycle",
@@ -20,10 +20,16 @@ use crate::actix_utils::ResolverWrapper;
 
 use crate::KI;
 
-use crate::rita_common::tunnel_manager::{GetNeighbors, TriggerGC, TunnelManager};
+use crate::rita_common::tunnel_manager::{
+    CheckTunnelHealth, GetNeighbors, TriggerGC, TunnelManager,
+};
 
 use crate::rita_common::traffic_watcher::{TrafficWatcher, Watch};
 
+use crate::rita_common::babel_client::{BabelClient, GetBabelTable};
+
+use crate::rita_common::route_history::{RouteHistory, SampleRoutes};
+
 use crate::rita_common::peer_listener::PeerListener;
 
 use crate::rita_common::debt_keeper::{DebtKeeper, SendUpdate};
@@ -41,7 +47,8 @@ use crate::rita_common::oracle::{Oracle, Update};
 
 use failure::Error;
 
-use futures::Future;
+use futures::future::Either;
+use futures::{future, Future};
 
 use crate::SETTING;
 use settings::RitaCommonSettings;
@@ -127,16 +134,31 @@ impl Handler<Tick> for RitaLoop {
                         start.elapsed().subsec_millis()
                     );
 
-                    TrafficWatcher::from_registry()
-                        .send(Watch::new(res))
+                    BabelClient::from_registry()
+                        .send(GetBabelTable)
                         .timeout(COMMON_LOOP_TIMEOUT)
-                        .then(move |_res| {
-                            info!(
-                                "TrafficWatcher completed in {}s {}ms",
-                                neigh.elapsed().as_secs(),
-                                neigh.elapsed().subsec_millis()
-                            );
-                            Ok(())
+                        .then(move |table| match table {
+                            Ok(Ok(table)) => Either::A(
+                                TrafficWatcher::from_registry()
+                                    .send(Watch::new(table, res))
+                                    .timeout(COMMON_LOOP_TIMEOUT)
+                                    .then(move |_res| {
+                                        info!(
+                                            "TrafficWatcher completed in {}s {}ms",
+                                            neigh.elapsed().as_secs(),
+                                            neigh.elapsed().subsec_millis()
+                                        );
+                                        Ok(())
+                                    }),
+                            ),
+                            Ok(Err(e)) => {
+                                error!("No babel table, traffic has gone unaccounted! {:?}", e);
+                                Either::B(future::ok(()))
+                            }
+                            Err(e) => {
+                                error!("BabelClient failed, traffic has gone unaccounted! {:?}", e);
+                                Either::B(future::ok(()))
+                            }
                         })
                 }),
         );
@@ -152,6 +174,52 @@ impl Handler<Tick> for RitaLoop {
         // Update blockchain info
         Oracle::from_registry().do_send(Update());
 
+        let start = Instant::now();
+        Arbiter::spawn(
+            BabelClient::from_registry()
+                .send(GetBabelTable)
+                .timeout(COMMON_LOOP_TIMEOUT)
+                .then(|table| {
+                    let babel_neighs = match table {
+                        Ok(Ok(table)) => Some(table.neighs()),
+                        res => {
+                            warn!(
+                                "Tunnel health check could not get babel neighbors {:?}",
+                                res
+                            );
+                            None
+                        }
+                    };
+                    TunnelManager::from_registry()
+                        .send(CheckTunnelHealth { babel_neighs })
+                        .timeout(COMMON_LOOP_TIMEOUT)
+                })
+                .then(move |res| {
+                    info!(
+                        "TunnelManager health check completed in {}s {}ms, with result {:?}",
+                        start.elapsed().as_secs(),
+                        start.elapsed().subsec_millis(),
+                        res
+                    );
+                    res
+                })
+                .then(|_| Ok(())),
+        );
+
+        // keep a history of the routes to our key destinations
+        Arbiter::spawn(
+            BabelClient::from_registry()
+                .send(GetBabelTable)
+                .timeout(COMMON_LOOP_TIMEOUT)
+                .then(|table| {
+                    match table {
+                        Ok(Ok(table)) => RouteHistory::from_registry().do_send(SampleRoutes(table)),
+                        res => warn!("Route history could not get babel table {:?}", res),
+                    }
+                    Ok(())
+                }),
+        );
+
         let start = Instant::now();
         Arbiter::spawn(
             TunnelManager::from_registry()
@@ -0,0 +1,399 @@
+//! Keeps a history of the routes babel picks to our exits and any other destinations the
+//! operator has asked us to track. Babel's tables are only ever a snapshot, this module samples
+//! them so that questions like 'why did my exit route flap last night' can be answered after
+//! the fact.
+//!
+//! Every tick we compare the installed route to each destination with the one we saw last,
+//! changes of neighbor and lost or restored routes are recorded as events and a destination
+//! with too many events in a short window is considered to be flapping. The route itself is
+//! sampled once a minute (and on every change) for charting. Both are ring buffers that are
+//! periodically saved to disk.
+
+use crate::SETTING;
+use ::actix::{Actor, Context, Handler, Message, Supervised, SystemService};
+use babel_monitor::{BabelTable, Route};
+use failure::Error;
+use serde_json::Error as SerdeError;
+use settings::RitaCommonSettings;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::Error as IOError;
+use std::io::Read;
+use std::io::Write;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Seconds between samples of an unchanged route
+const SAMPLE_INTERVAL: u64 = 60;
+/// One day of samples per destination
+const MAX_SAMPLES: usize = 1440;
+const MAX_EVENTS: usize = 1000;
+/// A destination with FLAP_THRESHOLD or more route changes within FLAP_WINDOW seconds is flapping
+const FLAP_WINDOW: u64 = 600;
+const FLAP_THRESHOLD: usize = 4;
+/// Seconds between saves to disk
+const SAVE_INTERVAL: u64 = 3600;
+
+/// The parts of a babel route we keep a history of
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct SampledRoute {
+    pub metric: u16,
+    pub price: u32,
+    pub full_path_rtt: f32,
+    /// The link local address of the neighbor we route through
+    pub neigh_ip: IpAddr,
+    pub iface: String,
+}
+
+impl SampledRoute {
+    fn new(route: &Route) -> SampledRoute {
+        SampledRoute {
+            metric: route.metric,
+            price: route.price,
+            full_path_rtt: route.full_path_rtt,
+            neigh_ip: route.neigh_ip,
+            iface: route.iface.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RouteSample {
+    /// Seconds since the unix epoch
+    pub time: u64,
+    /// None if we had no installed route at the time
+    pub route: Option<SampledRoute>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum RouteChange {
+    Lost,
+    Restored { neigh_ip: IpAddr },
+    NeighborChanged { from: IpAddr, to: IpAddr },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RouteEvent {
+    /// Seconds since the unix epoch
+    pub time: u64,
+    pub change: RouteChange,
+}
+
+/// Samples and events for a single destination, newest first
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DestinationHistory {
+    pub samples: VecDeque<RouteSample>,
+    pub events: VecDeque<RouteEvent>,
+    /// The route as of the last tick, which may be newer than the last sample
+    pub current: Option<SampledRoute>,
+}
+
+impl DestinationHistory {
+    /// Records the route babel currently has installed, returns the change if there was one
+    fn observe(&mut self, now: u64, route: Option<SampledRoute>) -> Option<RouteChange> {
+        let change = match (&self.current, &route) {
+            (Some(old), Some(new)) if old.neigh_ip != new.neigh_ip => {
+                Some(RouteChange::NeighborChanged {
+                    from: old.neigh_ip,
+                    to: new.neigh_ip,
+                })
+            }
+            (Some(_), None) => Some(RouteChange::Lost),
+            // the very first observation is not a change
+            (None, Some(new)) if !self.samples.is_empty() => Some(RouteChange::Restored {
+                neigh_ip: new.neigh_ip,
+            }),
+            _ => None,
+        };
+
+        if let Some(ref change) = change {
+            self.events.push_front(RouteEvent {
+                time: now,
+                change: change.clone(),
+            });
+            while self.events.len() > MAX_EVENTS {
+                self.events.pop_back();
+            }
+        }
+
+        let sample_due = self
+            .samples
+            .front()
+            .map_or(true, |sample| now >= sample.time + SAMPLE_INTERVAL);
+        if sample_due || change.is_some() {
+            self.samples.push_front(RouteSample {
+                time: now,
+                route: route.clone(),
+            });
+            while self.samples.len() > MAX_SAMPLES {
+                self.samples.pop_back();
+            }
+        }
+
+        self.current = route;
+        change
+    }
+
+    /// The number of route changes within the flap window
+    pub fn recent_changes(&self, now: u64) -> usize {
+        self.events
+            .iter()
+            .take_while(|event| event.time + FLAP_WINDOW >= now)
+            .count()
+    }
+
+    pub fn is_flapping(&self, now: u64) -> bool {
+        self.recent_changes(now) >= FLAP_THRESHOLD
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RouteHistory {
+    last_save: u64,
+    destinations: HashMap<IpAddr, DestinationHistory>,
+    /// Mesh ips of our exits, only set on clients
+    #[serde(skip)]
+    exits: Vec<IpAddr>,
+}
+
+impl Default for RouteHistory {
+    fn default() -> RouteHistory {
+        let blank_route_history = RouteHistory {
+            last_save: 0,
+            destinations: HashMap::new(),
+            exits: Vec::new(),
+        };
+
+        let mut contents = String::new();
+        match File::open(SETTING.get_network().route_history_file.clone())
+            .and_then(|mut file| file.read_to_string(&mut contents))
+        {
+            Ok(_) => {
+                let deserialized: Result<RouteHistory, SerdeError> =
+                    serde_json::from_str(&contents);
+                match deserialized {
+                    Ok(value) => value,
+                    Err(e) => {
+                        error!("Failed to deserialize route history {:?}", e);
+                        blank_route_history
+                    }
+                }
+            }
+            Err(e) => {
+                warn!("Failed to read route history file! {:?}", e);
+                blank_route_history
+            }
+        }
+    }
+}
+
+impl RouteHistory {
+    fn save(&mut self) -> Result<(), IOError> {
+        let serialized = serde_json::to_string(self)?;
+        let mut file = File::create(SETTING.get_network().route_history_file.clone())?;
+        file.write_all(serialized.as_bytes())
+    }
+
+    fn tracked_destinations(&self) -> Vec<IpAddr> {
+        let mut destinations = SETTING.get_network().route_history_destinations.clone();
+        for exit in self.exits.iter() {
+            if !destinations.contains(exit) {
+                destinations.push(*exit);
+            }
+        }
+        destinations
+    }
+}
+
+impl Actor for RouteHistory {
+    type Context = Context<Self>;
+}
+
+impl Supervised for RouteHistory {}
+impl SystemService for RouteHistory {
+    fn service_started(&mut self, _ctx: &mut Context<Self>) {
+        info!("RouteHistory started");
+    }
+}
+
+fn get_current_time() -> Result<u64, Error> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+/// Records the routes in this copy of babel's table for every tracked destination
+pub struct SampleRoutes(pub BabelTable);
+
+impl Message for SampleRoutes {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<SampleRoutes> for RouteHistory {
+    type Result = Result<(), Error>;
+    fn handle(&mut self, msg: SampleRoutes, _: &mut Context<Self>) -> Self::Result {
+        let now = get_current_time()?;
+        let destinations = self.tracked_destinations();
+        // histories of destinations we no longer track are kept until they are a day stale
+        self.destinations.retain(|destination, history| {
+            destinations.contains(destination)
+                || history.samples.front().map_or(false, |sample| {
+                    sample.time + SAMPLE_INTERVAL * MAX_SAMPLES as u64 > now
+                })
+        });
+
+        for destination in destinations {
+            let route = msg
+                .0
+                .get_installed_route(&destination)
+                .ok()
+                .map(|route| SampledRoute::new(&route));
+            let history = self
+                .destinations
+                .entry(destination)
+                .or_insert_with(DestinationHistory::default);
+            let was_flapping = history.is_flapping(now);
+            if let Some(change) = history.observe(now, route) {
+                info!("Route to {} changed {:?}", destination, change);
+                if !was_flapping && history.is_flapping(now) {
+                    warn!(
+                        "Route to {} is flapping, {} changes in the last {}s",
+                        destination,
+                        history.recent_changes(now),
+                        FLAP_WINDOW
+                    );
+                }
+            }
+        }
+
+        if now > self.last_save + SAVE_INTERVAL {
+            self.last_save = now;
+            let res = self.save();
+            info!("Saving route history: {:?}", res);
+        }
+        Ok(())
+    }
+}
+
+/// Sets the exits whose routes we track, sent by clients
+pub struct SetExitDestinations(pub Vec<IpAddr>);
+
+impl Message for SetExitDestinations {
+    type Result = ();
+}
+
+impl Handler<SetExitDestinations> for RouteHistory {
+    type Result = ();
+    fn handle(&mut self, msg: SetExitDestinations, _: &mut Context<Self>) -> Self::Result {
+        self.exits = msg.0;
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RouteSummary {
+    pub destination: IpAddr,
+    pub route: Option<SampledRoute>,
+    pub flapping: bool,
+    /// Route changes within the flap window
+    pub recent_changes: usize,
+    pub last_change: Option<RouteEvent>,
+}
+
+pub struct GetRouteSummaries;
+
+impl Message for GetRouteSummaries {
+    type Result = Result<Vec<RouteSummary>, Error>;
+}
+
+impl Handler<GetRouteSummaries> for RouteHistory {
+    type Result = Result<Vec<RouteSummary>, Error>;
+    fn handle(&mut self, _: GetRouteSummaries, _: &mut Context<Self>) -> Self::Result {
+        let now = get_current_time()?;
+        Ok(self
+            .destinations
+            .iter()
+            .map(|(destination, history)| RouteSummary {
+                destination: *destination,
+                route: history.current.clone(),
+                flapping: history.is_flapping(now),
+                recent_changes: history.recent_changes(now),
+                last_change: history.events.front().cloned(),
+            })
+            .collect())
+    }
+}
+
+pub struct GetDestinationHistory(pub IpAddr);
+
+impl Message for GetDestinationHistory {
+    type Result = Result<DestinationHistory, Error>;
+}
+
+impl Handler<GetDestinationHistory> for RouteHistory {
+    type Result = Result<DestinationHistory, Error>;
+    fn handle(&mut self, msg: GetDestinationHistory, _: &mut Context<Self>) -> Self::Result {
+        match self.destinations.get(&msg.0) {
+            Some(history) => Ok(history.clone()),
+            None => bail!("No route history for {}", msg.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(neigh: &str) -> Option<SampledRoute> {
+        Some(SampledRoute {
+            metric: 256,
+            price: 1000,
+            full_path_rtt: 20.0,
+            neigh_ip: neigh.parse().unwrap(),
+            iface: "wg0".to_string(),
+        })
+    }
+
+    #[test]
+    fn test_sampling() {
+        let mut history = DestinationHistory::default();
+        assert_eq!(history.observe(1000, route("fe80::1")), None);
+        // unchanged routes are only sampled once a minute
+        assert_eq!(history.observe(1005, route("fe80::1")), None);
+        assert_eq!(history.samples.len(), 1);
+        history.observe(1000 + SAMPLE_INTERVAL, route("fe80::1"));
+        assert_eq!(history.samples.len(), 2);
+        assert_eq!(history.samples[0].time, 1000 + SAMPLE_INTERVAL);
+    }
+
+    #[test]
+    fn test_route_changes() {
+        let mut history = DestinationHistory::default();
+        history.observe(1000, None);
+        assert_eq!(
+            history.observe(1005, route("fe80::1")),
+            Some(RouteChange::Restored {
+                neigh_ip: "fe80::1".parse().unwrap()
+            })
+        );
+        assert_eq!(
+            history.observe(1010, route("fe80::2")),
+            Some(RouteChange::NeighborChanged {
+                from: "fe80::1".parse().unwrap(),
+                to: "fe80::2".parse().unwrap()
+            })
+        );
+        assert_eq!(history.observe(1015, None), Some(RouteChange::Lost));
+        // every change is sampled
+        assert_eq!(history.samples.len(), 4);
+        assert_eq!(history.events.len(), 3);
+    }
+
+    #[test]
+    fn test_flapping() {
+        let mut history = DestinationHistory::default();
+        let neighs = ["fe80::1", "fe80::2"];
+        for i in 0..=FLAP_THRESHOLD {
+            history.observe(1000 + i as u64 * 5, route(neighs[i % 2]));
+        }
+        assert!(history.is_flapping(1000 + FLAP_THRESHOLD as u64 * 5));
+        assert!(!history.is_flapping(2000 + FLAP_WINDOW));
+    }
+}
@@ -0,0 +1,702 @@
+//! An in-process billing simulation. Every virtual node runs its own actix system with the
+//! real TrafficWatcher, DebtKeeper and PaymentController, fed by kernel counters served
+//! through the mock KernelInterface and route tables read from a mock babel stream. Payments
+//! go out over the mock network in PaymentController and move money on a mock chain so that
+//! we can check that no money or debt is created or lost along the way.
+//!
+//! Rita is a pair of binaries built around global settings and a global KernelInterface, so
+//! the simulation lives in the test build rather than in a crate of its own and the nodes
+//! take turns with the globals, only one node's actors run at a time.
+
+use crate::rita_common::debt_keeper::{
+    DebtAction, DebtData, DebtKeeper, Dump, PaymentReceived, SendUpdate,
+};
+use crate::rita_common::payment_controller::MOCK_NETWORK;
+use crate::rita_common::traffic_watcher::{TrafficWatcher, Watch};
+use crate::rita_common::tunnel_manager::{Neighbor, TunnelHealth};
+use crate::KI;
+use crate::SETTING;
+use ::actix::{MailboxError, System, SystemService};
+use althea_kernel_interface::FilterTarget;
+use althea_types::{Identity, LocalIdentity, PaymentTx, WgKey};
+use babel_monitor::{AsyncBabel, BabelTable};
+use clarity::PrivateKey;
+use failure::Error;
+use futures::{future, Future};
+use mockstream::SharedMockStream;
+use num256::{Int256, Uint256};
+use settings::RitaCommonSettings;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Write as FmtWrite;
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{ExitStatus, Output};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::prelude::{Async, Poll};
+
+lazy_static! {
+    /// The mock KernelInterface is global, simulations have to take turns with it
+    static ref SIMULATION_LOCK: Mutex<()> = Mutex::new(());
+}
+
+const TARGETS: [FilterTarget; 4] = [
+    FilterTarget::Input,
+    FilterTarget::Output,
+    FilterTarget::ForwardInput,
+    FilterTarget::ForwardOutput,
+];
+
+/// How many times we look for payments that are still in flight before giving up on them
+const SETTLE_ATTEMPTS: usize = 100;
+
+static BABEL_PREAMBLE: &str = "ALTHEA 0.1\nversion babeld-1.8.0-24-g6335378\nhost sim\nmy-id \
+                               ba:27:eb:ff:fe:00:00:00\nok\n";
+
+type KernelCounters = HashMap<(IpAddr, String), u64>;
+
+/// Only the last byte of the key differs between nodes, that's all we need to tell them apart
+fn sim_key(index: usize) -> WgKey {
+    const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let last = index as u8;
+    format!(
+        "{}{}{}=",
+        "A".repeat(41),
+        BASE64[(last >> 4) as usize] as char,
+        BASE64[((last & 15) << 2) as usize] as char
+    )
+    .parse()
+    .unwrap()
+}
+
+fn sim_private_key(index: usize) -> PrivateKey {
+    PrivateKey::from_slice(&[index as u8 + 1; 32]).unwrap()
+}
+
+fn sim_identity(index: usize) -> Identity {
+    Identity::new(
+        format!("fd00::{:x}", index + 1).parse().unwrap(),
+        sim_private_key(index).to_public_key().unwrap(),
+        sim_key(index),
+        None,
+    )
+}
+
+/// An in memory babeld, it answers with whatever was pushed to it
+#[derive(Clone)]
+struct MockBabel(SharedMockStream);
+
+impl Read for MockBabel {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for MockBabel {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl AsyncRead for MockBabel {}
+
+impl AsyncWrite for MockBabel {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+/// Reads a route table the way the babel source does, by connecting and starting a monitor
+fn read_babel_table(dump: &str) -> Box<dyn Future<Item = BabelTable, Error = Error>> {
+    let mut stream = SharedMockStream::new();
+    stream.push_bytes_to_read(BABEL_PREAMBLE.as_bytes());
+    stream.push_bytes_to_read(dump.as_bytes());
+    Box::new(
+        AsyncBabel::new(MockBabel(stream))
+            .start_connection()
+            .and_then(|babel| babel.start_monitor())
+            .map(|(_babel, table)| table),
+    )
+}
+
+/// Turns an actor's answer to a message into a plain result
+fn flatten<T>(res: Result<Result<T, Error>, MailboxError>) -> Result<T, Error> {
+    match res {
+        Ok(res) => res,
+        Err(e) => Err(e.into()),
+    }
+}
+
+type Job = Box<dyn FnOnce() -> Box<dyn Future<Item = (), Error = ()>> + Send>;
+
+/// A node's actix system. Each runs on a thread of its own, actors are looked up in the
+/// system of the thread they are used from so this gives every node its own set of them
+struct NodeSystem {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl NodeSystem {
+    fn start(name: String) -> NodeSystem {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        thread::spawn(move || {
+            let mut system = System::new(name);
+            for job in queue {
+                let _ = system.block_on(future::lazy(job));
+            }
+        });
+        NodeSystem { jobs }
+    }
+
+    /// Runs a future on the node's system and waits for it, whatever the node's actors
+    /// start along the way carries on the next time the system runs
+    fn run<T, F>(&self, job: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Box<dyn Future<Item = T, Error = Error>> + Send + 'static,
+    {
+        let (done, result) = mpsc::channel();
+        let job: Job = Box::new(move || -> Box<dyn Future<Item = (), Error = ()>> {
+            Box::new(job().then(move |res| {
+                let _ = done.send(res);
+                Ok(())
+            }))
+        });
+        self.jobs.send(job).expect("Simulated node stopped");
+        result.recv().expect("Simulated node panicked")
+    }
+
+    fn debts(&self) -> Result<DebtData, Error> {
+        self.run(|| Box::new(DebtKeeper::from_registry().send(Dump).then(flatten)))
+    }
+}
+
+/// The mock chain the nodes pay each other over
+struct Chain {
+    balances: Vec<Uint256>,
+    mesh_ips: Vec<IpAddr>,
+    /// Payments the receiver hasn't heard about yet and who they're for
+    receipts: Vec<(usize, PaymentTx)>,
+    txid: u64,
+}
+
+impl Chain {
+    fn index_of(&self, identity: &Identity) -> Result<usize, Error> {
+        match self.mesh_ips.iter().position(|ip| *ip == identity.mesh_ip) {
+            Some(index) => Ok(index),
+            None => bail!("No account for {}", identity.mesh_ip),
+        }
+    }
+
+    fn transfer(&mut self, pmt: &PaymentTx) -> Result<Uint256, Error> {
+        let from = self.index_of(&pmt.from)?;
+        let to = self.index_of(&pmt.to)?;
+        if self.balances[from] < pmt.amount {
+            bail!("Insufficient funds");
+        }
+        self.balances[from] -= pmt.amount.clone();
+        self.balances[to] += pmt.amount.clone();
+        self.receipts.push((to, pmt.clone()));
+        self.txid += 1;
+        Ok(Uint256::from(self.txid))
+    }
+}
+
+pub struct SimNode {
+    pub identity: Identity,
+    pub fee: u32,
+    /// Our tunnel interface to each neighbor
+    tunnels: HashMap<usize, String>,
+    /// What the kernel would report, these only ever count up
+    kernel_counters: HashMap<FilterTarget, KernelCounters>,
+    system: NodeSystem,
+    /// Our DebtKeeper's debts as of the end of the last round
+    pub debts: DebtData,
+    /// Neighbors we have cut off for not paying
+    pub suspended: HashSet<usize>,
+}
+
+impl SimNode {
+    fn count(&mut self, target: FilterTarget, dest: IpAddr, neighbor: usize, bytes: u64) {
+        let iface = self.tunnels[&neighbor].clone();
+        *self
+            .kernel_counters
+            .entry(target)
+            .or_insert_with(HashMap::new)
+            .entry((dest, iface))
+            .or_insert(0) += bytes;
+    }
+
+    /// Serves our counters the way `ipset save` prints them, every other counter command
+    /// succeeds
+    fn mock_kernel(&self) {
+        let mut saves = HashMap::new();
+        for target in TARGETS.iter() {
+            // the marker of the generation TrafficWatcher starts counting with
+            let mut save = format!("add {} ::1,gen0 packets 0 bytes 0\n", target.set_name());
+            if let Some(counters) = self.kernel_counters.get(target) {
+                for ((ip, iface), bytes) in counters {
+                    writeln!(
+                        save,
+                        "add {} {},{} packets 0 bytes {}",
+                        target.set_name(),
+                        ip,
+                        iface,
+                        bytes
+                    )
+                    .unwrap();
+                }
+            }
+            saves.insert(target.set_name().to_string(), save);
+        }
+        KI.set_mock(Box::new(move |program, args| {
+            let stdout = match (program.as_str(), args[0].as_str()) {
+                ("ipset", "save") => saves[&args[1]].clone().into_bytes(),
+                ("ipset", _) | ("ip6tables", _) => Vec::new(),
+                _ => panic!("Unexpected call {} {:?}", program, args),
+            };
+            Ok(Output {
+                stdout,
+                stderr: Vec::new(),
+                status: ExitStatus::from_raw(0),
+            })
+        }));
+    }
+
+    /// Where money has left this node (negative) or come in (positive) counting debts
+    /// that have not been paid yet
+    pub fn position(&self) -> Int256 {
+        let mut position = Int256::from(0);
+        for data in self.debts.values() {
+            position -= data.debt.clone();
+            position += data.total_payment_received.to_int256().unwrap();
+            position -= data.total_payment_sent.to_int256().unwrap();
+            position -= data.incoming_payments.to_int256().unwrap();
+        }
+        position
+    }
+}
+
+pub struct Simulation {
+    pub nodes: Vec<SimNode>,
+    chain: Arc<Mutex<Chain>>,
+    links: Vec<Vec<usize>>,
+}
+
+impl Simulation {
+    /// Nodes with the given fees, connected by `links`, each starting with `balance` on chain
+    pub fn new(fees: &[u32], links: &[(usize, usize)], balance: Uint256) -> Simulation {
+        let mut neighbors = vec![Vec::new(); fees.len()];
+        for &(a, b) in links {
+            neighbors[a].push(b);
+            neighbors[b].push(a);
+        }
+        for list in neighbors.iter_mut() {
+            list.sort();
+        }
+
+        let nodes: Vec<SimNode> = fees
+            .iter()
+            .enumerate()
+            .map(|(i, fee)| SimNode {
+                identity: sim_identity(i),
+                fee: *fee,
+                tunnels: neighbors[i]
+                    .iter()
+                    .enumerate()
+                    .map(|(n, neighbor)| (*neighbor, format!("wg{}", n)))
+                    .collect(),
+                kernel_counters: HashMap::new(),
+                system: NodeSystem::start(format!("sim node {}", i)),
+                debts: DebtData::new(),
+                suspended: HashSet::new(),
+            })
+            .collect();
+        let chain = Chain {
+            balances: vec![balance; fees.len()],
+            mesh_ips: nodes.iter().map(|node| node.identity.mesh_ip).collect(),
+            receipts: Vec::new(),
+            txid: 0,
+        };
+
+        Simulation {
+            nodes,
+            chain: Arc::new(Mutex::new(chain)),
+            links: neighbors,
+        }
+    }
+
+    pub fn balance(&self, node: usize) -> Uint256 {
+        self.chain.lock().unwrap().balances[node].clone()
+    }
+
+    pub fn set_balance(&mut self, node: usize, balance: Uint256) {
+        self.chain.lock().unwrap().balances[node] = balance;
+    }
+
+    pub fn total_balance(&self) -> Uint256 {
+        let mut total = Uint256::from(0u32);
+        for balance in self.chain.lock().unwrap().balances.iter() {
+            total += balance.clone();
+        }
+        total
+    }
+
+    /// The first hop of a shortest path between two nodes, ties go to the lowest numbered
+    /// neighbor
+    fn next_hop(&self, from: usize, to: usize) -> Option<usize> {
+        let mut previous = vec![None; self.nodes.len()];
+        let mut queue = VecDeque::new();
+        queue.push_back(from);
+        previous[from] = Some(from);
+        while let Some(node) = queue.pop_front() {
+            if node == to {
+                let mut hop = to;
+                while previous[hop] != Some(from) {
+                    hop = previous[hop].unwrap();
+                }
+                return Some(hop);
+            }
+            for next in self.links[node].iter() {
+                if previous[*next].is_none() {
+                    previous[*next] = Some(node);
+                    queue.push_back(*next);
+                }
+            }
+        }
+        None
+    }
+
+    /// The path traffic takes, like babel every node picks its own next hop
+    pub fn path(&self, from: usize, to: usize) -> Option<Vec<usize>> {
+        if from == to {
+            return None;
+        }
+        let mut path = vec![from];
+        let mut hop = from;
+        while hop != to {
+            hop = self.next_hop(hop, to)?;
+            path.push(hop);
+        }
+        Some(path)
+    }
+
+    /// The route price babel would advertise, the sum of the fees of every relay on the path
+    pub fn price(&self, from: usize, to: usize) -> u32 {
+        match self.path(from, to) {
+            Some(path) => path[1..path.len() - 1]
+                .iter()
+                .map(|hop| self.nodes[*hop].fee)
+                .sum(),
+            None => 0,
+        }
+    }
+
+    /// A babel dump of the node's route table
+    fn babel_dump(&self, node: usize) -> String {
+        let mut dump = format!("local fee {}\n", self.nodes[node].fee);
+        for dest in 0..self.nodes.len() {
+            if dest == node {
+                continue;
+            }
+            if let Some(path) = self.path(node, dest) {
+                let price = self.price(node, dest);
+                writeln!(
+                    dump,
+                    "add route {:x} prefix {}/128 from ::/0 installed yes id ba:27:eb:ff:fe:00:00:{:02x} \
+                     metric 256 price {} fee {} refmetric 0 full-path-rtt 10.0 via fe80::{:x} if {}",
+                    dest + 1,
+                    self.nodes[dest].identity.mesh_ip,
+                    dest,
+                    price,
+                    price,
+                    path[1] + 1,
+                    self.nodes[node].tunnels[&path[1]]
+                )
+                .unwrap();
+            }
+        }
+        dump.push_str("ok\n");
+        dump
+    }
+
+    /// Sends traffic along the shortest path, returns false if there is no path or some
+    /// node on it has cut off the hop before it
+    pub fn send(&mut self, from: usize, to: usize, bytes: u64) -> bool {
+        let path = match self.path(from, to) {
+            Some(path) => path,
+            None => return false,
+        };
+        for hop in path.windows(2) {
+            if self.nodes[hop[0]].suspended.contains(&hop[1])
+                || self.nodes[hop[1]].suspended.contains(&hop[0])
+            {
+                return false;
+            }
+        }
+
+        let dest = self.nodes[to].identity.mesh_ip;
+        let last = path.len() - 1;
+        self.nodes[from].count(FilterTarget::Output, dest, path[1], bytes);
+        for i in 1..last {
+            let node = &mut self.nodes[path[i]];
+            node.count(FilterTarget::ForwardInput, dest, path[i - 1], bytes);
+            node.count(FilterTarget::ForwardOutput, dest, path[i + 1], bytes);
+        }
+        self.nodes[to].count(FilterTarget::Input, dest, path[last - 1], bytes);
+        true
+    }
+
+    fn index_of(&self, identity: &Identity) -> usize {
+        self.nodes
+            .iter()
+            .position(|node| node.identity == *identity)
+            .unwrap()
+    }
+
+    /// Our tunnels as TunnelManager would list them
+    fn neighbors(&self, node: usize) -> Vec<Neighbor> {
+        self.nodes[node]
+            .tunnels
+            .iter()
+            .map(|(neighbor, iface)| Neighbor {
+                identity: LocalIdentity {
+                    wg_port: 0,
+                    have_tunnel: Some(true),
+                    global: sim_identity(*neighbor),
+                },
+                iface_name: iface.clone(),
+                tunnel_ip: format!("fe80::{:x}", neighbor + 1).parse().unwrap(),
+                health: TunnelHealth::Healthy,
+            })
+            .collect()
+    }
+
+    /// Hands the globals over to a node, its actors read our identity and balance from the
+    /// settings and count traffic through the KernelInterface
+    fn use_node(&self, node: usize) {
+        let sim_node = &self.nodes[node];
+        {
+            let mut network = SETTING.get_network_mut();
+            network.mesh_ip = Some(sim_node.identity.mesh_ip);
+            network.wg_public_key = Some(sim_node.identity.wg_public_key);
+            network.usage_tracker_file = std::env::temp_dir()
+                .join("rita-sim-usage.json")
+                .to_string_lossy()
+                .into_owned();
+        }
+        let balance = self.balance(node);
+        {
+            let mut payment = SETTING.get_payment_mut();
+            payment.eth_address = Some(sim_node.identity.eth_address);
+            payment.eth_private_key = Some(sim_private_key(node));
+            payment.balance = balance;
+            payment.local_fee = sim_node.fee;
+            payment.max_fee = u32::max_value() / 2;
+        }
+        sim_node.mock_kernel();
+    }
+
+    /// One round of the rita loop on every node, then the debt keepers send their updates.
+    /// Every node sends its updates twice, payments go out in the first pass and the second
+    /// shows the network once they have landed, which on a real network happens long before
+    /// the close threshold is reached
+    pub fn round(&mut self) -> Result<(), Error> {
+        let _lock = SIMULATION_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        let chain = self.chain.clone();
+        *MOCK_NETWORK.lock().unwrap_or_else(|e| e.into_inner()) =
+            Some(Box::new(move |pmt: &PaymentTx| {
+                chain.lock().unwrap().transfer(pmt)
+            }));
+        let res = self.run_round();
+        *MOCK_NETWORK.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        res
+    }
+
+    fn run_round(&mut self) -> Result<(), Error> {
+        for i in 0..self.nodes.len() {
+            let dump = self.babel_dump(i);
+            let neighbors = self.neighbors(i);
+            self.use_node(i);
+            self.nodes[i].system.run::<(), _>(move || {
+                Box::new(read_babel_table(&dump).and_then(move |table| {
+                    TrafficWatcher::from_registry()
+                        .send(Watch::new(table, neighbors))
+                        .then(flatten)
+                }))
+            })?;
+        }
+
+        for _ in 0..2 {
+            for i in 0..self.nodes.len() {
+                self.use_node(i);
+                self.nodes[i].system.run::<(), _>(|| {
+                    Box::new(DebtKeeper::from_registry().send(SendUpdate).then(flatten))
+                })?;
+                self.settle(i)?;
+                self.deliver_payments()?;
+            }
+        }
+
+        for i in 0..self.nodes.len() {
+            let debts = self.nodes[i].system.debts()?;
+            let suspended = debts
+                .iter()
+                .filter(|(_, data)| data.action == DebtAction::SuspendTunnel)
+                .map(|(identity, _)| self.index_of(identity))
+                .collect();
+            let node = &mut self.nodes[i];
+            node.debts = debts;
+            node.suspended = suspended;
+        }
+        Ok(())
+    }
+
+    /// Waits for every payment the node's DebtKeeper started to be reported back to it
+    fn settle(&self, node: usize) -> Result<(), Error> {
+        for _ in 0..SETTLE_ATTEMPTS {
+            let debts = self.nodes[node].system.debts()?;
+            if debts.values().all(|data| !data.payment_in_flight) {
+                return Ok(());
+            }
+        }
+        bail!("Payments from node {} never finished", node)
+    }
+
+    /// Tells the receivers about new payments, as their payment validators would once the
+    /// payments are on chain
+    fn deliver_payments(&self) -> Result<(), Error> {
+        let receipts: Vec<(usize, PaymentTx)> =
+            self.chain.lock().unwrap().receipts.drain(..).collect();
+        for (to, pmt) in receipts {
+            self.use_node(to);
+            self.nodes[to].system.run::<(), _>(move || {
+                Box::new(
+                    DebtKeeper::from_registry()
+                        .send(PaymentReceived {
+                            from: pmt.from,
+                            amount: pmt.amount,
+                        })
+                        .then(flatten),
+                )
+            })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SETTING;
+    use settings::RitaCommonSettings;
+    use tokio::runtime::current_thread::Runtime;
+
+    fn set_thresholds() {
+        SETTING.get_payment_mut().pay_threshold = Int256::from(5);
+        SETTING.get_payment_mut().close_threshold = Int256::from(-10);
+    }
+
+    #[test]
+    fn test_sim_paths() {
+        set_thresholds();
+        let sim = Simulation::new(
+            &[10, 20, 30, 40],
+            &[(0, 1), (1, 2), (2, 3)],
+            Uint256::from(0u32),
+        );
+        assert_eq!(sim.path(0, 3), Some(vec![0, 1, 2, 3]));
+        assert_eq!(sim.price(0, 3), 50);
+        assert_eq!(sim.price(0, 1), 0);
+        assert_eq!(sim.price(3, 1), 30);
+
+        let table = BabelTable::from_dump(&sim.babel_dump(0));
+        assert_eq!(table.local_fee, Some(10));
+        assert_eq!(table.routes().len(), 3);
+
+        // the same table read through the mock babel stream
+        let mut runtime = Runtime::new().unwrap();
+        let table = runtime
+            .block_on(read_babel_table(&sim.babel_dump(3)))
+            .unwrap();
+        assert_eq!(table.local_fee, Some(40));
+        assert_eq!(table.routes().len(), 3);
+    }
+
+    #[test]
+    fn test_sim_conservation() {
+        set_thresholds();
+        let fees = [10, 25, 7, 40, 3, 18];
+        // a ring with a chord, so some traffic has a choice of paths
+        let links = [(0, 1), (1, 2), (2, 3), (3, 4), (4, 5), (5, 0), (1, 4)];
+        let mut sim = Simulation::new(&fees, &links, Uint256::from(1_000_000_000u64));
+        let start_balance = sim.total_balance();
+
+        let mut expected = vec![0i128; fees.len()];
+        for round in 0..10u64 {
+            for from in 0..fees.len() {
+                for to in 0..fees.len() {
+                    if from == to {
+                        continue;
+                    }
+                    // uneven flows so that no two nodes see the same totals
+                    let bytes = 100 + (from as u64 * 37 + to as u64 * 11 + round * 5) % 400;
+                    assert!(sim.send(from, to, bytes));
+
+                    let path = sim.path(from, to).unwrap();
+                    for relay in path[1..path.len() - 1].iter() {
+                        expected[*relay] += i128::from(fees[*relay]) * i128::from(bytes);
+                    }
+                    expected[from] -= i128::from(sim.price(from, to)) * i128::from(bytes);
+                }
+            }
+            sim.round().unwrap();
+
+            let mut total = Int256::from(0);
+            for (i, node) in sim.nodes.iter().enumerate() {
+                assert_eq!(node.position(), Int256::from(expected[i]), "node {}", i);
+                assert!(node.suspended.is_empty());
+                total += node.position();
+            }
+            assert_eq!(total, Int256::from(0));
+            assert_eq!(sim.total_balance(), start_balance);
+        }
+    }
+
+    #[test]
+    fn test_sim_deadbeat() {
+        set_thresholds();
+        let mut sim = Simulation::new(
+            &[10, 20, 30],
+            &[(0, 1), (1, 2)],
+            Uint256::from(1_000_000u64),
+        );
+        sim.set_balance(0, Uint256::from(0u32));
+        let start_balance = sim.total_balance();
+
+        // node 0 can't pay node 1 for relaying, so node 1 cuts it off
+        assert!(sim.send(0, 2, 1000));
+        sim.round().unwrap();
+        assert!(sim.nodes[1].suspended.contains(&0));
+        assert!(!sim.send(0, 2, 1000));
+        assert!(sim.send(2, 1, 1000));
+        sim.round().unwrap();
+        assert!(sim.nodes[1].suspended.contains(&0));
+        assert_eq!(sim.total_balance(), start_balance);
+
+        // once it has money again the debt is paid and the tunnel reopened
+        sim.set_balance(0, Uint256::from(1_000_000u64));
+        sim.round().unwrap();
+        assert!(sim.nodes[1].suspended.is_empty());
+        assert!(sim.send(0, 2, 1000));
+        assert_eq!(sim.balance(0), Uint256::from(1_000_000u64 - 20 * 1000));
+        assert_eq!(sim.balance(1), Uint256::from(1_000_000u64 + 20 * 1000));
+        assert_eq!(sim.nodes[1].position(), Int256::from(20 * 1000));
+    }
+}
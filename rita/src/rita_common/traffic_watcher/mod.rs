@@ -1,29 +1,55 @@
 //! Traffic watcher monitors system traffic by interfacing with KernelInterface to create and check
-//! iptables and ipset counters on each per hop tunnel (the WireGuard tunnel between two devices). These counts
+//! iptables and ipset (or nftables) counters on each per hop tunnel (the WireGuard tunnel between two devices). These counts
 //! are then stored and used to compute amounts for bills.
 
+use crate::rita_common::babel_client::get_local_fee;
 use crate::rita_common::debt_keeper;
 use crate::rita_common::debt_keeper::DebtKeeper;
 use crate::rita_common::debt_keeper::Traffic;
 use crate::rita_common::tunnel_manager::Neighbor;
+use crate::rita_common::usage_tracker::DestinationUsage;
+use crate::rita_common::usage_tracker::UpdateRelayDestinations;
 use crate::rita_common::usage_tracker::UpdateUsage;
 use crate::rita_common::usage_tracker::UsageTracker;
 use crate::rita_common::usage_tracker::UsageType;
 use crate::KI;
 use crate::SETTING;
 use ::actix::{Actor, Context, Handler, Message, Supervised, SystemService};
-use althea_kernel_interface::FilterTarget;
+use althea_kernel_interface::{counter_deltas, CounterBackend, Counters, FilterTarget};
 use althea_types::Identity;
-use babel_monitor::open_babel_stream;
-use babel_monitor::Babel;
+use babel_monitor::BabelTable;
 use failure::Error;
 use ipnetwork::IpNetwork;
+use num256::Int256;
 use settings::RitaCommonSettings;
 use std::collections::HashMap;
-use std::io::{Read, Write};
+use std::convert::TryFrom;
 use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+pub struct TrafficWatcher {
+    /// Picked when the service starts, ipset unless the system only has nftables
+    counter_backend: CounterBackend,
+    /// The kernel counters as of the last successful read of each target, the kernel
+    /// counters only ever go up so this round's traffic is the difference
+    last_counters: HashMap<FilterTarget, Counters>,
+    /// When the counter sets were last swapped for empty ones
+    rotated_at: Instant,
+}
+
+/// How often the counter sets are swapped for empty ones, the sets never drop a destination
+/// on their own so without this they would fill up and new destinations would go uncounted
+const COUNTER_ROTATION_INTERVAL: Duration = Duration::from_secs(3600);
+/// Rotate early once any set holds this many destinations, well short of the 65535
+/// elements the kernel sets are created with
+const MAX_COUNTER_ENTRIES: usize = 16384;
 
-pub struct TrafficWatcher;
+const TARGETS: [FilterTarget; 4] = [
+    FilterTarget::Input,
+    FilterTarget::Output,
+    FilterTarget::ForwardInput,
+    FilterTarget::ForwardOutput,
+];
 
 impl Actor for TrafficWatcher {
     type Context = Context<Self>;
@@ -33,29 +59,49 @@ impl Supervised for TrafficWatcher {}
 
 impl SystemService for TrafficWatcher {
     fn service_started(&mut self, _ctx: &mut Context<Self>) {
-        KI.init_counter(&FilterTarget::Input).unwrap();
-        KI.init_counter(&FilterTarget::Output).unwrap();
-        KI.init_counter(&FilterTarget::ForwardInput).unwrap();
-        KI.init_counter(&FilterTarget::ForwardOutput).unwrap();
+        self.counter_backend = KI.detect_counter_backend().unwrap();
+        let backend = self.counter_backend;
+        for target in TARGETS.iter() {
+            // anything counted before we started may already have been billed by a previous
+            // run, init starts over with empty sets
+            KI.init_counter(backend, target).unwrap();
+            self.last_counters.insert(
+                *target,
+                Counters {
+                    generation: Some(0),
+                    values: HashMap::new(),
+                },
+            );
+        }
+        self.rotated_at = Instant::now();
 
-        info!("Traffic Watcher started");
+        info!("Traffic Watcher started counting with {:?}", backend);
     }
 }
 
 impl Default for TrafficWatcher {
     fn default() -> TrafficWatcher {
-        TrafficWatcher {}
+        TrafficWatcher {
+            counter_backend: CounterBackend::Ipset,
+            last_counters: HashMap::new(),
+            rotated_at: Instant::now(),
+        }
     }
 }
 
 pub struct Watch {
+    /// Babel's tables as of this round, used to price each destination
+    pub babel_table: BabelTable,
     /// List of neighbors to watch
     pub neighbors: Vec<Neighbor>,
 }
 
 impl Watch {
-    pub fn new(neighbors: Vec<Neighbor>) -> Watch {
-        Watch { neighbors }
+    pub fn new(babel_table: BabelTable, neighbors: Vec<Neighbor>) -> Watch {
+        Watch {
+            babel_table,
+            neighbors,
+        }
     }
 }
 
@@ -67,9 +113,23 @@ impl Handler<Watch> for TrafficWatcher {
     type Result = Result<(), Error>;
 
     fn handle(&mut self, msg: Watch, _: &mut Context<Self>) -> Self::Result {
-        let stream = open_babel_stream(SETTING.get_network().babel_port)?;
-
-        watch(Babel::new(stream), &msg.neighbors)
+        let rotate = self.rotated_at.elapsed() > COUNTER_ROTATION_INTERVAL
+            || self
+                .last_counters
+                .values()
+                .any(|counters| counters.values.len() > MAX_COUNTER_ENTRIES);
+        let res = watch(
+            self.counter_backend,
+            &mut self.last_counters,
+            rotate,
+            &msg.babel_table,
+            &msg.neighbors,
+        );
+        if rotate {
+            // a failed rotation is retried on the next interval rather than every round
+            self.rotated_at = Instant::now();
+        }
+        res
     }
 }
 
@@ -88,26 +148,30 @@ pub fn prepare_helper_maps(
     (identities, if_to_id)
 }
 
-pub fn get_babel_info<T: Read + Write>(
-    mut babel: Babel<T>,
-) -> Result<(HashMap<IpAddr, i128>, u32), Error> {
-    babel.start_connection()?;
+pub fn get_babel_info(babel_table: &BabelTable) -> Result<(HashMap<IpAddr, i128>, u32), Error> {
+    let mesh_ip = match SETTING.get_network().mesh_ip {
+        Some(ip) => ip,
+        None => bail!("No mesh IP configured yet"),
+    };
+    let local_fee = get_local_fee(babel_table)?;
+    let max_fee = SETTING.get_payment().max_fee;
+    Ok((
+        destination_prices(babel_table, mesh_ip, local_fee, max_fee),
+        local_fee,
+    ))
+}
 
-    trace!("Getting routes");
-    let routes = babel.parse_routes()?;
+/// The price of each destination including our fee, traffic to ourselves is free
+fn destination_prices(
+    babel_table: &BabelTable,
+    mesh_ip: IpAddr,
+    local_fee: u32,
+    max_fee: u32,
+) -> HashMap<IpAddr, i128> {
+    let routes = babel_table.routes();
     trace!("Got routes: {:?}", routes);
     let mut destinations = HashMap::new();
-    let local_fee = match babel.get_local_fee() {
-        Ok(fee) => fee,
-        Err(e) => {
-            error!("Babel fee not set properly! this is a bad sign! {:?}", e);
-            let configured_fee = SETTING.get_payment().local_fee;
-            babel.set_local_fee(configured_fee)?;
-            configured_fee
-        }
-    };
 
-    let max_fee = SETTING.get_payment().max_fee;
     for route in &routes {
         // Only ip6
         if let IpNetwork::V6(ref ip) = route.prefix {
@@ -125,112 +189,127 @@ pub fn get_babel_info<T: Read + Write>(
         }
     }
 
-    destinations.insert(
-        match SETTING.get_network().mesh_ip {
-            Some(ip) => ip,
-            None => bail!("No mesh IP configured yet"),
-        },
-        i128::from(0),
-    );
+    destinations.insert(mesh_ip, i128::from(0));
 
-    Ok((destinations, local_fee))
+    destinations
 }
 
-pub fn get_input_counters() -> Result<HashMap<(IpAddr, String), u64>, Error> {
-    let mut total_input_counters = HashMap::new();
-    trace!("Getting input counters");
-    let input_counters = match KI.read_counters(&FilterTarget::Input) {
-        Ok(res) => res,
-        Err(e) => {
-            warn!(
-                "Error getting input counters {:?} traffic has gone unaccounted!",
-                e
-            );
-            return Err(e);
-        }
-    };
-    trace!("Got input counters: {:?}", input_counters);
-    trace!("Getting fwd counters");
-    let fwd_input_counters = match KI.read_counters(&FilterTarget::ForwardInput) {
-        Ok(res) => res,
-        Err(e) => {
-            warn!(
-                "Error getting input counters {:?} traffic has gone unaccounted!",
-                e
-            );
-            return Err(e);
+/// The traffic counted for every target since the last round. Every target is read before
+/// any of them are used, so a failed read leaves the last values alone and this round's
+/// traffic is counted in the next round instead.
+///
+/// With `rotate` the sets are then swapped for empty ones of the next generation, whatever
+/// the old sets counted after the read is added to this round. Starting over also drops
+/// the destinations we no longer talk to from `last_counters`
+fn read_counter_deltas(
+    backend: CounterBackend,
+    last_counters: &mut HashMap<FilterTarget, Counters>,
+    rotate: bool,
+) -> Result<HashMap<FilterTarget, HashMap<(IpAddr, String), u64>>, Error> {
+    let mut current = Vec::new();
+    for target in TARGETS.iter() {
+        let generation = last_counters
+            .get(target)
+            .and_then(|last| last.generation)
+            .unwrap_or(0);
+        match KI.read_counters(backend, target, generation) {
+            Ok(counters) => current.push((*target, counters)),
+            Err(e) => {
+                warn!(
+                    "Error getting {:?} counters {:?} it will be counted next round",
+                    target, e
+                );
+                return Err(e);
+            }
         }
-    };
+    }
 
-    for (k, v) in input_counters {
-        *total_input_counters.entry(k).or_insert(0) += v
+    let mut deltas: HashMap<FilterTarget, HashMap<(IpAddr, String), u64>> = current
+        .into_iter()
+        .map(|(target, counters)| {
+            let last = last_counters
+                .entry(target)
+                .or_insert_with(Counters::default);
+            (target, counter_deltas(last, counters))
+        })
+        .collect();
+
+    if rotate {
+        for target in TARGETS.iter() {
+            let last = last_counters
+                .entry(*target)
+                .or_insert_with(Counters::default);
+            let next = last
+                .generation
+                .map_or(0, |generation| generation.wrapping_add(1));
+            match KI.rotate_counters(backend, target, next) {
+                Ok(old) => {
+                    if let Some(old) = old {
+                        let target_deltas = deltas.entry(*target).or_insert_with(HashMap::new);
+                        for (key, delta) in counter_deltas(last, old) {
+                            *target_deltas.entry(key).or_insert(0) += delta;
+                        }
+                    }
+                    *last = Counters {
+                        generation: Some(next),
+                        values: HashMap::new(),
+                    };
+                }
+                Err(e) => warn!("Failed to rotate {:?} counters {:?}", target, e),
+            }
+        }
     }
 
-    for (k, v) in fwd_input_counters {
-        *total_input_counters.entry(k).or_insert(0) += v
+    Ok(deltas)
+}
+
+/// Adds up the traffic for a set of targets
+fn total_counters(
+    deltas: &HashMap<FilterTarget, HashMap<(IpAddr, String), u64>>,
+    targets: &[FilterTarget],
+) -> HashMap<(IpAddr, String), u64> {
+    let mut total = HashMap::new();
+    for target in targets {
+        if let Some(counters) = deltas.get(target) {
+            trace!("Got {:?} counters: {:?}", target, counters);
+            for (k, v) in counters {
+                *total.entry(k.clone()).or_insert(0) += v
+            }
+        }
     }
+    total
+}
+
+pub fn get_input_counters(
+    deltas: &HashMap<FilterTarget, HashMap<(IpAddr, String), u64>>,
+) -> HashMap<(IpAddr, String), u64> {
+    let total_input_counters =
+        total_counters(deltas, &[FilterTarget::Input, FilterTarget::ForwardInput]);
     info!("Got final input counters: {:?}", total_input_counters);
 
-    let mut total_in: u64 = 0;
-    for entry in total_input_counters.iter() {
-        let input = entry.1;
-        total_in += input;
-    }
+    let total_in: u64 = total_input_counters.values().sum();
     info!("Total input of {} bytes this round", total_in);
 
-    Ok(total_input_counters)
+    total_input_counters
 }
 
-pub fn get_output_counters() -> Result<HashMap<(IpAddr, String), u64>, Error> {
-    let mut total_output_counters = HashMap::new();
-    trace!("Getting ouput counters");
-    let output_counters = match KI.read_counters(&FilterTarget::Output) {
-        Ok(res) => res,
-        Err(e) => {
-            warn!(
-                "Error getting output counters {:?} traffic has gone unaccounted!",
-                e
-            );
-            return Err(e);
-        }
-    };
-    trace!("Got output counters: {:?}", output_counters);
-
-    let fwd_output_counters = match KI.read_counters(&FilterTarget::ForwardOutput) {
-        Ok(res) => res,
-        Err(e) => {
-            warn!(
-                "Error getting input counters {:?} traffic has gone unaccounted!",
-                e
-            );
-            return Err(e);
-        }
-    };
-
-    for (k, v) in output_counters {
-        *total_output_counters.entry(k).or_insert(0) += v
-    }
-
-    for (k, v) in fwd_output_counters {
-        *total_output_counters.entry(k).or_insert(0) += v
-    }
+pub fn get_output_counters(
+    deltas: &HashMap<FilterTarget, HashMap<(IpAddr, String), u64>>,
+) -> HashMap<(IpAddr, String), u64> {
+    let total_output_counters =
+        total_counters(deltas, &[FilterTarget::Output, FilterTarget::ForwardOutput]);
     info!("Got final output counters: {:?}", total_output_counters);
 
-    let mut total_out: u64 = 0;
-    for entry in total_output_counters.iter() {
-        let output = entry.1;
-        total_out += output;
-    }
+    let total_out: u64 = total_output_counters.values().sum();
     info!("Total output of {} bytes this round", total_out);
 
-    Ok(total_output_counters)
+    total_output_counters
 }
 
-/// Takes and sumns the input and output counters for logging
 fn update_usage(
     input: &HashMap<(IpAddr, String), u64>,
     output: &HashMap<(IpAddr, String), u64>,
-    our_fee: u32,
+    (up_price, down_price): (u32, u32),
 ) {
     let mut total_in = 0;
     let mut total_out = 0;
@@ -250,34 +329,45 @@ fn update_usage(
         kind: UsageType::Relay,
         up: total_out,
         down: total_in,
-        price: our_fee,
+        up_price,
+        down_price,
     });
 }
 
-/// This traffic watcher watches how much traffic each neighbor sends to each destination
-/// between the last time watch was run, (This does _not_ block the thread)
-/// It also gathers the price to each destination from Babel and uses this information
-/// to calculate how much each neighbor owes. It returns a list of how much each neighbor owes.
-///
-/// This first time this is run, it will create the rules and then immediately read and zero them.
-/// (should return 0)
-pub fn watch<T: Read + Write>(babel: Babel<T>, neighbors: &[Neighbor]) -> Result<(), Error> {
-    let (identities, if_to_id) = prepare_helper_maps(neighbors);
-
-    let (destinations, local_fee) = get_babel_info(babel)?;
-
-    let total_input_counters = get_input_counters()?;
-    let total_output_counters = get_output_counters()?;
-    update_usage(&total_input_counters, &total_output_counters, local_fee);
+/// Our upload and download relay fees given the fee babel is actually advertising, which is
+/// what neighbors pay us. If babel isn't advertising the fee the settings ask for (no fees, or
+/// a fee babel hasn't been updated to yet) the advertised fee is all we can price by
+fn relay_prices(local_fee: u32) -> (u32, u32) {
+    let payment = SETTING.get_payment();
+    if local_fee == payment.get_relay_fee() {
+        (
+            payment.get_relay_upload_fee(),
+            payment.get_relay_download_fee(),
+        )
+    } else {
+        (local_fee, local_fee)
+    }
+}
 
+/// What each neighbor owes us (negative) or we owe them (positive) for a round of traffic.
+/// `local_fee` is the fee babel advertises for us, the mean of our upload and download relay
+/// fees, as that is the part of each destination's price neighbors pay us for relaying
+fn compute_debts(
+    identities: &HashMap<IpAddr, Identity>,
+    if_to_id: &HashMap<String, Identity>,
+    destinations: &HashMap<IpAddr, i128>,
+    total_input_counters: HashMap<(IpAddr, String), u64>,
+    total_output_counters: HashMap<(IpAddr, String), u64>,
+    local_fee: u32,
+) -> HashMap<Identity, i128> {
     // Flow counters should debit your neighbor which you received the packet from
     // Destination counters should credit your neighbor which you sent the packet to
 
     let mut debts = HashMap::new();
 
     // Setup the debts table
-    for (_, ident) in identities.clone() {
-        debts.insert(ident, 0i128);
+    for ident in identities.values() {
+        debts.insert(*ident, 0i128);
     }
 
     // We take the destination ip and input interface and then look up what local neighbor
@@ -330,6 +420,98 @@ pub fn watch<T: Read + Write>(babel: Babel<T>, neighbors: &[Neighbor]) -> Result
         }
     }
 
+    debts
+}
+
+/// Breaks the forwarded traffic down by neighbor and destination, only forwarded traffic is
+/// counted so that our own usage doesn't show up as relay costs
+fn relay_destinations(
+    deltas: &HashMap<FilterTarget, HashMap<(IpAddr, String), u64>>,
+    destinations: &HashMap<IpAddr, i128>,
+    if_to_id: &HashMap<String, Identity>,
+    local_fee: u32,
+) -> Vec<DestinationUsage> {
+    let no_counters = HashMap::new();
+    let forwarded_in = deltas
+        .get(&FilterTarget::ForwardInput)
+        .unwrap_or(&no_counters);
+    let forwarded_out = deltas
+        .get(&FilterTarget::ForwardOutput)
+        .unwrap_or(&no_counters);
+
+    let mut usage: HashMap<(IpAddr, IpAddr), DestinationUsage> = HashMap::new();
+    for (is_input, counters) in [(true, forwarded_in), (false, forwarded_out)].iter() {
+        for ((ip, interface), bytes) in counters.iter() {
+            let (price, id) = match (destinations.get(ip), if_to_id.get(interface)) {
+                (Some(price), Some(id)) => (*price, *id),
+                // watch warns about these
+                _ => continue,
+            };
+            let entry = usage
+                .entry((id.mesh_ip, *ip))
+                .or_insert_with(|| DestinationUsage {
+                    neighbor: id.mesh_ip,
+                    destination: *ip,
+                    up: 0,
+                    down: 0,
+                    // only shown on the dashboard, the revenue below uses the full price
+                    price: u32::try_from(price).unwrap_or(u32::max_value()),
+                    revenue: Int256::from(0),
+                });
+            // same amounts as the debts below
+            let revenue = if *is_input {
+                entry.down += bytes;
+                price * i128::from(*bytes)
+            } else {
+                entry.up += bytes;
+                -(price - i128::from(local_fee)) * i128::from(*bytes)
+            };
+            entry.revenue = entry.revenue.clone() + revenue.into();
+        }
+    }
+    usage.into_iter().map(|(_, usage)| usage).collect()
+}
+
+/// This traffic watcher watches how much traffic each neighbor sends to each destination
+/// between the last time watch was run, (This does _not_ block the thread)
+/// It also gathers the price to each destination from Babel and uses this information
+/// to calculate how much each neighbor owes. It returns a list of how much each neighbor owes.
+///
+/// The kernel counters are never reset, each round bills the difference from the last
+/// successful round so traffic is neither lost nor counted twice. With `rotate` the counter
+/// sets are also swapped for empty ones, see `read_counter_deltas`
+pub fn watch(
+    backend: CounterBackend,
+    last_counters: &mut HashMap<FilterTarget, Counters>,
+    rotate: bool,
+    babel_table: &BabelTable,
+    neighbors: &[Neighbor],
+) -> Result<(), Error> {
+    let (identities, if_to_id) = prepare_helper_maps(neighbors);
+
+    let (destinations, local_fee) = get_babel_info(babel_table)?;
+
+    let deltas = read_counter_deltas(backend, last_counters, rotate)?;
+    let total_input_counters = get_input_counters(&deltas);
+    let total_output_counters = get_output_counters(&deltas);
+    update_usage(
+        &total_input_counters,
+        &total_output_counters,
+        relay_prices(local_fee),
+    );
+    UsageTracker::from_registry().do_send(UpdateRelayDestinations {
+        usage: relay_destinations(&deltas, &destinations, &if_to_id, local_fee),
+    });
+
+    let debts = compute_debts(
+        &identities,
+        &if_to_id,
+        &destinations,
+        total_input_counters,
+        total_output_counters,
+        local_fee,
+    );
+
     trace!("Collated total Intermediary debts: {:?}", debts);
     info!("Computed Intermediary debts for {:?} peers", debts.len());
     let mut total_income = 0i128;
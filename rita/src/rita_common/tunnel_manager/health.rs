@@ -0,0 +1,166 @@
+//! Tunnel health checking, a tunnel can sit around with a dead WireGuard session or a
+//! neighbor babel can no longer hear for the entire tunnel timeout, these checks catch
+//! that early so that TunnelManager can try to repair the tunnel or tear it down
+
+use babel_monitor::Neighbor as BabelNeighbor;
+use std::fmt;
+use std::time::Duration;
+
+/// WireGuard re-handshakes every two minutes on an active session and our tunnels always
+/// carry keepalive traffic, so a handshake older than this means the session is dead
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(240);
+/// How long babel gets to pick up a newly monitored tunnel before we hold it against it
+pub const BABEL_GRACE_PERIOD: Duration = Duration::from_secs(60);
+/// Minimum time between repair attempts on a single tunnel
+pub const REPAIR_INTERVAL: Duration = Duration::from_secs(30);
+/// Babel's representation of an infinite cost
+const BABEL_INFINITY: u16 = 0xFFFF;
+
+#[derive(PartialEq, Debug, Clone, Copy, Eq, Hash, Serialize)]
+pub enum TunnelHealth {
+    /// Not checked yet, or too new to judge
+    Unknown,
+    /// Recent handshake and babel can hear the neighbor
+    Healthy,
+    /// Failed a health check, being repaired until the repair timeout runs out
+    Degraded,
+}
+
+impl fmt::Display for TunnelHealth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Judges the health of a single tunnel
+///
+/// `handshake_age` is the time since the last WireGuard handshake, None if there never was
+/// one. `babel_neigh` is babel's entry for the tunnel interface, the outer option is None
+/// when babel's opinion does not count, either because we could not reach babel or because
+/// the tunnel is not monitored.
+pub fn evaluate_health(
+    tunnel_age: Duration,
+    handshake_age: Option<Duration>,
+    babel_neigh: Option<Option<&BabelNeighbor>>,
+) -> TunnelHealth {
+    let handshake_ok = match handshake_age {
+        Some(age) => age < HANDSHAKE_TIMEOUT,
+        None => tunnel_age < HANDSHAKE_TIMEOUT,
+    };
+    if !handshake_ok {
+        return TunnelHealth::Degraded;
+    }
+
+    match babel_neigh {
+        Some(Some(neigh)) => {
+            if neigh.reach != 0 && neigh.rxcost != BABEL_INFINITY {
+                TunnelHealth::Healthy
+            } else if tunnel_age < BABEL_GRACE_PERIOD {
+                TunnelHealth::Unknown
+            } else {
+                TunnelHealth::Degraded
+            }
+        }
+        Some(None) => {
+            if tunnel_age < BABEL_GRACE_PERIOD {
+                TunnelHealth::Unknown
+            } else {
+                TunnelHealth::Degraded
+            }
+        }
+        None => {
+            if handshake_age.is_some() {
+                TunnelHealth::Healthy
+            } else {
+                TunnelHealth::Unknown
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn neigh(reach: u16, rxcost: u16) -> BabelNeighbor {
+        BabelNeighbor {
+            id: "14f19a8".to_string(),
+            address: "fe80::2cee:2fff:648:8796".parse().unwrap(),
+            iface: "wg0".to_string(),
+            reach,
+            txcost: 256,
+            rxcost,
+            rtt: 26.723,
+            rttcost: 912,
+            cost: 1168,
+        }
+    }
+
+    #[test]
+    fn test_healthy_tunnel() {
+        let n = neigh(0xffff, 256);
+        assert_eq!(
+            evaluate_health(
+                Duration::from_secs(600),
+                Some(Duration::from_secs(30)),
+                Some(Some(&n))
+            ),
+            TunnelHealth::Healthy
+        );
+    }
+
+    #[test]
+    fn test_stale_handshake() {
+        let n = neigh(0xffff, 256);
+        assert_eq!(
+            evaluate_health(
+                Duration::from_secs(600),
+                Some(Duration::from_secs(400)),
+                Some(Some(&n))
+            ),
+            TunnelHealth::Degraded
+        );
+        assert_eq!(
+            evaluate_health(Duration::from_secs(600), None, None),
+            TunnelHealth::Degraded
+        );
+    }
+
+    #[test]
+    fn test_babel_unreachable_neighbor() {
+        let n = neigh(0, 0xffff);
+        assert_eq!(
+            evaluate_health(
+                Duration::from_secs(600),
+                Some(Duration::from_secs(30)),
+                Some(Some(&n))
+            ),
+            TunnelHealth::Degraded
+        );
+        assert_eq!(
+            evaluate_health(
+                Duration::from_secs(600),
+                Some(Duration::from_secs(30)),
+                Some(None)
+            ),
+            TunnelHealth::Degraded
+        );
+    }
+
+    #[test]
+    fn test_new_tunnel_grace() {
+        assert_eq!(
+            evaluate_health(Duration::from_secs(10), None, Some(None)),
+            TunnelHealth::Unknown
+        );
+        let n = neigh(0, 0xffff);
+        assert_eq!(
+            evaluate_health(
+                Duration::from_secs(10),
+                Some(Duration::from_secs(5)),
+                Some(Some(&n))
+            ),
+            TunnelHealth::Unknown
+        );
+    }
+}
@@ -4,9 +4,19 @@
 //! up tunnels if they respond, likewise if someone calls us their hello goes through network_endpoints
 //! then into TunnelManager to open a tunnel for them.
 
+mod health;
+mod ports;
+
+pub use self::health::TunnelHealth;
+pub use self::ports::PortStats;
+
+use self::health::{evaluate_health, REPAIR_INTERVAL};
+use self::ports::PortAllocator;
 use crate::rita_common;
+use crate::rita_common::babel_client::{BabelCommand, BabelCommander};
+use crate::rita_common::babel_config::tunnel_config;
 use crate::rita_common::hello_handler::Hello;
-use crate::rita_common::peer_listener::Peer;
+use crate::rita_common::peer_listener::{is_link_local, Peer};
 use crate::KI;
 use crate::SETTING;
 #[cfg(test)]
@@ -15,19 +25,17 @@ use ::actix::actors::resolver;
 use ::actix::prelude::{Actor, Arbiter, Context, Handler, Message, Supervised, SystemService};
 use althea_types::Identity;
 use althea_types::LocalIdentity;
-use babel_monitor::open_babel_stream;
-use babel_monitor::Babel;
+use babel_monitor::Neighbor as BabelNeighbor;
 use failure::Error;
 use futures::Future;
-use rand::thread_rng;
-use rand::Rng;
+use settings::network::{NeighborShaping, ShapingPriority};
 use settings::RitaCommonSettings;
-use std::collections::HashMap;
+use std::cmp::min;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt;
-use std::io::{Read, Write};
-use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 #[cfg(test)]
 type HelloHandler = Mocker<rita_common::hello_handler::HelloHandler>;
 #[cfg(not(test))]
@@ -124,9 +132,14 @@ pub struct Tunnel {
     pub ip: IpAddr,              // Tunnel endpoint
     pub iface_name: String,      // name of wg#
     pub listen_ifidx: u32,       // the physical interface this tunnel is listening on
+    pub manual: bool,            // the other end is a manual peer, possibly behind NAT
     pub listen_port: u16,        // the local port this tunnel is listening on
     pub neigh_id: LocalIdentity, // the identity of the counterparty tunnel
     pub last_contact: Instant,   // When's the last we heard from the other end of this tunnel?
+    pub created: Instant,        // When this tunnel was opened
+    pub health: TunnelHealth,    // result of the most recent health check
+    unhealthy_since: Option<Instant>,
+    last_repair: Option<Instant>,
     state: TunnelState,
 }
 
@@ -136,15 +149,21 @@ impl Tunnel {
         iface_name: String,
         our_listen_port: u16,
         ifidx: u32,
+        manual: bool,
         their_id: LocalIdentity,
     ) -> Tunnel {
         Tunnel {
             ip,
             iface_name,
             listen_ifidx: ifidx,
+            manual,
             listen_port: our_listen_port,
             neigh_id: their_id,
             last_contact: Instant::now(),
+            created: Instant::now(),
+            health: TunnelHealth::Unknown,
+            unhealthy_since: None,
+            last_repair: None,
             // By default new tunnels are in Registered state
             state: TunnelState {
                 payment_state: PaymentState::Paid,
@@ -169,29 +188,114 @@ impl Tunnel {
             network.external_nic.clone(),
             &mut SETTING.get_network_mut().default_route,
         )?;
-        KI.set_codel_shaping(&self.iface_name)
+        let shaping = SETTING
+            .get_network()
+            .get_neighbor_shaping(&self.neigh_id.global.wg_public_key);
+        // limits from a hand edited config that are out of range are ignored
+        match shaping
+            .filter(NeighborShaping::bandwidth_is_valid)
+            .and_then(|shaping| shaping.max_bandwidth)
+        {
+            Some(bw) => KI.set_classless_limit(&self.iface_name, bw)?,
+            None => KI.set_codel_shaping(&self.iface_name)?,
+        }
+        self.set_priority()
     }
 
-    /// Register this tunnel into Babel monitor
-    pub fn monitor<T: Read + Write>(&self, stream: T) -> Result<(), Error> {
+    /// Marks the traffic of this tunnel with the operator set priority for this neighbor
+    fn set_priority(&self) -> Result<(), Error> {
+        let priority = SETTING
+            .get_network()
+            .get_neighbor_shaping(&self.neigh_id.global.wg_public_key)
+            .map(|shaping| shaping.priority)
+            .unwrap_or_default();
+        let class = match priority {
+            ShapingPriority::Low => Some("CS1"),
+            ShapingPriority::Normal => None,
+            ShapingPriority::High => Some("CS6"),
+        };
+        if class.is_some() {
+            // the marks only matter if the qdisc on the interface the packets leave through
+            // looks at them
+            match self.egress_iface() {
+                Some(iface) => {
+                    if let Err(e) = KI.set_diffserv_egress(&iface) {
+                        warn!(
+                            "The priority of {} is only honored upstream {:?}",
+                            self.iface_name, e
+                        );
+                    }
+                }
+                None => warn!(
+                    "Can't find the physical interface of {}, its priority is only honored upstream",
+                    self.iface_name
+                ),
+            }
+        }
+        KI.set_tunnel_dscp(self.listen_port, class)
+    }
+
+    /// The physical interface the encrypted packets of this tunnel leave through, tunnels to
+    /// peers that aren't on a local link go out the external nic
+    fn egress_iface(&self) -> Option<String> {
+        if self.listen_ifidx != 0 {
+            KI.get_iface_name(self.listen_ifidx).ok()
+        } else {
+            SETTING.get_network().external_nic.clone()
+        }
+    }
+
+    /// Removes the priority marks of this tunnel and deletes its interface
+    fn del_interface(&self) -> Result<(), Error> {
+        if let Err(e) = KI.set_tunnel_dscp(self.listen_port, None) {
+            warn!(
+                "Failed to remove the priority of {} with {:?}",
+                self.iface_name, e
+            );
+        }
+        KI.del_interface(&self.iface_name)
+    }
+
+    /// Register this tunnel into Babel monitor, or update its parameters if it already is.
+    /// Babel is told in the background, if it fails the tunnel never becomes healthy and the
+    /// health checks take it down
+    pub fn monitor(&self) {
         info!("Monitoring tunnel {}", self.iface_name);
-        let mut babel = Babel::new(stream);
-        babel.start_connection()?;
-        babel.monitor(&self.iface_name)?;
-        Ok(())
+        BabelCommander::from_registry().do_send(BabelCommand::ConfigureInterface(
+            self.iface_name.clone(),
+            tunnel_config(&self.iface_name, self.listen_ifidx),
+        ));
     }
 
-    pub fn unmonitor<T: Read + Write>(&self, stream: T) -> Result<(), Error> {
+    pub fn unmonitor(&self) {
         warn!("Unmonitoring tunnel {}", self.iface_name);
-        let mut babel = Babel::new(stream);
-        babel.start_connection()?;
-        babel.unmonitor(&self.iface_name)?;
-        Ok(())
+        BabelCommander::from_registry().do_send(BabelCommand::Unmonitor(self.iface_name.clone()));
+    }
+
+    /// The Peer struct used to send hellos to the other end of this tunnel
+    fn peer(&self) -> Peer {
+        match self.ip {
+            IpAddr::V6(ip) => Peer {
+                manual: self.manual,
+                ..Peer::new(ip, self.listen_ifidx)
+            },
+            IpAddr::V4(_) => Peer {
+                ifidx: self.listen_ifidx,
+                contact_socket: SocketAddr::new(self.ip, SETTING.get_network().rita_hello_port),
+                manual: self.manual,
+            },
+        }
+    }
+
+    /// Unmonitors this tunnel and deletes the underlying interface
+    pub fn close(&self) -> Result<(), Error> {
+        self.unmonitor();
+        self.del_interface()
     }
 }
 
 pub struct TunnelManager {
-    free_ports: Vec<u16>,
+    ports: PortAllocator,
     tunnels: HashMap<Identity, Vec<Tunnel>>,
 }
 
@@ -247,10 +351,10 @@ impl Handler<IdentityCallback> for TunnelManager {
     fn handle(&mut self, msg: IdentityCallback, _: &mut Context<Self>) -> Self::Result {
         let our_port = match msg.our_port {
             Some(port) => port,
-            _ => match self.get_port(0) {
-                Some(p) => p,
-                None => {
-                    warn!("Failed to allocate tunnel port! All tunnel opening will fail");
+            _ => match self.get_port() {
+                Ok(p) => p,
+                Err(e) => {
+                    warn!("Failed to allocate tunnel port! {:?}", e);
                     return None;
                 }
             },
@@ -279,16 +383,10 @@ impl Handler<PortCallback> for TunnelManager {
 
     fn handle(&mut self, msg: PortCallback, _: &mut Context<Self>) -> Self::Result {
         let port = msg.0;
-        self.free_ports.push(port);
+        self.ports.release(port);
     }
 }
 
-pub fn make_babel_stream() -> Result<TcpStream, Error> {
-    let stream = open_babel_stream(SETTING.get_network().babel_port)?;
-
-    Ok(stream)
-}
-
 pub struct GetNeighbors;
 
 #[derive(Debug)]
@@ -296,14 +394,21 @@ pub struct Neighbor {
     pub identity: LocalIdentity,
     pub iface_name: String,
     pub tunnel_ip: IpAddr,
+    pub health: TunnelHealth,
 }
 
 impl Neighbor {
-    fn new(identity: LocalIdentity, iface_name: String, tunnel_ip: IpAddr) -> Neighbor {
+    fn new(
+        identity: LocalIdentity,
+        iface_name: String,
+        tunnel_ip: IpAddr,
+        health: TunnelHealth,
+    ) -> Neighbor {
         Neighbor {
             identity,
             iface_name,
             tunnel_ip,
+            health,
         }
     }
 }
@@ -322,6 +427,7 @@ impl Handler<GetNeighbors> for TunnelManager {
                     tunnel.neigh_id,
                     tunnel.iface_name.clone(),
                     tunnel.ip,
+                    tunnel.health,
                 ));
             }
         }
@@ -329,6 +435,21 @@ impl Handler<GetNeighbors> for TunnelManager {
     }
 }
 
+/// Reports the state of the tunnel port allocator
+pub struct GetPortStats;
+
+impl Message for GetPortStats {
+    type Result = Result<PortStats, Error>;
+}
+
+impl Handler<GetPortStats> for TunnelManager {
+    type Result = Result<PortStats, Error>;
+
+    fn handle(&mut self, _: GetPortStats, _: &mut Context<Self>) -> Self::Result {
+        Ok(self.ports.stats())
+    }
+}
+
 /// A message type for deleting all tunnels we haven't heard from for more than the duration.
 pub struct TriggerGC(pub Duration);
 
@@ -373,34 +494,183 @@ impl Handler<TriggerGC> for TunnelManager {
         // The former would be a mere performance bug while inconsistent-with-reality Rita state
         // would lead to nasty bugs in case del_interface() goes wrong for whatever reason.
         self.tunnels = good;
+        self.reconcile_ports();
 
         for (_ident, tunnels) in timed_out {
             for tunnel in tunnels {
                 // In the same spirit, we return the port to the free port pool only after tunnel
                 // deletion goes well.
-                let stream = match make_babel_stream() {
-                    Ok(stream) => stream,
-                    Err(e) => {
-                        warn!("Tunnel GC failed to open babel stream with {:?}", e);
-                        return Err(e);
+                tunnel.close()?;
+                self.ports.release(tunnel.listen_port);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks the WireGuard handshake and babel neighbor state of every tunnel, unhealthy tunnels
+/// are repaired and if that doesn't work out before the repair timeout they are deleted
+pub struct CheckTunnelHealth {
+    /// Babel's neighbors, None if babel could not be reached this round
+    pub babel_neighs: Option<VecDeque<BabelNeighbor>>,
+}
+
+impl Message for CheckTunnelHealth {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<CheckTunnelHealth> for TunnelManager {
+    type Result = Result<(), Error>;
+    fn handle(&mut self, msg: CheckTunnelHealth, _ctx: &mut Context<Self>) -> Self::Result {
+        let handshakes = KI.get_latest_handshakes()?;
+        let babel_neighs = msg.babel_neighs;
+        let repair_timeout =
+            Duration::from_secs(SETTING.get_network().tunnel_repair_timeout_seconds);
+        let now = SystemTime::now();
+
+        let mut to_repair = Vec::new();
+        let mut to_close = Vec::new();
+        for tunnels in self.tunnels.values_mut() {
+            for tunnel in tunnels.iter_mut() {
+                let key = tunnel.neigh_id.global.wg_public_key;
+                let handshake_age = handshakes
+                    .iter()
+                    .find(|(iface, peer, _)| *iface == tunnel.iface_name && *peer == key)
+                    .and_then(|(_, _, time)| *time)
+                    .map(|time| now.duration_since(time).unwrap_or_default());
+                let babel_neigh = match (&babel_neighs, tunnel.state.registration_state) {
+                    (Some(neighs), RegistrationState::Registered) => {
+                        Some(neighs.iter().find(|neigh| neigh.iface == tunnel.iface_name))
                     }
+                    _ => None,
                 };
-                let mut babel = Babel::new(stream);
-                let res = babel.start_connection();
-                if res.is_err() {
-                    warn!("Failed to start Babel RPC connection! {:?}", res);
-                    bail!("Failed to start Babel RPC connection!");
+
+                tunnel.health =
+                    evaluate_health(tunnel.created.elapsed(), handshake_age, babel_neigh);
+                match tunnel.health {
+                    TunnelHealth::Healthy | TunnelHealth::Unknown => tunnel.unhealthy_since = None,
+                    TunnelHealth::Degraded => {
+                        let since = *tunnel.unhealthy_since.get_or_insert_with(Instant::now);
+                        if since.elapsed() > repair_timeout {
+                            to_close.push(tunnel.clone());
+                        } else if tunnel
+                            .last_repair
+                            .map_or(true, |last| last.elapsed() > REPAIR_INTERVAL)
+                        {
+                            tunnel.last_repair = Some(Instant::now());
+                            to_repair.push(tunnel.clone());
+                        }
+                    }
                 }
+            }
+        }
 
-                let res = babel.unmonitor(&tunnel.iface_name);
-                if res.is_err() {
-                    warn!("Failed to unmonitor {} with {:?}", tunnel.iface_name, res);
+        for tunnel in to_repair {
+            self.repair_tunnel(&tunnel);
+        }
+
+        for tunnel in to_close {
+            warn!(
+                "Tunnel {} to {:?} could not be repaired, removing",
+                tunnel.iface_name, tunnel.neigh_id.global
+            );
+            self.remove_tunnel(&tunnel);
+            match tunnel.close() {
+                Ok(_) => self.ports.release(tunnel.listen_port),
+                Err(e) => warn!("Failed to close tunnel {} with {:?}", tunnel.iface_name, e),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Tears down any tunnels the peer access control list no longer allows, sent whenever
+/// the acl is modified
+pub struct EnforcePeerAcl;
+
+impl Message for EnforcePeerAcl {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<EnforcePeerAcl> for TunnelManager {
+    type Result = Result<(), Error>;
+    fn handle(&mut self, _: EnforcePeerAcl, _ctx: &mut Context<Self>) -> Self::Result {
+        let acl = SETTING.get_network().peer_acl.clone();
+        let mut blocked = Vec::new();
+        for (identity, tunnels) in self.tunnels.iter_mut() {
+            let allowed = acl.is_allowed(identity);
+            tunnels.retain(|tunnel| {
+                if allowed && !acl.is_ip_blocked(&tunnel.ip) {
+                    true
+                } else {
+                    blocked.push(tunnel.clone());
+                    false
+                }
+            });
+        }
+        self.tunnels.retain(|_, tunnels| !tunnels.is_empty());
+
+        for tunnel in blocked {
+            info!(
+                "Removing tunnel {} to {:?}, blocked by the peer acl",
+                tunnel.iface_name, tunnel.neigh_id.global
+            );
+            match tunnel.close() {
+                Ok(_) => self.ports.release(tunnel.listen_port),
+                Err(e) => warn!("Failed to close tunnel {} with {:?}", tunnel.iface_name, e),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Reapplies bandwidth limits and priorities to all tunnels, sent whenever the operator
+/// changes the per neighbor shaping settings
+pub struct ApplyNeighborShaping;
+
+impl Message for ApplyNeighborShaping {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<ApplyNeighborShaping> for TunnelManager {
+    type Result = Result<(), Error>;
+    fn handle(&mut self, _: ApplyNeighborShaping, _ctx: &mut Context<Self>) -> Self::Result {
+        for tunnels in self.tunnels.values() {
+            for tunnel in tunnels.iter() {
+                if let Err(e) = tunnel.set_priority() {
+                    warn!(
+                        "Failed to set priority for {} with {:?}",
+                        tunnel.iface_name, e
+                    );
                 }
-                KI.del_interface(&tunnel.iface_name)?;
-                self.free_ports.push(tunnel.listen_port);
             }
         }
+        tunnel_bw_limit_update(&self.tunnels)
+    }
+}
+
+/// Sends the babel parameters for every monitored tunnel to babel again, sent whenever the
+/// operator changes the babel interface settings
+pub struct ConfigureBabelInterfaces;
 
+impl Message for ConfigureBabelInterfaces {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<ConfigureBabelInterfaces> for TunnelManager {
+    type Result = Result<(), Error>;
+    fn handle(&mut self, _: ConfigureBabelInterfaces, _ctx: &mut Context<Self>) -> Self::Result {
+        for tunnels in self.tunnels.values() {
+            for tunnel in tunnels.iter() {
+                if tunnel.state.registration_state != RegistrationState::Registered {
+                    continue;
+                }
+                tunnel.monitor();
+            }
+        }
         Ok(())
     }
 }
@@ -431,35 +701,35 @@ impl Handler<PeersToContact> for TunnelManager {
                 warn!("Neighbor inqury for {:?} failed! with {:?}", peer, res);
             }
         }
-        // Do not contact manual peers if we are not a gateway
-        if SETTING.get_network().is_gateway {
-            for manual_peer in SETTING.get_network().manual_peers.iter() {
-                let ip = manual_peer.parse::<IpAddr>();
-                let port = SETTING.get_network().rita_hello_port;
-
-                match ip {
-                    Ok(ip) => {
-                        let socket = SocketAddr::new(ip, port);
-                        let man_peer = Peer {
-                            ifidx: 0,
-                            contact_socket: socket,
-                        };
-                        let res = self.neighbor_inquiry(&man_peer);
-                        if res.is_err() {
-                            warn!(
-                                "Neighbor inqury for {:?} failed with: {:?}",
-                                manual_peer, res
-                            );
-                        }
+        // manual peers may be anywhere on the internet, over ipv4 or ipv6 and behind NAT
+        let manual_peers = SETTING.get_network().manual_peers.clone();
+        for manual_peer in manual_peers.iter() {
+            let ip = manual_peer.parse::<IpAddr>();
+            let port = SETTING.get_network().rita_hello_port;
+
+            match ip {
+                Ok(ip) => {
+                    if SETTING.get_network().peer_acl.is_ip_blocked(&ip) {
+                        trace!("Not contacting blocked manual peer {:?}", ip);
+                        continue;
                     }
-                    Err(_) => {
-                        let res = self.neighbor_inquiry_hostname(manual_peer.to_string());
-                        if res.is_err() {
-                            warn!(
-                                "Neighbor inqury for {:?} failed with: {:?}",
-                                manual_peer, res
-                            );
-                        }
+                    let socket = SocketAddr::new(ip, port);
+                    let man_peer = Peer::manual(socket);
+                    let res = self.neighbor_inquiry(&man_peer);
+                    if res.is_err() {
+                        warn!(
+                            "Neighbor inqury for {:?} failed with: {:?}",
+                            manual_peer, res
+                        );
+                    }
+                }
+                Err(_) => {
+                    let res = self.neighbor_inquiry_hostname(manual_peer.to_string());
+                    if res.is_err() {
+                        warn!(
+                            "Neighbor inqury for {:?} failed with: {:?}",
+                            manual_peer, res
+                        );
                     }
                 }
             }
@@ -470,10 +740,13 @@ impl Handler<PeersToContact> for TunnelManager {
 /// Sets out to contact a neighbor, takes a speculative port (only assigned if the neighbor
 /// responds successfully)
 fn contact_neighbor(peer: &Peer, our_port: u16) -> Result<(), Error> {
-    KI.manual_peers_route(
-        &peer.contact_socket.ip(),
-        &mut SETTING.get_network_mut().default_route,
-    )?;
+    // gateways keep manual peers off the exit tunnel by pinning them to the wan route
+    if peer.ifidx == 0 && SETTING.get_network().is_gateway {
+        KI.manual_peers_route(
+            &peer.contact_socket.ip(),
+            &mut SETTING.get_network_mut().default_route,
+        )?;
+    }
 
     HelloHandler::from_registry().do_send(Hello {
         my_id: LocalIdentity {
@@ -527,49 +800,83 @@ fn del_tunnel(to_del: &Tunnel, tunnels: &mut Vec<Tunnel>) {
 impl TunnelManager {
     pub fn new() -> Self {
         let start = SETTING.get_network().wg_start_port;
-        let ports = (start..65535).collect();
         TunnelManager {
-            free_ports: ports,
+            ports: PortAllocator::new(start, 65535),
             tunnels: HashMap::new(),
         }
     }
 
     /// Gets a port off of the internal port list after checking that said port is free
-    /// with the operating system, level argument is always zero for callers and is used
-    /// interally to prevent unchecked recursion
-    fn get_port(&mut self, level: usize) -> Option<u16> {
-        let udp_table = KI.used_ports();
-        let mut rng = thread_rng();
-        let val = rng.gen_range(0, self.free_ports.len());
-        let port = self.free_ports.remove(val);
-        match (port, udp_table) {
-            (p, Ok(used_ports)) => {
-                if used_ports.contains(&p) {
-                    warn!(
-                        "We tried to allocate a used port {}!, there are {} ports remaining",
-                        p,
-                        self.free_ports.len()
-                    );
+    /// with the operating system
+    fn get_port(&mut self) -> Result<u16, Error> {
+        // better not to open an individual tunnel than it is to risk having a failed one
+        let used_ports = KI.used_ports()?;
+        self.ports.allocate(&used_ports)
+    }
 
-                    if level < 10 {
-                        self.free_ports.push(p);
-                        self.get_port(level + 1)
-                    } else {
-                        // we've tried a bunch of ports and all are used
-                        // break recusion and die, hopefully to be restarted in 15min
-                        error!("We ran out of ports!");
-                        panic!("We ran out of ports!");
-                    }
-                } else {
-                    Some(p)
-                }
+    /// Rebuilds the list of free ports from our tunnels and the ports the OS reports as
+    /// in use, reclaiming ports leaked by hellos that never completed
+    fn reconcile_ports(&mut self) {
+        let tunnel_ports: HashSet<u16> = self
+            .tunnels
+            .values()
+            .flat_map(|tunnels| tunnels.iter().map(|tunnel| tunnel.listen_port))
+            .collect();
+        match KI.used_ports() {
+            Ok(used_ports) => self.ports.reconcile(&tunnel_ports, &used_ports),
+            Err(e) => warn!("Failed to get used ports for reconciliation {:?}", e),
+        }
+    }
+
+    /// Attempts to bring a tunnel that failed its health check back without tearing it down
+    fn repair_tunnel(&mut self, tunnel: &Tunnel) {
+        info!(
+            "Attempting to repair tunnel {} to {:?}",
+            tunnel.iface_name, tunnel.neigh_id.global
+        );
+        // WireGuard may have roamed to a stale endpoint, point it back to where we last
+        // heard from our neighbor. Manual peers may be behind NAT where the address we
+        // contacted is not the one their packets come from, so there roaming is left alone
+        if !tunnel.manual {
+            let endpoint = SocketAddr::new(tunnel.ip, tunnel.neigh_id.wg_port);
+            if let Err(e) = KI.set_peer_endpoint(
+                &tunnel.iface_name,
+                &tunnel.neigh_id.global.wg_public_key,
+                &endpoint,
+            ) {
+                warn!(
+                    "Failed to reset endpoint for {} with {:?}",
+                    tunnel.iface_name, e
+                );
             }
-            (_p, Err(e)) => {
-                // better not to open an individual tunnel than it is to
-                // risk having a failed one
-                warn!("Failed to check if port was in use! {:?}", e);
-                None
+        }
+        // a link local address is useless without the interface it's on
+        if is_link_local(tunnel.ip) && tunnel.listen_ifidx == 0 {
+            warn!(
+                "Can't send a repair hello for {}, the interface of {} is unknown",
+                tunnel.iface_name, tunnel.ip
+            );
+            return;
+        }
+        // if our neighbor has lost their end of the tunnel their hello response says so
+        // and open_tunnel rebuilds it
+        if let Err(e) = self.neighbor_inquiry(&tunnel.peer()) {
+            warn!("Repair hello for {} failed with {:?}", tunnel.iface_name, e);
+        }
+    }
+
+    /// Removes a tunnel from the internal map, does not touch the actual interface
+    fn remove_tunnel(&mut self, tunnel: &Tunnel) {
+        let key = tunnel.neigh_id.global;
+        let now_empty = match self.tunnels.get_mut(&key) {
+            Some(tunnels) => {
+                tunnels.retain(|val| val.iface_name != tunnel.iface_name);
+                tunnels.is_empty()
             }
+            None => false,
+        };
+        if now_empty {
+            self.tunnels.remove(&key);
         }
     }
 
@@ -579,13 +886,11 @@ impl TunnelManager {
     pub fn neighbor_inquiry_hostname(&mut self, their_hostname: String) -> Result<(), Error> {
         trace!("Getting tunnel, inq");
 
-        let our_port = match self.get_port(0) {
-            Some(p) => p,
-            None => {
+        let our_port = match self.get_port() {
+            Ok(p) => p,
+            Err(e) => {
                 warn!("Failed to allocate tunnel port! All tunnel opening will fail");
-                return Err(
-                    TunnelManagerError::PortError("No remaining ports!".to_string()).into(),
-                );
+                return Err(e);
             }
         };
 
@@ -595,39 +900,38 @@ impl TunnelManager {
             .then(move |res| match res {
                 Ok(Ok(dnsresult)) => {
                     let port = SETTING.get_network().rita_hello_port;
-                    let url = format!("http://[{}]:{}/hello", their_hostname, port);
-                    trace!("Saying hello to: {:?} at ip {:?}", url, dnsresult);
-                    if !dnsresult.is_empty() && SETTING.get_network().is_gateway {
+                    trace!(
+                        "Saying hello to: {:?} at ip {:?}",
+                        their_hostname,
+                        dnsresult
+                    );
+                    if !dnsresult.is_empty() {
                         // dns records may have many ip's if we get multiple it's a load
                         // balanced exit and we need to create tunnels to all of them
                         for dns_socket in dnsresult {
                             let their_ip = dns_socket.ip();
                             let socket = SocketAddr::new(their_ip, port);
-                            let man_peer = Peer {
-                                ifidx: 0,
-                                contact_socket: socket,
-                            };
+                            let man_peer = Peer::manual(socket);
                             let res = contact_neighbor(&man_peer, our_port);
                             if res.is_err() {
                                 warn!("Contact neighbor failed with {:?}", res);
                             }
                         }
                     } else {
-                        trace!(
-                            "We're not a gateway or we got a zero length dns response: {:?}",
-                            dnsresult
-                        );
+                        trace!("We got a zero length dns response: {:?}", dnsresult);
+                        TunnelManager::from_registry().do_send(PortCallback(our_port));
                     }
                     Ok(())
                 }
                 Err(e) => {
                     warn!("Actor mailbox failure from DNS resolver! {:?}", e);
-                    // We might need a port callback here
+                    TunnelManager::from_registry().do_send(PortCallback(our_port));
                     Ok(())
                 }
 
                 Ok(Err(e)) => {
                     warn!("DNS resolution failed with {:?}", e);
+                    TunnelManager::from_registry().do_send(PortCallback(our_port));
                     Ok(())
                 }
             });
@@ -639,17 +943,19 @@ impl TunnelManager {
     /// interface name.
     pub fn neighbor_inquiry(&mut self, peer: &Peer) -> Result<(), Error> {
         trace!("TunnelManager neigh inquiry for {:?}", peer);
-        let our_port = match self.get_port(0) {
-            Some(p) => p,
-            None => {
+        let our_port = match self.get_port() {
+            Ok(p) => p,
+            Err(e) => {
                 warn!("Failed to allocate tunnel port! All tunnel opening will fail");
-                return Err(
-                    TunnelManagerError::PortError("No remaining ports!".to_string()).into(),
-                );
+                return Err(e);
             }
         };
 
-        contact_neighbor(peer, our_port)
+        let res = contact_neighbor(peer, our_port);
+        if res.is_err() {
+            self.ports.release(our_port);
+        }
+        res
     }
 
     /// Given a LocalIdentity, connect to the neighbor over wireguard
@@ -661,10 +967,48 @@ impl TunnelManager {
         our_port: u16,
     ) -> Result<(Tunnel, bool), Error> {
         trace!("getting existing tunnel or opening a new one");
+        {
+            let network = SETTING.get_network();
+            let acl = &network.peer_acl;
+            if !acl.is_allowed(&their_localid.global)
+                || acl.is_ip_blocked(&peer.contact_socket.ip())
+            {
+                self.ports.release(our_port);
+                bail!("Peer {:?} is blocked by the peer acl", their_localid.global);
+            }
+        }
         // ifidx must be a part of the key so that we can open multiple tunnels
         // if we have more than one physical connection to the same peer
         let key = their_localid.global;
 
+        // Our neighbor's link local address changed but it's still the same neighbor on the
+        // same interface, repoint the existing tunnel rather than building a second one and
+        // leaving the first to time out
+        if peer.ifidx != 0 {
+            if let Some(tunnels) = self.tunnels.get_mut(&key) {
+                for tunnel in tunnels.iter_mut() {
+                    if tunnel.listen_ifidx == peer.ifidx && tunnel.ip != peer.contact_socket.ip() {
+                        info!(
+                            "Neighbor {:?} moved from {:?} to {:?}, updating endpoint",
+                            key,
+                            tunnel.ip,
+                            peer.contact_socket.ip()
+                        );
+                        tunnel.ip = peer.contact_socket.ip();
+                        let endpoint = SocketAddr::new(tunnel.ip, their_localid.wg_port);
+                        if let Err(e) =
+                            KI.set_peer_endpoint(&tunnel.iface_name, &key.wg_public_key, &endpoint)
+                        {
+                            warn!(
+                                "Failed to update endpoint for {} with {:?}",
+                                tunnel.iface_name, e
+                            );
+                        }
+                    }
+                }
+            }
+        }
+
         let we_have_tunnel = match self.tunnels.get(&key) {
             Some(tunnels) => {
                 have_tunnel_by_ifidx(peer.ifidx, tunnels)
@@ -698,7 +1042,7 @@ impl TunnelManager {
 
             if they_have_tunnel {
                 // return allocated port as it's not required
-                self.free_ports.push(our_port);
+                self.ports.release(our_port);
                 trace!("Looking up for a tunnels by {:?}", key);
                 // Unwrap is safe because we confirm membership
                 let tunnels = &self.tunnels[&key];
@@ -739,7 +1083,7 @@ impl TunnelManager {
                 }
 
                 // Remove interface
-                let res = KI.del_interface(&tunnel.iface_name);
+                let res = tunnel.del_interface();
                 if res.is_err() {
                     warn!(
                         "We failed to delete the interface {:?} with {:?} it's now orphaned",
@@ -747,7 +1091,7 @@ impl TunnelManager {
                     );
                 }
 
-                self.free_ports.push(tunnel.listen_port);
+                self.ports.release(tunnel.listen_port);
                 return_bool = true;
             }
         }
@@ -762,6 +1106,7 @@ impl TunnelManager {
             KI.setup_wg_if().unwrap(),
             our_port,
             peer.ifidx,
+            peer.manual,
             their_localid,
         );
         // Open tunnel
@@ -772,24 +1117,15 @@ impl TunnelManager {
                 return Err(e);
             }
         }
-        match tunnel.monitor(make_babel_stream()?) {
-            Ok(_) => {
-                let new_key = tunnel.neigh_id.global;
-                // Add a tunnel to internal map based on identity, and interface index.
-                self.tunnels
-                    .entry(new_key)
-                    .or_insert_with(Vec::new)
-                    .push(tunnel.clone());
-                Ok((tunnel, return_bool))
-            }
-            Err(e) => {
-                error!(
-                    "Unable to execute babel monitor on tunnel {:?}: {}",
-                    tunnel, e
-                );
-                Err(e)
-            }
-        }
+        tunnel.monitor();
+        let new_key = tunnel.neigh_id.global;
+        // Add a tunnel to internal map based on identity, and interface index.
+        self.tunnels
+            .entry(new_key)
+            .or_insert_with(Vec::new)
+            .push(tunnel.clone());
+        self.ports.confirm(our_port);
+        Ok((tunnel, return_bool))
     }
 }
 
@@ -848,7 +1184,7 @@ fn tunnel_state_change(
                         );
                         match tunnel.state.registration_state {
                             RegistrationState::NotRegistered => {
-                                tunnel.monitor(make_babel_stream()?)?;
+                                tunnel.monitor();
                                 tunnel.state.registration_state = RegistrationState::Registered;
                             }
                             RegistrationState::Registered => {
@@ -860,7 +1196,7 @@ fn tunnel_state_change(
                         trace!("Membership for identity {:?} is expired", id);
                         match tunnel.state.registration_state {
                             RegistrationState::Registered => {
-                                tunnel.unmonitor(make_babel_stream()?)?;
+                                tunnel.unmonitor();
                                 tunnel.state.registration_state = RegistrationState::NotRegistered;
                             }
                             RegistrationState::NotRegistered => {
@@ -926,7 +1262,8 @@ fn tunnel_state_change(
 
 /// Takes the tunnels list and iterates over it to update all of the traffic control settings
 /// since we can't figure out how to combine interfaces badnwidth budgets we're subdividing it
-/// here with manual terminal commands whenever there is a change
+/// here with manual terminal commands whenever there is a change. Operator set limits apply
+/// to paid tunnels, overdue tunnels get the lower of that limit and their free tier share
 fn tunnel_bw_limit_update(tunnels: &HashMap<Identity, Vec<Tunnel>>) -> Result<(), Error> {
     info!("Running tunnel bw limit update!");
     // number of interfaces over which we will have to divide free tier BW
@@ -946,27 +1283,52 @@ fn tunnel_bw_limit_update(tunnels: &HashMap<Identity, Vec<Tunnel>>) -> Result<()
 
     for sublist in tunnels.iter() {
         for tunnel in sublist.1.iter() {
-            let payment_state = &tunnel.state.payment_state;
-            let iface_name = &tunnel.iface_name;
-            let has_limit = KI.has_limit(iface_name)?;
-
-            if *payment_state == PaymentState::Overdue {
-                KI.set_classless_limit(iface_name, bw_per_iface)?;
-            } else if *payment_state == PaymentState::Paid && has_limit {
-                KI.set_codel_shaping(iface_name)?;
+            // one tunnel failing must not leave the ones after it, overdue ones included,
+            // without their limits
+            if let Err(e) = tunnel_bw_limit_apply(tunnel, bw_per_iface) {
+                error!(
+                    "Failed to update the bandwidth limit of {} {:?}",
+                    tunnel.iface_name, e
+                );
             }
         }
     }
     Ok(())
 }
 
+fn tunnel_bw_limit_apply(tunnel: &Tunnel, bw_per_iface: u32) -> Result<(), Error> {
+    let payment_state = &tunnel.state.payment_state;
+    let iface_name = &tunnel.iface_name;
+    let has_limit = KI.has_limit(iface_name)?;
+    let operator_limit = SETTING
+        .get_network()
+        .get_neighbor_shaping(&tunnel.neigh_id.global.wg_public_key)
+        .filter(NeighborShaping::bandwidth_is_valid)
+        .and_then(|shaping| shaping.max_bandwidth);
+
+    if *payment_state == PaymentState::Overdue {
+        let bw = match operator_limit {
+            Some(limit) => min(limit, bw_per_iface),
+            None => bw_per_iface,
+        };
+        KI.set_classless_limit(iface_name, bw)?;
+    } else if let Some(limit) = operator_limit {
+        KI.set_classless_limit(iface_name, limit)?;
+    } else if *payment_state == PaymentState::Paid && has_limit {
+        KI.set_codel_shaping(iface_name)?;
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use crate::rita_common::tunnel_manager::RegistrationState;
     use crate::rita_common::tunnel_manager::Tunnel;
     use crate::rita_common::tunnel_manager::TunnelManager;
+    use crate::SETTING;
     use althea_types::Identity;
     use althea_types::LocalIdentity;
+    use settings::RitaCommonSettings;
 
     /// gets a mutable reference tunnel from the list with the given index
     fn get_mut_tunnel_by_ifidx(ifidx: u32, tunnels: &mut Vec<Tunnel>) -> Option<&mut Tunnel> {
@@ -980,8 +1342,58 @@ mod tests {
 
     #[test]
     pub fn test_tunnel_manager() {
-        let mut tunnel_manager = TunnelManager::new();
-        assert_eq!(tunnel_manager.free_ports.pop().unwrap(), 65534);
+        let tunnel_manager = TunnelManager::new();
+        let stats = tunnel_manager.ports.stats();
+        assert_eq!(stats.range_start, SETTING.get_network().wg_start_port);
+        assert!(stats.free > 0);
+    }
+
+    #[test]
+    pub fn test_tunnel_peer() {
+        use crate::rita_common::peer_listener::is_link_local;
+        use std::net::SocketAddr;
+        let id = LocalIdentity {
+            wg_port: 65535,
+            have_tunnel: Some(true),
+            global: Identity::new(
+                "0.0.0.0".parse().unwrap(),
+                "0x0000000000000000000000000000000000000001"
+                    .parse()
+                    .unwrap(),
+                "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                    .parse()
+                    .unwrap(),
+                None,
+            ),
+        };
+
+        // a hello from a link local neighbor, the repair hello goes out the same interface
+        let tunnel = Tunnel::new(
+            "fe80::1".parse().unwrap(),
+            "wg0".into(),
+            60000,
+            5,
+            false,
+            id,
+        );
+        assert!(is_link_local(tunnel.ip));
+        let peer = tunnel.peer();
+        assert!(!peer.manual);
+        match peer.contact_socket {
+            SocketAddr::V6(socket) => assert_eq!(socket.scope_id(), 5),
+            SocketAddr::V4(_) => panic!("Expected an ipv6 contact socket"),
+        }
+
+        let tunnel = Tunnel::new(
+            "192.0.2.1".parse().unwrap(),
+            "wg1".into(),
+            60001,
+            0,
+            true,
+            id,
+        );
+        assert!(!is_link_local(tunnel.ip));
+        assert!(tunnel.peer().manual);
     }
 
     #[test]
@@ -1012,6 +1424,7 @@ mod tests {
                 "iface".into(),
                 65535,
                 0,
+                false,
                 LocalIdentity {
                     wg_port: 65535,
                     have_tunnel: Some(true),
@@ -0,0 +1,202 @@
+//! Allocation of the local ports our tunnels listen on. Ports are handed out before we say
+//! hello to a neighbor and not every failure path after that hands them back, so instead of
+//! trusting callbacks alone the allocator is reconciled against the tunnels we actually have
+//! and the sockets the OS reports as open every time tunnels are garbage collected
+
+use super::TunnelManagerError;
+use failure::Error;
+use rand::thread_rng;
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// The range of ports we use grows by this many ports at a time
+pub const PORT_BLOCK_SIZE: u16 = 256;
+/// A port handed out for a hello that has neither become a tunnel nor been returned after
+/// this long has been leaked and is reclaimed
+pub const PENDING_PORT_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PortStats {
+    /// Ports ready to be allocated
+    pub free: usize,
+    /// Ports handed out for hellos that have not become tunnels yet
+    pub pending: usize,
+    pub range_start: u16,
+    /// Exclusive end of the range of ports currently in use, grows on demand
+    pub range_end: u16,
+    /// Exclusive upper limit the range may grow to
+    pub range_limit: u16,
+    /// Number of times an allocation failed because there were no ports left
+    pub exhausted_count: u64,
+}
+
+pub struct PortAllocator {
+    start: u16,
+    end: u16,
+    limit: u16,
+    free: Vec<u16>,
+    pending: HashMap<u16, Instant>,
+    exhausted_count: u64,
+}
+
+impl PortAllocator {
+    /// Creates an allocator for ports starting at `start` that may grow up to (but not
+    /// including) `limit`
+    pub fn new(start: u16, limit: u16) -> PortAllocator {
+        let limit = limit.max(start);
+        let mut allocator = PortAllocator {
+            start,
+            end: start,
+            limit,
+            free: Vec::new(),
+            pending: HashMap::new(),
+            exhausted_count: 0,
+        };
+        allocator.grow();
+        allocator
+    }
+
+    /// Takes a random free port that the OS does not report as in use, growing the range
+    /// of ports if there is none
+    pub fn allocate(&mut self, used_ports: &[u16]) -> Result<u16, Error> {
+        loop {
+            let candidates: Vec<usize> = (0..self.free.len())
+                .filter(|i| !used_ports.contains(&self.free[*i]))
+                .collect();
+            if !candidates.is_empty() {
+                let index = candidates[thread_rng().gen_range(0, candidates.len())];
+                let port = self.free.swap_remove(index);
+                self.pending.insert(port, Instant::now());
+                return Ok(port);
+            }
+            if !self.grow() {
+                self.exhausted_count += 1;
+                return Err(TunnelManagerError::PortError(format!(
+                    "No remaining ports! {} ports in {}-{} are pending or in use",
+                    self.limit - self.start,
+                    self.start,
+                    self.limit
+                ))
+                .into());
+            }
+        }
+    }
+
+    /// Returns a port that is no longer needed
+    pub fn release(&mut self, port: u16) {
+        self.pending.remove(&port);
+        if port >= self.start && port < self.end && !self.free.contains(&port) {
+            self.free.push(port);
+        }
+    }
+
+    /// Marks a port as owned by a tunnel, it comes back through `release` when the tunnel
+    /// is closed
+    pub fn confirm(&mut self, port: u16) {
+        self.pending.remove(&port);
+    }
+
+    /// Rebuilds the free list from the tunnels we have and the ports the OS reports as in use,
+    /// pending ports that have timed out are reclaimed
+    pub fn reconcile(&mut self, tunnel_ports: &HashSet<u16>, used_ports: &[u16]) {
+        self.pending
+            .retain(|_, allocated| allocated.elapsed() < PENDING_PORT_TIMEOUT);
+        self.pending.retain(|port, _| !tunnel_ports.contains(port));
+
+        let before = self.free.len();
+        let pending = &self.pending;
+        self.free = (self.start..self.end)
+            .filter(|port| {
+                !tunnel_ports.contains(port)
+                    && !pending.contains_key(port)
+                    && !used_ports.contains(port)
+            })
+            .collect();
+        if self.free.len() > before {
+            info!("Reclaimed {} leaked tunnel ports", self.free.len() - before);
+        }
+    }
+
+    pub fn stats(&self) -> PortStats {
+        PortStats {
+            free: self.free.len(),
+            pending: self.pending.len(),
+            range_start: self.start,
+            range_end: self.end,
+            range_limit: self.limit,
+            exhausted_count: self.exhausted_count,
+        }
+    }
+
+    /// Adds the next block of ports to the free list, returns false if we are at the limit
+    fn grow(&mut self) -> bool {
+        if self.end >= self.limit {
+            return false;
+        }
+        let new_end = self.end.saturating_add(PORT_BLOCK_SIZE).min(self.limit);
+        info!("Growing tunnel port range to {}-{}", self.start, new_end);
+        self.free.extend(self.end..new_end);
+        self.end = new_end;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate_skips_used_ports() {
+        let mut ports = PortAllocator::new(60000, 60003);
+        let used = vec![60000, 60002];
+        assert_eq!(ports.allocate(&used).unwrap(), 60001);
+        assert!(ports.allocate(&used).is_err());
+        assert_eq!(ports.stats().exhausted_count, 1);
+    }
+
+    #[test]
+    fn test_range_grows_to_limit() {
+        let mut ports = PortAllocator::new(60000, 65535);
+        assert_eq!(ports.stats().range_end, 60000 + PORT_BLOCK_SIZE);
+        let used: Vec<u16> = (60000..60000 + PORT_BLOCK_SIZE).collect();
+        let port = ports.allocate(&used).unwrap();
+        assert!(port >= 60000 + PORT_BLOCK_SIZE);
+        assert_eq!(ports.stats().range_end, 60000 + 2 * PORT_BLOCK_SIZE);
+
+        let mut ports = PortAllocator::new(65500, 65535);
+        assert_eq!(ports.stats().range_end, 65535);
+        let used: Vec<u16> = (65500..65535).collect();
+        assert!(ports.allocate(&used).is_err());
+    }
+
+    #[test]
+    fn test_release_and_reconcile() {
+        let mut ports = PortAllocator::new(60000, 60002);
+        let a = ports.allocate(&[]).unwrap();
+        let b = ports.allocate(&[]).unwrap();
+        assert!(ports.allocate(&[]).is_err());
+
+        // a became a tunnel, b was handed back
+        ports.confirm(a);
+        ports.release(b);
+        assert_eq!(ports.allocate(&[]).unwrap(), b);
+
+        // b leaked, it comes back once it has been pending for too long
+        let mut tunnel_ports = HashSet::new();
+        tunnel_ports.insert(a);
+        ports.reconcile(&tunnel_ports, &[a]);
+        assert_eq!(ports.stats().free, 0);
+        ports
+            .pending
+            .insert(b, Instant::now() - PENDING_PORT_TIMEOUT);
+        ports.reconcile(&tunnel_ports, &[a]);
+        assert_eq!(ports.stats().free, 1);
+        assert_eq!(ports.stats().pending, 0);
+        assert_eq!(ports.allocate(&[a]).unwrap(), b);
+
+        // the tunnel is gone, but something else holds the port
+        ports.reconcile(&HashSet::new(), &[a]);
+        assert_eq!(ports.stats().free, 0);
+    }
+}
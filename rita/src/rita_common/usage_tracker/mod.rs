@@ -16,15 +16,18 @@ use actix::SystemService;
 use althea_types::Identity;
 use althea_types::PaymentTx;
 use failure::Error;
+use num256::Int256;
 use num256::Uint256;
 use serde::{Deserialize, Serialize};
 use serde_json::Error as SerdeError;
 use settings::RitaCommonSettings;
+use std::collections::HashMap;
 use std::collections::VecDeque;
 use std::fs::File;
 use std::io::Error as IOError;
 use std::io::Read;
 use std::io::Write;
+use std::net::IpAddr;
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
 
@@ -32,6 +35,14 @@ use std::time::UNIX_EPOCH;
 const MAX_ENTRIES: usize = 8760;
 /// Save every 24 hours
 const SAVE_FREQENCY: u64 = 24;
+/// A month of per destination relay usage, it's much larger per hour than the totals
+const MAX_DESTINATION_ENTRIES: usize = 720;
+/// The number of (neighbor, destination) pairs kept for each past hour, the rest are
+/// only kept as a total
+const MAX_DESTINATIONS_PER_HOUR: usize = 20;
+/// The number of pairs the current hour keeps while it's still open, beyond that the quietest
+/// are folded into other early since a busy relay can see thousands of destinations in an hour
+const MAX_CURRENT_HOUR_DESTINATIONS: usize = 200;
 
 /// In an effort to converge this module between the three possible bw tracking
 /// use cases this enum is used to identify which sort of usage we are tracking
@@ -46,11 +57,107 @@ pub enum UsageType {
 /// A struct for tracking each hour of usage, indexed by time in hours since
 /// the unix epoch
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(from = "StoredUsageHour")]
 pub struct UsageHour {
     index: u64,
     up: u64,
     down: u64,
+    up_price: u32,
+    down_price: u32,
+}
+
+/// Usage saved before upload and download were priced separately has a single price
+#[derive(Deserialize)]
+struct StoredUsageHour {
+    index: u64,
+    up: u64,
+    down: u64,
+    #[serde(default)]
     price: u32,
+    up_price: Option<u32>,
+    down_price: Option<u32>,
+}
+
+impl From<StoredUsageHour> for UsageHour {
+    fn from(stored: StoredUsageHour) -> UsageHour {
+        UsageHour {
+            index: stored.index,
+            up: stored.up,
+            down: stored.down,
+            up_price: stored.up_price.unwrap_or(stored.price),
+            down_price: stored.down_price.unwrap_or(stored.price),
+        }
+    }
+}
+
+/// Relay traffic with one neighbor for one destination over an hour. Down is what the
+/// neighbor sent us to forward to the destination, up is what we sent through the neighbor
+/// towards the destination. Revenue is what the neighbor owes us for the traffic they sent
+/// less what we owe them for the traffic we sent, it's negative when we're paying
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DestinationUsage {
+    /// The neighbor's mesh ip, a whole identity per entry adds up to megabytes over a month
+    pub neighbor: IpAddr,
+    pub destination: IpAddr,
+    pub up: u64,
+    pub down: u64,
+    /// The price to the destination including our fee, sampled at the last update
+    pub price: u32,
+    pub revenue: Int256,
+}
+
+/// An hour of relay traffic broken down by neighbor and destination, once the hour has
+/// passed only the busiest pairs are kept and everything else is added up into other. The
+/// current hour is cut back to its `MAX_CURRENT_HOUR_DESTINATIONS` busiest pairs if it grows
+/// past that
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DestinationHour {
+    index: u64,
+    destinations: Vec<DestinationUsage>,
+    other_up: u64,
+    other_down: u64,
+    other_revenue: Int256,
+}
+
+impl DestinationHour {
+    fn new(index: u64) -> DestinationHour {
+        DestinationHour {
+            index,
+            destinations: Vec::new(),
+            other_up: 0,
+            other_down: 0,
+            other_revenue: Int256::from(0),
+        }
+    }
+
+    fn add(&mut self, usage: DestinationUsage) {
+        let existing = self.destinations.iter_mut().find(|entry| {
+            entry.neighbor == usage.neighbor && entry.destination == usage.destination
+        });
+        match existing {
+            Some(entry) => {
+                entry.up += usage.up;
+                entry.down += usage.down;
+                entry.price = usage.price;
+                entry.revenue = entry.revenue.clone() + usage.revenue;
+            }
+            None => self.destinations.push(usage),
+        }
+    }
+
+    /// Keeps the pairs that moved the most traffic and folds the rest into other
+    fn prune(&mut self, keep: usize) {
+        self.destinations
+            .sort_by(|a, b| (b.up + b.down).cmp(&(a.up + a.down)));
+        if self.destinations.len() <= keep {
+            return;
+        }
+        for entry in self.destinations.split_off(keep) {
+            self.other_up += entry.up;
+            self.other_down += entry.down;
+            self.other_revenue = self.other_revenue.clone() + entry.revenue;
+        }
+    }
 }
 
 /// A version of payment tx with a string txid so that the formatting is correct
@@ -99,6 +206,9 @@ pub struct UsageTracker {
     exit_bandwith: VecDeque<UsageHour>,
     /// A history of payments
     payments: VecDeque<PaymentHour>,
+    /// Relay usage by neighbor and destination
+    #[serde(default)]
+    relay_destinations: VecDeque<DestinationHour>,
 }
 
 impl Default for UsageTracker {
@@ -111,6 +221,7 @@ impl Default for UsageTracker {
             relay_bandwith: VecDeque::new(),
             exit_bandwith: VecDeque::new(),
             payments: VecDeque::new(),
+            relay_destinations: VecDeque::new(),
         };
 
         match file {
@@ -175,7 +286,8 @@ pub struct UpdateUsage {
     pub kind: UsageType,
     pub up: u64,
     pub down: u64,
-    pub price: u32,
+    pub up_price: u32,
+    pub down_price: u32,
 }
 
 impl Message for UpdateUsage {
@@ -213,7 +325,8 @@ fn process_usage_update(current_hour: u64, msg: UpdateUsage, data: &mut UsageTra
             index: current_hour,
             up: msg.up,
             down: msg.down,
-            price: msg.price,
+            up_price: msg.up_price,
+            down_price: msg.down_price,
         }),
         Some(entry) => {
             if entry.index == current_hour {
@@ -224,7 +337,8 @@ fn process_usage_update(current_hour: u64, msg: UpdateUsage, data: &mut UsageTra
                     index: current_hour,
                     up: msg.up,
                     down: msg.down,
-                    price: msg.price,
+                    up_price: msg.up_price,
+                    down_price: msg.down_price,
                 })
             }
         }
@@ -286,6 +400,61 @@ impl Handler<UpdatePayments> for UsageTracker {
     }
 }
 
+/// The message used by the relay traffic watcher to record each round's traffic by
+/// neighbor and destination
+pub struct UpdateRelayDestinations {
+    pub usage: Vec<DestinationUsage>,
+}
+
+impl Message for UpdateRelayDestinations {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<UpdateRelayDestinations> for UsageTracker {
+    type Result = Result<(), Error>;
+    fn handle(&mut self, msg: UpdateRelayDestinations, _: &mut Context<Self>) -> Self::Result {
+        let current_hour = match get_current_hour() {
+            Ok(hour) => hour,
+            Err(e) => {
+                error!("System time is set earlier than unix epoch! {:?}", e);
+                return Ok(());
+            }
+        };
+        process_destination_update(current_hour, msg.usage, &mut self.relay_destinations);
+        // saved along with the totals, which are updated every round
+        Ok(())
+    }
+}
+
+fn process_destination_update(
+    current_hour: u64,
+    usage: Vec<DestinationUsage>,
+    history: &mut VecDeque<DestinationHour>,
+) {
+    let new_hour = match history.front() {
+        Some(entry) => entry.index != current_hour,
+        None => true,
+    };
+    if new_hour {
+        // the last hour is over, nothing more will be added to it
+        if let Some(entry) = history.front_mut() {
+            entry.prune(MAX_DESTINATIONS_PER_HOUR);
+        }
+        history.push_front(DestinationHour::new(current_hour));
+    }
+    // we just made sure there's an entry
+    let entry = history.front_mut().unwrap();
+    for destination in usage {
+        entry.add(destination);
+    }
+    if entry.destinations.len() > MAX_CURRENT_HOUR_DESTINATIONS {
+        entry.prune(MAX_CURRENT_HOUR_DESTINATIONS);
+    }
+    while history.len() > MAX_DESTINATION_ENTRIES {
+        let _discarded_entry = history.pop_back();
+    }
+}
+
 pub struct GetUsage {
     pub kind: UsageType,
 }
@@ -317,3 +486,116 @@ impl Handler<GetPayments> for UsageTracker {
         Ok(self.payments.clone())
     }
 }
+
+pub struct GetRelayDestinations;
+
+impl Message for GetRelayDestinations {
+    type Result = Result<VecDeque<DestinationHour>, Error>;
+}
+
+impl Handler<GetRelayDestinations> for UsageTracker {
+    type Result = Result<VecDeque<DestinationHour>, Error>;
+    fn handle(&mut self, _msg: GetRelayDestinations, _: &mut Context<Self>) -> Self::Result {
+        Ok(self.relay_destinations.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_test_identity(mesh_ip: &str) -> Identity {
+        Identity::new(
+            mesh_ip.parse().unwrap(),
+            "0x0000000000000000000000000000000000000001"
+                .parse()
+                .unwrap(),
+            "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+            None,
+        )
+    }
+
+    fn usage(neighbor: &Identity, destination: &str, bytes: u64) -> DestinationUsage {
+        DestinationUsage {
+            neighbor: neighbor.mesh_ip,
+            destination: destination.parse().unwrap(),
+            up: 0,
+            down: bytes,
+            price: 10,
+            revenue: Int256::from(10 * bytes as i64),
+        }
+    }
+
+    #[test]
+    fn test_destination_hours() {
+        let a = get_test_identity("fd00::1");
+        let b = get_test_identity("fd00::2");
+        let mut history = VecDeque::new();
+
+        process_destination_update(
+            1,
+            vec![usage(&a, "fd00::10", 100), usage(&b, "fd00::10", 5)],
+            &mut history,
+        );
+        process_destination_update(1, vec![usage(&a, "fd00::10", 100)], &mut history);
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].destinations.len(), 2);
+        assert_eq!(history[0].destinations[0].down, 200);
+
+        // enough pairs in one hour that some have to be folded into other
+        let mut busy = Vec::new();
+        for i in 0..(MAX_DESTINATIONS_PER_HOUR as u64 + 5) {
+            busy.push(usage(&a, &format!("fd00::{:x}", 0x100 + i), 1000 + i));
+        }
+        process_destination_update(2, busy, &mut history);
+        // only pruned once the hour is over
+        assert_eq!(history[0].destinations.len(), MAX_DESTINATIONS_PER_HOUR + 5);
+        process_destination_update(3, Vec::new(), &mut history);
+        assert_eq!(history.len(), 3);
+        let pruned = &history[1];
+        assert_eq!(pruned.destinations.len(), MAX_DESTINATIONS_PER_HOUR);
+        // the five smallest went to other
+        assert_eq!(pruned.other_down, 1000 + 1001 + 1002 + 1003 + 1004);
+        assert_eq!(pruned.other_revenue, Int256::from(10 * 5010i64));
+        assert_eq!(
+            pruned.destinations[0].down,
+            1000 + MAX_DESTINATIONS_PER_HOUR as u64 + 4
+        );
+
+        // the current hour is cut back once it's too big, nothing is lost
+        let mut flood = Vec::new();
+        for i in 0..(MAX_CURRENT_HOUR_DESTINATIONS as u64 + 1) {
+            flood.push(usage(&b, &format!("fd00::{:x}", 0x1000 + i), 1 + i));
+        }
+        process_destination_update(3, flood, &mut history);
+        let current = &history[0];
+        assert_eq!(current.destinations.len(), MAX_CURRENT_HOUR_DESTINATIONS);
+        assert_eq!(current.other_down, 1);
+        let total: u64 = current.destinations.iter().map(|entry| entry.down).sum();
+        let n = MAX_CURRENT_HOUR_DESTINATIONS as u64 + 1;
+        assert_eq!(total + current.other_down, n * (n + 1) / 2);
+    }
+
+    #[test]
+    fn test_usage_hour_prices() {
+        // saved before prices were split
+        let old: UsageHour =
+            serde_json::from_str(r#"{"index":1,"up":2,"down":3,"price":10}"#).unwrap();
+        assert_eq!((old.up_price, old.down_price), (10, 10));
+
+        let new: UsageHour = serde_json::from_str(
+            &serde_json::to_string(&UsageHour {
+                index: 1,
+                up: 2,
+                down: 3,
+                up_price: 5,
+                down_price: 7,
+            })
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!((new.up_price, new.down_price), (5, 7));
+    }
+}
@@ -0,0 +1,247 @@
+//! The exit admin api, lets the operator look through registered clients and step in when
+//! verification needs a human. It's served on its own port and every request has to carry
+//! the token from the `admin_api` settings as `Authorization: Bearer <token>`. Everything
+//! but reading the audit log itself is written to the audit log, including rejected requests.
+
+use crate::rita_exit::database::admin::{
+    ban_key, clamp_page, get_audit_log, get_bans, get_client_details, record_audit, search_clients,
+    set_client_internal_ip, set_client_verified, unban_key, ClientDetails, Page,
+};
+use crate::rita_exit::database::get_database_connection;
+use crate::SETTING;
+use actix_web::http::{header, StatusCode};
+use actix_web::middleware::{Middleware, Started};
+use actix_web::{HttpRequest, HttpResponse, Json, Path, Query, Result};
+use althea_types::WgKey;
+use exit_db::models;
+use failure::Error;
+use settings::exit::RitaExitSettings;
+use std::net::{IpAddr, SocketAddr};
+
+pub struct AdminAuth;
+
+impl<S> Middleware<S> for AdminAuth {
+    fn start(&self, req: &HttpRequest<S>) -> Result<Started> {
+        let token = match SETTING.get_admin_api() {
+            Some(admin_api) => admin_api.token,
+            None => return Ok(Started::Response(HttpResponse::NotFound().finish())),
+        };
+        let header = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok());
+        if authorized(header, &token) {
+            Ok(Started::Done)
+        } else {
+            let source = request_source(req);
+            warn!(
+                "Rejected admin api request to {} from {}",
+                req.path(),
+                source
+            );
+            // the request is turned away either way, failing to audit it only gets logged
+            match get_database_connection() {
+                Ok(conn) => {
+                    if let Err(e) = record_audit(&source, "rejected", req.path(), "", &conn) {
+                        error!("Failed to audit a rejected admin api request {:?}", e);
+                    }
+                }
+                Err(e) => error!("Failed to audit a rejected admin api request {:?}", e),
+            }
+            Ok(Started::Response(HttpResponse::Unauthorized().finish()))
+        }
+    }
+}
+
+/// Checks an Authorization header against our token, an empty token lets nobody in
+fn authorized(header: Option<&str>, token: &str) -> bool {
+    let bearer = "Bearer ";
+    match header {
+        Some(header) if header.starts_with(bearer) && !token.is_empty() => {
+            tokens_match(header[bearer.len()..].as_bytes(), token.as_bytes())
+        }
+        _ => false,
+    }
+}
+
+/// Compares without returning early so the time taken doesn't give the token away
+fn tokens_match(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// The address a request came from, as written to the audit log
+fn request_source<S>(req: &HttpRequest<S>) -> String {
+    match req.connection_info().remote() {
+        Some(remote) => match remote.parse::<SocketAddr>() {
+            Ok(socket) => socket.ip().to_string(),
+            Err(_) => remote.to_string(),
+        },
+        None => "unknown".to_string(),
+    }
+}
+
+fn bad_request(message: String) -> HttpResponse {
+    HttpResponse::new(StatusCode::BAD_REQUEST)
+        .into_builder()
+        .json(message)
+}
+
+/// Mesh ips are stored in their canonical form, so parse them before looking them up
+fn parse_mesh_ip(mesh_ip: &str) -> Result<String, HttpResponse> {
+    match mesh_ip.parse::<IpAddr>() {
+        Ok(ip) => Ok(ip.to_string()),
+        Err(_) => Err(bad_request(format!("{} is not a valid mesh ip", mesh_ip))),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PageQuery {
+    offset: Option<i64>,
+    limit: Option<i64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ClientQuery {
+    search: Option<String>,
+    offset: Option<i64>,
+    limit: Option<i64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InternalIpRequest {
+    internal_ip: IpAddr,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BanRequest {
+    wg_pubkey: WgKey,
+    #[serde(default)]
+    reason: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UnbanRequest {
+    wg_pubkey: WgKey,
+}
+
+pub fn list_clients(
+    (req, query): (HttpRequest, Query<ClientQuery>),
+) -> Result<Json<Page<models::Client>>, Error> {
+    trace!("Admin client list hit with {:?}", query);
+    let (offset, limit) = clamp_page(query.offset, query.limit);
+    let conn = get_database_connection()?;
+    record_audit(
+        &request_source(&req),
+        "list_clients",
+        "",
+        &format!(
+            "search {:?} offset {} limit {}",
+            query.search.as_ref().map(String::as_str).unwrap_or(""),
+            offset,
+            limit
+        ),
+        &conn,
+    )?;
+    Ok(Json(search_clients(
+        query.search.as_ref().map(String::as_str),
+        offset,
+        limit,
+        &conn,
+    )?))
+}
+
+pub fn client_details((req, path): (HttpRequest, Path<String>)) -> Result<HttpResponse, Error> {
+    let mesh_ip = match parse_mesh_ip(&path.into_inner()) {
+        Ok(mesh_ip) => mesh_ip,
+        Err(response) => return Ok(response),
+    };
+    trace!("Admin client details hit for {}", mesh_ip);
+    let conn = get_database_connection()?;
+    record_audit(&request_source(&req), "view_client", &mesh_ip, "", &conn)?;
+    let details: Option<ClientDetails> = get_client_details(&mesh_ip, &conn)?;
+    match details {
+        Some(details) => Ok(HttpResponse::Ok().json(details)),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+fn set_verified(req: &HttpRequest, mesh_ip: &str, verified: bool) -> Result<HttpResponse, Error> {
+    let mesh_ip = match parse_mesh_ip(mesh_ip) {
+        Ok(mesh_ip) => mesh_ip,
+        Err(response) => return Ok(response),
+    };
+    let conn = get_database_connection()?;
+    if set_client_verified(&mesh_ip, verified, &request_source(req), &conn)? {
+        Ok(HttpResponse::Ok().json(()))
+    } else {
+        Ok(HttpResponse::NotFound().finish())
+    }
+}
+
+pub fn verify_client_http((req, path): (HttpRequest, Path<String>)) -> Result<HttpResponse, Error> {
+    set_verified(&req, &path.into_inner(), true)
+}
+
+pub fn deny_client_http((req, path): (HttpRequest, Path<String>)) -> Result<HttpResponse, Error> {
+    set_verified(&req, &path.into_inner(), false)
+}
+
+pub fn set_internal_ip(
+    (req, path, body): (HttpRequest, Path<String>, Json<InternalIpRequest>),
+) -> Result<HttpResponse, Error> {
+    let mesh_ip = match parse_mesh_ip(&path.into_inner()) {
+        Ok(mesh_ip) => mesh_ip,
+        Err(response) => return Ok(response),
+    };
+    let conn = get_database_connection()?;
+    match set_client_internal_ip(&mesh_ip, body.internal_ip, &request_source(&req), &conn) {
+        Ok(true) => Ok(HttpResponse::Ok().json(())),
+        Ok(false) => Ok(HttpResponse::NotFound().finish()),
+        Err(e) => Ok(bad_request(e.to_string())),
+    }
+}
+
+pub fn list_bans(req: HttpRequest) -> Result<Json<Vec<models::BannedKey>>, Error> {
+    let conn = get_database_connection()?;
+    record_audit(&request_source(&req), "list_bans", "", "", &conn)?;
+    Ok(Json(get_bans(&conn)?))
+}
+
+pub fn add_ban((req, body): (HttpRequest, Json<BanRequest>)) -> Result<Json<()>, Error> {
+    let conn = get_database_connection()?;
+    ban_key(&body.wg_pubkey, &body.reason, &request_source(&req), &conn)?;
+    Ok(Json(()))
+}
+
+pub fn remove_ban((req, body): (HttpRequest, Json<UnbanRequest>)) -> Result<HttpResponse, Error> {
+    let conn = get_database_connection()?;
+    if unban_key(&body.wg_pubkey, &request_source(&req), &conn)? {
+        Ok(HttpResponse::Ok().json(()))
+    } else {
+        Ok(HttpResponse::NotFound().finish())
+    }
+}
+
+pub fn audit_log(query: Query<PageQuery>) -> Result<Json<Page<models::AuditEntry>>, Error> {
+    let (offset, limit) = clamp_page(query.offset, query.limit);
+    let conn = get_database_connection()?;
+    Ok(Json(get_audit_log(offset, limit, &conn)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authorized() {
+        assert!(authorized(Some("Bearer hunter2"), "hunter2"));
+        assert!(!authorized(Some("Bearer hunter3"), "hunter2"));
+        assert!(!authorized(Some("Bearer hunter22"), "hunter2"));
+        assert!(!authorized(Some("hunter2"), "hunter2"));
+        assert!(!authorized(None, "hunter2"));
+        assert!(!authorized(Some("Bearer "), ""));
+    }
+}
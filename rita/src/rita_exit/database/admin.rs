@@ -0,0 +1,369 @@
+//! Database side of the exit admin api. Every function that changes something takes the
+//! address the request came from and writes an entry to the `admin_audit_log` table in the
+//! same transaction, so an action that can't be audited doesn't happen either.
+
+use crate::rita_exit::database::plans::get_plan_status;
+use crate::rita_exit::database::secs_since_unix_epoch;
+use crate::rita_exit::database::verification::approval::APPROVAL;
+use crate::rita_exit::database::verification::{
+    get_step_state, set_step_state, STATE_DENIED, STATE_VERIFIED,
+};
+use crate::SETTING;
+use althea_types::{ExitPlanStatus, WgKey};
+use diesel;
+use diesel::prelude::{
+    BoolExpressionMethods, Connection, ExpressionMethods, PgConnection, PgTextExpressionMethods,
+    QueryDsl, RunQueryDsl,
+};
+use exit_db::{models, schema};
+use failure::Error;
+use ipnetwork::IpNetwork;
+use settings::exit::RitaExitSettings;
+use std::net::IpAddr;
+
+/// The most entries returned in a single page
+pub const MAX_PAGE_SIZE: i64 = 100;
+pub const DEFAULT_PAGE_SIZE: i64 = 50;
+
+#[derive(Serialize, Debug)]
+pub struct Page<T> {
+    /// How many entries match in total, not just on this page
+    pub total: i64,
+    pub offset: i64,
+    pub entries: Vec<T>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ClientDetails {
+    pub client: models::Client,
+    pub plan: Option<ExitPlanStatus>,
+    pub verifications: Vec<models::ClientVerification>,
+    pub ban: Option<models::BannedKey>,
+}
+
+/// Turns the offset and limit from a request into ones that are safe to hand to the database
+pub fn clamp_page(offset: Option<i64>, limit: Option<i64>) -> (i64, i64) {
+    let offset = offset.unwrap_or(0).max(0);
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).max(1).min(MAX_PAGE_SIZE);
+    (offset, limit)
+}
+
+/// Escapes the wildcards in user input so that it's matched literally by LIKE
+fn like_pattern(search: &str) -> String {
+    let escaped = search
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+/// Lists clients ordered by mesh ip, if a search is given only the clients whose nickname,
+/// email, phone number or mesh ip contain it are returned
+pub fn search_clients(
+    search: Option<&str>,
+    offset: i64,
+    limit: i64,
+    conn: &PgConnection,
+) -> Result<Page<models::Client>, Error> {
+    use self::schema::clients::dsl::{clients, email, mesh_ip, nickname, phone};
+    let (total, entries) = match search {
+        Some(search) if !search.is_empty() => {
+            let pattern = like_pattern(search);
+            let matches = || {
+                nickname
+                    .ilike(pattern.clone())
+                    .or(email.ilike(pattern.clone()))
+                    .or(phone.ilike(pattern.clone()))
+                    .or(mesh_ip.ilike(pattern.clone()))
+            };
+            (
+                clients.filter(matches()).count().get_result(conn)?,
+                clients
+                    .filter(matches())
+                    .order(mesh_ip)
+                    .offset(offset)
+                    .limit(limit)
+                    .load::<models::Client>(conn)?,
+            )
+        }
+        _ => (
+            clients.count().get_result(conn)?,
+            clients
+                .order(mesh_ip)
+                .offset(offset)
+                .limit(limit)
+                .load::<models::Client>(conn)?,
+        ),
+    };
+    Ok(Page {
+        total,
+        offset,
+        entries,
+    })
+}
+
+fn get_client_by_mesh_ip(
+    client_mesh_ip: &str,
+    conn: &PgConnection,
+) -> Result<Option<models::Client>, Error> {
+    use self::schema::clients::dsl::clients;
+    Ok(clients
+        .find(client_mesh_ip)
+        .load::<models::Client>(conn)?
+        .pop())
+}
+
+/// Everything we know about a single client, None if there's no such client
+pub fn get_client_details(
+    client_mesh_ip: &str,
+    conn: &PgConnection,
+) -> Result<Option<ClientDetails>, Error> {
+    use self::schema::client_verifications::dsl::{client_verifications, mesh_ip};
+    let client = match get_client_by_mesh_ip(client_mesh_ip, conn)? {
+        Some(client) => client,
+        None => return Ok(None),
+    };
+    let verifications = client_verifications
+        .filter(mesh_ip.eq(client_mesh_ip))
+        .load::<models::ClientVerification>(conn)?;
+    Ok(Some(ClientDetails {
+        plan: get_plan_status(&client.mesh_ip, conn),
+        ban: get_ban(&client.wg_pubkey, conn)?,
+        verifications,
+        client,
+    }))
+}
+
+/// Writes an admin action to the audit log, reads and rejected requests are recorded here too
+pub fn record_audit(
+    source: &str,
+    action: &str,
+    target: &str,
+    details: &str,
+    conn: &PgConnection,
+) -> Result<(), Error> {
+    use self::schema::admin_audit_log::dsl::admin_audit_log;
+    info!(
+        "Admin action {} on {} from {}: {}",
+        action, target, source, details
+    );
+    let entry = models::NewAuditEntry {
+        time: secs_since_unix_epoch(),
+        source: source.to_string(),
+        action: action.to_string(),
+        target: target.to_string(),
+        details: details.to_string(),
+    };
+    diesel::insert_into(admin_audit_log)
+        .values(&entry)
+        .execute(conn)?;
+    Ok(())
+}
+
+/// The audit log, newest entries first
+pub fn get_audit_log(
+    offset: i64,
+    limit: i64,
+    conn: &PgConnection,
+) -> Result<Page<models::AuditEntry>, Error> {
+    use self::schema::admin_audit_log::dsl::{admin_audit_log, id};
+    Ok(Page {
+        total: admin_audit_log.count().get_result(conn)?,
+        offset,
+        entries: admin_audit_log
+            .order(id.desc())
+            .offset(offset)
+            .limit(limit)
+            .load::<models::AuditEntry>(conn)?,
+    })
+}
+
+/// Lets a client in or turns them away regardless of how far along verification they are,
+/// the operator's decision is also recorded as the client's approval step so that a denied
+/// client stays out when they try to register again
+pub fn set_client_verified(
+    client_mesh_ip: &str,
+    verified_value: bool,
+    source: &str,
+    conn: &PgConnection,
+) -> Result<bool, Error> {
+    use self::schema::clients::dsl::{clients, verified};
+    conn.transaction::<_, Error, _>(|| {
+        let updated = diesel::update(clients.find(client_mesh_ip))
+            .set(verified.eq(verified_value))
+            .execute(conn)?;
+        if updated == 0 {
+            return Ok(false);
+        }
+        let (state, action) = if verified_value {
+            (STATE_VERIFIED, "verify")
+        } else {
+            (STATE_DENIED, "deny")
+        };
+        set_step_state(client_mesh_ip, APPROVAL, state, conn)?;
+        record_audit(source, action, client_mesh_ip, "", conn)?;
+        Ok(true)
+    })
+}
+
+/// Checks that an address can be handed to a client, it has to be an ipv4 address in the
+/// exit's subnet that isn't the exit's own
+fn check_internal_ip(ip: IpAddr, own_ip: IpAddr, netmask: u8) -> Result<(), Error> {
+    let subnet = IpNetwork::new(own_ip, netmask)?;
+    if !ip.is_ipv4() || !subnet.contains(ip) {
+        bail!("{} is not in the exit subnet {}", ip, subnet);
+    }
+    if ip == own_ip || ip == subnet.network() || Some(ip) == broadcast(subnet) {
+        bail!("{} can't be given to a client", ip);
+    }
+    Ok(())
+}
+
+fn broadcast(subnet: IpNetwork) -> Option<IpAddr> {
+    match subnet {
+        IpNetwork::V4(subnet) => Some(subnet.broadcast().into()),
+        IpNetwork::V6(_) => None,
+    }
+}
+
+/// Moves a client to a different internal ip, the client picks it up the next time it
+/// checks its registration status
+pub fn set_client_internal_ip(
+    client_mesh_ip: &str,
+    new_ip: IpAddr,
+    source: &str,
+    conn: &PgConnection,
+) -> Result<bool, Error> {
+    use self::schema::clients::dsl::{clients, internal_ip, mesh_ip};
+    let exit_network = SETTING.get_exit_network();
+    let own_ip = exit_network.own_internal_ip.into();
+    let netmask = exit_network.netmask;
+    drop(exit_network);
+    check_internal_ip(new_ip, own_ip, netmask)?;
+
+    conn.transaction::<_, Error, _>(|| {
+        let old_ip = match get_client_by_mesh_ip(client_mesh_ip, conn)? {
+            Some(client) => client.internal_ip,
+            None => return Ok(false),
+        };
+        let taken: i64 = clients
+            .filter(internal_ip.eq(new_ip.to_string()))
+            .filter(mesh_ip.ne(client_mesh_ip))
+            .count()
+            .get_result(conn)?;
+        if taken > 0 {
+            bail!("{} is already in use by another client", new_ip);
+        }
+        diesel::update(clients.find(client_mesh_ip))
+            .set(internal_ip.eq(new_ip.to_string()))
+            .execute(conn)?;
+        record_audit(
+            source,
+            "set_internal_ip",
+            client_mesh_ip,
+            &format!("{} -> {}", old_ip, new_ip),
+            conn,
+        )?;
+        Ok(true)
+    })
+}
+
+pub fn get_ban(key: &str, conn: &PgConnection) -> Result<Option<models::BannedKey>, Error> {
+    use self::schema::banned_keys::dsl::banned_keys;
+    Ok(banned_keys.find(key).load::<models::BannedKey>(conn)?.pop())
+}
+
+pub fn get_bans(conn: &PgConnection) -> Result<Vec<models::BannedKey>, Error> {
+    use self::schema::banned_keys::dsl::{banned_at, banned_keys};
+    Ok(banned_keys
+        .order(banned_at.desc())
+        .load::<models::BannedKey>(conn)?)
+}
+
+/// Bans a WireGuard key, any client using it is unverified so it drops off the exit tunnel
+pub fn ban_key(key: &WgKey, reason: &str, source: &str, conn: &PgConnection) -> Result<(), Error> {
+    use self::schema::banned_keys::dsl::banned_keys;
+    use self::schema::clients::dsl::{clients, verified, wg_pubkey};
+    let ban = models::BannedKey {
+        wg_pubkey: key.to_string(),
+        reason: reason.to_string(),
+        banned_at: secs_since_unix_epoch(),
+    };
+    conn.transaction::<_, Error, _>(|| {
+        diesel::insert_into(banned_keys)
+            .values(&ban)
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+        diesel::update(clients.filter(wg_pubkey.eq(&ban.wg_pubkey)))
+            .set(verified.eq(false))
+            .execute(conn)?;
+        record_audit(source, "ban", &ban.wg_pubkey, reason, conn)
+    })
+}
+
+/// Lifts a ban, clients using the key have to register again. Returns false if the key
+/// wasn't banned
+pub fn unban_key(key: &WgKey, source: &str, conn: &PgConnection) -> Result<bool, Error> {
+    use self::schema::banned_keys::dsl::banned_keys;
+    let key = key.to_string();
+    conn.transaction::<_, Error, _>(|| {
+        let removed = diesel::delete(banned_keys.find(&key)).execute(conn)?;
+        if removed == 0 {
+            return Ok(false);
+        }
+        record_audit(source, "unban", &key, "", conn)?;
+        Ok(true)
+    })
+}
+
+/// Why a client may not use the exit because of something an operator did, None if they
+/// haven't been banned or denied
+pub fn admin_denial(
+    their_record: &models::Client,
+    conn: &PgConnection,
+) -> Result<Option<String>, Error> {
+    if get_ban(&their_record.wg_pubkey, conn)?.is_some() {
+        return Ok(Some(
+            "This key has been banned by the exit operator".to_string(),
+        ));
+    }
+    if get_step_state(&their_record.mesh_ip, APPROVAL, conn)?
+        .as_ref()
+        .map(String::as_str)
+        == Some(STATE_DENIED)
+    {
+        return Ok(Some(
+            "The exit operator declined this registration".to_string(),
+        ));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clamp_page() {
+        assert_eq!(clamp_page(None, None), (0, DEFAULT_PAGE_SIZE));
+        assert_eq!(clamp_page(Some(-5), Some(0)), (0, 1));
+        assert_eq!(clamp_page(Some(200), Some(10_000)), (200, MAX_PAGE_SIZE));
+    }
+
+    #[test]
+    fn test_like_pattern() {
+        assert_eq!(like_pattern("bob"), "%bob%");
+        assert_eq!(like_pattern("100%_\\"), "%100\\%\\_\\\\%");
+    }
+
+    #[test]
+    fn test_check_internal_ip() {
+        let own_ip: IpAddr = "172.168.1.254".parse().unwrap();
+        assert!(check_internal_ip("172.168.1.5".parse().unwrap(), own_ip, 24).is_ok());
+        assert!(check_internal_ip(own_ip, own_ip, 24).is_err());
+        assert!(check_internal_ip("172.168.1.0".parse().unwrap(), own_ip, 24).is_err());
+        assert!(check_internal_ip("172.168.1.255".parse().unwrap(), own_ip, 24).is_err());
+        assert!(check_internal_ip("172.168.2.5".parse().unwrap(), own_ip, 24).is_err());
+        assert!(check_internal_ip("fd00::5".parse().unwrap(), own_ip, 24).is_err());
+    }
+}
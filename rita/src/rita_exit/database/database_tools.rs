@@ -1,4 +1,4 @@
-use crate::rita_exit::database::ip_increment::increment;
+use crate::rita_exit::database::ip_increment::{increment, nth_prefix_v6};
 use crate::rita_exit::database::secs_since_unix_epoch;
 use crate::SETTING;
 use ::actix_web::Result;
@@ -10,7 +10,9 @@ use diesel::prelude::{ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
 use diesel::select;
 use exit_db::{models, schema};
 use failure::Error;
+use ipnetwork::IpNetwork;
 use settings::exit::RitaExitSettings;
+use std::collections::HashSet;
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
 
@@ -61,6 +63,62 @@ pub fn get_next_client_ip(conn: &PgConnection) -> Result<IpAddr, Error> {
     Ok(new_ip)
 }
 
+/// Gets the first free ipv6 prefix in the exit's subnet, None if the exit does not have one
+pub fn get_next_client_ipv6(conn: &PgConnection) -> Result<Option<IpNetwork>, Error> {
+    use self::schema::clients::dsl::clients;
+    let exit_settings = SETTING.get_exit_network();
+    let subnet = match exit_settings.subnet_v6 {
+        Some(subnet) => subnet,
+        None => return Ok(None),
+    };
+    let prefix_len = exit_settings.client_prefix_len_v6;
+    drop(exit_settings);
+
+    let mut used = HashSet::new();
+    for client in clients.load::<models::Client>(conn)? {
+        if let Ok(IpNetwork::V6(prefix)) = client.internal_ipv6.parse() {
+            used.insert(prefix);
+        }
+    }
+    // the first prefix holds the exit's own address
+    let mut n = 1;
+    loop {
+        let prefix = nth_prefix_v6(subnet, prefix_len, n)?;
+        if !used.contains(&prefix) {
+            trace!("The new client's ipv6 prefix is {}", prefix);
+            return Ok(Some(IpNetwork::V6(prefix)));
+        }
+        n += 1;
+    }
+}
+
+/// Returns the client's ipv6 prefix, giving them one if they don't have one yet or the one
+/// they have is no longer in the exit's subnet
+pub fn assign_client_ipv6(
+    client: &models::Client,
+    conn: &PgConnection,
+) -> Result<Option<IpNetwork>, Error> {
+    use self::schema::clients::dsl::{clients, internal_ipv6};
+    let subnet = match SETTING.get_exit_network().subnet_v6 {
+        Some(subnet) => subnet,
+        None => return Ok(None),
+    };
+    if let Ok(IpNetwork::V6(prefix)) = client.internal_ipv6.parse() {
+        if subnet.contains(prefix.network()) {
+            return Ok(Some(IpNetwork::V6(prefix)));
+        }
+    }
+
+    let prefix = get_next_client_ipv6(conn)?;
+    if let Some(prefix) = prefix {
+        info!("Delegating {} to {}", prefix, client.mesh_ip);
+        diesel::update(clients.find(&client.mesh_ip))
+            .set(internal_ipv6.eq(prefix.to_string()))
+            .execute(conn)?;
+    }
+    Ok(prefix)
+}
+
 /// updates the last seen time
 pub fn update_client(client: &ExitClientIdentity, conn: &PgConnection) -> Result<(), Error> {
     use self::schema::clients::dsl::{clients, email, last_seen, phone};
@@ -1,19 +1,23 @@
-use crate::rita_common::tunnel_manager::make_babel_stream;
+use crate::rita_exit::database::secs_since_unix_epoch;
 use crate::KI;
 use crate::SETTING;
-use babel_monitor::{Babel, Route};
+use babel_monitor::{BabelTable, Route};
+use diesel;
+use diesel::prelude::{ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use exit_db::{models, schema};
 use failure::Error;
 use ipnetwork::IpNetwork;
+use maxminddb::Reader;
 use reqwest;
 use settings::exit::RitaExitSettings;
 use std::collections::HashMap;
+use std::fs;
 use std::net::IpAddr;
+use std::time::SystemTime;
 
 /// gets the gateway ip for a given mesh IP
-pub fn get_gateway_ip_single(mesh_ip: IpAddr) -> Result<IpAddr, Error> {
-    let mut babel = Babel::new(make_babel_stream()?);
-    babel.start_connection()?;
-    let routes = babel.parse_routes()?;
+pub fn get_gateway_ip_single(mesh_ip: IpAddr, babel_table: &BabelTable) -> Result<IpAddr, Error> {
+    let routes = babel_table.routes();
 
     let mut route_to_des: Option<Route> = None;
 
@@ -41,10 +45,11 @@ pub struct IpPair {
 
 /// gets the gateway ip for a given set of mesh IPs, inactive addresses will simply
 /// not appear in the result vec
-pub fn get_gateway_ip_bulk(mesh_ip_list: Vec<IpAddr>) -> Result<Vec<IpPair>, Error> {
-    let mut babel = Babel::new(make_babel_stream()?);
-    babel.start_connection()?;
-    let routes = babel.parse_routes()?;
+pub fn get_gateway_ip_bulk(
+    mesh_ip_list: Vec<IpAddr>,
+    babel_table: &BabelTable,
+) -> Result<Vec<IpPair>, Error> {
+    let routes = babel_table.routes();
     let mut results = Vec::new();
 
     for mesh_ip in mesh_ip_list {
@@ -78,63 +83,212 @@ struct CountryDetails {
     iso_code: String,
 }
 
-/// get ISO country code from ip, consults a in memory cache
-pub fn get_country(ip: &IpAddr, cache: &mut HashMap<IpAddr, String>) -> Result<String, Error> {
-    trace!("get GeoIP country for {}", ip.to_string());
+/// A country record from a MaxMind or DB-IP database, some networks (anycast, satellite)
+/// have no country
+#[derive(Deserialize, Debug)]
+struct DatabaseRecord {
+    country: Option<DatabaseCountry>,
+}
+
+#[derive(Deserialize, Debug)]
+struct DatabaseCountry {
+    iso_code: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+struct CachedCountry {
+    country: String,
+    lookup_time: i64,
+}
+
+/// Countries we have already looked up along with the local database, which is reloaded
+/// whenever the file changes. Lookups from the api are also kept in the exit database so
+/// that they survive restarts and are shared between the exit loop and signups
+#[derive(Default)]
+pub struct GeoIpCache {
+    countries: HashMap<IpAddr, CachedCountry>,
+    database: Option<LoadedDatabase>,
+}
+
+struct LoadedDatabase {
+    path: String,
+    modified: Option<SystemTime>,
+    reader: Reader<Vec<u8>>,
+}
+
+fn is_fresh(lookup_time: i64, now: i64, ttl: u64) -> bool {
+    now.saturating_sub(lookup_time) < ttl as i64
+}
+
+/// Finds the country of an ip in a local database, None if the database doesn't have one
+fn lookup_database(reader: &Reader<Vec<u8>>, ip: &IpAddr) -> Option<String> {
+    match reader.lookup::<DatabaseRecord>(*ip) {
+        Ok(record) => record.country.and_then(|country| country.iso_code),
+        Err(e) => {
+            trace!("{} is not in the GeoIP database {:?}", ip, e);
+            None
+        }
+    }
+}
+
+impl GeoIpCache {
+    /// Opens the database at path unless we already have it open and it hasn't changed
+    fn load_database(&mut self, path: &str) -> Result<&Reader<Vec<u8>>, Error> {
+        let modified = fs::metadata(path)?.modified().ok();
+        let current = match self.database {
+            Some(ref database) => database.path == path && database.modified == modified,
+            None => false,
+        };
+        if !current {
+            info!("Loading GeoIP database {}", path);
+            let reader = match Reader::open_readfile(path) {
+                Ok(reader) => reader,
+                Err(e) => bail!("Failed to open GeoIP database {} {:?}", path, e),
+            };
+            self.database = Some(LoadedDatabase {
+                path: path.to_string(),
+                modified,
+                reader,
+            });
+        }
+        match self.database {
+            Some(ref database) => Ok(&database.reader),
+            None => bail!("GeoIP database {} not loaded", path),
+        }
+    }
+
+    fn insert(&mut self, ip: IpAddr, country: String, lookup_time: i64) {
+        self.countries.insert(
+            ip,
+            CachedCountry {
+                country,
+                lookup_time,
+            },
+        );
+    }
+}
+
+/// The country the api found for an ip, if it's still fresh
+fn get_cached_country(
+    ip: &IpAddr,
+    now: i64,
+    ttl: u64,
+    conn: &PgConnection,
+) -> Result<Option<models::GeoipCacheEntry>, Error> {
+    use self::schema::geoip_cache::dsl::{geoip_cache, ip as cached_ip};
+    let entry = geoip_cache
+        .filter(cached_ip.eq(ip.to_string()))
+        .load::<models::GeoipCacheEntry>(conn)?
+        .pop();
+    Ok(entry.filter(|entry| is_fresh(entry.lookup_time, now, ttl)))
+}
+
+fn save_cached_country(entry: &models::GeoipCacheEntry, conn: &PgConnection) -> Result<(), Error> {
+    use self::schema::geoip_cache::dsl::{geoip_cache, ip};
+    diesel::insert_into(geoip_cache)
+        .values(entry)
+        .on_conflict(ip)
+        .do_update()
+        .set(entry)
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Asks the Maxmind web api for the country of an ip
+fn request_country(ip: &IpAddr, api_user: String, api_key: String) -> Result<String, Error> {
     let client = reqwest::Client::new();
-    let api_user = SETTING
-        .get_exit_network()
-        .geoip_api_user
-        .clone()
-        .expect("No api key configured!");
-    let api_key = SETTING
-        .get_exit_network()
-        .geoip_api_key
-        .clone()
-        .expect("No api key configured!");
-
-    match cache.get(ip) {
-        Some(code) => Ok(code.clone()),
-        None => {
-            let geo_ip_url = format!("https://geoip.maxmind.com/geoip/v2.1/country/{}", ip);
-            info!(
-                "making GeoIP request to {} for {}",
-                geo_ip_url,
-                ip.to_string()
-            );
-
-            let res: GeoIPRet = match client
-                .get(&geo_ip_url)
-                .basic_auth(api_user, Some(api_key))
-                .send()
-            {
-                Ok(mut r) => match r.json() {
-                    Ok(v) => v,
-                    Err(e) => {
-                        warn!("Failed to Jsonize GeoIP response {:?}", e);
-                        bail!("Failed to jsonize GeoIP response {:?}", e)
-                    }
-                },
-                Err(e) => {
-                    warn!("Get request for GeoIP failed! {:?}", e);
-                    bail!("Get request for GeoIP failed {:?}", e)
+    let geo_ip_url = format!("https://geoip.maxmind.com/geoip/v2.1/country/{}", ip);
+    info!(
+        "making GeoIP request to {} for {}",
+        geo_ip_url,
+        ip.to_string()
+    );
+
+    let res: GeoIPRet = match client
+        .get(&geo_ip_url)
+        .basic_auth(api_user, Some(api_key))
+        .send()
+    {
+        Ok(mut r) => match r.json() {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Failed to Jsonize GeoIP response {:?}", e);
+                bail!("Failed to jsonize GeoIP response {:?}", e)
+            }
+        },
+        Err(e) => {
+            warn!("Get request for GeoIP failed! {:?}", e);
+            bail!("Get request for GeoIP failed {:?}", e)
+        }
+    };
+    info!("Got {:?} from GeoIP request", res);
+    Ok(res.country.iso_code)
+}
+
+/// get ISO country code from ip, checking the in memory cache, then the local database, then
+/// the cache in the exit database and finally the Maxmind api if it's configured
+pub fn get_country(
+    ip: &IpAddr,
+    cache: &mut GeoIpCache,
+    conn: &PgConnection,
+) -> Result<String, Error> {
+    trace!("get GeoIP country for {}", ip.to_string());
+    let now = secs_since_unix_epoch();
+    let exit_network = SETTING.get_exit_network().clone();
+    let ttl = exit_network.geoip_cache_ttl;
+
+    if let Some(cached) = cache.countries.get(ip) {
+        if is_fresh(cached.lookup_time, now, ttl) {
+            return Ok(cached.country.clone());
+        }
+    }
+
+    if let Some(ref path) = exit_network.geoip_database {
+        match cache.load_database(path) {
+            Ok(reader) => {
+                if let Some(country) = lookup_database(reader, ip) {
+                    cache.insert(*ip, country.clone(), now);
+                    return Ok(country);
                 }
-            };
-            info!("Got {:?} from GeoIP request", res);
-            cache.insert(*ip, res.country.iso_code.clone());
+            }
+            Err(e) => error!("Failed to load GeoIP database {:?}", e),
+        }
+    }
 
-            Ok(res.country.iso_code)
+    if let Some(entry) = get_cached_country(ip, now, ttl, conn)? {
+        cache.insert(*ip, entry.country.clone(), entry.lookup_time);
+        return Ok(entry.country);
+    }
+
+    match (exit_network.geoip_api_user, exit_network.geoip_api_key) {
+        (Some(api_user), Some(api_key)) => {
+            let country = request_country(ip, api_user, api_key)?;
+            let entry = models::GeoipCacheEntry {
+                ip: ip.to_string(),
+                country: country.clone(),
+                lookup_time: now,
+            };
+            if let Err(e) = save_cached_country(&entry, conn) {
+                error!("Failed to save GeoIP result for {} {:?}", ip, e);
+            }
+            cache.insert(*ip, country.clone(), now);
+            Ok(country)
         }
+        _ => bail!("No GeoIP source has a country for {}", ip),
     }
 }
 
 /// Returns true or false if an ip is confirmed to be inside or outside the region and error
 /// if an api error is encountered trying to figure that out.
-pub fn verify_ip(request_ip: &IpAddr, cache: &mut HashMap<IpAddr, String>) -> Result<bool, Error> {
+pub fn verify_ip(
+    request_ip: &IpAddr,
+    cache: &mut GeoIpCache,
+    conn: &PgConnection,
+) -> Result<bool, Error> {
     if SETTING.get_allowed_countries().is_empty() {
         Ok(true)
     } else {
-        let country = get_country(request_ip, cache)?;
+        let country = get_country(request_ip, cache, conn)?;
 
         if !SETTING.get_allowed_countries().is_empty()
             && !SETTING.get_allowed_countries().contains(&country)
@@ -146,8 +300,33 @@ pub fn verify_ip(request_ip: &IpAddr, cache: &mut HashMap<IpAddr, String>) -> Re
     }
 }
 
-#[test]
-#[ignore]
-fn test_get_country() {
-    get_country(&"8.8.8.8".parse().unwrap(), &mut HashMap::new()).unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_database() -> Reader<Vec<u8>> {
+        // generated by test_data/make_geoip_fixture.py
+        let buf = include_bytes!("../../../test_data/geoip_country.mmdb").to_vec();
+        Reader::from_source(buf).unwrap()
+    }
+
+    #[test]
+    fn test_lookup_database() {
+        let reader = test_database();
+        let lookup = |ip: &str| lookup_database(&reader, &ip.parse().unwrap());
+        assert_eq!(lookup("8.8.8.8"), Some("US".to_string()));
+        assert_eq!(lookup("1.1.1.1"), Some("AU".to_string()));
+        assert_eq!(lookup("81.2.69.160"), Some("GB".to_string()));
+        assert_eq!(lookup("8.8.9.1"), None);
+        // the fixture is ipv4 only
+        assert_eq!(lookup("2001:db8::1"), None);
+    }
+
+    #[test]
+    fn test_is_fresh() {
+        assert!(is_fresh(1000, 1500, 600));
+        assert!(!is_fresh(1000, 1600, 600));
+        // a clock that went backwards doesn't expire anything
+        assert!(is_fresh(1000, 900, 600));
+    }
 }
@@ -1,5 +1,6 @@
 use failure::Error;
-use std::net::IpAddr;
+use ipnetwork::Ipv6Network;
+use std::net::{IpAddr, Ipv6Addr};
 
 /// adds one to whole netmask ip addresses
 pub fn increment(address: IpAddr, netmask: u8) -> Result<IpAddr, Error> {
@@ -72,9 +73,44 @@ pub fn increment(address: IpAddr, netmask: u8) -> Result<IpAddr, Error> {
     }
 }
 
+/// The nth prefix of the given length within the subnet, counting from zero
+pub fn nth_prefix_v6(subnet: Ipv6Network, prefix_len: u8, n: u128) -> Result<Ipv6Network, Error> {
+    if prefix_len < subnet.prefix() || prefix_len > 128 {
+        bail!(
+            "Can't split {} into prefixes of length {}",
+            subnet,
+            prefix_len
+        );
+    }
+    let count = 1u128
+        .checked_shl(u32::from(prefix_len - subnet.prefix()))
+        .unwrap_or(0);
+    if count != 0 && n >= count {
+        bail!("No more ipv6 prefixes in {}", subnet);
+    }
+    let offset = n.checked_shl(u32::from(128 - prefix_len)).unwrap_or(0);
+    let network = Ipv6Addr::from(u128::from(subnet.network()) + offset);
+    Ok(Ipv6Network::new(network, prefix_len)?)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    #[test]
+    fn nth_prefix_v6_basic() {
+        let subnet: Ipv6Network = "2001:db8::/48".parse().unwrap();
+        assert_eq!(
+            nth_prefix_v6(subnet, 64, 0).unwrap(),
+            "2001:db8::/64".parse().unwrap()
+        );
+        assert_eq!(
+            nth_prefix_v6(subnet, 64, 0x1f).unwrap(),
+            "2001:db8:0:1f::/64".parse().unwrap()
+        );
+        assert!(nth_prefix_v6(subnet, 64, 0x10000).is_err());
+        assert!(nth_prefix_v6(subnet, 40, 0).is_err());
+    }
     #[test]
     fn increment_basic_v4() {
         let addr1: IpAddr = [0, 0, 0, 0].into();
@@ -5,6 +5,8 @@
 use crate::rita_common::debt_keeper::DebtAction;
 use crate::rita_common::debt_keeper::DebtKeeper;
 use crate::rita_common::debt_keeper::GetDebtsList;
+use crate::rita_exit::database::admin::admin_denial;
+use crate::rita_exit::database::database_tools::assign_client_ipv6;
 use crate::rita_exit::database::database_tools::client_exists;
 use crate::rita_exit::database::database_tools::delete_client;
 use crate::rita_exit::database::database_tools::get_client;
@@ -14,22 +16,25 @@ use crate::rita_exit::database::database_tools::update_client;
 use crate::rita_exit::database::database_tools::update_low_balance_notification_time;
 use crate::rita_exit::database::database_tools::verify_client;
 use crate::rita_exit::database::database_tools::verify_db_client;
-use crate::rita_exit::database::email::handle_email_registration;
-use crate::rita_exit::database::email::send_low_balance_email;
 use crate::rita_exit::database::geoip::get_country;
 use crate::rita_exit::database::geoip::get_gateway_ip_bulk;
 use crate::rita_exit::database::geoip::get_gateway_ip_single;
 use crate::rita_exit::database::geoip::verify_ip;
-use crate::rita_exit::database::sms::handle_sms_registration;
-use crate::rita_exit::database::sms::send_low_balance_sms;
+use crate::rita_exit::database::geoip::GeoIpCache;
+use crate::rita_exit::database::plans::get_plan_status;
+use crate::rita_exit::database::plans::should_throttle;
 use crate::rita_exit::database::struct_tools::display_hashset;
 use crate::rita_exit::database::struct_tools::to_exit_client;
 use crate::rita_exit::database::struct_tools::to_identity;
 use crate::rita_exit::database::struct_tools::verif_done;
+use crate::rita_exit::database::verification::{
+    first_verif_mode, get_providers, handle_verification,
+};
 use crate::KI;
 use crate::SETTING;
 use ::actix::prelude::SystemService;
 use althea_types::{ExitClientDetails, ExitClientIdentity, ExitDetails, ExitState, ExitVerifMode};
+use babel_monitor::BabelTable;
 use diesel;
 use diesel::prelude::{Connection, ConnectionError, PgConnection, RunQueryDsl};
 use exit_db::{models, schema};
@@ -46,13 +51,14 @@ use std::net::IpAddr;
 use std::time::Instant;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+pub mod admin;
 mod database_tools;
 pub mod db_client;
-mod email;
-mod geoip;
+pub mod geoip;
 mod ip_increment;
-mod sms;
+pub mod plans;
 pub mod struct_tools;
+pub mod verification;
 
 /// Gets the Postgres database connection
 pub fn get_database_connection() -> Result<PgConnection, ConnectionError> {
@@ -76,16 +82,23 @@ pub fn get_database_connection() -> Result<PgConnection, ConnectionError> {
 }
 
 pub fn get_exit_info() -> ExitDetails {
+    let upload_price = SETTING.get_exit_network().get_upload_price();
+    let download_price = SETTING.get_exit_network().get_download_price();
     ExitDetails {
         server_internal_ip: SETTING.get_exit_network().own_internal_ip.into(),
+        server_internal_ipv6: SETTING
+            .get_exit_network()
+            .own_internal_ipv6()
+            .map(|own_ip| own_ip.ip()),
         wg_exit_port: SETTING.get_exit_network().wg_tunnel_port,
-        exit_price: SETTING.get_exit_network().exit_price,
+        exit_price: upload_price.max(download_price),
+        exit_upload_price: Some(upload_price),
+        exit_download_price: Some(download_price),
         exit_currency: SETTING.get_payment().system_chain,
         netmask: SETTING.get_exit_network().netmask,
         description: SETTING.get_description(),
         verif_mode: match SETTING.get_verif_settings() {
-            Some(ExitVerifSettings::Email(_mailer_settings)) => ExitVerifMode::Email,
-            Some(ExitVerifSettings::Phone(_phone_settings)) => ExitVerifMode::Phone,
+            Some(verif_settings) => first_verif_mode(&verif_settings),
             None => ExitVerifMode::Off,
         },
     }
@@ -123,18 +136,23 @@ fn client_to_new_db_client(
         email_sent_time: 0,
         last_seen: 0,
         last_balance_warning_time: 0,
+        // delegated once the client is registered
+        internal_ipv6: String::new(),
     }
 }
 
 /// Handles a new client registration api call. Performs a geoip lookup
 /// on their registration ip to make sure that they are coming from a valid gateway
 /// ip and then sends out an email of phone message
-pub fn signup_client(client: ExitClientIdentity) -> Result<ExitState, Error> {
+pub fn signup_client(
+    client: ExitClientIdentity,
+    babel_table: &BabelTable,
+) -> Result<ExitState, Error> {
     use self::schema::clients::dsl::clients;
-    let mut tmp_cache = HashMap::new();
+    let mut tmp_cache = GeoIpCache::default();
     let conn = get_database_connection()?;
     let client_mesh_ip = client.global.mesh_ip;
-    let gateway_ip = get_gateway_ip_single(client_mesh_ip)?;
+    let gateway_ip = get_gateway_ip_single(client_mesh_ip, babel_table)?;
 
     trace!("got setup request {:?}", client);
 
@@ -151,7 +169,7 @@ pub fn signup_client(client: ExitClientIdentity) -> Result<ExitState, Error> {
         let user_country = if SETTING.get_allowed_countries().is_empty() {
             String::new()
         } else {
-            get_country(&gateway_ip, &mut tmp_cache)?
+            get_country(&gateway_ip, &mut tmp_cache, &conn)?
         };
 
         let c = client_to_new_db_client(&client, new_ip, user_country);
@@ -162,25 +180,20 @@ pub fn signup_client(client: ExitClientIdentity) -> Result<ExitState, Error> {
         c
     };
 
+    if let Some(message) = admin_denial(&their_record, &conn)? {
+        return Ok(ExitState::Denied { message });
+    }
+
     match (
-        verify_ip(&gateway_ip, &mut tmp_cache),
+        verify_ip(&gateway_ip, &mut tmp_cache, &conn),
         SETTING.get_verif_settings(),
     ) {
-        (Ok(true), Some(ExitVerifSettings::Email(mailer))) => {
-            handle_email_registration(&client, &their_record, &conn, mailer.email_cooldown as i64)
-        }
-        (Ok(true), Some(ExitVerifSettings::Phone(phone))) => {
-            handle_sms_registration(&client, &their_record, phone.auth_api_key, &conn)
+        (Ok(true), Some(verif_settings)) => {
+            handle_verification(&client, &their_record, &verif_settings, &conn)
         }
         (Ok(true), None) => {
             verify_client(&client, true, &conn)?;
-            Ok(ExitState::Registered {
-                our_details: ExitClientDetails {
-                    client_internal_ip: their_record.internal_ip.parse()?,
-                },
-                general_details: get_exit_info(),
-                message: "Registration OK".to_string(),
-            })
+            registered_state(&their_record, &conn)
         }
         (Ok(false), _) => Ok(ExitState::Denied {
             message: format!(
@@ -194,6 +207,22 @@ pub fn signup_client(client: ExitClientIdentity) -> Result<ExitState, Error> {
     }
 }
 
+/// The state of a client that has passed verification
+pub fn registered_state(
+    their_record: &models::Client,
+    conn: &PgConnection,
+) -> Result<ExitState, Error> {
+    Ok(ExitState::Registered {
+        our_details: ExitClientDetails {
+            client_internal_ip: their_record.internal_ip.parse()?,
+            client_internal_ipv6: assign_client_ipv6(&their_record, conn)?,
+            plan: get_plan_status(&their_record.mesh_ip, conn),
+        },
+        general_details: get_exit_info(),
+        message: "Registration OK".to_string(),
+    })
+}
+
 /// Gets the status of a client and updates it in the database
 pub fn client_status(client: ExitClientIdentity) -> Result<ExitState, Error> {
     let conn = get_database_connection()?;
@@ -206,10 +235,14 @@ pub fn client_status(client: ExitClientIdentity) -> Result<ExitState, Error> {
 
         let their_record = get_client(client_mesh_ip, &conn)?;
 
+        if let Some(message) = admin_denial(&their_record, &conn)? {
+            return Ok(ExitState::Denied { message });
+        }
+
         if !verif_done(&their_record) {
             return Ok(ExitState::Pending {
                 general_details: get_exit_info(),
-                message: "awaiting email verification".to_string(),
+                message: "awaiting verification".to_string(),
                 email_code: None,
                 phone_code: None,
             });
@@ -236,6 +269,8 @@ pub fn client_status(client: ExitClientIdentity) -> Result<ExitState, Error> {
         Ok(ExitState::Registered {
             our_details: ExitClientDetails {
                 client_internal_ip: current_ip,
+                client_internal_ipv6: assign_client_ipv6(&their_record, &conn)?,
+                plan: get_plan_status(&their_record.mesh_ip, &conn),
             },
             general_details: get_exit_info(),
             message: "Registration OK".to_string(),
@@ -245,10 +280,8 @@ pub fn client_status(client: ExitClientIdentity) -> Result<ExitState, Error> {
     }
 }
 
-/// Handles the dispatching of low balance notifications based on what validation method the exit
-/// is currently using and what the configured interval is. There are many many possible combinations
-/// of state to handle so this is a bit of a mess. May be possible to clean up by making more things
-/// mandatory?
+/// Handles the dispatching of low balance notifications through the first verification
+/// method that has a way to reach the client, at most once per its configured interval
 fn low_balance_notification(
     client: ExitClientIdentity,
     their_record: &exit_db::models::Client,
@@ -259,50 +292,30 @@ fn low_balance_notification(
     let time_since_last_notification =
         secs_since_unix_epoch() - their_record.last_balance_warning_time;
 
-    match (client.low_balance, config) {
-        (Some(true), Some(ExitVerifSettings::Phone(val))) => match (
-            client.reg_details.phone.clone(),
-            time_since_last_notification > i64::from(val.balance_notification_interval),
-        ) {
-            (Some(number), true) => {
-                let res = send_low_balance_sms(&number, val);
-                if let Err(e) = res {
-                    warn!(
-                        "Failed to notify {} of their low balance with {:?}",
-                        number, e
-                    );
-                } else if let Err(e) = update_low_balance_notification_time(&client, conn) {
-                    error!(
-                        "Failed to find {:?} in the database to update notified time! {:?}",
-                        client, e
-                    );
-                }
-            }
-            (Some(_), false) => {}
-            (None, _) => error!("Client is registered but has no phone number!"),
-        },
-        (Some(true), Some(ExitVerifSettings::Email(val))) => match (
-            client.reg_details.email.clone(),
-            time_since_last_notification > i64::from(val.balance_notification_interval),
-        ) {
-            (Some(email), true) => {
-                let res = send_low_balance_email(&email, val);
-                if let Err(e) = res {
-                    warn!(
-                        "Failed to notify {} of their low balance with {:?}",
-                        email, e
-                    );
-                } else if let Err(e) = update_low_balance_notification_time(&client, conn) {
-                    error!(
-                        "Failed to find {:?} in the database to update notified time! {:?}",
-                        client, e
-                    );
-                }
+    let config = match (client.low_balance, config) {
+        (Some(true), Some(config)) => config,
+        (_, _) => return,
+    };
+    let providers = get_providers(&config);
+    let notifier = providers
+        .iter()
+        .find_map(|step| Some((step, step.balance_notification_interval()?)));
+    match notifier {
+        Some((step, interval)) if time_since_last_notification > i64::from(interval) => {
+            if let Err(e) = step.notify_low_balance(&client) {
+                warn!(
+                    "Failed to notify {:?} of their low balance with {:?}",
+                    client, e
+                );
+            } else if let Err(e) = update_low_balance_notification_time(&client, conn) {
+                error!(
+                    "Failed to find {:?} in the database to update notified time! {:?}",
+                    client, e
+                );
             }
-            (Some(_), false) => {}
-            (None, _) => error!("Client is registered but has no phone number!"),
-        },
-        (_, _) => {}
+        }
+        Some(_) => {}
+        None => trace!("No verification method can notify {:?}", client),
     }
 }
 
@@ -310,7 +323,8 @@ fn low_balance_notification(
 /// we also do this in the client status requests but we want to handle the edge case of a modified
 /// client that doesn't make status requests
 pub fn validate_clients_region(
-    mut geoip_cache: &mut HashMap<IpAddr, String>,
+    mut geoip_cache: &mut GeoIpCache,
+    babel_table: &BabelTable,
     clients_list: &[exit_db::models::Client],
     conn: &PgConnection,
 ) -> Result<(), Error> {
@@ -330,12 +344,12 @@ pub fn validate_clients_region(
         }
     }
 
-    let mesh_to_gateway_ip_list = get_gateway_ip_bulk(ip_vec);
+    let mesh_to_gateway_ip_list = get_gateway_ip_bulk(ip_vec, babel_table);
 
     match mesh_to_gateway_ip_list {
         Ok(list) => {
             for item in list {
-                match verify_ip(&item.gateway_ip, &mut geoip_cache) {
+                match verify_ip(&item.gateway_ip, &mut geoip_cache, conn) {
                     Ok(true) => trace!("{:?} is from an allowed ip", item),
                     Ok(false) => {
                         // get_gateway_ip_bulk can't add new entires to the list
@@ -448,6 +462,7 @@ pub fn setup_clients(clients_list: &[exit_db::models::Client]) -> Result<(), Err
         &SETTING.get_exit_network().wg_private_key_path,
         &SETTING.get_exit_network().own_internal_ip.into(),
         SETTING.get_exit_network().netmask,
+        SETTING.get_exit_network().own_internal_ipv6(),
     );
 
     match exit_status {
@@ -472,8 +487,11 @@ pub fn setup_clients(clients_list: &[exit_db::models::Client]) -> Result<(), Err
 /// setting the htb class they are assigned to to a maximum speed of the free tier value.
 /// Unlike intermediary enforcement we do not need to subdivide the free tier to prevent
 /// ourselves from exceeding the upstream free tier. As an exit we are the upstream.
+///
+/// Clients on a plan that throttles them once it's used up are limited the same way.
 pub fn enforce_exit_clients(
     clients_list: Vec<exit_db::models::Client>,
+    plans: HashMap<IpAddr, exit_db::models::ClientPlan>,
 ) -> Box<Future<Item = (), Error = ()>> {
     let start = Instant::now();
     Box::new(
@@ -484,6 +502,7 @@ pub fn enforce_exit_clients(
                 Ok(list) => {
                     let mut clients_by_id = HashMap::new();
                     let free_tier_limit = SETTING.get_payment().free_tier_throughput;
+                    let now = secs_since_unix_epoch();
                     for client in clients_list.iter() {
                         if let Ok(id) = to_identity(client) {
                             clients_by_id.insert(id, client);
@@ -493,10 +512,16 @@ pub fn enforce_exit_clients(
                     for debt_entry in list.iter() {
                         match clients_by_id.get(&debt_entry.identity) {
                             Some(client) => {
+                                // the class is keyed by the client's ipv4 address, their ipv6
+                                // prefix is filtered into the same class
                                 match client.internal_ip.parse() {
                                     Ok(IpAddr::V4(ip)) => {
+                                        let plan_used_up = plans
+                                            .get(&debt_entry.identity.mesh_ip)
+                                            .map_or(false, |plan| should_throttle(plan, now));
                                         let res = if debt_entry.payment_details.action
                                             == DebtAction::SuspendTunnel
+                                            || plan_used_up
                                         {
                                             KI.set_class_limit(
                                                 "wg_exit",
@@ -0,0 +1,333 @@
+//! Billing plans for exit clients. Plans live in the `client_plans` table, a client without
+//! an entry there pays per byte at the exit price. A flat rate plan charges its price at the
+//! start of every period and renews its quota with it, a prepaid plan charges its price once
+//! for a bundle that lasts until the quota runs out or the period ends. Operators renew a
+//! prepaid bundle by setting its `period_start` back to zero.
+//!
+//! Traffic within the quota is not billed, what happens after that is up to the plan's
+//! `over_quota`, either the client is throttled to the free tier or billed per byte again.
+
+use crate::rita_exit::database::secs_since_unix_epoch;
+use althea_kernel_interface::wg_iface_counter::WgUsage;
+use althea_types::{ExitPlanStatus, ExitPlanType, OverQuotaAction};
+use diesel;
+use diesel::prelude::{Connection, ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use exit_db::{models, schema};
+use failure::Error;
+use num256::Uint256;
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+pub fn plan_type(plan: &models::ClientPlan) -> Result<ExitPlanType, Error> {
+    match plan.plan_type.as_str() {
+        "flat_rate" => Ok(ExitPlanType::FlatRate),
+        "prepaid" => Ok(ExitPlanType::Prepaid),
+        other => bail!("Unknown plan type {} for {}", other, plan.mesh_ip),
+    }
+}
+
+pub fn over_quota_action(plan: &models::ClientPlan) -> Result<OverQuotaAction, Error> {
+    match plan.over_quota.as_str() {
+        "throttle" => Ok(OverQuotaAction::Throttle),
+        "per_byte" => Ok(OverQuotaAction::PerByte),
+        other => bail!("Unknown over quota action {} for {}", other, plan.mesh_ip),
+    }
+}
+
+/// Checks what the database can't, a plan that fails this is ignored and the client billed
+/// per byte like everyone else
+pub fn validate_plan(plan: &models::ClientPlan) -> Result<(), Error> {
+    plan_type(plan)?;
+    over_quota_action(plan)?;
+    if plan.period <= 0 {
+        bail!("Plan for {} has a period of {}", plan.mesh_ip, plan.period);
+    }
+    if plan.quota < 0 {
+        bail!("Plan for {} has a quota of {}", plan.mesh_ip, plan.quota);
+    }
+    if plan.price.parse::<Uint256>().is_err() {
+        bail!(
+            "Plan for {} has an invalid price {}",
+            plan.mesh_ip,
+            plan.price
+        );
+    }
+    Ok(())
+}
+
+fn is_expired(plan: &models::ClientPlan, plan_type: ExitPlanType, now: i64) -> bool {
+    plan_type == ExitPlanType::Prepaid
+        && plan.period_start != 0
+        && now >= plan.period_start + plan.period
+}
+
+/// True if the client has used up their plan and should be limited to the free tier
+pub fn should_throttle(plan: &models::ClientPlan, now: i64) -> bool {
+    match (plan_type(plan), over_quota_action(plan)) {
+        (Ok(plan_type), Ok(OverQuotaAction::Throttle)) => {
+            plan.used >= plan.quota || is_expired(plan, plan_type, now)
+        }
+        (Ok(_), Ok(OverQuotaAction::PerByte)) => false,
+        (Err(e), _) | (_, Err(e)) => {
+            warn!("Invalid plan {:?}", e);
+            false
+        }
+    }
+}
+
+/// The plan as the client sees it
+pub fn plan_status(plan: &models::ClientPlan, now: i64) -> Result<ExitPlanStatus, Error> {
+    validate_plan(plan)?;
+    let plan_type = plan_type(plan)?;
+    let remaining = if is_expired(plan, plan_type, now) {
+        0
+    } else {
+        (plan.quota - plan.used).max(0) as u64
+    };
+    Ok(ExitPlanStatus {
+        plan_type,
+        quota: plan.quota as u64,
+        period: plan.period as u64,
+        price: plan.price.parse()?,
+        over_quota: over_quota_action(plan)?,
+        period_start: plan.period_start as u64,
+        used: plan.used as u64,
+        remaining,
+    })
+}
+
+/// All valid plans keyed by the client's mesh ip
+pub fn get_plans(conn: &PgConnection) -> Result<HashMap<IpAddr, models::ClientPlan>, Error> {
+    use self::schema::client_plans::dsl::client_plans;
+    let mut plans = HashMap::new();
+    for plan in client_plans.load::<models::ClientPlan>(conn)? {
+        if let Err(e) = validate_plan(&plan) {
+            error!("Ignoring invalid plan {:?}", e);
+            continue;
+        }
+        match plan.mesh_ip.parse() {
+            Ok(ip) => {
+                plans.insert(ip, plan);
+            }
+            Err(_e) => error!("Plan with invalid mesh ip! {:?}", plan),
+        }
+    }
+    Ok(plans)
+}
+
+/// The status of a client's plan, None if they have no plan or it can't be read
+pub fn get_plan_status(client_mesh_ip: &str, conn: &PgConnection) -> Option<ExitPlanStatus> {
+    use self::schema::client_plans::dsl::{client_plans, mesh_ip};
+    let plan = match client_plans
+        .filter(mesh_ip.eq(client_mesh_ip))
+        .load::<models::ClientPlan>(conn)
+    {
+        Ok(mut entry) => entry.pop()?,
+        Err(e) => {
+            error!(
+                "We failed to lookup the plan for {} {:?}",
+                client_mesh_ip, e
+            );
+            return None;
+        }
+    };
+    match plan_status(&plan, secs_since_unix_epoch()) {
+        Ok(status) => Some(status),
+        Err(e) => {
+            error!("Invalid plan {:?} {:?}", plan, e);
+            None
+        }
+    }
+}
+
+/// A round of billing for a client on a plan
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlanCharge {
+    /// The part of the usage that is billed per byte
+    pub billable: WgUsage,
+    /// Charged for starting a new period or bundle
+    pub period_price: Option<Uint256>,
+    /// Set if a new period started, the usage is counted from this time
+    pub new_period: Option<i64>,
+    /// Bytes to add to the plan's usage
+    pub used: i64,
+}
+
+/// Works out what a client on a plan owes for a round of usage. The plan is updated the same
+/// way `save_plan_usage` updates the database
+pub fn apply_plan(
+    plan: &mut models::ClientPlan,
+    usage: &WgUsage,
+    now: i64,
+) -> Result<PlanCharge, Error> {
+    let plan_type = plan_type(plan)?;
+    let over_quota = over_quota_action(plan)?;
+
+    let starts_period = match plan_type {
+        ExitPlanType::FlatRate => plan.period_start == 0 || now >= plan.period_start + plan.period,
+        ExitPlanType::Prepaid => plan.period_start == 0,
+    };
+    let (period_price, new_period) = if starts_period {
+        // a flat rate plan that has been idle for more than a period starts over from now
+        let start = if plan.period_start != 0 && now < plan.period_start + 2 * plan.period {
+            plan.period_start + plan.period
+        } else {
+            now
+        };
+        info!(
+            "Starting a new plan period for {} at {}",
+            plan.mesh_ip, start
+        );
+        plan.period_start = start;
+        plan.used = 0;
+        (Some(plan.price.parse()?), Some(start))
+    } else {
+        (None, None)
+    };
+
+    let total = usage.upload + usage.download;
+    let remaining = if is_expired(plan, plan_type, now) {
+        0
+    } else {
+        (plan.quota - plan.used).max(0) as u64
+    };
+    let over = total.saturating_sub(remaining);
+    let billable = if over == 0 || over_quota == OverQuotaAction::Throttle {
+        WgUsage {
+            upload: 0,
+            download: 0,
+        }
+    } else {
+        // the quota covers upload and download in proportion to how much of each was used
+        let upload = (u128::from(usage.upload) * u128::from(over) / u128::from(total)) as u64;
+        WgUsage {
+            upload,
+            download: over - upload,
+        }
+    };
+    plan.used += total as i64;
+
+    Ok(PlanCharge {
+        billable,
+        period_price,
+        new_period,
+        used: total as i64,
+    })
+}
+
+/// Writes the usage from a round of billing, usage is added to what is in the database so that
+/// usage from a round that raced with another update isn't lost. Either every charge is saved
+/// or none are, so that an error means no new period was recorded and none may be charged
+pub fn save_plan_usage(charges: &[(String, PlanCharge)], conn: &PgConnection) -> Result<(), Error> {
+    use self::schema::client_plans::dsl::{client_plans, period_start, used};
+    conn.transaction::<_, Error, _>(|| {
+        for (client_mesh_ip, charge) in charges {
+            match charge.new_period {
+                Some(start) => diesel::update(client_plans.find(client_mesh_ip))
+                    .set((period_start.eq(start), used.eq(charge.used)))
+                    .execute(conn)?,
+                None => diesel::update(client_plans.find(client_mesh_ip))
+                    .set(used.eq(used + charge.used))
+                    .execute(conn)?,
+            };
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_plan(plan_type: &str, over_quota: &str) -> models::ClientPlan {
+        models::ClientPlan {
+            mesh_ip: "fd00::1".to_string(),
+            plan_type: plan_type.to_string(),
+            quota: 1000,
+            period: 100,
+            price: "5000".to_string(),
+            over_quota: over_quota.to_string(),
+            period_start: 0,
+            used: 0,
+        }
+    }
+
+    fn usage(upload: u64, download: u64) -> WgUsage {
+        WgUsage { upload, download }
+    }
+
+    #[test]
+    fn test_flat_rate_plan() {
+        let mut plan = test_plan("flat_rate", "per_byte");
+
+        // the first round starts a period and charges for it
+        let charge = apply_plan(&mut plan, &usage(100, 300), 1000).unwrap();
+        assert_eq!(charge.period_price, Some(5000u32.into()));
+        assert_eq!(charge.new_period, Some(1000));
+        assert_eq!(charge.billable, usage(0, 0));
+
+        // going over the quota bills the excess split between upload and download
+        let charge = apply_plan(&mut plan, &usage(200, 600), 1050).unwrap();
+        assert_eq!(charge.period_price, None);
+        assert_eq!(charge.billable, usage(50, 150));
+        assert_eq!(plan.used, 1200);
+
+        // the next period renews the quota
+        let charge = apply_plan(&mut plan, &usage(0, 500), 1100).unwrap();
+        assert_eq!(charge.new_period, Some(1100));
+        assert_eq!(charge.period_price, Some(5000u32.into()));
+        assert_eq!(charge.billable, usage(0, 0));
+        assert_eq!(plan.used, 500);
+
+        // after a long idle stretch periods start from now rather than catching up
+        let charge = apply_plan(&mut plan, &usage(0, 0), 5000).unwrap();
+        assert_eq!(charge.new_period, Some(5000));
+    }
+
+    #[test]
+    fn test_validate_plan() {
+        assert!(validate_plan(&test_plan("flat_rate", "per_byte")).is_ok());
+
+        let mut plan = test_plan("flat_rate", "per_byte");
+        plan.period = 0;
+        assert!(validate_plan(&plan).is_err());
+        assert!(plan_status(&plan, 1000).is_err());
+        plan.period = -100;
+        assert!(validate_plan(&plan).is_err());
+
+        let mut plan = test_plan("prepaid", "throttle");
+        plan.quota = -1;
+        assert!(validate_plan(&plan).is_err());
+
+        let mut plan = test_plan("prepaid", "throttle");
+        plan.price = "-5000".to_string();
+        assert!(validate_plan(&plan).is_err());
+
+        assert!(validate_plan(&test_plan("monthly", "throttle")).is_err());
+    }
+
+    #[test]
+    fn test_prepaid_plan() {
+        let mut plan = test_plan("prepaid", "throttle");
+        let charge = apply_plan(&mut plan, &usage(0, 600), 1000).unwrap();
+        assert_eq!(charge.period_price, Some(5000u32.into()));
+        assert!(!should_throttle(&plan, 1000));
+
+        // a throttled plan never bills per byte
+        let charge = apply_plan(&mut plan, &usage(0, 600), 1010).unwrap();
+        assert_eq!(charge.billable, usage(0, 0));
+        assert!(should_throttle(&plan, 1010));
+        assert_eq!(plan_status(&plan, 1010).unwrap().remaining, 0);
+
+        // the bundle never renews by itself
+        let charge = apply_plan(&mut plan, &usage(0, 10), 2000).unwrap();
+        assert_eq!(charge.period_price, None);
+
+        // once it expires all traffic is over quota
+        let mut plan = test_plan("prepaid", "per_byte");
+        apply_plan(&mut plan, &usage(0, 10), 1000).unwrap();
+        let charge = apply_plan(&mut plan, &usage(10, 10), 1100).unwrap();
+        assert_eq!(charge.billable, usage(10, 10));
+        assert_eq!(plan_status(&plan, 1100).unwrap().remaining, 0);
+    }
+}
@@ -20,6 +20,8 @@ pub fn to_exit_client(client: Client) -> Result<ExitClient, Error> {
     Ok(ExitClient {
         mesh_ip: client.mesh_ip.parse()?,
         internal_ip: client.internal_ip.parse()?,
+        // clients get a prefix once they are registered
+        internal_ipv6: client.internal_ipv6.parse().ok(),
         port: client.wg_port as u16,
         public_key: client.wg_pubkey.parse()?,
     })
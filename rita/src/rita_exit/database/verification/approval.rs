@@ -0,0 +1,80 @@
+//! A queue of clients waiting for the operator to let them in. A client joins the queue the
+//! first time it reaches this step, with an `approval` entry in `client_verifications` in the
+//! `pending` state. The operator approves a client by setting that entry to `verified`, or
+//! turns them away with `denied`:
+//!
+//! ```sql
+//! UPDATE client_verifications SET state = 'verified'
+//!     WHERE mesh_ip = 'fd00::1' AND method = 'approval';
+//! ```
+
+use crate::rita_exit::database::verification::{
+    get_step_state, set_step_state, VerifStatus, VerificationProvider, STATE_DENIED, STATE_PENDING,
+    STATE_VERIFIED,
+};
+use althea_types::{ExitClientIdentity, ExitVerifMode};
+use diesel::prelude::PgConnection;
+use exit_db::models;
+use failure::Error;
+
+pub const APPROVAL: &str = "approval";
+
+pub struct ApprovalProvider;
+
+fn approval_status(state: Option<&str>) -> Result<VerifStatus, Error> {
+    let pending = || VerifStatus::Pending("awaiting approval by the exit operator".to_string());
+    match state {
+        None | Some(STATE_PENDING) => Ok(pending()),
+        Some(STATE_VERIFIED) => Ok(VerifStatus::Verified),
+        Some(STATE_DENIED) => Ok(VerifStatus::Denied(
+            "The exit operator declined this registration".to_string(),
+        )),
+        Some(other) => bail!("Unknown approval state {}", other),
+    }
+}
+
+impl VerificationProvider for ApprovalProvider {
+    fn name(&self) -> &'static str {
+        APPROVAL
+    }
+
+    fn mode(&self) -> ExitVerifMode {
+        ExitVerifMode::Off
+    }
+
+    fn verify(
+        &self,
+        client: &ExitClientIdentity,
+        their_record: &models::Client,
+        conn: &PgConnection,
+    ) -> Result<VerifStatus, Error> {
+        let state = get_step_state(&their_record.mesh_ip, APPROVAL, conn)?;
+        if state.is_none() {
+            info!("{:?} is waiting for operator approval", client);
+            set_step_state(&their_record.mesh_ip, APPROVAL, STATE_PENDING, conn)?;
+        }
+        approval_status(state.as_ref().map(String::as_str))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_approval_status() {
+        assert!(match approval_status(None).unwrap() {
+            VerifStatus::Pending(_) => true,
+            _ => false,
+        });
+        assert_eq!(
+            approval_status(Some("verified")).unwrap(),
+            VerifStatus::Verified
+        );
+        assert!(match approval_status(Some("denied")).unwrap() {
+            VerifStatus::Denied(_) => true,
+            _ => false,
+        });
+        assert!(approval_status(Some("lost")).is_err());
+    }
+}
@@ -0,0 +1,260 @@
+use crate::rita_exit::database::database_tools::update_mail_sent_time;
+use crate::rita_exit::database::secs_since_unix_epoch;
+use crate::rita_exit::database::verification::{VerifStatus, VerificationProvider};
+use althea_types::{ExitClientIdentity, ExitVerifMode};
+use diesel::prelude::PgConnection;
+use exit_db::models;
+use failure::Error;
+use handlebars::Handlebars;
+use lettre::file::FileTransport;
+use lettre::smtp::authentication::{Credentials, Mechanism};
+use lettre::smtp::extension::ClientId;
+use lettre::smtp::ConnectionReuseParameters;
+use lettre::{SmtpClient, SmtpTransport, Transport};
+use lettre_email::{Email, EmailBuilder};
+use settings::exit::EmailVerifSettings;
+
+/// Applies the credentials and options from our settings to an SMTP client
+fn smtp_transport(client: SmtpClient, mailer: &EmailVerifSettings) -> SmtpTransport {
+    client
+        .hello_name(ClientId::Domain(mailer.smtp_domain.clone()))
+        .credentials(Credentials::new(
+            mailer.smtp_username.clone(),
+            mailer.smtp_password.clone(),
+        ))
+        .smtp_utf8(true)
+        .authentication_mechanism(Mechanism::Plain)
+        .connection_reuse(ConnectionReuseParameters::ReuseUnlimited)
+        .transport()
+}
+
+/// Sends over SMTP, or into `test_dir` when `test` is set
+fn deliver(email: Email, mailer: &EmailVerifSettings) -> Result<(), Error> {
+    if mailer.test {
+        let mut transport = FileTransport::new(&mailer.test_dir);
+        transport.send(email.into())?;
+    } else {
+        // TODO add serde to lettre
+        let mut transport = smtp_transport(SmtpClient::new_simple(&mailer.smtp_url)?, mailer);
+        transport.send(email.into())?;
+    }
+    Ok(())
+}
+
+fn signup_email(client: &models::Client, mailer: &EmailVerifSettings) -> Result<Email, Error> {
+    let reg = Handlebars::new();
+
+    Ok(EmailBuilder::new()
+        .to(client.email.clone())
+        .from(mailer.from_address.clone())
+        .subject(mailer.signup_subject.clone())
+        // TODO: maybe have a proper templating engine
+        .text(reg.render_template(
+            &mailer.signup_body,
+            &json!({"email_code": client.email_code.to_string()}),
+        )?)
+        .build()?)
+}
+
+pub fn send_mail(client: &models::Client, mailer: &EmailVerifSettings) -> Result<(), Error> {
+    info!("Sending exit signup email for client");
+    deliver(signup_email(client, mailer)?, mailer)
+}
+
+/// Sends the client a code by email over SMTP, or into `test_dir` when `test` is set
+pub struct EmailProvider {
+    mailer: EmailVerifSettings,
+}
+
+impl EmailProvider {
+    pub fn new(mailer: EmailVerifSettings) -> EmailProvider {
+        EmailProvider { mailer }
+    }
+}
+
+impl VerificationProvider for EmailProvider {
+    fn name(&self) -> &'static str {
+        "email"
+    }
+
+    fn mode(&self) -> ExitVerifMode {
+        ExitVerifMode::Email
+    }
+
+    /// handles the minutia of emails and cooldowns
+    fn verify(
+        &self,
+        client: &ExitClientIdentity,
+        their_record: &models::Client,
+        conn: &PgConnection,
+    ) -> Result<VerifStatus, Error> {
+        if their_record.email.is_empty() {
+            return Ok(VerifStatus::Denied(
+                "This exit requires an email address to register!".to_string(),
+            ));
+        }
+        if client.reg_details.email_code == Some(their_record.email_code.clone()) {
+            info!("email verification complete for {:?}", client);
+            return Ok(VerifStatus::Verified);
+        }
+
+        let cooldown = self.mailer.email_cooldown as i64;
+        let time_since_last_email = secs_since_unix_epoch() - their_record.email_sent_time;
+
+        if time_since_last_email < cooldown {
+            Ok(VerifStatus::Cooldown(format!(
+                "Wait {} more seconds for verification cooldown",
+                cooldown - time_since_last_email
+            )))
+        } else {
+            update_mail_sent_time(&client, &conn)?;
+            send_mail(&their_record, &self.mailer)?;
+            Ok(VerifStatus::Pending(
+                "awaiting email verification".to_string(),
+            ))
+        }
+    }
+
+    fn balance_notification_interval(&self) -> Option<u32> {
+        Some(self.mailer.balance_notification_interval)
+    }
+
+    fn notify_low_balance(&self, client: &ExitClientIdentity) -> Result<(), Error> {
+        match client.reg_details.email {
+            Some(ref email) => send_low_balance_email(email, self.mailer.clone()),
+            None => bail!("Client is registered but has no email!"),
+        }
+    }
+}
+
+pub fn send_low_balance_email(email: &str, mailer: EmailVerifSettings) -> Result<(), Error> {
+    info!("Sending low balance email to {}", email);
+
+    let email = EmailBuilder::new()
+        .to(email)
+        .from(mailer.from_address.clone())
+        .subject(mailer.balance_notification_subject.clone())
+        .text(mailer.balance_notification_body.clone())
+        .build()?;
+    deliver(email, &mailer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lettre::ClientSecurity;
+    use std::fs;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Plays the server side of an SMTP session for a single message and sends back every
+    /// line the client wrote
+    fn mock_smtp_server() -> (u16, mpsc::Receiver<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let reader = BufReader::new(stream);
+            let mut lines = Vec::new();
+            let mut in_data = false;
+            writer.write_all(b"220 mock.example.com ESMTP\r\n").unwrap();
+            for line in reader.lines() {
+                let line = line.unwrap();
+                lines.push(line.clone());
+                let reply: &[u8] = if in_data {
+                    if line != "." {
+                        continue;
+                    }
+                    in_data = false;
+                    tx.send(lines.clone()).unwrap();
+                    b"250 2.0.0 queued\r\n"
+                } else if line.starts_with("EHLO") {
+                    b"250-mock.example.com\r\n250 AUTH PLAIN\r\n"
+                } else if line.starts_with("AUTH") {
+                    b"235 2.7.0 authenticated\r\n"
+                } else if line == "DATA" {
+                    in_data = true;
+                    b"354 end with .\r\n"
+                } else if line == "QUIT" {
+                    writer.write_all(b"221 bye\r\n").unwrap();
+                    break;
+                } else {
+                    b"250 OK\r\n"
+                };
+                writer.write_all(reply).unwrap();
+            }
+        });
+        (port, rx)
+    }
+
+    #[test]
+    fn test_send_mail_to_file() {
+        let test_dir = std::env::temp_dir().join(format!("rita-email-test-{}", std::process::id()));
+        fs::create_dir_all(&test_dir).unwrap();
+        let mailer = EmailVerifSettings {
+            from_address: "verification@example.com".to_string(),
+            signup_body: "Your code is {{email_code}}".to_string(),
+            test: true,
+            test_dir: test_dir.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let client = models::Client {
+            email: "client@example.com".to_string(),
+            email_code: "123456".to_string(),
+            ..Default::default()
+        };
+
+        send_mail(&client, &mailer).unwrap();
+
+        let sent: Vec<String> = fs::read_dir(&test_dir)
+            .unwrap()
+            .map(|entry| fs::read_to_string(entry.unwrap().path()).unwrap())
+            .collect();
+        fs::remove_dir_all(&test_dir).unwrap();
+        // the file transport writes out the envelope along with the message
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].contains("client@example.com"));
+    }
+
+    #[test]
+    fn test_send_mail_over_smtp() {
+        let (port, sent) = mock_smtp_server();
+        let mailer = EmailVerifSettings {
+            from_address: "verification@example.com".to_string(),
+            signup_body: "Your code is {{email_code}}".to_string(),
+            smtp_domain: "exit.example.com".to_string(),
+            smtp_username: "verifier".to_string(),
+            smtp_password: "secret".to_string(),
+            ..Default::default()
+        };
+        let client = models::Client {
+            email: "client@example.com".to_string(),
+            email_code: "123456".to_string(),
+            ..Default::default()
+        };
+
+        let smtp = SmtpClient::new(("127.0.0.1", port), ClientSecurity::None).unwrap();
+        smtp_transport(smtp, &mailer)
+            .send(signup_email(&client, &mailer).unwrap().into())
+            .unwrap();
+
+        let lines = sent.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(lines[0], "EHLO exit.example.com");
+        // the username and password, nul separated and base64 encoded
+        assert!(lines.contains(&"AUTH PLAIN AHZlcmlmaWVyAHNlY3JldA==".to_string()));
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("MAIL FROM:<verification@example.com>")));
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("RCPT TO:<client@example.com>")));
+        assert!(lines
+            .iter()
+            .any(|line| line.contains("Your code is 123456")));
+    }
+}
@@ -0,0 +1,391 @@
+//! Registration verification. Each way of verifying a client is a `VerificationProvider` and
+//! operators can chain several of them, in which case a client has to pass every one in the
+//! configured order. Finished steps are recorded in the `client_verifications` table so that
+//! a client who confirmed their email doesn't have to do it again while waiting on the next
+//! step.
+//!
+//! Clients only have a single code field, so while a client is pending the exit details it's
+//! sent carry the verification mode of the step it's on rather than the first step.
+
+use crate::rita_exit::database::database_tools::verify_client;
+use crate::rita_exit::database::get_exit_info;
+use crate::rita_exit::database::registered_state;
+use crate::rita_exit::database::secs_since_unix_epoch;
+use crate::rita_exit::database::struct_tools::verif_done;
+use althea_types::{ExitClientIdentity, ExitState, ExitVerifMode};
+use diesel;
+use diesel::prelude::{ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use exit_db::{models, schema};
+use failure::Error;
+use settings::exit::ExitVerifSettings;
+use std::collections::HashSet;
+
+pub mod approval;
+pub mod email;
+pub mod sms;
+pub mod webhook;
+
+use self::approval::ApprovalProvider;
+use self::email::EmailProvider;
+use self::sms::PhoneProvider;
+use self::webhook::WebhookProvider;
+
+pub const STATE_PENDING: &str = "pending";
+pub const STATE_VERIFIED: &str = "verified";
+pub const STATE_DENIED: &str = "denied";
+
+/// Where a client stands with a single verification step
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifStatus {
+    /// The client passed this step
+    Verified,
+    /// Waiting on the client or someone else, the message is passed on to the client
+    Pending(String),
+    /// The client has to wait before this step can be started again
+    Cooldown(String),
+    Denied(String),
+}
+
+pub trait VerificationProvider {
+    /// Identifies this step in the `client_verifications` table
+    fn name(&self) -> &'static str;
+
+    /// Tells clients where to put the code this step sends them
+    fn mode(&self) -> ExitVerifMode;
+
+    /// Checks the registration details the client sent us, starting this step (sending a code,
+    /// queueing the client for approval) if the client hasn't been through it yet
+    fn verify(
+        &self,
+        client: &ExitClientIdentity,
+        their_record: &models::Client,
+        conn: &PgConnection,
+    ) -> Result<VerifStatus, Error>;
+
+    /// How often in seconds this provider may tell a client their balance is low, None if it
+    /// has no way to reach clients
+    fn balance_notification_interval(&self) -> Option<u32> {
+        None
+    }
+
+    fn notify_low_balance(&self, _client: &ExitClientIdentity) -> Result<(), Error> {
+        bail!("{} verification can't send notifications", self.name())
+    }
+}
+
+/// The steps a client has to pass, in order
+pub fn get_providers(settings: &ExitVerifSettings) -> Vec<Box<dyn VerificationProvider>> {
+    match settings {
+        ExitVerifSettings::Email(email) => vec![Box::new(EmailProvider::new(email.clone()))],
+        ExitVerifSettings::Phone(phone) => vec![Box::new(PhoneProvider::new(phone.clone()))],
+        ExitVerifSettings::Webhook(webhook) => {
+            vec![Box::new(WebhookProvider::new(webhook.clone()))]
+        }
+        ExitVerifSettings::Approval => vec![Box::new(ApprovalProvider)],
+        ExitVerifSettings::Chain(chain) => chain.iter().flat_map(get_providers).collect(),
+    }
+}
+
+/// Steps record their progress in `client_verifications` under their name, so a chain can't
+/// have two steps of the same kind
+pub fn check_providers(settings: &ExitVerifSettings) -> Result<(), Error> {
+    let mut names = HashSet::new();
+    for step in get_providers(settings) {
+        if !names.insert(step.name()) {
+            bail!("The {} verification step is configured twice", step.name());
+        }
+    }
+    Ok(())
+}
+
+/// The mode of the first step, what we tell clients that haven't started registering
+pub fn first_verif_mode(settings: &ExitVerifSettings) -> ExitVerifMode {
+    get_providers(settings)
+        .first()
+        .map(|step| step.mode())
+        .unwrap_or(ExitVerifMode::Off)
+}
+
+/// The steps the client hasn't passed yet
+fn remaining_steps<'a>(
+    steps: &'a [Box<dyn VerificationProvider>],
+    done: &HashSet<String>,
+) -> Vec<&'a dyn VerificationProvider> {
+    steps
+        .iter()
+        .filter(|step| !done.contains(step.name()))
+        .map(|step| step.as_ref())
+        .collect()
+}
+
+/// The state of a client in one step, None if they haven't started it
+pub fn get_step_state(
+    client_mesh_ip: &str,
+    step: &str,
+    conn: &PgConnection,
+) -> Result<Option<String>, Error> {
+    use self::schema::client_verifications::dsl::{client_verifications, mesh_ip, method};
+    let entry = client_verifications
+        .filter(mesh_ip.eq(client_mesh_ip))
+        .filter(method.eq(step))
+        .load::<models::ClientVerification>(conn)?
+        .pop();
+    Ok(entry.map(|entry| entry.state))
+}
+
+fn get_steps_done(client_mesh_ip: &str, conn: &PgConnection) -> Result<HashSet<String>, Error> {
+    use self::schema::client_verifications::dsl::{client_verifications, mesh_ip, state};
+    Ok(client_verifications
+        .filter(mesh_ip.eq(client_mesh_ip))
+        .filter(state.eq(STATE_VERIFIED))
+        .load::<models::ClientVerification>(conn)?
+        .into_iter()
+        .map(|entry| entry.method)
+        .collect())
+}
+
+pub fn set_step_state(
+    client_mesh_ip: &str,
+    step: &str,
+    new_state: &str,
+    conn: &PgConnection,
+) -> Result<(), Error> {
+    use self::schema::client_verifications::dsl::{client_verifications, mesh_ip, method};
+    let entry = models::ClientVerification {
+        mesh_ip: client_mesh_ip.to_string(),
+        method: step.to_string(),
+        state: new_state.to_string(),
+        updated: secs_since_unix_epoch(),
+    };
+    diesel::insert_into(client_verifications)
+        .values(&entry)
+        .on_conflict((mesh_ip, method))
+        .do_update()
+        .set(&entry)
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Walks a client through the configured verification steps, registering them once they have
+/// passed them all
+pub fn handle_verification(
+    client: &ExitClientIdentity,
+    their_record: &models::Client,
+    settings: &ExitVerifSettings,
+    conn: &PgConnection,
+) -> Result<ExitState, Error> {
+    if verif_done(their_record) {
+        return registered_state(their_record, conn);
+    }
+
+    check_providers(settings)?;
+    let steps = get_providers(settings);
+    let done = get_steps_done(&their_record.mesh_ip, conn)?;
+    for step in remaining_steps(&steps, &done) {
+        match step.verify(client, their_record, conn)? {
+            VerifStatus::Verified => {
+                info!("{:?} passed {} verification", client, step.name());
+                set_step_state(&their_record.mesh_ip, step.name(), STATE_VERIFIED, conn)?;
+            }
+            VerifStatus::Pending(message) => {
+                let mut general_details = get_exit_info();
+                general_details.verif_mode = step.mode();
+                return Ok(ExitState::Pending {
+                    general_details,
+                    message,
+                    email_code: None,
+                    phone_code: None,
+                });
+            }
+            VerifStatus::Cooldown(message) => {
+                return Ok(ExitState::GotInfo {
+                    general_details: get_exit_info(),
+                    message,
+                    auto_register: true,
+                });
+            }
+            VerifStatus::Denied(message) => return Ok(ExitState::Denied { message }),
+        }
+    }
+
+    info!("{:?} is now registered", client);
+    verify_client(client, true, conn)?;
+    registered_state(their_record, conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use althea_types::{ExitRegistrationDetails, Identity};
+    use diesel::prelude::Connection;
+    use settings::exit::{EmailVerifSettings, PhoneVerifSettings, WebhookVerifSettings};
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_chain_steps() {
+        let settings = ExitVerifSettings::Chain(vec![
+            ExitVerifSettings::Email(EmailVerifSettings::default()),
+            ExitVerifSettings::Chain(vec![
+                ExitVerifSettings::Phone(PhoneVerifSettings::default()),
+                ExitVerifSettings::Approval,
+            ]),
+        ]);
+        let steps = get_providers(&settings);
+        let names: Vec<&str> = steps.iter().map(|step| step.name()).collect();
+        assert_eq!(names, vec!["email", "phone", "approval"]);
+        assert_eq!(first_verif_mode(&settings), ExitVerifMode::Email);
+
+        let mut done = HashSet::new();
+        done.insert("email".to_string());
+        let remaining = remaining_steps(&steps, &done);
+        assert_eq!(remaining.len(), 2);
+        assert_eq!(remaining[0].mode(), ExitVerifMode::Phone);
+        assert_eq!(remaining[1].mode(), ExitVerifMode::Off);
+
+        assert_eq!(
+            first_verif_mode(&ExitVerifSettings::Chain(Vec::new())),
+            ExitVerifMode::Off
+        );
+    }
+
+    #[test]
+    fn test_check_providers() {
+        let email = ExitVerifSettings::Email(EmailVerifSettings::default());
+        let webhook = ExitVerifSettings::Webhook(WebhookVerifSettings {
+            url: "http://localhost/verify".to_string(),
+            auth_token: None,
+            verif_mode: ExitVerifMode::Off,
+        });
+        assert!(check_providers(&email).is_ok());
+        assert!(check_providers(&ExitVerifSettings::Chain(vec![
+            email.clone(),
+            webhook.clone(),
+            ExitVerifSettings::Approval,
+        ]))
+        .is_ok());
+        assert!(check_providers(&ExitVerifSettings::Chain(vec![
+            email.clone(),
+            ExitVerifSettings::Chain(vec![ExitVerifSettings::Approval, email]),
+        ]))
+        .is_err());
+        assert!(
+            check_providers(&ExitVerifSettings::Chain(vec![webhook.clone(), webhook])).is_err()
+        );
+    }
+
+    fn test_client(email_code: Option<String>) -> ExitClientIdentity {
+        ExitClientIdentity {
+            wg_port: 60000,
+            global: Identity::new(
+                "fd00::1".parse().unwrap(),
+                "0x0000000000000000000000000000000000000001"
+                    .parse()
+                    .unwrap(),
+                "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                    .parse()
+                    .unwrap(),
+                None,
+            ),
+            reg_details: ExitRegistrationDetails {
+                email: Some("client@example.com".to_string()),
+                email_code,
+                phone: None,
+                phone_code: None,
+            },
+            low_balance: None,
+        }
+    }
+
+    fn get_record(conn: &PgConnection) -> models::Client {
+        use self::schema::clients::dsl::clients;
+        clients.find("fd00::1").first(conn).unwrap()
+    }
+
+    /// Needs a postgres database with the exit_db migrations applied in TEST_DATABASE_URL, run
+    /// with `cargo test -- --ignored`. Everything happens in a transaction that is rolled back
+    #[test]
+    #[ignore]
+    fn test_chain_progression() {
+        use self::schema::clients::dsl::clients;
+        let conn = PgConnection::establish(&env::var("TEST_DATABASE_URL").unwrap()).unwrap();
+        conn.begin_test_transaction().unwrap();
+
+        let test_dir = env::temp_dir().join(format!("rita-chain-test-{}", std::process::id()));
+        fs::create_dir_all(&test_dir).unwrap();
+        let settings = ExitVerifSettings::Chain(vec![
+            ExitVerifSettings::Email(EmailVerifSettings {
+                from_address: "verification@example.com".to_string(),
+                test: true,
+                test_dir: test_dir.to_str().unwrap().to_string(),
+                ..Default::default()
+            }),
+            ExitVerifSettings::Approval,
+        ]);
+        diesel::insert_into(clients)
+            .values(&models::Client {
+                mesh_ip: "fd00::1".to_string(),
+                internal_ip: "172.16.0.2".to_string(),
+                email: "client@example.com".to_string(),
+                email_code: "123456".to_string(),
+                ..Default::default()
+            })
+            .execute(&conn)
+            .unwrap();
+
+        // the first step sends the code
+        let state =
+            handle_verification(&test_client(None), &get_record(&conn), &settings, &conn).unwrap();
+        match state {
+            ExitState::Pending {
+                general_details, ..
+            } => assert_eq!(general_details.verif_mode, ExitVerifMode::Email),
+            other => panic!("Expected pending email verification, got {:?}", other),
+        }
+        assert_eq!(fs::read_dir(&test_dir).unwrap().count(), 1);
+        fs::remove_dir_all(&test_dir).unwrap();
+        assert_ne!(get_record(&conn).email_sent_time, 0);
+
+        // the right code passes the email step and queues the client for approval
+        let state = handle_verification(
+            &test_client(Some("123456".to_string())),
+            &get_record(&conn),
+            &settings,
+            &conn,
+        )
+        .unwrap();
+        match state {
+            ExitState::Pending {
+                general_details, ..
+            } => assert_eq!(general_details.verif_mode, ExitVerifMode::Off),
+            other => panic!("Expected pending approval, got {:?}", other),
+        }
+        assert_eq!(
+            get_step_state("fd00::1", "email", &conn).unwrap(),
+            Some(STATE_VERIFIED.to_string())
+        );
+        assert_eq!(
+            get_step_state("fd00::1", "approval", &conn).unwrap(),
+            Some(STATE_PENDING.to_string())
+        );
+
+        // the email step is done, so no code is needed while waiting
+        let state =
+            handle_verification(&test_client(None), &get_record(&conn), &settings, &conn).unwrap();
+        match state {
+            ExitState::Pending { .. } => {}
+            other => panic!("Expected pending approval, got {:?}", other),
+        }
+        assert!(!get_record(&conn).verified);
+
+        // the operator lets them in
+        set_step_state("fd00::1", "approval", STATE_VERIFIED, &conn).unwrap();
+        let state =
+            handle_verification(&test_client(None), &get_record(&conn), &settings, &conn).unwrap();
+        match state {
+            ExitState::Registered { .. } => {}
+            other => panic!("Expected registered, got {:?}", other),
+        }
+        assert!(get_record(&conn).verified);
+    }
+}
@@ -0,0 +1,327 @@
+use crate::rita_exit::database::database_tools::text_sent;
+use crate::rita_exit::database::struct_tools::texts_sent;
+use crate::rita_exit::database::verification::{VerifStatus, VerificationProvider};
+use althea_types::{ExitClientIdentity, ExitVerifMode};
+use diesel::prelude::PgConnection;
+use exit_db::models;
+use failure::Error;
+use phonenumber::PhoneNumber;
+use reqwest;
+use settings::exit::PhoneVerifSettings;
+use std::time::Duration;
+
+const AUTHY_URL: &str = "https://api.authy.com";
+const TWILIO_URL: &str = "https://api.twilio.com";
+const TWILIO_VERIFY_URL: &str = "https://verify.twilio.com";
+
+/// How many codes we send a client before they have to use one of them
+const MAX_TEXTS: i32 = 10;
+
+#[derive(Serialize)]
+pub struct SmsCheck {
+    api_key: String,
+    verification_code: String,
+    phone_number: String,
+    country_code: String,
+}
+
+#[derive(Serialize)]
+pub struct SmsRequest {
+    api_key: String,
+    via: String,
+    phone_number: String,
+    country_code: String,
+}
+
+#[derive(Serialize)]
+struct VerifyRequest {
+    #[serde(rename = "To")]
+    to: String,
+    #[serde(rename = "Channel")]
+    channel: String,
+}
+
+#[derive(Serialize)]
+struct VerifyCheck {
+    #[serde(rename = "To")]
+    to: String,
+    #[serde(rename = "Code")]
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct VerifyCheckResponse {
+    status: String,
+}
+
+#[derive(Serialize)]
+pub struct SmsNotification {
+    #[serde(rename = "To")]
+    to: String,
+    #[serde(rename = "From")]
+    from: String,
+    #[serde(rename = "Body")]
+    body: String,
+}
+
+/// Texts the client a code using either Authy or Twilio Verify, low balance notifications are
+/// always sent through Twilio's messaging api
+pub struct PhoneProvider {
+    phone: PhoneVerifSettings,
+    authy_url: String,
+    twilio_url: String,
+    twilio_verify_url: String,
+}
+
+impl PhoneProvider {
+    pub fn new(phone: PhoneVerifSettings) -> PhoneProvider {
+        PhoneProvider {
+            phone,
+            authy_url: AUTHY_URL.to_string(),
+            twilio_url: TWILIO_URL.to_string(),
+            twilio_verify_url: TWILIO_VERIFY_URL.to_string(),
+        }
+    }
+
+    fn http_client() -> Result<reqwest::Client, Error> {
+        Ok(reqwest::Client::builder()
+            .timeout(Duration::from_secs(1))
+            .build()?)
+    }
+
+    /// Sends the verification text by hitting the api endpoint
+    fn send_text(&self, number: &str) -> Result<(), Error> {
+        info!("Sending message for {}", number);
+        let number: PhoneNumber = number.parse()?;
+        let client = PhoneProvider::http_client()?;
+        let res = match self.phone.twilio_verify_service {
+            Some(ref service) => client
+                .post(&format!(
+                    "{}/v2/Services/{}/Verifications",
+                    self.twilio_verify_url, service
+                ))
+                .basic_auth(
+                    self.phone.twillio_account_id.clone(),
+                    Some(self.phone.twillio_auth_token.clone()),
+                )
+                .form(&VerifyRequest {
+                    to: number.to_string(),
+                    channel: "sms".to_string(),
+                })
+                .send()?,
+            None => client
+                .post(&format!(
+                    "{}/protected/json/phones/verification/start",
+                    self.authy_url
+                ))
+                .form(&SmsRequest {
+                    api_key: self.phone.auth_api_key.clone(),
+                    via: "sms".to_string(),
+                    phone_number: number.national().to_string(),
+                    country_code: number.code().value().to_string(),
+                })
+                .send()?,
+        };
+        if res.status().is_success() {
+            Ok(())
+        } else {
+            bail!("SMS API failure! Maybe bad number?")
+        }
+    }
+
+    /// Asks the api if the code is the same as the one sent to the user
+    fn check_text(&self, number: &str, code: &str) -> Result<bool, Error> {
+        trace!("About to check text message status for {}", number);
+        let number: PhoneNumber = number.parse()?;
+        let client = PhoneProvider::http_client()?;
+        match self.phone.twilio_verify_service {
+            Some(ref service) => {
+                let mut res = client
+                    .post(&format!(
+                        "{}/v2/Services/{}/VerificationCheck",
+                        self.twilio_verify_url, service
+                    ))
+                    .basic_auth(
+                        self.phone.twillio_account_id.clone(),
+                        Some(self.phone.twillio_auth_token.clone()),
+                    )
+                    .form(&VerifyCheck {
+                        to: number.to_string(),
+                        code: code.to_string(),
+                    })
+                    .send()?;
+                if !res.status().is_success() {
+                    return Ok(false);
+                }
+                let check: VerifyCheckResponse = res.json()?;
+                Ok(check.status == "approved")
+            }
+            None => {
+                let res = client
+                    .get(&format!(
+                        "{}/protected/json/phones/verification/check",
+                        self.authy_url
+                    ))
+                    .form(&SmsCheck {
+                        api_key: self.phone.auth_api_key.clone(),
+                        verification_code: code.to_string(),
+                        phone_number: number.national().to_string(),
+                        country_code: number.code().value().to_string(),
+                    })
+                    .send()?;
+                Ok(res.status().is_success())
+            }
+        }
+    }
+
+    fn send_low_balance_sms(&self, number: &str) -> Result<(), Error> {
+        info!("Sending low balance message for {}", number);
+
+        let url = format!(
+            "{}/2010-04-01/Accounts/{}/Messages.json",
+            self.twilio_url, self.phone.twillio_account_id
+        );
+        let number: PhoneNumber = number.parse()?;
+        let client = PhoneProvider::http_client()?;
+        let res = client
+            .post(&url)
+            .basic_auth(
+                self.phone.twillio_account_id.clone(),
+                Some(self.phone.twillio_auth_token.clone()),
+            )
+            .form(&SmsNotification {
+                to: number.to_string(),
+                from: self.phone.notification_number.clone(),
+                body: self.phone.balance_notification_body.clone(),
+            })
+            .send()?;
+        if res.status().is_success() {
+            Ok(())
+        } else {
+            bail!("SMS API failure! Maybe bad number?")
+        }
+    }
+}
+
+impl VerificationProvider for PhoneProvider {
+    fn name(&self) -> &'static str {
+        "phone"
+    }
+
+    fn mode(&self) -> ExitVerifMode {
+        ExitVerifMode::Phone
+    }
+
+    /// Handles the minutia of phone registration states
+    fn verify(
+        &self,
+        client: &ExitClientIdentity,
+        their_record: &models::Client,
+        conn: &PgConnection,
+    ) -> Result<VerifStatus, Error> {
+        trace!("Handling phone registration for {:?}", client);
+        let text_num = texts_sent(their_record);
+        let sent_more_than_allowed_texts = text_num > MAX_TEXTS;
+        let pending = || VerifStatus::Pending("awaiting phone verification".to_string());
+        match (
+            client.reg_details.phone.clone(),
+            client.reg_details.phone_code.clone(),
+            sent_more_than_allowed_texts,
+        ) {
+            // user is submitting a code, even with all texts exhausted they can still submit
+            // the correct one
+            (Some(number), Some(code), _) => {
+                if self.check_text(&number, &code)? {
+                    Ok(VerifStatus::Verified)
+                } else {
+                    Ok(pending())
+                }
+            }
+            // user has exhausted attempts but is still not submitting code
+            (Some(_number), None, true) => Ok(pending()),
+            // user has attempts remaining and is requesting the code be resent
+            (Some(number), None, false) => {
+                self.send_text(&number)?;
+                text_sent(&client, &conn, text_num)?;
+                Ok(pending())
+            }
+            // user did not submit a phonenumber
+            (None, _, _) => Ok(VerifStatus::Denied(
+                "This exit requires a phone number to register!".to_string(),
+            )),
+        }
+    }
+
+    fn balance_notification_interval(&self) -> Option<u32> {
+        Some(self.phone.balance_notification_interval)
+    }
+
+    fn notify_low_balance(&self, client: &ExitClientIdentity) -> Result<(), Error> {
+        match client.reg_details.phone {
+            Some(ref number) => self.send_low_balance_sms(number),
+            None => bail!("Client is registered but has no phone number!"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::{mock, Matcher, SERVER_URL};
+
+    fn test_provider(twilio_verify_service: Option<String>) -> PhoneProvider {
+        PhoneProvider {
+            phone: PhoneVerifSettings {
+                auth_api_key: "authy-key".to_string(),
+                twillio_account_id: "AC123".to_string(),
+                twillio_auth_token: "token".to_string(),
+                twilio_verify_service,
+                ..Default::default()
+            },
+            authy_url: SERVER_URL.to_string(),
+            twilio_url: SERVER_URL.to_string(),
+            twilio_verify_url: SERVER_URL.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_authy() {
+        let provider = test_provider(None);
+        let _start = mock("POST", "/protected/json/phones/verification/start")
+            .match_body(Matcher::Regex("phone_number=2025550123".to_string()))
+            .with_status(200)
+            .create();
+        let _check = mock("GET", "/protected/json/phones/verification/check")
+            .with_status(401)
+            .create();
+
+        provider.send_text("+12025550123").unwrap();
+        assert!(!provider.check_text("+12025550123", "000000").unwrap());
+    }
+
+    #[test]
+    fn test_twilio_verify() {
+        let provider = test_provider(Some("VA123".to_string()));
+        let _start = mock("POST", "/v2/Services/VA123/Verifications")
+            .match_body(Matcher::Regex("Channel=sms".to_string()))
+            .with_status(201)
+            .create();
+        let _check = mock("POST", "/v2/Services/VA123/VerificationCheck")
+            .match_body(Matcher::Regex("Code=123456".to_string()))
+            .with_status(200)
+            .with_body(r#"{"status": "approved", "valid": true}"#)
+            .create();
+
+        provider.send_text("+12025550123").unwrap();
+        assert!(provider.check_text("+12025550123", "123456").unwrap());
+    }
+
+    #[test]
+    fn test_low_balance_sms() {
+        let provider = test_provider(None);
+        let _send = mock("POST", "/2010-04-01/Accounts/AC123/Messages.json")
+            .with_status(500)
+            .create();
+        assert!(provider.send_low_balance_sms("+12025550123").is_err());
+    }
+}
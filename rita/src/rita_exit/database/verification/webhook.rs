@@ -0,0 +1,167 @@
+//! Hands verification to an outside service. Every time a client asks to register their
+//! details are posted to the service, which answers whether the client is verified, still
+//! pending or denied. Clients waiting on a step that doesn't need a code keep asking, so the
+//! service should expect repeated requests for the same client.
+
+use crate::rita_exit::database::verification::{VerifStatus, VerificationProvider};
+use althea_types::{ExitClientIdentity, ExitVerifMode};
+use diesel::prelude::PgConnection;
+use exit_db::models;
+use failure::Error;
+use reqwest;
+use settings::exit::WebhookVerifSettings;
+use std::net::IpAddr;
+use std::time::Duration;
+
+#[derive(Serialize, Debug)]
+struct WebhookRequest {
+    mesh_ip: IpAddr,
+    wg_public_key: String,
+    email: Option<String>,
+    phone: Option<String>,
+    email_code: Option<String>,
+    phone_code: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum WebhookState {
+    Verified,
+    Pending,
+    Denied,
+}
+
+#[derive(Deserialize, Debug)]
+struct WebhookResponse {
+    status: WebhookState,
+    /// Shown to the client
+    #[serde(default)]
+    message: Option<String>,
+}
+
+pub struct WebhookProvider {
+    webhook: WebhookVerifSettings,
+}
+
+impl WebhookProvider {
+    pub fn new(webhook: WebhookVerifSettings) -> WebhookProvider {
+        WebhookProvider { webhook }
+    }
+
+    fn ask(&self, client: &ExitClientIdentity) -> Result<VerifStatus, Error> {
+        let request = WebhookRequest {
+            mesh_ip: client.global.mesh_ip,
+            wg_public_key: client.global.wg_public_key.to_string(),
+            email: client.reg_details.email.clone(),
+            phone: client.reg_details.phone.clone(),
+            email_code: client.reg_details.email_code.clone(),
+            phone_code: client.reg_details.phone_code.clone(),
+        };
+        trace!("Sending webhook verification request {:?}", request);
+        let http_client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()?;
+        let mut builder = http_client.post(&self.webhook.url).json(&request);
+        if let Some(ref token) = self.webhook.auth_token {
+            builder = builder.bearer_auth(token);
+        }
+        let mut res = builder.send()?;
+        if !res.status().is_success() {
+            bail!("Verification webhook returned {}", res.status());
+        }
+        let response: WebhookResponse = res.json()?;
+        let message = response.message.unwrap_or_default();
+        Ok(match response.status {
+            WebhookState::Verified => VerifStatus::Verified,
+            WebhookState::Pending if message.is_empty() => {
+                VerifStatus::Pending("awaiting verification".to_string())
+            }
+            WebhookState::Pending => VerifStatus::Pending(message),
+            WebhookState::Denied if message.is_empty() => {
+                VerifStatus::Denied("Registration was denied".to_string())
+            }
+            WebhookState::Denied => VerifStatus::Denied(message),
+        })
+    }
+}
+
+impl VerificationProvider for WebhookProvider {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn mode(&self) -> ExitVerifMode {
+        self.webhook.verif_mode
+    }
+
+    fn verify(
+        &self,
+        client: &ExitClientIdentity,
+        _their_record: &models::Client,
+        _conn: &PgConnection,
+    ) -> Result<VerifStatus, Error> {
+        self.ask(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use althea_types::{ExitRegistrationDetails, Identity};
+    use mockito::{mock, Matcher, SERVER_URL};
+
+    fn test_client(email_code: Option<String>) -> ExitClientIdentity {
+        ExitClientIdentity {
+            wg_port: 60000,
+            global: Identity::new(
+                "fd00::1".parse().unwrap(),
+                "0x0000000000000000000000000000000000000001"
+                    .parse()
+                    .unwrap(),
+                "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                    .parse()
+                    .unwrap(),
+                None,
+            ),
+            reg_details: ExitRegistrationDetails {
+                email: Some("client@example.com".to_string()),
+                email_code,
+                phone: None,
+                phone_code: None,
+            },
+            low_balance: None,
+        }
+    }
+
+    #[test]
+    fn test_webhook() {
+        let provider = WebhookProvider::new(WebhookVerifSettings {
+            url: format!("{}/verify", SERVER_URL),
+            auth_token: Some("secret".to_string()),
+            verif_mode: ExitVerifMode::Email,
+        });
+        let _pending = mock("POST", "/verify")
+            .match_header("authorization", "Bearer secret")
+            .match_body(Matcher::Regex(r#""email_code":null"#.to_string()))
+            .with_status(200)
+            .with_body(r#"{"status": "pending", "message": "check your inbox"}"#)
+            .create();
+        let _verified = mock("POST", "/verify")
+            .match_body(Matcher::Regex(r#""email_code":"1234""#.to_string()))
+            .with_status(200)
+            .with_body(r#"{"status": "verified"}"#)
+            .create();
+
+        assert_eq!(
+            provider.ask(&test_client(None)).unwrap(),
+            VerifStatus::Pending("check your inbox".to_string())
+        );
+        assert_eq!(
+            provider
+                .ask(&test_client(Some("1234".to_string())))
+                .unwrap(),
+            VerifStatus::Verified
+        );
+        assert_eq!(provider.mode(), ExitVerifMode::Email);
+    }
+}
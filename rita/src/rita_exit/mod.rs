@@ -1,3 +1,4 @@
+pub mod admin_endpoints;
 pub mod database;
 pub mod network_endpoints;
 pub mod rita_loop;
@@ -1,6 +1,7 @@
 //! Network endpoints for rita-exit that are not dashboard or local infromational endpoints
 //! these are called by rita instances to operate the mesh
 
+use crate::rita_common::babel_client::{BabelClient, GetBabelTable};
 use crate::rita_common::debt_keeper::DebtKeeper;
 use crate::rita_common::debt_keeper::GetDebtsList;
 #[cfg(feature = "development")]
@@ -17,6 +18,7 @@ use actix_web::AsyncResponder;
 use althea_types::Identity;
 use althea_types::{ExitClientIdentity, ExitState, RTTimestamps};
 use failure::Error;
+use futures::future;
 use futures::Future;
 use num256::Int256;
 use std::net::SocketAddr;
@@ -24,7 +26,7 @@ use std::time::SystemTime;
 
 pub fn setup_request(
     their_id: (Json<ExitClientIdentity>, HttpRequest),
-) -> Result<Json<ExitState>, Error> {
+) -> Box<dyn Future<Item = Json<ExitState>, Error = Error>> {
     trace!("Received requester identity for setup, {:?}", their_id.0);
     let client_mesh_ip = their_id.0.global.mesh_ip;
     let client = their_id.0.into_inner();
@@ -37,13 +39,19 @@ pub fn setup_request(
         .unwrap();
 
     let remote_mesh_ip = remote_mesh_socket.ip();
-    if remote_mesh_ip == client_mesh_ip {
-        Ok(Json(signup_client(client)?))
-    } else {
-        Ok(Json(ExitState::Denied {
+    if remote_mesh_ip != client_mesh_ip {
+        return Box::new(future::ok(Json(ExitState::Denied {
             message: "The request ip does not match the signup ip".to_string(),
-        }))
+        })));
     }
+
+    // the client's gateway is found from babel's routes to it
+    Box::new(
+        BabelClient::from_registry()
+            .send(GetBabelTable)
+            .from_err()
+            .and_then(move |table| Ok(Json(signup_client(client, &table?)?))),
+    )
 }
 
 pub fn status_request(their_id: Json<ExitClientIdentity>) -> Result<Json<ExitState>, Error> {
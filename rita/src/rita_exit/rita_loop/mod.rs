@@ -4,6 +4,9 @@
 //! In this loop the exit checks it's database for registered users and deploys the endpoint for
 //! their exit tunnel
 
+use crate::rita_common::babel_client::{BabelClient, GetBabelTable};
+use crate::rita_exit::database::geoip::GeoIpCache;
+use crate::rita_exit::database::plans::get_plans;
 use crate::rita_exit::database::struct_tools::clients_to_ids;
 use crate::rita_exit::database::{
     cleanup_exit_clients, enforce_exit_clients, get_database_connection, setup_clients,
@@ -15,19 +18,19 @@ use actix::prelude::{
     Actor, ActorContext, Addr, Arbiter, AsyncContext, Context, Handler, Message, Supervised,
     SystemService,
 };
+use babel_monitor::BabelTable;
 use diesel::query_dsl::RunQueryDsl;
 use exit_db::models;
 use failure::Error;
 use futures::future::Future;
 use settings::exit::RitaExitSettings;
 use std::collections::HashMap;
-use std::net::IpAddr;
 use std::time::Duration;
 
 #[derive(Default)]
 pub struct RitaLoop {
-    /// a simple cache to prevent regularly asking Maxmind for the same geoip data
-    pub geoip_cache: HashMap<IpAddr, String>,
+    /// a simple cache to prevent regularly looking up the same geoip data
+    pub geoip_cache: GeoIpCache,
 }
 
 // the speed in seconds for the exit loop
@@ -93,8 +96,42 @@ impl Handler<Tick> for RitaLoop {
         let clients_list = clients.load::<models::Client>(&conn)?;
         let ids = clients_to_ids(clients_list.clone());
 
+        // Make sure no one we are setting up is geoip unauthorized, this needs babel's routes
+        // so it's done once we have the table below
+        let region_check = if SETTING.get_allowed_countries().is_empty() {
+            None
+        } else {
+            Some(clients_list.clone())
+        };
+
         // watch and bill for traffic it's super important this gets spawned!
-        TrafficWatcher::from_registry().do_send(Watch(ids));
+        Arbiter::spawn(
+            BabelClient::from_registry()
+                .send(GetBabelTable)
+                .then(move |table| {
+                    match table {
+                        Ok(Ok(babel_table)) => {
+                            if let Some(clients_list) = region_check {
+                                RitaLoop::from_registry().do_send(ValidateRegions {
+                                    babel_table: babel_table.clone(),
+                                    clients_list,
+                                });
+                            }
+                            TrafficWatcher::from_registry().do_send(Watch {
+                                babel_table,
+                                clients: ids,
+                            })
+                        }
+                        Ok(Err(e)) => {
+                            error!("No babel table, traffic has gone unaccounted! {:?}", e)
+                        }
+                        Err(e) => {
+                            error!("BabelClient failed, traffic has gone unaccounted! {:?}", e)
+                        }
+                    }
+                    Ok(())
+                }),
+        );
 
         // Create and update client tunnels
         let res = setup_clients(&clients_list);
@@ -109,18 +146,51 @@ impl Handler<Tick> for RitaLoop {
             error!("Exit client cleanup failed with {:?}", res);
         }
 
-        // Make sure no one we are setting up is geoip unauthorized
-        if !SETTING.get_allowed_countries().is_empty() {
-            let res = validate_clients_region(&mut self.geoip_cache, &clients_list, &conn);
-            if res.is_err() {
-                error!("Validate clients failed with {:?}", res);
+        // clients that have used up their plan are throttled along with those that haven't paid
+        let plans = match get_plans(&conn) {
+            Ok(plans) => plans,
+            Err(e) => {
+                error!("Failed to get client plans {:?}", e);
+                HashMap::new()
             }
-        }
+        };
 
         // handle enforcement on client tunnels by querying debt keeper
         // this consumes client list, you can move it up in exchange for a clone
-        Arbiter::spawn(enforce_exit_clients(clients_list));
+        Arbiter::spawn(enforce_exit_clients(clients_list, plans));
 
         Ok(())
     }
 }
+
+/// Checks the region of every client against babel's current routes
+struct ValidateRegions {
+    babel_table: BabelTable,
+    clients_list: Vec<models::Client>,
+}
+
+impl Message for ValidateRegions {
+    type Result = ();
+}
+
+impl Handler<ValidateRegions> for RitaLoop {
+    type Result = ();
+    fn handle(&mut self, msg: ValidateRegions, _ctx: &mut Context<Self>) -> Self::Result {
+        let conn = match get_database_connection() {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!("Validate clients could not reach the database {:?}", e);
+                return;
+            }
+        };
+        let res = validate_clients_region(
+            &mut self.geoip_cache,
+            &msg.babel_table,
+            &msg.clients_list,
+            &conn,
+        );
+        if res.is_err() {
+            error!("Validate clients failed with {:?}", res);
+        }
+    }
+}
@@ -8,31 +8,87 @@
 //!
 //! Also handles enforcement of nonpayment, since there's no need for a complicated TunnelManager for exits
 
+use crate::rita_common::babel_client::get_local_fee;
 use crate::rita_common::debt_keeper;
 use crate::rita_common::debt_keeper::DebtKeeper;
 use crate::rita_common::debt_keeper::Traffic;
 use crate::rita_common::usage_tracker::UpdateUsage;
 use crate::rita_common::usage_tracker::UsageTracker;
 use crate::rita_common::usage_tracker::UsageType;
+use crate::rita_exit::database::get_database_connection;
+use crate::rita_exit::database::plans::{apply_plan, get_plans, save_plan_usage, PlanCharge};
+use crate::rita_exit::database::secs_since_unix_epoch;
 use crate::SETTING;
 use ::actix::{Actor, Context, Handler, Message, Supervised, SystemService};
 use althea_kernel_interface::wg_iface_counter::WgUsage;
 use althea_kernel_interface::KI;
 use althea_types::Identity;
 use althea_types::WgKey;
-use babel_monitor::open_babel_stream;
-use babel_monitor::Babel;
+use babel_monitor::BabelTable;
+use exit_db::models;
 use ipnetwork::IpNetwork;
+use num256::{Int256, Uint256};
+use settings::exit::ExitIpv6Mode;
 use settings::exit::RitaExitSettings;
 use settings::RitaCommonSettings;
 use std::collections::HashMap;
-use std::io::{Read, Write};
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
 use std::net::IpAddr;
 
 use failure::Error;
 
 pub struct TrafficWatcher {
-    last_seen_bytes: HashMap<WgKey, WgUsage>,
+    /// The counters of each client as of the last time we billed them, None if we have never
+    /// seen the counters so we don't know what has been billed already
+    usage_history: Option<HashMap<WgKey, WgUsage>>,
+}
+
+/// What's saved to disk for each client
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SavedUsage {
+    wg_key: WgKey,
+    upload: u64,
+    download: u64,
+}
+
+fn load_usage_history(path: &str) -> Result<HashMap<WgKey, WgUsage>, Error> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    let saved: Vec<SavedUsage> = serde_json::from_str(&contents)?;
+    Ok(saved
+        .into_iter()
+        .map(|usage| {
+            (
+                usage.wg_key,
+                WgUsage {
+                    upload: usage.upload,
+                    download: usage.download,
+                },
+            )
+        })
+        .collect())
+}
+
+/// Written after every round so that a restart never bills the same bytes twice, the
+/// rename makes sure a crash can't leave a half written file behind
+fn save_usage_history(path: &str, usage_history: &HashMap<WgKey, WgUsage>) -> Result<(), Error> {
+    let saved: Vec<SavedUsage> = usage_history
+        .iter()
+        .map(|(wg_key, usage)| SavedUsage {
+            wg_key: *wg_key,
+            upload: usage.upload,
+            download: usage.download,
+        })
+        .collect();
+    let tmp_path = format!("{}.tmp", path);
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(serde_json::to_string(&saved)?.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
 }
 
 impl Actor for TrafficWatcher {
@@ -45,21 +101,41 @@ impl SystemService for TrafficWatcher {
         if let Err(e) = KI.setup_wg_if_named("wg_exit") {
             warn!("exit setup returned {}", e)
         }
-        KI.setup_nat(&SETTING.get_network().external_nic.clone().unwrap())
-            .unwrap();
+        let external_nic = SETTING.get_network().external_nic.clone().unwrap();
+        KI.setup_nat(&external_nic).unwrap();
+        let exit_network = SETTING.get_exit_network();
+        if let Some(subnet) = exit_network.subnet_v6 {
+            let nat66 = exit_network.ipv6_mode == ExitIpv6Mode::Nat66;
+            if let Err(e) = KI.setup_ipv6_forwarding(&external_nic, &IpNetwork::V6(subnet), nat66) {
+                error!("Failed to setup ipv6 forwarding {:?}", e);
+            }
+        }
 
         info!("Traffic Watcher started");
     }
 }
 impl Default for TrafficWatcher {
     fn default() -> TrafficWatcher {
-        TrafficWatcher {
-            last_seen_bytes: HashMap::new(),
-        }
+        let path = SETTING.get_exit_network().usage_history_file.clone();
+        let usage_history = match load_usage_history(&path) {
+            Ok(usage_history) => {
+                info!("Loaded usage history for {} clients", usage_history.len());
+                Some(usage_history)
+            }
+            Err(e) => {
+                warn!("Failed to load usage history from {} {:?}", path, e);
+                None
+            }
+        };
+        TrafficWatcher { usage_history }
     }
 }
 
-pub struct Watch(pub Vec<Identity>);
+pub struct Watch {
+    /// Babel's tables as of this round, used to price each client
+    pub babel_table: BabelTable,
+    pub clients: Vec<Identity>,
+}
 
 impl Message for Watch {
     type Result = Result<(), Error>;
@@ -69,32 +145,19 @@ impl Handler<Watch> for TrafficWatcher {
     type Result = Result<(), Error>;
 
     fn handle(&mut self, msg: Watch, _: &mut Context<Self>) -> Self::Result {
-        let stream = open_babel_stream(SETTING.get_network().babel_port)?;
-
-        watch(&mut self.last_seen_bytes, Babel::new(stream), &msg.0)
+        watch(&mut self.usage_history, &msg.babel_table, &msg.clients)
     }
 }
 
-fn get_babel_info<T: Read + Write>(
-    mut babel: Babel<T>,
+fn get_babel_info(
+    babel_table: &BabelTable,
     our_id: Identity,
     id_from_ip: HashMap<IpAddr, Identity>,
 ) -> Result<HashMap<WgKey, u64>, Error> {
-    babel.start_connection()?;
-
-    trace!("Getting routes");
-    let routes = babel.parse_routes()?;
+    let routes = babel_table.routes();
     info!("Got routes: {:?}", routes);
 
-    let local_fee = match babel.get_local_fee() {
-        Ok(fee) => fee,
-        Err(e) => {
-            error!("Babel fee not set properly! this is a bad sign! {:?}", e);
-            let configured_fee = SETTING.get_payment().local_fee;
-            babel.set_local_fee(configured_fee)?;
-            configured_fee
-        }
-    };
+    let local_fee = get_local_fee(babel_table)?;
 
     // insert ourselves as a destination, don't think this is actually needed
     let mut destinations = HashMap::new();
@@ -141,7 +204,7 @@ fn generate_helper_maps(
     Ok((identities, id_from_ip))
 }
 
-fn counters_logging(counters: &HashMap<WgKey, WgUsage>, exit_fee: u32) {
+fn counters_logging(counters: &HashMap<WgKey, WgUsage>, upload_price: u32, download_price: u32) {
     trace!("exit counters: {:?}", counters);
 
     let mut total_in: u64 = 0;
@@ -171,7 +234,8 @@ fn counters_logging(counters: &HashMap<WgKey, WgUsage>, exit_fee: u32) {
         kind: UsageType::Exit,
         up: total_out,
         down: total_in,
-        price: exit_fee,
+        up_price: download_price,
+        down_price: upload_price,
     });
 
     info!("Total Exit output of {} bytes this round", total_out);
@@ -193,40 +257,98 @@ fn debts_logging(debts: &HashMap<Identity, i128>) {
     }
 }
 
+/// The traffic a counter counted since it was last at previous, a counter that went down
+/// was reset, either the client's peer was removed and added back or wg_exit was recreated,
+/// and has counted up from zero since
+fn counter_delta(previous: u64, current: u64, wg_key: &WgKey) -> u64 {
+    if current >= previous {
+        current - previous
+    } else {
+        info!(
+            "Counter for {} went from {} to {}, it was reset",
+            wg_key, previous, current
+        );
+        current
+    }
+}
+
+/// Returns what each client used since the history was taken and moves the history up to
+/// the current counters. Clients that are missing from the counters have had their peer
+/// removed, so they are dropped and if they come back everything they've used is new
 pub fn update_usage_history(
     counters: &HashMap<WgKey, WgUsage>,
     usage_history: &mut HashMap<WgKey, WgUsage>,
-) {
+) -> HashMap<WgKey, WgUsage> {
+    let mut used = HashMap::new();
     for (wg_key, bytes) in counters.iter() {
-        match usage_history.get_mut(&wg_key) {
-            Some(history) => {
-                // tunnel has been reset somehow, reset usage
-                if history.download > bytes.download {
-                    history.download = 0;
+        let usage = match usage_history.get(wg_key) {
+            Some(history) => WgUsage {
+                upload: counter_delta(history.upload, bytes.upload, wg_key),
+                download: counter_delta(history.download, bytes.download, wg_key),
+            },
+            None => {
+                trace!("We have not seen {:?} before, billing from zero", wg_key);
+                bytes.clone()
+            }
+        };
+        used.insert(*wg_key, usage);
+    }
+    *usage_history = counters.clone();
+    used
+}
+
+/// Applies the plans of clients that have one to what they used. Returns what is billed per
+/// byte, what clients are charged for starting a new plan period and the usage to save
+fn apply_plans(
+    used: &HashMap<WgKey, WgUsage>,
+    identities: &HashMap<WgKey, Identity>,
+    plans: &mut HashMap<IpAddr, models::ClientPlan>,
+    now: i64,
+) -> (
+    HashMap<WgKey, WgUsage>,
+    Vec<(Identity, Uint256)>,
+    Vec<(String, PlanCharge)>,
+) {
+    let mut billable = HashMap::new();
+    let mut period_prices = Vec::new();
+    let mut charges = Vec::new();
+    for (wg_key, bytes) in used.iter() {
+        let plan = identities
+            .get(wg_key)
+            .and_then(|id| plans.get_mut(&id.mesh_ip).map(|plan| (id, plan)));
+        match plan {
+            Some((id, plan)) => match apply_plan(plan, bytes, now) {
+                Ok(charge) => {
+                    trace!("Plan charge for {}: {:?}", id.mesh_ip, charge);
+                    billable.insert(*wg_key, charge.billable.clone());
+                    if let Some(ref price) = charge.period_price {
+                        period_prices.push((*id, price.clone()));
+                    }
+                    charges.push((plan.mesh_ip.clone(), charge));
                 }
-                if history.upload > bytes.upload {
-                    history.download = 0;
+                // we would rather undercharge than overcharge, bill them like everyone else
+                Err(e) => {
+                    error!("Invalid plan for {} {:?}", id.mesh_ip, e);
+                    billable.insert(*wg_key, bytes.clone());
                 }
-            }
+            },
             None => {
-                trace!(
-                    "We have not seen {:?} before, starting counter off at {:?}",
-                    wg_key,
-                    bytes
-                );
-                usage_history.insert(wg_key.clone(), bytes.clone());
+                billable.insert(*wg_key, bytes.clone());
             }
         }
     }
+    (billable, period_prices, charges)
 }
 
 /// This traffic watcher watches how much traffic each we send and receive from each client.
-pub fn watch<T: Read + Write>(
-    usage_history: &mut HashMap<WgKey, WgUsage>,
-    babel: Babel<T>,
+pub fn watch(
+    usage_history: &mut Option<HashMap<WgKey, WgUsage>>,
+    babel_table: &BabelTable,
     clients: &[Identity],
 ) -> Result<(), Error> {
-    let our_price = SETTING.get_exit_network().exit_price;
+    // prices are from the client's point of view, what they upload is our input
+    let upload_price = SETTING.get_exit_network().get_upload_price();
+    let download_price = SETTING.get_exit_network().get_download_price();
     let our_id = match SETTING.get_identity() {
         Some(id) => id,
         None => {
@@ -236,23 +358,53 @@ pub fn watch<T: Read + Write>(
     };
 
     let (identities, id_from_ip) = generate_helper_maps(&our_id, clients)?;
-    let destinations = get_babel_info(babel, our_id, id_from_ip)?;
+    let destinations = get_babel_info(babel_table, our_id, id_from_ip)?;
+
+    // plans are read before the counters so that a failure here leaves the usage for next round
+    let conn = get_database_connection()?;
+    let mut plans = get_plans(&conn)?;
 
     let counters = match KI.read_wg_counters("wg_exit") {
         Ok(res) => res,
         Err(e) => {
             warn!(
-                "Error getting input counters {:?} traffic has gone unaccounted!",
+                "Error getting input counters {:?} it will be billed next round",
                 e
             );
             return Err(e);
         }
     };
 
-    counters_logging(&counters, our_price as u32);
+    let used = if let Some(usage_history) = usage_history.as_mut() {
+        update_usage_history(&counters, usage_history)
+    } else {
+        // without a history we can't tell what's already been billed, so we start
+        // counting from here
+        info!("No usage history, billing from the current counters");
+        *usage_history = Some(counters.clone());
+        HashMap::new()
+    };
+    if let Some(ref usage_history) = usage_history {
+        let path = SETTING.get_exit_network().usage_history_file.clone();
+        if let Err(e) = save_usage_history(&path, usage_history) {
+            error!("Failed to save usage history to {} {:?}", path, e);
+        }
+    }
 
-    // creates new usage entires does not actualy update the values
-    update_usage_history(&counters, usage_history);
+    counters_logging(&used, upload_price as u32, download_price as u32);
+
+    let (billable, mut period_prices, plan_charges) =
+        apply_plans(&used, &identities, &mut plans, secs_since_unix_epoch());
+    if let Err(e) = save_plan_usage(&plan_charges, &conn) {
+        // the new periods weren't recorded so they start again next round, charging for
+        // them now would charge twice. This round's usage within the quota goes unbilled
+        error!(
+            "Failed to save plan usage, skipping {} period charges {:?}",
+            period_prices.len(),
+            e
+        );
+        period_prices.clear();
+    }
 
     let mut debts = HashMap::new();
 
@@ -262,61 +414,46 @@ pub fn watch<T: Read + Write>(
     }
 
     // accounting for 'input'
-    for (wg_key, bytes) in counters.clone() {
-        let state = (
-            identities.get(&wg_key),
-            destinations.get(&wg_key),
-            usage_history.get_mut(&wg_key),
-        );
+    for (wg_key, bytes) in billable.iter() {
+        let state = (identities.get(wg_key), destinations.get(wg_key));
         match state {
-            (Some(id), Some(_dest), Some(history)) => match debts.get_mut(&id) {
+            (Some(id), Some(_dest)) => match debts.get_mut(&id) {
                 Some(debt) => {
-                    let used = bytes.download - history.download;
-                    let value = i128::from(our_price) * i128::from(used);
-                    trace!("We are billing for {} bytes input (client output) times a exit price of {} for a total of -{}", used, our_price, value);
+                    let value = i128::from(upload_price) * i128::from(bytes.download);
+                    trace!("We are billing for {} bytes input (client output) times a exit upload price of {} for a total of -{}", bytes.download, upload_price, value);
                     *debt -= value;
-                    // update history so that we know what was used from previous cycles
-                    history.download = bytes.download;
                 }
                 // debts is generated from identities, this should be impossible
                 None => warn!("No debts entry for input entry id {:?}", id),
             },
-            (Some(id), Some(_dest), None) => warn!("Entry for {:?} should have been created", id),
             // this can be caused by a peer that has not yet formed a babel route
-            (Some(id), None, _) => trace!("We have an id {:?} but not destination", id),
+            (Some(id), None) => trace!("We have an id {:?} but not destination", id),
             // if we have a babel route we should have a peer it's possible we have a mesh client sneaking in?
-            (None, Some(dest), _) => trace!("We have a destination {:?} but no id", dest),
+            (None, Some(dest)) => trace!("We have a destination {:?} but no id", dest),
             // dead entry?
-            (None, None, _) => warn!("We have no id or dest for an input counter on {:?}", wg_key),
+            (None, None) => warn!("We have no id or dest for an input counter on {:?}", wg_key),
         }
     }
 
     // accounting for 'output'
-    for (wg_key, bytes) in counters {
-        let state = (
-            identities.get(&wg_key),
-            destinations.get(&wg_key),
-            usage_history.get_mut(&wg_key),
-        );
+    for (wg_key, bytes) in billable.iter() {
+        let state = (identities.get(wg_key), destinations.get(wg_key));
         match state {
-            (Some(id), Some(dest), Some(history)) => match debts.get_mut(&id) {
+            (Some(id), Some(dest)) => match debts.get_mut(&id) {
                 Some(debt) => {
-                    let used = bytes.upload - history.upload;
-                    let value = i128::from(dest + our_price) * i128::from(used);
-                    trace!("We are billing for {} bytes output (client input) times a exit dest price of {} for a total of -{}", used, dest + our_price, value);
+                    let value = i128::from(dest + download_price) * i128::from(bytes.upload);
+                    trace!("We are billing for {} bytes output (client input) times a exit dest price of {} for a total of -{}", bytes.upload, dest + download_price, value);
                     *debt -= value;
-                    history.upload = bytes.upload;
                 }
                 // debts is generated from identities, this should be impossible
                 None => warn!("No debts entry for input entry id {:?}", id),
             },
-            (Some(id), Some(_dest), None) => warn!("Entry for {:?} should have been created", id),
             // this can be caused by a peer that has not yet formed a babel route
-            (Some(id), None, _) => trace!("We have an id {:?} but not destination", id),
+            (Some(id), None) => trace!("We have an id {:?} but not destination", id),
             // if we have a babel route we should have a peer it's possible we have a mesh client sneaking in?
-            (None, Some(dest), _) => warn!("We have a destination {:?} but no id", dest),
+            (None, Some(dest)) => warn!("We have a destination {:?} but no id", dest),
             // dead entry?
-            (None, None, _) => warn!("We have no id or dest for an input counter on {:?}", wg_key),
+            (None, None) => warn!("We have no id or dest for an input counter on {:?}", wg_key),
         }
     }
 
@@ -329,6 +466,20 @@ pub fn watch<T: Read + Write>(
             amount: amount.into(),
         })
     }
+    for (from, price) in period_prices {
+        info!(
+            "Charging {} {} wei for a new plan period",
+            from.mesh_ip, price
+        );
+        match price.to_int256() {
+            Some(price) => {
+                let mut amount = Int256::from(0);
+                amount -= price;
+                traffic_vec.push(Traffic { from, amount });
+            }
+            None => error!("Plan price {} is too large to charge", price),
+        }
+    }
     let update = debt_keeper::TrafficUpdate {
         traffic: traffic_vec,
     };
@@ -336,3 +487,72 @@ pub fn watch<T: Read + Write>(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(upload: u64, download: u64) -> WgUsage {
+        WgUsage { upload, download }
+    }
+
+    #[test]
+    fn test_usage_history_resets() {
+        let a: WgKey = "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+            .parse()
+            .unwrap();
+        let b: WgKey = "Ha2YlTfDimJNboqxOSCh6M29W/H0jKtB4utitjaTO3A="
+            .parse()
+            .unwrap();
+        let mut history = HashMap::new();
+        let mut billed = usage(0, 0);
+        let mut bill = |used: HashMap<WgKey, WgUsage>| {
+            if let Some(used) = used.get(&a) {
+                billed.upload += used.upload;
+                billed.download += used.download;
+            }
+        };
+
+        let mut counters = HashMap::new();
+        counters.insert(a, usage(100, 1000));
+        counters.insert(b, usage(5, 5));
+        bill(update_usage_history(&counters, &mut history));
+
+        counters.insert(a, usage(150, 1200));
+        bill(update_usage_history(&counters, &mut history));
+
+        // the peer was removed and added back, the counters start from zero
+        counters.insert(a, usage(10, 20));
+        bill(update_usage_history(&counters, &mut history));
+
+        // the peer was removed for a round
+        counters.remove(&a);
+        bill(update_usage_history(&counters, &mut history));
+        assert!(!history.contains_key(&a));
+
+        // and is back with counts bigger than before it was removed
+        counters.insert(a, usage(500, 5000));
+        bill(update_usage_history(&counters, &mut history));
+
+        drop(bill);
+        assert_eq!(billed.upload, 150 + 10 + 500);
+        assert_eq!(billed.download, 1200 + 20 + 5000);
+        assert_eq!(history[&b].upload, 5);
+    }
+
+    #[test]
+    fn test_usage_history_file() {
+        let a: WgKey = "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+            .parse()
+            .unwrap();
+        let path = std::env::temp_dir().join("rita-test-usage-history.json");
+        let path = path.to_str().unwrap();
+        let mut history = HashMap::new();
+        history.insert(a, usage(1 << 40, 3));
+        save_usage_history(path, &history).unwrap();
+        let loaded = load_usage_history(path).unwrap();
+        assert_eq!(loaded[&a].upload, 1 << 40);
+        assert_eq!(loaded[&a].download, 3);
+        let _ = fs::remove_file(path);
+    }
+}
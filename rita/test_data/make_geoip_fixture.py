@@ -0,0 +1,107 @@
+#!/usr/bin/env python3
+"""Writes geoip_country.mmdb, a tiny ipv4 country database in the MaxMind DB format used by
+the geoip tests. Run from this directory to regenerate it."""
+
+NETWORKS = [
+    ("1.1.1.0", 24, "AU"),
+    ("8.8.8.0", 24, "US"),
+    ("81.2.69.0", 24, "GB"),
+]
+
+
+def encode_uint(type_num, value):
+    data = value.to_bytes((value.bit_length() + 7) // 8, "big")
+    return control(type_num, len(data)) + data
+
+
+def control(type_num, size):
+    assert size < 29
+    if type_num <= 7:
+        return bytes([(type_num << 5) | size])
+    # extended types are stored as type 0 with the real type following
+    return bytes([size, type_num - 7])
+
+
+def encode(value):
+    if isinstance(value, str):
+        data = value.encode()
+        return control(2, len(data)) + data
+    if isinstance(value, dict):
+        out = control(7, len(value))
+        for key, item in value.items():
+            out += encode(key) + encode(item)
+        return out
+    if isinstance(value, list):
+        return control(11, len(value)) + b"".join(encode(item) for item in value)
+    kind, number = value
+    return encode_uint(kind, number)
+
+
+def uint16(value):
+    return (5, value)
+
+
+def uint32(value):
+    return (6, value)
+
+
+def uint64(value):
+    return (9, value)
+
+
+def main():
+    data = b""
+    offsets = {}
+    for _ip, _prefix, country in NETWORKS:
+        if country not in offsets:
+            offsets[country] = len(data)
+            data += encode({"country": {"iso_code": country}})
+
+    # each node is a [left, right] pair of either a node index, a country or None
+    nodes = [[None, None]]
+    for ip, prefix, country in NETWORKS:
+        bits = int.from_bytes(bytes(int(octet) for octet in ip.split(".")), "big")
+        node = 0
+        for i in range(prefix):
+            bit = (bits >> (31 - i)) & 1
+            if i == prefix - 1:
+                nodes[node][bit] = country
+            else:
+                if nodes[node][bit] is None:
+                    nodes.append([None, None])
+                    nodes[node][bit] = len(nodes) - 1
+                node = nodes[node][bit]
+
+    node_count = len(nodes)
+
+    def record(value):
+        if value is None:
+            return node_count
+        if isinstance(value, str):
+            return node_count + 16 + offsets[value]
+        return value
+
+    tree = b""
+    for left, right in nodes:
+        tree += record(left).to_bytes(3, "big") + record(right).to_bytes(3, "big")
+
+    metadata = encode(
+        {
+            "binary_format_major_version": uint16(2),
+            "binary_format_minor_version": uint16(0),
+            "build_epoch": uint64(1563148800),
+            "database_type": "GeoIP2-Country",
+            "description": {"en": "Rita geoip test database"},
+            "ip_version": uint16(4),
+            "languages": ["en"],
+            "node_count": uint32(node_count),
+            "record_size": uint16(24),
+        }
+    )
+
+    with open("geoip_country.mmdb", "wb") as f:
+        f.write(tree + bytes(16) + data + b"\xab\xcd\xefMaxMind.com" + metadata)
+
+
+if __name__ == "__main__":
+    main()
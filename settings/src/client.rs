@@ -39,6 +39,36 @@ fn default_balance_notification() -> bool {
     true
 }
 
+fn default_bill_tolerance_percent() -> u32 {
+    150
+}
+
+/// How we respond when the exit bills more than our own estimate of our usage allows. We always
+/// warn, the other responses are opt in
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct ExitBillSettings {
+    /// The exit may bill up to this percentage of our estimate, the exit prices traffic with its
+    /// own route back to us which need not cost the same as ours
+    #[serde(default = "default_bill_tolerance_percent")]
+    pub tolerance_percent: u32,
+    /// Only accept debt up to the tolerance, the rest is left unpaid
+    #[serde(default)]
+    pub refuse_overbilling: bool,
+    /// Switch to another registered exit after this many bills in a row over the tolerance
+    #[serde(default)]
+    pub switch_after: Option<u32>,
+}
+
+impl Default for ExitBillSettings {
+    fn default() -> Self {
+        ExitBillSettings {
+            tolerance_percent: default_bill_tolerance_percent(),
+            refuse_overbilling: false,
+            switch_after: None,
+        }
+    }
+}
+
 /// This struct is used by rita to encapsulate all the state/information needed to connect/register
 /// to a exit and to setup the exit tunnel
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
@@ -58,6 +88,12 @@ pub struct ExitClientSettings {
     /// Specifies if the user would like to receive low balance messages from the exit
     #[serde(default = "default_balance_notification")]
     pub low_balance_notification: bool,
+    /// If set neighbors the fraud detector is confident are misreporting their routes to the
+    /// exit are added to the peer blocklist, otherwise they are only reported
+    #[serde(default)]
+    pub block_fraudulent_neighbors: bool,
+    #[serde(default)]
+    pub bill_check: ExitBillSettings,
 }
 
 impl Default for ExitClientSettings {
@@ -74,6 +110,8 @@ impl Default for ExitClientSettings {
             }),
             lan_nics: HashSet::new(),
             low_balance_notification: true,
+            block_fraudulent_neighbors: false,
+            bill_check: ExitBillSettings::default(),
         }
     }
 }
@@ -1,3 +1,4 @@
+use althea_types::ExitVerifMode;
 use althea_types::WgKey;
 use config;
 use core::str::FromStr;
@@ -6,8 +7,9 @@ use serde_json;
 
 use owning_ref::{RwLockReadGuardRef, RwLockWriteGuardRefMut};
 
+use ipnetwork::{IpNetwork, Ipv6Network};
 use std::collections::HashSet;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::{Arc, RwLock};
 
 use config::Config;
@@ -23,6 +25,24 @@ use crate::payment::PaymentSettings;
 use crate::spawn_watch_thread;
 use crate::RitaCommonSettings;
 
+/// How client ipv6 traffic gets to the internet
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExitIpv6Mode {
+    /// The subnet is routed to the exit and client prefixes are used as they are
+    Routed,
+    /// The subnet is private and client traffic is masqueraded behind the exit's own address
+    Nat66,
+}
+
+fn default_ipv6_mode() -> ExitIpv6Mode {
+    ExitIpv6Mode::Routed
+}
+
+fn default_client_prefix_len_v6() -> u8 {
+    64
+}
+
 /// This is the network settings specific to rita_exit
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
 pub struct ExitNetworkSettings {
@@ -32,7 +52,14 @@ pub struct ExitNetworkSettings {
     /// This is the port which the exit tunnel listens on
     pub wg_tunnel_port: u16,
     /// Price in wei per byte which is charged to traffic both coming in and out over the internet
+    /// unless a direction has its own price below
     pub exit_price: u64,
+    /// Price in wei per byte for traffic clients send out to the internet
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_upload_price: Option<u64>,
+    /// Price in wei per byte for traffic clients receive from the internet
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_download_price: Option<u64>,
     /// This is the exit's own ip/gateway ip in the exit wireguard tunnel
     pub own_internal_ip: Ipv4Addr,
     /// This is the start of the exit tunnel's internal address allocation to clients, incremented
@@ -40,12 +67,27 @@ pub struct ExitNetworkSettings {
     pub exit_start_ip: Ipv4Addr,
     /// The netmask, in bits to mask out, for the exit tunnel
     pub netmask: u8,
+    /// The subnet each client is delegated an ipv6 prefix from, the first prefix is kept for
+    /// the exit's own address. Clients only get ipv4 if this is not set
+    #[serde(default)]
+    pub subnet_v6: Option<Ipv6Network>,
+    /// The length of the prefix delegated to each client
+    #[serde(default = "default_client_prefix_len_v6")]
+    pub client_prefix_len_v6: u8,
+    #[serde(default = "default_ipv6_mode")]
+    pub ipv6_mode: ExitIpv6Mode,
     /// Time in seconds before user is dropped from the db due to inactivity
     /// 0 means disabled
     pub entry_timeout: u32,
-    /// api credentials for Maxmind geoip
+    /// api credentials for Maxmind geoip, only used for ips the local database doesn't have
     pub geoip_api_user: Option<String>,
     pub geoip_api_key: Option<String>,
+    /// Path to a local MaxMind or DB-IP country database (.mmdb), checked before the api
+    #[serde(default)]
+    pub geoip_database: Option<String>,
+    /// How long in seconds a country found for an ip is trusted before looking it up again
+    #[serde(default = "default_geoip_cache_ttl")]
+    pub geoip_cache_ttl: u64,
     /// The our public key for the wg_exit tunnel
     pub wg_public_key: WgKey,
     /// Our private key for the wg_exit tunnel, not an option because it's better
@@ -53,6 +95,9 @@ pub struct ExitNetworkSettings {
     pub wg_private_key: WgKey,
     /// path for the exit tunnel keyfile must be distinct from the common tunnel path!
     pub wg_private_key_path: String,
+    /// Full file path for the per client counters billing was last done at
+    #[serde(default = "default_usage_history_file")]
+    pub usage_history_file: String,
 }
 
 impl ExitNetworkSettings {
@@ -64,18 +109,52 @@ impl ExitNetworkSettings {
             exit_hello_port: 4875,
             wg_tunnel_port: 59999,
             exit_price: 10,
+            exit_upload_price: None,
+            exit_download_price: None,
             own_internal_ip: "172.16.255.254".parse().unwrap(),
             exit_start_ip: "172.16.0.0".parse().unwrap(),
             netmask: 12,
+            subnet_v6: None,
+            client_prefix_len_v6: default_client_prefix_len_v6(),
+            ipv6_mode: default_ipv6_mode(),
             entry_timeout: 0,
             geoip_api_user: None,
             geoip_api_key: None,
+            geoip_database: None,
+            geoip_cache_ttl: default_geoip_cache_ttl(),
             wg_public_key: WgKey::from_str("Ha2YlTfDimJNboqxOSCh6M29W/H0jKtB4utitjaTO3A=").unwrap(),
             wg_private_key: WgKey::from_str("mFFBLqQYrycxfHo10P9l8I2G7zbw8tia4WkGGgjGCn8=")
                 .unwrap(),
             wg_private_key_path: String::new(),
+            usage_history_file: default_usage_history_file(),
         }
     }
+
+    pub fn get_upload_price(&self) -> u64 {
+        self.exit_upload_price.unwrap_or(self.exit_price)
+    }
+
+    pub fn get_download_price(&self) -> u64 {
+        self.exit_download_price.unwrap_or(self.exit_price)
+    }
+
+    /// The exit's own address in the exit tunnel, the first address of the ipv6 subnet
+    pub fn own_internal_ipv6(&self) -> Option<IpNetwork> {
+        let subnet = self.subnet_v6?;
+        let own_ip = Ipv6Addr::from(u128::from(subnet.network()) + 1);
+        Ipv6Network::new(own_ip, subnet.prefix())
+            .ok()
+            .map(IpNetwork::V6)
+    }
+}
+
+fn default_geoip_cache_ttl() -> u64 {
+    // one week
+    604_800
+}
+
+fn default_usage_history_file() -> String {
+    "/var/rita-exit-usage-history.json".to_string()
 }
 
 fn default_signup_email_subject() -> String {
@@ -158,6 +237,27 @@ pub struct PhoneVerifSettings {
     pub balance_notification_body: String,
     /// time in seconds between notifications
     pub balance_notification_interval: u32,
+    /// A Twilio Verify service to send and check codes with using the Twilio credentials
+    /// above, when this is not set codes go through Authy with the auth_api_key
+    #[serde(default)]
+    pub twilio_verify_service: Option<String>,
+}
+
+fn default_webhook_verif_mode() -> ExitVerifMode {
+    ExitVerifMode::Off
+}
+
+/// Settings for handing verification to an outside service, the client's registration
+/// details are posted to the url and the service answers whether they are verified
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct WebhookVerifSettings {
+    pub url: String,
+    /// Sent as a bearer token so the service knows the request came from us
+    #[serde(default)]
+    pub auth_token: Option<String>,
+    /// Where clients should put a code the service sends them, Off if it doesn't send one
+    #[serde(default = "default_webhook_verif_mode")]
+    pub verif_mode: ExitVerifMode,
 }
 
 /// Struct containing the different types of supported verification
@@ -167,6 +267,33 @@ pub struct PhoneVerifSettings {
 pub enum ExitVerifSettings {
     Email(EmailVerifSettings),
     Phone(PhoneVerifSettings),
+    Webhook(WebhookVerifSettings),
+    /// Clients wait in a queue until the operator approves them
+    Approval,
+    /// Clients must pass every method in order
+    Chain(Vec<ExitVerifSettings>),
+}
+
+fn default_admin_api_port() -> u16 {
+    4878
+}
+
+fn default_admin_api_bind() -> IpAddr {
+    IpAddr::V6(Ipv6Addr::LOCALHOST)
+}
+
+/// The operator api for managing clients, it listens on its own port so that it can be
+/// reachable from outside without opening up the dashboard
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct ExitAdminSettings {
+    #[serde(default = "default_admin_api_port")]
+    pub port: u16,
+    /// The api is plain http, so it only listens on localhost unless this is set to an
+    /// address that is only reachable over an encrypted link, such as the exit's mesh ip
+    #[serde(default = "default_admin_api_bind")]
+    pub bind: IpAddr,
+    /// Every request has to carry this as a bearer token
+    pub token: String,
 }
 
 /// This is the main settings struct for rita_exit
@@ -185,6 +312,9 @@ pub struct RitaExitSettingsStruct {
     allowed_countries: HashSet<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     verif_settings: Option<ExitVerifSettings>, // mailer's successor with new verif methods readiness
+    /// The admin api is off unless this is set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    admin_api: Option<ExitAdminSettings>,
     #[serde(skip)]
     future: bool,
 }
@@ -202,6 +332,7 @@ impl RitaExitSettingsStruct {
             exit_network: ExitNetworkSettings::test_default(),
             allowed_countries: HashSet::new(),
             verif_settings: None,
+            admin_api: None,
             future: false,
         }
     }
@@ -215,6 +346,7 @@ pub trait RitaExitSettings {
     fn get_verif_settings_mut<'ret, 'me: 'ret>(
         &'me self,
     ) -> RwLockWriteGuardRefMut<'ret, RitaExitSettingsStruct, Option<ExitVerifSettings>>;
+    fn get_admin_api(&self) -> Option<ExitAdminSettings>;
     fn get_db_uri(&self) -> String;
     fn get_description(&self) -> String;
     fn get_allowed_countries<'ret, 'me: 'ret>(
@@ -228,6 +360,9 @@ impl RitaExitSettings for Arc<RwLock<RitaExitSettingsStruct>> {
     ) -> RwLockReadGuardRef<'ret, RitaExitSettingsStruct, ExitNetworkSettings> {
         RwLockReadGuardRef::new(self.read().unwrap()).map(|g| &g.exit_network)
     }
+    fn get_admin_api(&self) -> Option<ExitAdminSettings> {
+        self.read().unwrap().admin_api.clone()
+    }
     fn get_db_uri(&self) -> String {
         self.read().unwrap().db_uri.clone()
     }
@@ -1,10 +1,13 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::net::{IpAddr, Ipv6Addr};
+use std::str::FromStr;
 
-use althea_types::WgKey;
+use althea_types::{Identity, WgKey};
 
 use arrayvec::ArrayString;
 
+use clarity::Address;
+
 fn default_discovery_ip() -> Ipv6Addr {
     warn!("Add discovery_ip to network, removed in the next version!");
     Ipv6Addr::new(0xff02, 0x0, 0x0, 0x0, 0x0, 0x0, 0x1, 0x8)
@@ -14,6 +17,10 @@ fn default_tunnel_timeout() -> u64 {
     900 // 15 minutes
 }
 
+fn default_tunnel_repair_timeout() -> u64 {
+    300 // 5 minutes
+}
+
 fn default_metric_factor() -> u32 {
     1_900u32
 }
@@ -22,6 +29,231 @@ fn default_usage_tracker_file() -> String {
     "/var/rita-usage-tracker.json".to_string()
 }
 
+fn default_route_history_file() -> String {
+    "/var/rita-route-history.json".to_string()
+}
+
+fn default_babel_tunnel_config() -> BabelInterfaceConfig {
+    BabelInterfaceConfig {
+        enable_timestamps: Some(true),
+        max_rtt_penalty: Some(500),
+        ..Default::default()
+    }
+}
+
+/// A single entry in the peer access control list, any identity or address matching one
+/// of these is considered 'listed'
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Hash)]
+#[serde(tag = "type", content = "value")]
+pub enum PeerAclEntry {
+    /// Matches a peer's mesh ip as well as the address it contacts us from, the latter is
+    /// the only thing we know about a peer during discovery
+    Ip(IpAddr),
+    WgKey(WgKey),
+    EthAddress(Address),
+}
+
+impl PeerAclEntry {
+    pub fn matches(&self, id: &Identity) -> bool {
+        match *self {
+            PeerAclEntry::Ip(ip) => id.mesh_ip == ip,
+            PeerAclEntry::WgKey(key) => id.wg_public_key == key,
+            PeerAclEntry::EthAddress(address) => id.eth_address == address,
+        }
+    }
+}
+
+/// Determines how the entries of the peer access control list are interpreted
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum PeerAclMode {
+    /// Peer with everyone except listed entries
+    Blocklist,
+    /// Peer only with listed entries
+    Allowlist,
+}
+
+impl Default for PeerAclMode {
+    fn default() -> PeerAclMode {
+        PeerAclMode::Blocklist
+    }
+}
+
+impl FromStr for PeerAclMode {
+    type Err = ();
+    fn from_str(s: &str) -> Result<PeerAclMode, ()> {
+        match s {
+            "Blocklist" => Ok(PeerAclMode::Blocklist),
+            "Allowlist" => Ok(PeerAclMode::Allowlist),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Access control list for mesh peers, enforced during peer discovery, when responding
+/// to hellos and when opening tunnels
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Default)]
+pub struct PeerAcl {
+    #[serde(default)]
+    pub mode: PeerAclMode,
+    #[serde(default)]
+    pub entries: HashSet<PeerAclEntry>,
+}
+
+impl PeerAcl {
+    /// Returns true if we may peer with the given identity
+    pub fn is_allowed(&self, id: &Identity) -> bool {
+        let listed = self.entries.iter().any(|entry| entry.matches(id));
+        match self.mode {
+            PeerAclMode::Blocklist => !listed,
+            PeerAclMode::Allowlist => listed,
+        }
+    }
+
+    /// Returns true if the given address is explicitly blocked, used during discovery and
+    /// hellos where we may not know the identity behind an address yet. In allowlist mode
+    /// nothing is blocked by address alone, we have to wait for the identity
+    pub fn is_ip_blocked(&self, ip: &IpAddr) -> bool {
+        self.mode == PeerAclMode::Blocklist && self.entries.contains(&PeerAclEntry::Ip(*ip))
+    }
+}
+
+/// Priority class for the traffic of a neighbor relative to our other neighbors
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum ShapingPriority {
+    /// Bulk traffic, yields to everyone else under contention
+    Low,
+    Normal,
+    /// Goes first when the link is congested, for backhaul links
+    High,
+}
+
+impl Default for ShapingPriority {
+    fn default() -> ShapingPriority {
+        ShapingPriority::Normal
+    }
+}
+
+/// Operator set traffic shaping for the tunnels to a single neighbor
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub struct NeighborShaping {
+    pub wg_public_key: WgKey,
+    /// Bandwidth limit in kbit/s, when the neighbor is overdue the lower of this and their
+    /// free tier share applies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bandwidth: Option<u32>,
+    #[serde(default)]
+    pub priority: ShapingPriority,
+}
+
+/// The highest bandwidth limit we accept in kbit/s, about 4.3Gbit/s. tc takes the tbf burst
+/// size, 1000 bytes per kbit of the limit, as a 32 bit number
+pub const MAX_NEIGHBOR_BANDWIDTH: u32 = 4_294_967;
+
+impl NeighborShaping {
+    /// A limit of 0 would cut the neighbor off entirely and very large ones overflow the
+    /// burst size we compute from them
+    pub fn bandwidth_is_valid(&self) -> bool {
+        match self.max_bandwidth {
+            Some(bw) => bw > 0 && bw <= MAX_NEIGHBOR_BANDWIDTH,
+            None => true,
+        }
+    }
+}
+
+/// How babeld computes the cost of a link
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub enum BabelLinkType {
+    Auto,
+    Wired,
+    Wireless,
+    Tunnel,
+}
+
+/// Parameters for babel on an interface, anything left out keeps babeld's default.
+/// Intervals and rtts are in milliseconds
+#[derive(Debug, Serialize, Deserialize, Clone, Default, Eq, PartialEq)]
+pub struct BabelInterfaceConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link_type: Option<BabelLinkType>,
+    /// Overrides the link cost babeld derives from the link type
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rxcost: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hello_interval: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub update_interval: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub split_horizon: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enable_timestamps: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rtt_min: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rtt_max: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_rtt_penalty: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub enum BabelFilterType {
+    /// Routes learned from neighbors
+    In,
+    /// Routes announced to neighbors
+    Out,
+    /// Kernel routes we announce as our own
+    Redistribute,
+    /// Routes babeld installs in the kernel
+    Install,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub enum BabelFilterAction {
+    Allow,
+    Deny,
+    /// Allow the route but add this much to its metric
+    Metric(u16),
+}
+
+/// A babeld route filter, it matches routes that match every selector that is set
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct BabelFilter {
+    pub filter_type: BabelFilterType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iface: Option<String>,
+    /// An ip prefix such as fd00::/8
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+    /// The link local address of the neighbor a route was learned from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub neigh: Option<IpAddr>,
+    pub action: BabelFilterAction,
+}
+
+/// Operator tuning of babeld, applied at runtime over the management socket
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct BabelConfig {
+    /// Applied to every tunnel
+    #[serde(default = "default_babel_tunnel_config")]
+    pub tunnel_defaults: BabelInterfaceConfig,
+    /// Overrides by interface name, either a tunnel (wg3) or the physical interface tunnels
+    /// were made over (eth0) so wired and wireless links can be tuned separately. Tunnel
+    /// entries take precedence over physical ones
+    #[serde(default)]
+    pub interfaces: HashMap<String, BabelInterfaceConfig>,
+    #[serde(default)]
+    pub filters: Vec<BabelFilter>,
+}
+
+impl Default for BabelConfig {
+    fn default() -> BabelConfig {
+        BabelConfig {
+            tunnel_defaults: default_babel_tunnel_config(),
+            interfaces: HashMap::new(),
+            filters: Vec::new(),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
 pub struct NetworkSettings {
     /// How much non-financial metrics matter compared to a route's cost. By default a 2x more
@@ -66,7 +298,8 @@ pub struct NetworkSettings {
     pub peer_interfaces: HashSet<String>,
     /// List of URLs/IPs which we will manually send hellos to, used when neighbor detection fails,
     /// such as for connecting to external peers from gateways or to peer 2 althea nodes with a
-    /// complex network in between
+    /// complex network in between. Any node may have manual peers, over ipv4 or ipv6, tunnel
+    /// keepalives and WireGuard endpoint roaming keep links through NAT alive
     pub manual_peers: Vec<String>,
     /// This is a route in the format of `ip route` which is set by default (assuming it will reach
     /// the internet), used to tunnel manual peers over a specific route
@@ -81,6 +314,9 @@ pub struct NetworkSettings {
     /// How long do we wait without contact from a peer before we delete the associated tunnel?
     #[serde(default = "default_tunnel_timeout")]
     pub tunnel_timeout_seconds: u64,
+    /// How long do we try to repair a tunnel that has failed health checks before we delete it?
+    #[serde(default = "default_tunnel_repair_timeout")]
+    pub tunnel_repair_timeout_seconds: u64,
     /// The name of the device or router model
     #[serde(skip_serializing_if = "Option::is_none")]
     pub device: Option<String>,
@@ -90,6 +326,35 @@ pub struct NetworkSettings {
     /// Full file path for usage tracker storage
     #[serde(default = "default_usage_tracker_file")]
     pub usage_tracker_file: String,
+    /// Identities we refuse (or exclusively accept) as peers
+    #[serde(default)]
+    pub peer_acl: PeerAcl,
+    /// Per neighbor bandwidth limits and priority classes
+    #[serde(default)]
+    pub neighbor_shaping: Vec<NeighborShaping>,
+    /// Full file path for route history storage
+    #[serde(default = "default_route_history_file")]
+    pub route_history_file: String,
+    /// Mesh ips we keep a route history for in addition to our exits
+    #[serde(default)]
+    pub route_history_destinations: Vec<IpAddr>,
+    /// Per interface babel parameters and route filters
+    #[serde(default)]
+    pub babel_config: BabelConfig,
+    /// Path to the output of babel's `dump` command, if set this table is used for billing
+    /// instead of the one from babeld. For testing only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub static_route_table: Option<String>,
+}
+
+impl NetworkSettings {
+    /// Returns the operator set shaping for the neighbor with the given key, if any
+    pub fn get_neighbor_shaping(&self, key: &WgKey) -> Option<NeighborShaping> {
+        self.neighbor_shaping
+            .iter()
+            .find(|shaping| shaping.wg_public_key == *key)
+            .cloned()
+    }
 }
 
 impl Default for NetworkSettings {
@@ -115,9 +380,98 @@ impl Default for NetworkSettings {
             default_route: Vec::new(),
             is_gateway: false,
             tunnel_timeout_seconds: default_tunnel_timeout(),
+            tunnel_repair_timeout_seconds: default_tunnel_repair_timeout(),
             device: None,
             nickname: None,
             usage_tracker_file: default_usage_tracker_file(),
+            peer_acl: PeerAcl::default(),
+            neighbor_shaping: Vec::new(),
+            route_history_file: default_route_history_file(),
+            route_history_destinations: Vec::new(),
+            babel_config: BabelConfig::default(),
+            static_route_table: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_identity() -> Identity {
+        Identity::new(
+            "fd00::1".parse().unwrap(),
+            "ffffffffffffffffffffffffffffffffffffffff".parse().unwrap(),
+            "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+            None,
+        )
+    }
+
+    #[test]
+    fn test_peer_acl_blocklist() {
+        let id = test_identity();
+        let mut acl = PeerAcl::default();
+        assert!(acl.is_allowed(&id));
+
+        acl.entries.insert(PeerAclEntry::WgKey(id.wg_public_key));
+        assert!(!acl.is_allowed(&id));
+        assert!(!acl.is_ip_blocked(&id.mesh_ip));
+
+        acl.entries.clear();
+        acl.entries.insert(PeerAclEntry::Ip(id.mesh_ip));
+        assert!(!acl.is_allowed(&id));
+        assert!(acl.is_ip_blocked(&id.mesh_ip));
+    }
+
+    #[test]
+    fn test_peer_acl_allowlist() {
+        let id = test_identity();
+        let mut acl = PeerAcl {
+            mode: PeerAclMode::Allowlist,
+            entries: HashSet::new(),
+        };
+        assert!(!acl.is_allowed(&id));
+
+        acl.entries.insert(PeerAclEntry::EthAddress(id.eth_address));
+        assert!(acl.is_allowed(&id));
+        assert!(!acl.is_ip_blocked(&"fe80::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_neighbor_shaping_defaults() {
+        let shaping: NeighborShaping = serde_json::from_str(
+            r#"{"wg_public_key": "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="}"#,
+        )
+        .unwrap();
+        assert_eq!(shaping.max_bandwidth, None);
+        assert_eq!(shaping.priority, ShapingPriority::Normal);
+
+        let mut network = NetworkSettings::default();
+        network.neighbor_shaping.push(NeighborShaping {
+            max_bandwidth: Some(10_000),
+            ..shaping
+        });
+        let found = network
+            .get_neighbor_shaping(&test_identity().wg_public_key)
+            .unwrap();
+        assert_eq!(found.max_bandwidth, Some(10_000));
+        assert!(found.bandwidth_is_valid());
+        assert!(!NeighborShaping {
+            max_bandwidth: Some(0),
+            ..found
+        }
+        .bandwidth_is_valid());
+        assert!(NeighborShaping {
+            max_bandwidth: Some(MAX_NEIGHBOR_BANDWIDTH),
+            ..found
+        }
+        .bandwidth_is_valid());
+        assert!(!NeighborShaping {
+            max_bandwidth: Some(MAX_NEIGHBOR_BANDWIDTH + 1),
+            ..found
         }
+        .bandwidth_is_valid());
     }
 }
@@ -53,9 +53,17 @@ fn default_system_chain() -> SystemChain {
 /// debt keeper
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
 pub struct PaymentSettings {
-    /// What we charge other nodes
+    /// What we charge other nodes per relayed byte unless a direction has its own fee below
     #[serde(default = "default_local_fee")]
     pub local_fee: u32,
+    /// What we charge per byte for the upload half of relaying, sending traffic on to the
+    /// next hop
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relay_upload_fee: Option<u32>,
+    /// What we charge per byte for the download half of relaying, receiving traffic from the
+    /// neighbor that hands it to us
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relay_download_fee: Option<u32>,
     /// A price limit, we will not pay more than this
     #[serde(default = "default_max_fee")]
     pub max_fee: u32,
@@ -105,6 +113,8 @@ impl Default for PaymentSettings {
     fn default() -> Self {
         PaymentSettings {
             local_fee: 3000000,
+            relay_upload_fee: None,
+            relay_download_fee: None,
             max_fee: 73333333,
             dynamic_fee_multiplier: 20,
             free_tier_throughput: 1000,
@@ -126,3 +136,46 @@ impl Default for PaymentSettings {
         }
     }
 }
+
+impl PaymentSettings {
+    pub fn get_relay_upload_fee(&self) -> u32 {
+        self.relay_upload_fee.unwrap_or(self.local_fee)
+    }
+
+    pub fn get_relay_download_fee(&self) -> u32 {
+        self.relay_download_fee.unwrap_or(self.local_fee)
+    }
+
+    /// The fee advertised through babel. Babel only carries a single fee per node and every
+    /// relayed byte is both downloaded from one neighbor and uploaded to another, so the fee
+    /// neighbors pay per relayed byte is the mean of the two directions
+    pub fn get_relay_fee(&self) -> u32 {
+        let total =
+            u64::from(self.get_relay_upload_fee()) + u64::from(self.get_relay_download_fee());
+        (total / 2) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relay_fees() {
+        // single fee configs price both directions the same
+        let mut payment = PaymentSettings::default();
+        payment.local_fee = 1000;
+        assert_eq!(payment.get_relay_upload_fee(), 1000);
+        assert_eq!(payment.get_relay_download_fee(), 1000);
+        assert_eq!(payment.get_relay_fee(), 1000);
+
+        payment.relay_upload_fee = Some(3000);
+        assert_eq!(payment.get_relay_upload_fee(), 3000);
+        assert_eq!(payment.get_relay_download_fee(), 1000);
+        assert_eq!(payment.get_relay_fee(), 2000);
+
+        payment.relay_download_fee = Some(u32::max_value());
+        payment.relay_upload_fee = Some(u32::max_value());
+        assert_eq!(payment.get_relay_fee(), u32::max_value());
+    }
+}